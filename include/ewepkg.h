@@ -0,0 +1,38 @@
+/*
+ * C ABI of libewepkg, built with `cargo build --release --features cdylib`.
+ *
+ * Versions are ordered exactly as ewepkg orders them: by epoch, then by
+ * upstream version, then by revision, with `~` sorting before anything, even
+ * the end of a version.
+ */
+
+#ifndef EWEPKG_H
+#define EWEPKG_H
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+/* A parsed package version, like `1:2.0~rc1-3`. */
+typedef struct ewepkg_version ewepkg_version;
+
+/* Parses `s`. Returns NULL if it is not a valid version; otherwise the
+ * version is to be freed with ewepkg_version_free. */
+ewepkg_version *ewepkg_version_parse(const char *s);
+
+/* Frees a version from ewepkg_version_parse. NULL is ignored. */
+void ewepkg_version_free(ewepkg_version *version);
+
+/* Returns -1, 0 or 1 if `a` is older than, the same as or newer than `b`. */
+int ewepkg_version_cmp(const ewepkg_version *a, const ewepkg_version *b);
+
+/* Compares two upstream versions or revisions, without epochs, storing -1, 0
+ * or 1 in `result`. Returns 0, or -1 without touching `result` if either is
+ * not a valid version part. */
+int ewepkg_cmp_version(const char *a, const char *b, int *result);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
@@ -0,0 +1,248 @@
+use super::fetch::{download, tree_digest, FetchOptions};
+use super::hashing::hash_file;
+use super::lock::Lockfile;
+use super::manifest::BuildManifest;
+use crate::sign::{DigestBackend, SigningConfig};
+use crate::stats::Stats;
+use crate::types::{ChecksumKind, Hash, SourceInfo, SourceLocation};
+use crate::util::{cache_dir, is_safe_name, par_map};
+use crate::warning;
+use anyhow::anyhow;
+use clap::Args;
+use openssl::sha::Sha256;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::{self, create_dir_all, File};
+use std::io::{self, BufReader, BufWriter, Write};
+use std::path::Path;
+use tempfile::{tempdir_in, TempDir};
+use url::Url;
+
+/// What the build cache keeps for each build key, next to the package
+/// archives.
+const ENTRY_FILE: &str = "build.json";
+
+/// Options for reusing packages built from the same inputs before.
+#[derive(Debug, Clone, Args)]
+pub struct BuildCacheOptions {
+  /// Always build, without looking up or storing packages in the build cache
+  #[arg(long)]
+  pub no_build_cache: bool,
+
+  /// Also look up packages in this remote cache, laid out like the local one
+  /// as `<URL>/<build key>/build.json`
+  #[arg(long, value_name = "URL")]
+  pub build_cache_url: Option<Url>,
+}
+
+impl BuildCacheOptions {
+  /// Command line arguments reproducing these options.
+  pub fn to_args(&self) -> Vec<String> {
+    let mut args = Vec::new();
+    if self.no_build_cache {
+      args.push("--no-build-cache".into());
+    }
+    if let Some(url) = &self.build_cache_url {
+      args.push("--build-cache-url".into());
+      args.push(url.to_string());
+    }
+    args
+  }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct CachedBuild {
+  manifest: BuildManifest,
+  /// Digest of each package archive.
+  sha256: BTreeMap<Box<str>, Hash>,
+}
+
+/// Identifies a build by everything that goes into it: the build script, the
+/// sources pinned by its lockfile, local sources, the build profile and the
+/// target architecture. Returns `None` if the sources have not been pinned
+/// yet.
+pub fn build_key(
+  script: &Path,
+  source: &SourceInfo,
+  profile: &str,
+  arch: &str,
+) -> anyhow::Result<Option<String>> {
+  let lock = match fs::read(Lockfile::path_for(script)) {
+    Ok(x) => x,
+    Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+    Err(e) => return Err(e.into()),
+  };
+  let mut hasher = Sha256::new();
+  let mut field = |name: &str, data: &[u8]| {
+    hasher.update(name.as_bytes());
+    hasher.update(&(data.len() as u64).to_le_bytes());
+    hasher.update(data);
+  };
+  field("ewepkg", env!("CARGO_PKG_VERSION").as_bytes());
+  field("script", &fs::read(script)?);
+  field("lock", &lock);
+  for file in &source.source {
+    if let SourceLocation::Local(path) = &file.location {
+      field("local", &tree_digest(path)?);
+    }
+  }
+  field("profile", profile.as_bytes());
+  field("arch", arch.as_bytes());
+  Ok(Some(hex::encode(hasher.finish())))
+}
+
+/// Hashes `files` in `dir` with SHA-256, several at a time.
+fn files_sha256(dir: &Path, files: &[&str], backend: DigestBackend) -> io::Result<Vec<Hash>> {
+  let results = par_map(files, 0, |x| {
+    hash_file(&dir.join(x), backend, &ChecksumKind::Sha256)
+  });
+  let (hashes, samples): (Vec<_>, Vec<_>) = (results.into_iter())
+    .map(|x| x.map(|(digest, sample)| (digest.into(), sample)))
+    .collect::<io::Result<Vec<_>>>()?
+    .into_iter()
+    .unzip();
+  Stats::update(|stats| {
+    for x in samples {
+      stats.record_hash(x.kind, x.bytes, x.elapsed);
+    }
+  });
+  Ok(hashes)
+}
+
+/// Package archives of previous builds, keyed by [`build_key`] and kept
+/// apart by target architecture and profile.
+#[derive(Debug, Clone)]
+pub struct BuildCache {
+  dir: Box<Path>,
+  remote: Option<Url>,
+  digest: DigestBackend,
+}
+
+impl BuildCache {
+  /// Opens the part of the cache for builds in `namespace`, or returns `None`
+  /// if the build cache is disabled.
+  pub fn new(options: &BuildCacheOptions, namespace: &Path) -> anyhow::Result<Option<Self>> {
+    if options.no_build_cache {
+      return Ok(None);
+    }
+    let dir = cache_dir()
+      .ok_or_else(|| anyhow!("cannot determine cache directory"))?
+      .join("builds")
+      .join(namespace);
+    create_dir_all(&dir)?;
+    Ok(Some(Self {
+      dir: dir.into(),
+      remote: options.build_cache_url.clone(),
+      digest: SigningConfig::load()?.digest,
+    }))
+  }
+
+  /// Moves the complete entry in `tmp` into place for `key`.
+  fn persist(&self, tmp: TempDir, key: &str) -> io::Result<()> {
+    let dir = self.dir.join(key);
+    if dir.exists() {
+      fs::remove_dir_all(&dir)?;
+    }
+    fs::rename(tmp.path(), dir)
+  }
+
+  /// Reads the entry in `dir`, checking that its archives are intact.
+  fn load(&self, dir: &Path) -> anyhow::Result<Option<CachedBuild>> {
+    let f = match File::open(dir.join(ENTRY_FILE)) {
+      Ok(f) => f,
+      Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+      Err(e) => return Err(e.into()),
+    };
+    let entry: CachedBuild = serde_json::from_reader(BufReader::new(f))?;
+    if let Some(file) = (entry.sha256.keys()).find(|x| !is_safe_name(x) || x.contains('/')) {
+      warning!("ignoring cached build with invalid file name `{file}`");
+      return Ok(None);
+    }
+    let files = entry.sha256.keys().map(|x| &**x).collect::<Vec<_>>();
+    let hashes = files_sha256(dir, &files, self.digest)?;
+    for ((file, expected), hash) in entry.sha256.iter().zip(hashes) {
+      if hash != *expected {
+        warning!("ignoring cached build with corrupted `{file}`");
+        return Ok(None);
+      }
+    }
+    Ok(Some(entry))
+  }
+
+  /// Downloads the entry for `key` from the remote cache into the local one.
+  fn fetch_remote(&self, key: &str, options: &FetchOptions) -> anyhow::Result<bool> {
+    let Some(remote) = &self.remote else {
+      return Ok(false);
+    };
+    let url = |file: &str| {
+      let mut url = remote.clone();
+      (url.path_segments_mut())
+        .map_err(|_| anyhow!("invalid build cache URL `{remote}`"))?
+        .pop_if_empty()
+        .extend([key, file]);
+      anyhow::Ok(url)
+    };
+    let tmp = tempdir_in(&self.dir)?;
+    if !download(&url(ENTRY_FILE)?, &tmp.path().join(ENTRY_FILE), options)? {
+      return Ok(false);
+    }
+    let f = File::open(tmp.path().join(ENTRY_FILE))?;
+    let entry: CachedBuild = serde_json::from_reader(BufReader::new(f))?;
+    for file in entry.sha256.keys() {
+      if !is_safe_name(file) || file.contains('/') {
+        return Ok(false);
+      }
+      if !download(&url(file)?, &tmp.path().join(&**file), options)? {
+        return Ok(false);
+      }
+    }
+    self.persist(tmp, key)?;
+    Ok(true)
+  }
+
+  /// Copies the packages built with `key` into `dst`, looking in the remote
+  /// cache if they are not cached locally. Returns their manifest, or `None`
+  /// on a cache miss.
+  pub fn restore(
+    &self,
+    key: &str,
+    dst: &Path,
+    options: &FetchOptions,
+  ) -> anyhow::Result<Option<BuildManifest>> {
+    let dir = self.dir.join(key);
+    let entry = match self.load(&dir)? {
+      Some(entry) => entry,
+      None if self.fetch_remote(key, options)? => match self.load(&dir)? {
+        Some(entry) => entry,
+        None => return Ok(None),
+      },
+      None => return Ok(None),
+    };
+    for file in entry.sha256.keys() {
+      fs::copy(dir.join(&**file), dst.join(&**file))?;
+    }
+    Ok(Some(entry.manifest))
+  }
+
+  /// Stores the packages of `manifest` found in `src` under `key`.
+  pub fn store(&self, key: &str, manifest: &BuildManifest, src: &Path) -> anyhow::Result<()> {
+    let tmp = tempdir_in(&self.dir)?;
+    for file in &manifest.packages {
+      fs::copy(src.join(&**file), tmp.path().join(&**file))?;
+    }
+    let files = manifest.packages.iter().map(|x| &**x).collect::<Vec<_>>();
+    let hashes = files_sha256(tmp.path(), &files, self.digest)?;
+    let sha256 = manifest.packages.iter().cloned().zip(hashes).collect();
+    let entry = CachedBuild {
+      manifest: manifest.clone(),
+      sha256,
+    };
+    let mut f = BufWriter::new(File::create(tmp.path().join(ENTRY_FILE))?);
+    serde_json::to_writer_pretty(&mut f, &entry)?;
+    f.write_all(b"\n")?;
+    drop(f);
+
+    self.persist(tmp, key)?;
+    Ok(())
+  }
+}
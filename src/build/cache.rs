@@ -0,0 +1,182 @@
+use crate::util::cache_dir;
+use anyhow::anyhow;
+use openssl::sha::sha256;
+use reqwest::header::{HeaderMap, ETAG, LAST_MODIFIED};
+use serde::{Deserialize, Serialize};
+use std::fs::{create_dir_all, remove_file, rename, File};
+use std::io::{self, BufReader, BufWriter};
+use std::path::{Path, PathBuf};
+use tempfile::{Builder, NamedTempFile};
+use url::Url;
+
+/// HTTP validators of a cached response, used to revalidate entries whose
+/// content cannot be checked against a known checksum.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Validators {
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub etag: Option<String>,
+
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub last_modified: Option<String>,
+}
+
+impl Validators {
+  pub fn from_headers(headers: &HeaderMap) -> Self {
+    let get = |name| {
+      headers
+        .get(name)
+        .and_then(|x| x.to_str().ok())
+        .map(String::from)
+    };
+    Self {
+      etag: get(ETAG),
+      last_modified: get(LAST_MODIFIED),
+    }
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.etag.is_none() && self.last_modified.is_none()
+  }
+
+  /// The validator to resume a download with in `If-Range`, which has to be
+  /// a strong one.
+  pub fn if_range(&self) -> Option<&str> {
+    match &self.etag {
+      Some(etag) if !etag.starts_with("W/") => Some(etag),
+      _ => self.last_modified.as_deref(),
+    }
+  }
+}
+
+/// Metadata stored alongside a cache entry.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct EntryMeta {
+  #[serde(flatten)]
+  pub validators: Validators,
+
+  #[serde(default, skip_serializing_if = "Vec::is_empty")]
+  pub redirects: Vec<Url>,
+
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub final_url: Option<Url>,
+}
+
+/// Metadata of an interrupted download kept to be resumed.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Partial {
+  /// Where the download came from, which may be a mirror.
+  pub from: Url,
+
+  #[serde(flatten)]
+  pub validators: Validators,
+}
+
+#[derive(Debug, Clone)]
+pub struct SourceCache {
+  dir: Box<Path>,
+}
+
+impl SourceCache {
+  pub fn new() -> anyhow::Result<Self> {
+    let dir = cache_dir()
+      .ok_or_else(|| anyhow!("cannot determine cache directory"))?
+      .join("sources");
+    Self::in_dir(dir)
+  }
+
+  /// Keeps the cache in `dir` instead of the user's cache directory.
+  pub fn in_dir(dir: PathBuf) -> anyhow::Result<Self> {
+    create_dir_all(&dir)?;
+    Ok(Self { dir: dir.into() })
+  }
+
+  fn key(url: &Url) -> String {
+    hex::encode(sha256(url.as_str().as_bytes()))
+  }
+
+  pub fn entry(&self, url: &Url) -> CacheEntry {
+    let key = Self::key(url);
+    CacheEntry {
+      path: self.dir.join(&key).into(),
+      meta_path: self.dir.join(format!("{key}.json")).into(),
+      partial_path: self.dir.join(format!("{key}.part")).into(),
+      partial_meta_path: self.dir.join(format!("{key}.part.json")).into(),
+    }
+  }
+
+  /// Where the bare mirror of the Git repository at `url` is kept.
+  pub fn git_dir(&self, url: &Url) -> PathBuf {
+    self.dir.join(format!("{}.git", Self::key(url)))
+  }
+
+  /// Creates a temporary file inside the cache directory, so that it can be
+  /// atomically persisted into an entry afterwards.
+  pub fn tempfile(&self) -> io::Result<NamedTempFile> {
+    NamedTempFile::new_in(&self.dir)
+  }
+}
+
+#[derive(Debug, Clone)]
+pub struct CacheEntry {
+  path: Box<Path>,
+  meta_path: Box<Path>,
+  partial_path: Box<Path>,
+  partial_meta_path: Box<Path>,
+}
+
+impl CacheEntry {
+  pub fn path(&self) -> &Path {
+    &self.path
+  }
+
+  pub fn exists(&self) -> bool {
+    self.path.is_file()
+  }
+
+  pub fn meta(&self) -> EntryMeta {
+    File::open(&self.meta_path)
+      .ok()
+      .and_then(|f| serde_json::from_reader(BufReader::new(f)).ok())
+      .unwrap_or_default()
+  }
+
+  pub fn set_meta(&self, meta: &EntryMeta) -> anyhow::Result<()> {
+    let f = BufWriter::new(File::create(&self.meta_path)?);
+    serde_json::to_writer(f, meta)?;
+    Ok(())
+  }
+
+  pub fn store(&self, file: NamedTempFile, meta: &EntryMeta) -> anyhow::Result<()> {
+    self.set_meta(meta)?;
+    file.persist(&self.path)?;
+    Ok(())
+  }
+
+  /// Keeps the interrupted download `file` of the entry to be resumed later,
+  /// replacing any other.
+  pub fn keep_partial(&self, file: NamedTempFile, partial: &Partial) -> anyhow::Result<()> {
+    let f = BufWriter::new(File::create(&self.partial_meta_path)?);
+    serde_json::to_writer(f, partial)?;
+    file.persist(&self.partial_path)?;
+    Ok(())
+  }
+
+  /// Moves the interrupted download of the entry from `from`, if any, to a
+  /// temporary file, so that no other download takes it too.
+  pub fn take_partial(&self, from: &Url) -> Option<(NamedTempFile, Partial)> {
+    let f = File::open(&self.partial_meta_path).ok()?;
+    let partial: Partial = serde_json::from_reader(BufReader::new(f)).ok()?;
+    if partial.from != *from {
+      return None;
+    }
+    let dir = self.partial_path.parent()?;
+    let file = Builder::new()
+      .make_in(dir, |path| {
+        rename(&self.partial_path, path)?;
+        File::options().read(true).write(true).open(path)
+      })
+      .ok()?;
+    let _ = remove_file(&self.partial_meta_path);
+    Some((file, partial))
+  }
+}
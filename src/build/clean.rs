@@ -0,0 +1,290 @@
+use super::exec::{allocated, disk_usage};
+use super::manifest::BuildManifest;
+use crate::util::cache_dir;
+use crate::{segment_info, warning};
+use anyhow::{anyhow, bail};
+use clap::Args;
+use indicatif::HumanBytes;
+use std::collections::BTreeMap;
+use std::env::temp_dir;
+use std::fs::{self, File};
+use std::io::{self, BufReader};
+use std::path::{Path, PathBuf};
+use tempfile::{Builder, TempDir};
+
+/// Prefix of build directories in the temporary directory, telling leftover
+/// ones apart from those of other programs. It is followed by the target
+/// architecture and profile, as `ewe-build-<arch>+<profile>-<random>`.
+const BUILD_DIR_PREFIX: &str = "ewe-build-";
+
+/// Options for removing what builds leave behind.
+#[derive(Debug, Clone, Args)]
+pub struct CleanOptions {
+  /// Remove the download cache
+  #[arg(long)]
+  pub sources: bool,
+
+  /// Remove the build cache
+  #[arg(long)]
+  pub builds: bool,
+
+  /// Remove build directories of kept or interrupted builds
+  #[arg(long)]
+  pub build_dirs: bool,
+
+  /// Also remove packages in this directory superseded by newer versions
+  #[arg(short, long, value_name = "DIR")]
+  pub output: Option<PathBuf>,
+
+  /// Only remove what was built for this architecture
+  #[arg(long)]
+  pub arch: Option<String>,
+
+  /// Only remove what was built with this profile, e.g. `asan`, or `default`
+  /// for regular builds
+  #[arg(long)]
+  pub profile: Option<String>,
+
+  /// Only list what would be removed
+  #[arg(short = 'n', long)]
+  pub dry_run: bool,
+}
+
+impl CleanOptions {
+  fn matches(&self, arch: &str, profile: &str) -> bool {
+    self.arch.as_ref().is_none_or(|x| x == arch)
+      && self.profile.as_ref().is_none_or(|x| x == profile)
+  }
+}
+
+#[cfg(unix)]
+fn flock(f: &File, operation: libc::c_int) -> io::Result<()> {
+  use std::os::fd::AsRawFd;
+  // SAFETY: takes no pointers.
+  match unsafe { libc::flock(f.as_raw_fd(), operation) } {
+    0 => Ok(()),
+    _ => Err(io::Error::last_os_error()),
+  }
+}
+
+/// Whether a build holds the lock of the build directory at `path`.
+#[cfg(unix)]
+fn is_in_use(path: &Path) -> io::Result<bool> {
+  match flock(&File::open(path)?, libc::LOCK_EX | libc::LOCK_NB) {
+    Ok(()) => Ok(false),
+    Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(true),
+    Err(e) => Err(e),
+  }
+}
+
+#[cfg(not(unix))]
+fn is_in_use(_: &Path) -> io::Result<bool> {
+  Ok(true)
+}
+
+/// Temporary directory a build runs in, locked while it does so that
+/// `ewe clean` leaves it alone.
+#[derive(Debug)]
+pub struct BuildDir {
+  dir: TempDir,
+  keep: bool,
+  _lock: File,
+}
+
+impl BuildDir {
+  /// Creates a build directory for `arch` and `profile`, which with `keep`
+  /// stays after the build.
+  pub fn new(keep: bool, arch: &str, profile: &str) -> io::Result<Self> {
+    let dir = Builder::new()
+      .prefix(&format!("{BUILD_DIR_PREFIX}{arch}+{profile}-"))
+      .disable_cleanup(keep)
+      .tempdir()?;
+    let lock = File::open(dir.path())?;
+    #[cfg(unix)]
+    flock(&lock, libc::LOCK_SH)?;
+    Ok(Self {
+      dir,
+      keep,
+      _lock: lock,
+    })
+  }
+
+  pub fn path(&self) -> &Path {
+    self.dir.path()
+  }
+}
+
+impl Drop for BuildDir {
+  fn drop(&mut self) {
+    if self.keep {
+      segment_info!("Kept build directory", "{}", self.path().display());
+    }
+  }
+}
+
+/// Disk space taken up by the file or directory at `path`.
+fn size(path: &Path) -> io::Result<u64> {
+  let meta = path.symlink_metadata()?;
+  match meta.is_dir() {
+    true => Ok(allocated(&meta) + disk_usage(path)?),
+    false => Ok(allocated(&meta)),
+  }
+}
+
+/// Target architecture and profile of the build directory named `name`.
+fn parse_build_dir(name: &str) -> Option<(&str, &str)> {
+  let (arch, rest) = name.strip_prefix(BUILD_DIR_PREFIX)?.split_once('+')?;
+  Some((arch, rest.rsplit_once('-')?.0))
+}
+
+/// Build directories matching `options` left by builds that were kept or did
+/// not finish.
+fn leftover_build_dirs(options: &CleanOptions) -> io::Result<Vec<PathBuf>> {
+  let mut dirs = Vec::new();
+  for entry in fs::read_dir(temp_dir())? {
+    let entry = entry?;
+    let name = entry.file_name();
+    let matches = (name.to_str().and_then(parse_build_dir))
+      .is_some_and(|(arch, profile)| options.matches(arch, profile));
+    if matches && entry.file_type()?.is_dir() && !is_in_use(&entry.path())? {
+      dirs.push(entry.path());
+    }
+  }
+  dirs.sort();
+  Ok(dirs)
+}
+
+/// Parts of the build cache at `dir` matching `options`.
+fn build_cache_dirs(dir: &Path, options: &CleanOptions) -> io::Result<Vec<PathBuf>> {
+  if options.arch.is_none() && options.profile.is_none() {
+    return Ok(vec![dir.into()]);
+  }
+  let mut dirs = Vec::new();
+  for arch in fs::read_dir(dir)? {
+    let arch = arch?;
+    if !arch.file_type()?.is_dir() {
+      continue;
+    }
+    for profile in fs::read_dir(arch.path())? {
+      let profile = profile?;
+      let matches = options.matches(
+        &arch.file_name().to_string_lossy(),
+        &profile.file_name().to_string_lossy(),
+      );
+      if matches && profile.file_type()?.is_dir() {
+        dirs.push(profile.path());
+      }
+    }
+  }
+  dirs.sort();
+  Ok(dirs)
+}
+
+/// Manifests in `output` of builds for `arch`, or any architecture, that are
+/// superseded by a newer version of the same package for the same
+/// architecture, and the archives they list.
+fn old_packages(output: &Path, arch: Option<&str>) -> anyhow::Result<Vec<PathBuf>> {
+  let mut builds = BTreeMap::<_, Vec<_>>::new();
+  for entry in fs::read_dir(output)? {
+    let path = entry?.path();
+    let is_manifest =
+      (path.file_name().and_then(|x| x.to_str())).is_some_and(|x| x.ends_with(".manifest.json"));
+    if !is_manifest {
+      continue;
+    }
+    let f = File::open(&path)?;
+    let manifest: BuildManifest = match serde_json::from_reader(BufReader::new(f)) {
+      Ok(manifest) => manifest,
+      Err(e) => {
+        warning!("skipping {}: {e}", path.display());
+        continue;
+      }
+    };
+    if arch.is_some_and(|x| x != manifest.architecture) {
+      continue;
+    }
+    let key = (manifest.name.clone(), manifest.architecture.clone());
+    builds.entry(key).or_default().push((manifest, path));
+  }
+
+  let mut old = Vec::new();
+  for mut builds in builds.into_values() {
+    builds.sort_by(|a, b| b.0.version.cmp(&a.0.version));
+    for (manifest, path) in builds.into_iter().skip(1) {
+      let archives = (manifest.packages.iter()).map(|x| output.join(&**x));
+      old.extend(archives.filter(|x| x.exists()));
+      old.push(path);
+    }
+  }
+  Ok(old)
+}
+
+/// Removes the download cache, the build cache, leftover build directories,
+/// or all three if none is asked for, and superseded packages in the output
+/// directory. With a target architecture or profile, only removes what was
+/// built for it, leaving the download cache shared by all builds alone.
+pub fn clean(options: CleanOptions) -> anyhow::Result<()> {
+  let filtered = options.arch.is_some() || options.profile.is_some();
+  let everything = !(options.sources || options.builds || options.build_dirs);
+  if filtered && options.sources {
+    bail!("the download cache is shared by all architectures and profiles");
+  }
+  if options.profile.is_some() && options.output.is_some() {
+    bail!("packages in the output directory are not kept apart by profile");
+  }
+  let mut paths = Vec::new();
+  let cache = cache_dir().ok_or_else(|| anyhow!("cannot determine cache directory"))?;
+  let sources = cache.join("sources");
+  if (everything && !filtered || options.sources) && sources.exists() {
+    paths.push(sources);
+  }
+  let builds = cache.join("builds");
+  if (everything || options.builds) && builds.exists() {
+    paths.extend(build_cache_dirs(&builds, &options)?);
+  }
+  if everything || options.build_dirs {
+    paths.extend(leftover_build_dirs(&options)?);
+  }
+  if let Some(output) = &options.output {
+    paths.extend(old_packages(output, options.arch.as_deref())?);
+  }
+
+  let mut freed = 0;
+  for path in &paths {
+    let bytes = size(path)?;
+    freed += bytes;
+    println!("{} ({})", path.display(), HumanBytes(bytes));
+    if options.dry_run {
+      continue;
+    }
+    match path.is_dir() {
+      true => fs::remove_dir_all(path)?,
+      false => fs::remove_file(path)?,
+    }
+  }
+  if options.dry_run {
+    segment_info!("Would free", "{}", HumanBytes(freed));
+  } else {
+    segment_info!("Freed", "{}", HumanBytes(freed));
+  }
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_parse_build_dir() {
+    let parse = parse_build_dir;
+    assert_eq!(
+      parse("ewe-build-x86_64+default-Ab12Cd"),
+      Some(("x86_64", "default"))
+    );
+    assert_eq!(
+      parse("ewe-build-aarch64+map-build-paths,asan-Ab12Cd"),
+      Some(("aarch64", "map-build-paths,asan"))
+    );
+    assert_eq!(parse("ewe-build-Ab12Cd"), None);
+  }
+}
@@ -0,0 +1,431 @@
+use crate::warning;
+use serde::Deserialize;
+use std::fs::{self, File};
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::process::{Command, ExitStatus, Stdio};
+use std::time::SystemTime;
+use tempfile::tempdir;
+
+const ET_CORE: u64 = 4;
+const PT_NOTE: u64 = 4;
+const NT_AUXV: u64 = 6;
+const NT_FILE: u64 = 0x4649_4c45;
+const AT_NULL: u64 = 0;
+const AT_ENTRY: u64 = 9;
+
+/// Most core dumps shown after a failed command.
+const MAX_CORES: usize = 3;
+
+/// Most program headers read from a core, the most its header can count
+/// without the extended numbering this reader does not follow.
+const MAX_PHNUM: u64 = 0xffff;
+
+/// Integers in the class and byte order of an ELF file.
+#[derive(Debug, Clone, Copy)]
+struct Layout {
+  wide: bool,
+  little: bool,
+}
+
+impl Layout {
+  /// Reads the identification at the start of `header`, returning `None` if
+  /// it is not an ELF file.
+  fn parse(header: &[u8]) -> Option<Self> {
+    if !header.starts_with(b"\x7fELF") {
+      return None;
+    }
+    let wide = match header.get(4)? {
+      1 => false,
+      2 => true,
+      _ => return None,
+    };
+    let little = match header.get(5)? {
+      1 => true,
+      2 => false,
+      _ => return None,
+    };
+    Some(Self { wide, little })
+  }
+
+  fn word(&self) -> usize {
+    if self.wide {
+      8
+    } else {
+      4
+    }
+  }
+
+  fn read(&self, data: &[u8], offset: usize, size: usize) -> Option<u64> {
+    let bytes = data.get(offset..offset.checked_add(size)?)?;
+    let mut buf = [0; 8];
+    if self.little {
+      buf[..size].copy_from_slice(bytes);
+      Some(u64::from_le_bytes(buf))
+    } else {
+      buf[8 - size..].copy_from_slice(bytes);
+      Some(u64::from_be_bytes(buf))
+    }
+  }
+
+  /// Words of `data`, up to the last whole one.
+  fn words<'a>(&self, data: &'a [u8]) -> impl Iterator<Item = u64> + 'a {
+    let layout = *self;
+    (data.chunks_exact(self.word())).map(move |x| layout.read(x, 0, x.len()).unwrap_or_default())
+  }
+
+  /// `(type, descriptor)` of each note in `data`.
+  fn notes<'a>(&self, data: &'a [u8]) -> Vec<(u64, &'a [u8])> {
+    let align = |x: usize| x.next_multiple_of(4);
+    let mut notes = Vec::new();
+    let mut offset = 0;
+    while let (Some(namesz), Some(descsz), Some(kind)) = (
+      self.read(data, offset, 4),
+      self.read(data, offset + 4, 4),
+      self.read(data, offset + 8, 4),
+    ) {
+      let desc = offset + 12 + align(namesz as usize);
+      let Some(bytes) = data.get(desc..desc.saturating_add(descsz as usize)) else {
+        break;
+      };
+      notes.push((kind, bytes));
+      offset = desc + align(descsz as usize);
+    }
+    notes
+  }
+}
+
+/// Whether the file at `path` is an ELF core dump.
+fn is_core(path: &Path) -> bool {
+  let mut header = [0; 18];
+  let read = File::open(path).and_then(|mut f| f.read_exact(&mut header));
+  let layout = read.ok().and_then(|_| Layout::parse(&header));
+  layout.and_then(|x| x.read(&header, 16, 2)) == Some(ET_CORE)
+}
+
+/// Executable that dumped the core at `path`, as the file mapped at the
+/// entry point of the process.
+fn core_executable(path: &Path) -> Option<PathBuf> {
+  let mut f = File::open(path).ok()?;
+  let mut header = [0; 64];
+  f.read_exact(&mut header).ok()?;
+  let elf = Layout::parse(&header)?;
+  let (phoff, phentsize, phnum) = if elf.wide {
+    (
+      elf.read(&header, 32, 8)?,
+      elf.read(&header, 54, 2)?,
+      elf.read(&header, 56, 2)?,
+    )
+  } else {
+    (
+      elf.read(&header, 28, 4)?,
+      elf.read(&header, 42, 2)?,
+      elf.read(&header, 44, 2)?,
+    )
+  };
+  let len = f.metadata().ok()?.len();
+  let size = phentsize
+    .checked_mul(phnum)
+    .filter(|_| phnum <= MAX_PHNUM)?;
+  if phoff.checked_add(size)? > len {
+    return None;
+  }
+  let mut phdrs = vec![0; usize::try_from(size).ok()?];
+  f.seek(SeekFrom::Start(phoff)).ok()?;
+  f.read_exact(&mut phdrs).ok()?;
+
+  let mut entry = None;
+  let mut files = Vec::new();
+  for i in 0..phnum as usize {
+    let ph = i * phentsize as usize;
+    if elf.read(&phdrs, ph, 4)? != PT_NOTE {
+      continue;
+    }
+    // (p_offset, p_filesz)
+    let (offset, filesz) = if elf.wide {
+      (elf.read(&phdrs, ph + 8, 8)?, elf.read(&phdrs, ph + 32, 8)?)
+    } else {
+      (elf.read(&phdrs, ph + 4, 4)?, elf.read(&phdrs, ph + 16, 4)?)
+    };
+    // Notes take up a few pages, unlike the memory dumped next to them.
+    let filesz = filesz.min(64 << 20);
+    if offset.checked_add(filesz)? > len {
+      return None;
+    }
+    let mut data = vec![0; usize::try_from(filesz).ok()?];
+    f.seek(SeekFrom::Start(offset)).ok()?;
+    f.read_exact(&mut data).ok()?;
+    for (kind, desc) in elf.notes(&data) {
+      match kind {
+        NT_AUXV => {
+          let auxv = elf.words(desc).collect::<Vec<_>>();
+          entry = (auxv.chunks_exact(2))
+            .take_while(|x| x[0] != AT_NULL)
+            .find(|x| x[0] == AT_ENTRY)
+            .map(|x| x[1]);
+        }
+        NT_FILE => {
+          // Count and page size, a (start, end, page offset) for each file,
+          // then their names.
+          let words = elf.words(desc).collect::<Vec<_>>();
+          let count = usize::try_from(*words.first()?).ok()?;
+          let end = count.checked_mul(3)?.checked_add(2)?;
+          let ranges = words.get(2..end)?.chunks_exact(3);
+          let names = desc.get(end.checked_mul(elf.word())?..)?.split(|x| *x == 0);
+          let names = names.map(|x| PathBuf::from(String::from_utf8_lossy(x).into_owned()));
+          files = ranges.map(|x| (x[0], x[1])).zip(names).collect();
+        }
+        _ => {}
+      }
+    }
+  }
+  let entry = entry?;
+  let (_, name) = files
+    .into_iter()
+    .find(|((start, end), _)| (*start..*end).contains(&entry))?;
+  Some(name)
+}
+
+/// Best-effort backtrace of every thread in the core dump at `core`, from
+/// gdb or else eu-stack, using the debug info of `exe` and the libraries it
+/// loaded.
+fn backtrace(core: &Path, exe: Option<&Path>) -> Option<String> {
+  let mut gdb = Command::new("gdb");
+  gdb.args(["-batch", "-nx", "-ex", "thread apply all bt"]);
+  gdb.args(exe).arg(core);
+  let mut eu_stack = Command::new("eu-stack");
+  eu_stack.args(["-s", "--core"]).arg(core);
+  if let Some(exe) = exe {
+    eu_stack.arg("--executable").arg(exe);
+  }
+  [gdb, eu_stack].into_iter().find_map(|mut command| {
+    let output = (command.stdin(Stdio::null()).stderr(Stdio::null()))
+      .output()
+      .ok()?;
+    let output = String::from_utf8_lossy(&output.stdout)
+      .trim_end()
+      .to_string();
+    (!output.is_empty()).then_some(output)
+  })
+}
+
+/// Core dumps changed since `since` in `dir`, and below it with `recursive`.
+fn find_cores(dir: &Path, since: SystemTime, recursive: bool, cores: &mut Vec<PathBuf>) {
+  let mut stack = vec![dir.to_path_buf()];
+  while let Some(dir) = stack.pop() {
+    let Ok(entries) = fs::read_dir(&dir) else {
+      continue;
+    };
+    for entry in entries.flatten() {
+      let Ok(meta) = entry.metadata() else {
+        continue;
+      };
+      if meta.is_dir() && recursive {
+        stack.push(entry.path());
+      } else if meta.is_file()
+        && meta.modified().is_ok_and(|x| x >= since)
+        && is_core(&entry.path())
+      {
+        cores.push(entry.path());
+      }
+    }
+  }
+}
+
+/// The kernel's template for where core dumps go, see core(5).
+fn core_pattern() -> Option<String> {
+  let pattern = fs::read_to_string("/proc/sys/kernel/core_pattern").ok()?;
+  Some(pattern.trim_end().to_string())
+}
+
+#[derive(Debug, Deserialize)]
+struct CoredumpctlEntry {
+  pid: u32,
+  uid: u32,
+  #[serde(default)]
+  exe: PathBuf,
+}
+
+/// Process IDs of our processes running executables in `dirs` whose cores
+/// systemd-coredump kept since `since`.
+#[cfg(unix)]
+fn coredumpctl_pids(since: SystemTime, dirs: &[PathBuf]) -> Vec<u32> {
+  let secs = (since.duration_since(SystemTime::UNIX_EPOCH)).map_or(0, |x| x.as_secs());
+  let output = Command::new("coredumpctl")
+    .args(["--no-pager", "--json=short", "list"])
+    .arg(format!("--since=@{secs}"))
+    .stdin(Stdio::null())
+    .stderr(Stdio::null())
+    .output();
+  let Ok(output) = output else {
+    return Vec::new();
+  };
+  let entries = serde_json::from_slice::<Vec<CoredumpctlEntry>>(&output.stdout);
+  // SAFETY: takes no pointers.
+  let uid = unsafe { libc::getuid() };
+  (entries.unwrap_or_default().into_iter())
+    .filter(|x| x.uid == uid && dirs.iter().any(|dir| x.exe.starts_with(dir)))
+    .map(|x| x.pid)
+    .collect()
+}
+
+#[cfg(not(unix))]
+fn coredumpctl_pids(_: SystemTime, _: &[PathBuf]) -> Vec<u32> {
+  Vec::new()
+}
+
+/// Whether a command exiting with `status` crashed, either killed by a signal
+/// itself or, being a shell, reporting a command it ran killed by one as 128
+/// plus the signal.
+#[cfg(unix)]
+fn crashed(status: ExitStatus) -> bool {
+  use std::os::unix::process::ExitStatusExt;
+  status.signal().is_some() || status.code().is_some_and(|x| (129..=192).contains(&x))
+}
+
+#[cfg(not(unix))]
+fn crashed(_: ExitStatus) -> bool {
+  false
+}
+
+/// Core dumps of processes crashing while a build command runs, looked for in
+/// the directories it works in, where the kernel writes them with a relative
+/// `core_pattern`, and in systemd-coredump.
+#[derive(Debug, Clone)]
+pub struct CoreDumps {
+  dirs: Vec<PathBuf>,
+  since: SystemTime,
+}
+
+impl CoreDumps {
+  /// Starts watching for core dumps in `dirs`.
+  pub fn new(dirs: &[&Path]) -> Self {
+    Self {
+      dirs: dirs.iter().map(|x| x.to_path_buf()).collect(),
+      since: SystemTime::now(),
+    }
+  }
+
+  /// Lets `command` and its children dump core, as much as the hard limit on
+  /// core dump size allows.
+  pub fn enable(&self, command: &mut std::process::Command) {
+    #[cfg(unix)]
+    {
+      use std::os::unix::process::CommandExt;
+      // SAFETY: only makes system calls, which are async-signal-safe.
+      unsafe {
+        command.pre_exec(|| {
+          let mut limit = libc::rlimit {
+            rlim_cur: 0,
+            rlim_max: 0,
+          };
+          // SAFETY: `limit` outlives the calls.
+          if libc::getrlimit(libc::RLIMIT_CORE, &mut limit) == 0 {
+            limit.rlim_cur = limit.rlim_max;
+            libc::setrlimit(libc::RLIMIT_CORE, &limit);
+          }
+          Ok(())
+        });
+      }
+    }
+    #[cfg(not(unix))]
+    let _ = command;
+  }
+
+  /// If a command exiting with `status` crashed, shows where the processes
+  /// that crashed since [`Self::new`] dumped core, with a backtrace of each.
+  pub fn report(&self, status: ExitStatus) {
+    if !crashed(status) {
+      return;
+    }
+    let mut cores = Vec::new();
+    for dir in &self.dirs {
+      find_cores(dir, self.since, true, &mut cores);
+    }
+    let pattern = core_pattern().unwrap_or_default();
+    if pattern.starts_with('/') {
+      if let Some(dir) = Path::new(&pattern).parent() {
+        find_cores(dir, self.since, false, &mut cores);
+      }
+    }
+    // Cores kept by systemd-coredump are extracted to be read like the
+    // others.
+    let tmp = tempdir().ok();
+    if let (true, Some(tmp)) = (pattern.contains("systemd-coredump"), &tmp) {
+      let dirs = (self.dirs.iter())
+        .map(|x| fs::canonicalize(x).unwrap_or_else(|_| x.clone()))
+        .collect::<Vec<_>>();
+      for pid in coredumpctl_pids(self.since, &dirs)
+        .into_iter()
+        .take(MAX_CORES)
+      {
+        let core = tmp.path().join(format!("core.{pid}"));
+        let status = Command::new("coredumpctl")
+          .args(["--no-pager", "dump"])
+          .arg(format!("--output={}", core.display()))
+          .arg(pid.to_string())
+          .stdin(Stdio::null())
+          .stderr(Stdio::null())
+          .status();
+        if status.is_ok_and(|x| x.success()) {
+          cores.push(core);
+        }
+      }
+    }
+
+    if cores.len() > MAX_CORES {
+      warning!(
+        "{} processes dumped core, showing the first {MAX_CORES}",
+        cores.len()
+      );
+    }
+    let mut missing_debugger = false;
+    for core in cores.iter().take(MAX_CORES) {
+      let exe = core_executable(core);
+      let name = exe.as_deref().unwrap_or(Path::new("a process")).display();
+      if tmp.as_ref().is_some_and(|x| core.starts_with(x.path())) {
+        warning!("{name} crashed, see `coredumpctl info`");
+      } else {
+        warning!("{name} crashed, dumping core to {}", core.display());
+      }
+      match backtrace(core, exe.as_deref()) {
+        Some(trace) => trace.lines().for_each(|x| eprintln!("  {x}")),
+        None => missing_debugger = true,
+      }
+    }
+    if missing_debugger {
+      warning!("install gdb or elfutils to see backtraces of core dumps");
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_notes() {
+    let layout = Layout {
+      wide: true,
+      little: true,
+    };
+    let mut data = Vec::new();
+    for (kind, name, desc) in [
+      (NT_AUXV, &b"CORE\0"[..], &[9u8, 0, 0, 0, 0, 0, 0, 0][..]),
+      (NT_FILE, b"CORE\0", b"ab"),
+    ] {
+      data.extend((name.len() as u32).to_le_bytes());
+      data.extend((desc.len() as u32).to_le_bytes());
+      data.extend((kind as u32).to_le_bytes());
+      data.extend(name);
+      data.resize(data.len().next_multiple_of(4), 0);
+      data.extend(desc);
+      data.resize(data.len().next_multiple_of(4), 0);
+    }
+    let notes = layout.notes(&data);
+    assert_eq!(notes.len(), 2);
+    assert_eq!(notes[0].0, NT_AUXV);
+    assert_eq!(layout.words(notes[0].1).collect::<Vec<_>>(), [AT_ENTRY]);
+    assert_eq!(notes[1], (NT_FILE, &b"ab"[..]));
+  }
+}
@@ -0,0 +1,174 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
+
+const APPLICATIONS_DIR: &str = "usr/share/applications";
+const ICON_EXTENSIONS: &[&str] = &["png", "svg", "xpm"];
+
+const BOOLEAN_KEYS: &[&str] = &[
+  "NoDisplay",
+  "Hidden",
+  "DBusActivatable",
+  "Terminal",
+  "StartupNotify",
+  "PrefersNonDefaultGPU",
+  "SingleMainWindow",
+];
+
+/// Tells whether `file`, relative to the package root, is a desktop entry.
+pub fn is_desktop_entry(file: &Path) -> bool {
+  file.starts_with(APPLICATIONS_DIR) && file.extension().is_some_and(|x| x == "desktop")
+}
+
+/// The `[Desktop Entry]` group of a desktop entry file, see
+/// https://specifications.freedesktop.org/desktop-entry-spec/latest/.
+#[derive(Debug, Default)]
+pub struct DesktopEntry {
+  entries: BTreeMap<String, String>,
+}
+
+impl DesktopEntry {
+  /// Parses a desktop entry, checking the parts of the spec every reader
+  /// relies on.
+  pub fn parse(text: &str) -> Result<Self, String> {
+    let mut entry = Self::default();
+    let mut group = None;
+    let mut seen = BTreeSet::new();
+    for (i, line) in text.lines().enumerate() {
+      let n = i + 1;
+      let line = line.trim();
+      if line.is_empty() || line.starts_with('#') {
+        continue;
+      }
+      if let Some(name) = line.strip_prefix('[') {
+        let name =
+          (name.strip_suffix(']')).ok_or_else(|| format!("line {n}: malformed group header"))?;
+        if group.is_none() && name != "Desktop Entry" {
+          return Err("first group is not [Desktop Entry]".into());
+        }
+        if !seen.insert(name.to_string()) {
+          return Err(format!("line {n}: duplicate group [{name}]"));
+        }
+        group = Some(name.to_string());
+        continue;
+      }
+      let Some(group) = &group else {
+        return Err(format!("line {n}: entry outside of any group"));
+      };
+      let Some((key, value)) = line.split_once('=') else {
+        return Err(format!("line {n}: expected `key=value`"));
+      };
+      let key = key.trim_end();
+      let base = key.split_once('[').map_or(key, |(x, _)| x);
+      if base.is_empty() || !base.chars().all(|x| x.is_ascii_alphanumeric() || x == '-') {
+        return Err(format!("line {n}: invalid key `{key}`"));
+      }
+      if group != "Desktop Entry" {
+        continue;
+      }
+      if (entry.entries)
+        .insert(key.into(), value.trim_start().into())
+        .is_some()
+      {
+        return Err(format!("line {n}: duplicate key `{key}`"));
+      }
+    }
+    if group.is_none() {
+      return Err("no [Desktop Entry] group".into());
+    }
+    Ok(entry)
+  }
+
+  pub fn get(&self, key: &str) -> Option<&str> {
+    self.entries.get(key).map(|x| &**x)
+  }
+
+  /// Looks for required keys and invalid values.
+  pub fn check(&self) -> Vec<String> {
+    let mut problems = Vec::new();
+    for key in BOOLEAN_KEYS {
+      if let Some(value) = self.get(key).filter(|x| !["true", "false"].contains(x)) {
+        problems.push(format!("{key}={value} is not a boolean"));
+      }
+    }
+    if self.get("Name").is_none() {
+      problems.push("missing Name=".into());
+    }
+    match self.get("Type") {
+      None => problems.push("missing Type=".into()),
+      Some("Application") => {
+        if self.get("Exec").is_none() && self.get("DBusActivatable") != Some("true") {
+          problems.push("application without Exec=".into());
+        }
+      }
+      Some("Link") if self.get("URL").is_none() => problems.push("link without URL=".into()),
+      Some("Link" | "Directory") => {}
+      // Unknown types are reserved for extensions starting with `X-`.
+      Some(x) if x.starts_with("X-") => {}
+      Some(x) => problems.push(format!("unknown Type={x}")),
+    }
+    for key in [
+      "Categories", "MimeType", "Keywords", "OnlyShowIn", "NotShowIn",
+    ] {
+      if self
+        .get(key)
+        .is_some_and(|x| !x.is_empty() && !x.ends_with(';'))
+      {
+        problems.push(format!("{key}= list does not end with `;`"));
+      }
+    }
+    problems
+  }
+}
+
+/// Whether an icon named `name` is among `files`, either as a themed icon or
+/// in the legacy pixmaps directory.
+pub fn has_icon(files: &[PathBuf], name: &str) -> bool {
+  files.iter().any(|file| {
+    let Some(stem) = file.file_stem() else {
+      return false;
+    };
+    stem == name
+      && file
+        .extension()
+        .is_some_and(|x| ICON_EXTENSIONS.iter().any(|y| x == *y))
+      && (file.starts_with("usr/share/icons") || file.starts_with("usr/share/pixmaps"))
+  })
+}
+
+/// Whether an icon named `name` is installed on this system, which is where
+/// dependencies of the package live at build time.
+pub fn has_system_icon(name: &str) -> bool {
+  let pixmaps = Path::new("/usr/share/pixmaps");
+  if (ICON_EXTENSIONS.iter()).any(|x| pixmaps.join(format!("{name}.{x}")).exists()) {
+    return true;
+  }
+  // Themes are laid out as <theme>/<size>/<context>/<name>.<ext>.
+  let Ok(themes) = Path::new("/usr/share/icons").read_dir() else {
+    return false;
+  };
+  let subdirs = |x: &Path| -> Vec<PathBuf> {
+    (x.read_dir().into_iter().flatten().flatten())
+      .map(|x| x.path())
+      .collect()
+  };
+  themes.flatten().any(|theme| {
+    (subdirs(&theme.path()).iter())
+      .flat_map(|x| subdirs(x))
+      .any(|dir| (ICON_EXTENSIONS.iter()).any(|x| dir.join(format!("{name}.{x}")).exists()))
+  })
+}
+
+/// System caches the installer should refresh after installing `files`.
+pub fn trigger_hints(files: &[PathBuf]) -> BTreeSet<&'static str> {
+  let mut hints = BTreeSet::new();
+  for file in files {
+    if file.starts_with("usr/share/icons") && file.components().count() > 4 {
+      hints.insert("icon-cache");
+    } else if is_desktop_entry(file) {
+      hints.insert("desktop-database");
+    } else if file.starts_with("usr/share/mime/packages") {
+      hints.insert("mime-database");
+    }
+  }
+  hints
+}
@@ -0,0 +1,155 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+
+const PKGCONFIG_DIRS: &[&str] = &[
+  "usr/lib/pkgconfig",
+  "usr/lib64/pkgconfig",
+  "usr/share/pkgconfig",
+];
+const CMAKE_DIRS: &[&str] = &["usr/lib/cmake", "usr/lib64/cmake", "usr/share/cmake"];
+
+/// Files telling other builds where to find a package's libraries and
+/// headers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BuildConfig {
+  PkgConfig,
+  Cmake,
+}
+
+/// Tells whether `file`, relative to the package root, is a pkg-config file
+/// or CMake package configuration.
+pub fn classify(file: &Path) -> Option<BuildConfig> {
+  let name = file.file_name()?.to_str()?;
+  let parent = file.parent()?;
+  if name.ends_with(".pc") && PKGCONFIG_DIRS.iter().any(|x| parent == Path::new(x)) {
+    Some(BuildConfig::PkgConfig)
+  } else if name.ends_with(".cmake") && CMAKE_DIRS.iter().any(|x| file.starts_with(x)) {
+    Some(BuildConfig::Cmake)
+  } else {
+    None
+  }
+}
+
+/// A pkg-config file with its variables expanded, see pkg-config(1).
+#[derive(Debug, Default)]
+pub struct PkgConfig {
+  pub variables: BTreeMap<String, String>,
+  pub fields: BTreeMap<String, String>,
+}
+
+fn expand(value: &str, variables: &BTreeMap<String, String>) -> Result<String, String> {
+  let mut expanded = String::new();
+  let mut rest = value;
+  while let Some(i) = rest.find('$') {
+    expanded.push_str(&rest[..i]);
+    rest = &rest[i + 1..];
+    if let Some(x) = rest.strip_prefix('$') {
+      expanded.push('$');
+      rest = x;
+    } else if let Some(x) = rest.strip_prefix('{') {
+      let (name, x) = x.split_once('}').ok_or("unterminated variable reference")?;
+      let value = (variables.get(name)).ok_or_else(|| format!("undefined variable `{name}`"))?;
+      expanded.push_str(value);
+      rest = x;
+    } else {
+      expanded.push('$');
+    }
+  }
+  expanded.push_str(rest);
+  Ok(expanded)
+}
+
+impl PkgConfig {
+  pub fn parse(text: &str) -> Result<Self, String> {
+    let mut pc = Self::default();
+    for (i, line) in text.lines().enumerate() {
+      let n = i + 1;
+      let line = line.split('#').next().unwrap().trim();
+      if line.is_empty() {
+        continue;
+      }
+      let Some(at) = line.find([':', '=']) else {
+        return Err(format!("line {n}: expected a variable or field"));
+      };
+      let (key, value) = (line[..at].trim(), line[at + 1..].trim());
+      if key.is_empty() || !key.chars().all(|x| x.is_alphanumeric() || "_.".contains(x)) {
+        return Err(format!("line {n}: invalid name `{key}`"));
+      }
+      let value = expand(value, &pc.variables).map_err(|e| format!("line {n}: {e}"))?;
+      let map = match &line[at..=at] {
+        "=" => &mut pc.variables,
+        _ => &mut pc.fields,
+      };
+      if map.insert(key.into(), value).is_some() {
+        return Err(format!("line {n}: `{key}` defined twice"));
+      }
+    }
+    for field in ["Name", "Description", "Version"] {
+      if !pc.fields.contains_key(field) {
+        return Err(format!("missing required field `{field}`"));
+      }
+    }
+    Ok(pc)
+  }
+
+  /// Absolute paths the file points other builds at: its directory variables
+  /// and the directories of `-I` and `-L` flags.
+  pub fn paths(&self) -> Vec<&str> {
+    let variables = (self.variables.iter())
+      .filter(|(k, v)| k.ends_with("dir") && v.starts_with('/'))
+      .map(|(_, v)| &**v);
+    let flags = ["Cflags", "Cflags.private", "Libs", "Libs.private"];
+    let flags = (flags.iter())
+      .filter_map(|x| self.fields.get(*x))
+      .flat_map(|x| x.split_whitespace())
+      .filter_map(|x| x.strip_prefix("-I").or_else(|| x.strip_prefix("-L")))
+      .filter(|x| x.starts_with('/'));
+    variables.chain(flags).collect()
+  }
+}
+
+/// Absolute paths written into a CMake package configuration. Paths relative
+/// to the installed file, like `${_IMPORT_PREFIX}/include`, are left out.
+pub fn cmake_paths(text: &str) -> Vec<&str> {
+  let mut paths = Vec::new();
+  for line in text.lines() {
+    let line = line.trim_start();
+    if line.starts_with('#') {
+      continue;
+    }
+    let tokens = line.split(|x: char| x.is_whitespace() || "\"();".contains(x));
+    paths.extend(tokens.filter(|x| x.len() > 1 && x.starts_with('/')));
+  }
+  paths
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_pkgconfig() {
+    let pc = PkgConfig::parse(
+      "prefix=/usr\nlibdir=${prefix}/lib # comment\nincludedir=${prefix}/include/foo\n\n\
+       Name: foo\nDescription: Foo\nVersion: 1.0\n\
+       Libs: -L${libdir} -lfoo\nCflags: -I${includedir} -I/tmp/build/include\n",
+    )
+    .unwrap();
+    assert_eq!(pc.variables["libdir"], "/usr/lib");
+    assert_eq!(
+      pc.paths(),
+      [
+        "/usr/include/foo",
+        "/usr/lib",
+        "/usr/include/foo",
+        "/tmp/build/include",
+        "/usr/lib"
+      ]
+    );
+    assert!(PkgConfig::parse("Name: a\nDescription: b\n").is_err());
+    assert!(PkgConfig::parse("libdir=${prefix}/lib\n").is_err());
+    let cmake = "set_target_properties(foo PROPERTIES\n  \
+      INTERFACE_INCLUDE_DIRECTORIES \"${_IMPORT_PREFIX}/include;/usr/local/include\"\n)";
+    assert_eq!(cmake_paths(cmake), ["/usr/local/include"]);
+  }
+}
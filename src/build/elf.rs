@@ -0,0 +1,318 @@
+use std::collections::BTreeSet;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+
+const PT_LOAD: u64 = 1;
+const PT_DYNAMIC: u64 = 2;
+
+const DT_NULL: u64 = 0;
+const DT_NEEDED: u64 = 1;
+const DT_STRTAB: u64 = 5;
+const DT_SONAME: u64 = 14;
+const DT_RPATH: u64 = 15;
+const DT_RUNPATH: u64 = 29;
+
+/// Just enough of an ELF file to read and edit its dynamic section.
+#[derive(Debug)]
+pub struct ElfFile {
+  data: Vec<u8>,
+  wide: bool,
+  little: bool,
+  /// `(vaddr, offset, filesz)` of each loadable segment.
+  loads: Vec<(u64, u64, u64)>,
+  /// File offset of the dynamic section and its number of slots.
+  dynamic: usize,
+  dyn_slots: usize,
+}
+
+impl ElfFile {
+  /// Parses `data`, returning `None` if it is not an ELF file with a dynamic
+  /// section.
+  pub fn parse(data: Vec<u8>) -> Option<Self> {
+    if !data.starts_with(b"\x7fELF") {
+      return None;
+    }
+    let wide = match data.get(4)? {
+      1 => false,
+      2 => true,
+      _ => return None,
+    };
+    let little = match data.get(5)? {
+      1 => true,
+      2 => false,
+      _ => return None,
+    };
+    let mut elf = Self {
+      data,
+      wide,
+      little,
+      loads: Vec::new(),
+      dynamic: 0,
+      dyn_slots: 0,
+    };
+
+    let (phoff, phentsize, phnum) = if wide {
+      (elf.read(32, 8)?, elf.read(54, 2)?, elf.read(56, 2)?)
+    } else {
+      (elf.read(28, 4)?, elf.read(42, 2)?, elf.read(44, 2)?)
+    };
+    let mut dynamic = None;
+    for i in 0..phnum {
+      let ph = phoff.checked_add(i * phentsize)?;
+      // (p_offset, p_vaddr, p_filesz)
+      let (offset, vaddr, filesz) = if wide {
+        (
+          elf.read(ph + 8, 8)?,
+          elf.read(ph + 16, 8)?,
+          elf.read(ph + 32, 8)?,
+        )
+      } else {
+        (
+          elf.read(ph + 4, 4)?,
+          elf.read(ph + 8, 4)?,
+          elf.read(ph + 16, 4)?,
+        )
+      };
+      match elf.read(ph, 4)? {
+        PT_LOAD => elf.loads.push((vaddr, offset, filesz)),
+        PT_DYNAMIC => dynamic = Some((offset, filesz)),
+        _ => {}
+      }
+    }
+    let (offset, size) = dynamic?;
+    elf.dynamic = offset.try_into().ok()?;
+    elf.dyn_slots = usize::try_from(size).ok()? / (2 * elf.word());
+    Some(elf)
+  }
+
+  pub fn into_bytes(self) -> Vec<u8> {
+    self.data
+  }
+
+  fn word(&self) -> usize {
+    if self.wide {
+      8
+    } else {
+      4
+    }
+  }
+
+  fn read(&self, offset: u64, size: usize) -> Option<u64> {
+    let offset = usize::try_from(offset).ok()?;
+    let bytes = self.data.get(offset..offset.checked_add(size)?)?;
+    let mut buf = [0; 8];
+    if self.little {
+      buf[..size].copy_from_slice(bytes);
+      Some(u64::from_le_bytes(buf))
+    } else {
+      buf[8 - size..].copy_from_slice(bytes);
+      Some(u64::from_be_bytes(buf))
+    }
+  }
+
+  fn write(&mut self, offset: usize, size: usize, value: u64) {
+    let bytes = if self.little {
+      value.to_le_bytes()[..size].to_vec()
+    } else {
+      value.to_be_bytes()[8 - size..].to_vec()
+    };
+    self.data[offset..offset + size].copy_from_slice(&bytes);
+  }
+
+  /// Dynamic entries as `(tag, value)`, up to the terminating `DT_NULL`.
+  fn entries(&self) -> Vec<(u64, u64)> {
+    let word = self.word();
+    (0..self.dyn_slots)
+      .map_while(|i| {
+        let offset = (self.dynamic + i * 2 * word) as u64;
+        Some((
+          self.read(offset, word)?,
+          self.read(offset + word as u64, word)?,
+        ))
+      })
+      .take_while(|(tag, _)| *tag != DT_NULL)
+      .collect()
+  }
+
+  fn set_entries(&mut self, entries: &[(u64, u64)]) {
+    let word = self.word();
+    let nulls = std::iter::repeat((DT_NULL, 0));
+    for (i, (tag, value)) in entries.iter().copied().chain(nulls).enumerate() {
+      if i == self.dyn_slots {
+        break;
+      }
+      let offset = self.dynamic + i * 2 * word;
+      self.write(offset, word, tag);
+      self.write(offset + word, word, value);
+    }
+  }
+
+  /// File offset and content of the string at `index` in the dynamic string
+  /// table.
+  fn string(&self, index: u64) -> Option<(usize, String)> {
+    let (_, vaddr) = (self.entries().into_iter()).find(|(tag, _)| *tag == DT_STRTAB)?;
+    let (base, offset, _) =
+      (self.loads.iter()).find(|(base, _, size)| (*base..base + size).contains(&vaddr))?;
+    let start = usize::try_from(vaddr - base + offset + index).ok()?;
+    let len = self.data.get(start..)?.iter().position(|x| *x == 0)?;
+    let s = String::from_utf8_lossy(&self.data[start..start + len]);
+    Some((start, s.into_owned()))
+  }
+
+  /// Strings of the dynamic entries `tag` returns true for.
+  fn strings(&self, tag: impl Fn(u64) -> bool) -> Vec<String> {
+    (self.entries().into_iter())
+      .filter(|(x, _)| tag(*x))
+      .filter_map(|(_, value)| Some(self.string(value)?.1))
+      .collect()
+  }
+
+  /// Contents of the `DT_RPATH` and `DT_RUNPATH` entries.
+  pub fn run_paths(&self) -> Vec<String> {
+    self.strings(|x| x == DT_RPATH || x == DT_RUNPATH)
+  }
+
+  pub fn soname(&self) -> Option<String> {
+    self.strings(|x| x == DT_SONAME).into_iter().next()
+  }
+
+  /// Sonames of the libraries this file links against.
+  pub fn needed(&self) -> Vec<String> {
+    self.strings(|x| x == DT_NEEDED)
+  }
+
+  /// Removes the run path components `keep` returns false for, dropping
+  /// entries that end up empty. Returns whether anything changed.
+  pub fn retain_run_paths(&mut self, mut keep: impl FnMut(&str) -> bool) -> bool {
+    let mut changed = false;
+    let mut entries = self.entries();
+    entries.retain(|&(tag, value)| {
+      if tag != DT_RPATH && tag != DT_RUNPATH {
+        return true;
+      }
+      let Some((start, old)) = self.string(value) else {
+        return true;
+      };
+      let new = (old.split(':'))
+        .filter(|x| keep(x))
+        .collect::<Vec<_>>()
+        .join(":");
+      if new == old {
+        return true;
+      }
+      changed = true;
+      // The new value is never longer, so it fits in place of the old one.
+      self.data[start..start + old.len()].fill(0);
+      self.data[start..start + new.len()].copy_from_slice(new.as_bytes());
+      !new.is_empty()
+    });
+    if changed {
+      self.set_entries(&entries);
+    }
+    changed
+  }
+}
+
+/// Shared libraries a package provides and links against.
+#[derive(Debug, Clone, Default)]
+pub struct Sonames {
+  pub provided: BTreeSet<Box<str>>,
+  /// Only those not provided by the package itself.
+  pub needed: BTreeSet<Box<str>>,
+}
+
+/// Sonames of the ELF files among `files`.
+pub fn sonames(package_dir: &Path, files: &[PathBuf]) -> io::Result<Sonames> {
+  let (mut provided, mut needed) = (BTreeSet::new(), BTreeSet::new());
+  for file in files {
+    let path = package_dir.join(file);
+    if !path.symlink_metadata()?.is_file() {
+      continue;
+    }
+    let Some(elf) = open(&path)? else {
+      continue;
+    };
+    provided.extend(elf.soname().map(Into::into));
+    needed.extend(elf.needed().into_iter().map(Into::into));
+  }
+  let needed = needed.difference(&provided).cloned().collect();
+  Ok(Sonames { provided, needed })
+}
+
+/// Reads the file at `path` if it is an ELF file with a dynamic section.
+pub fn open(path: &Path) -> io::Result<Option<ElfFile>> {
+  let mut file = File::open(path)?;
+  let mut data = vec![0; 4];
+  if file.read_exact(&mut data).is_err() || data != b"\x7fELF" {
+    return Ok(None);
+  }
+  file.read_to_end(&mut data)?;
+  Ok(ElfFile::parse(data))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  /// A 64-bit little-endian ELF with one segment holding the dynamic section
+  /// and its string table.
+  fn build(entries: &[(u64, u64)], strtab: &[u8]) -> Vec<u8> {
+    let dyn_offset = 64 + 2 * 56;
+    let strtab_offset = dyn_offset + (entries.len() + 2) * 16;
+    let mut data = vec![0; strtab_offset];
+    data[..6].copy_from_slice(b"\x7fELF\x02\x01");
+    data[32..40].copy_from_slice(&64u64.to_le_bytes());
+    data[54..56].copy_from_slice(&56u16.to_le_bytes());
+    data[56..58].copy_from_slice(&2u16.to_le_bytes());
+    let phdrs = [
+      (PT_LOAD, 0, (strtab_offset + strtab.len()) as u64),
+      (
+        PT_DYNAMIC,
+        dyn_offset as u64,
+        ((entries.len() + 2) * 16) as u64,
+      ),
+    ];
+    for (i, (kind, offset, size)) in phdrs.into_iter().enumerate() {
+      let ph = 64 + i * 56;
+      data[ph..ph + 4].copy_from_slice(&(kind as u32).to_le_bytes());
+      data[ph + 8..ph + 16].copy_from_slice(&offset.to_le_bytes());
+      data[ph + 16..ph + 24].copy_from_slice(&offset.to_le_bytes());
+      data[ph + 32..ph + 40].copy_from_slice(&size.to_le_bytes());
+    }
+    let all = [(DT_STRTAB, strtab_offset as u64)]
+      .into_iter()
+      .chain(entries.iter().copied());
+    for (i, (tag, value)) in all.enumerate() {
+      let offset = dyn_offset + i * 16;
+      data[offset..offset + 8].copy_from_slice(&tag.to_le_bytes());
+      data[offset + 8..offset + 16].copy_from_slice(&value.to_le_bytes());
+    }
+    data.extend_from_slice(strtab);
+    data
+  }
+
+  #[test]
+  fn test_run_paths() {
+    let data = build(
+      &[(DT_RUNPATH, 1), (DT_NEEDED, 0), (DT_RPATH, 27)],
+      b"\0/tmp/build:$ORIGIN/../lib\0/usr/lib\0",
+    );
+    let mut elf = ElfFile::parse(data).unwrap();
+    assert_eq!(elf.run_paths(), ["/tmp/build:$ORIGIN/../lib", "/usr/lib"]);
+    assert_eq!(elf.needed(), [""]);
+    assert_eq!(elf.soname(), None);
+
+    assert!(elf.retain_run_paths(|x| !x.starts_with("/usr")));
+    assert!(!elf.retain_run_paths(|x| !x.starts_with("/usr")));
+    assert_eq!(elf.run_paths(), ["/tmp/build:$ORIGIN/../lib"]);
+    assert!(elf.retain_run_paths(|x| x.starts_with('$')));
+    assert_eq!(elf.run_paths(), ["$ORIGIN/../lib"]);
+
+    let elf = ElfFile::parse(elf.into_bytes()).unwrap();
+    assert_eq!(
+      elf.entries(),
+      [(DT_STRTAB, 256), (DT_RUNPATH, 1), (DT_NEEDED, 0)]
+    );
+  }
+}
@@ -1,5 +1,20 @@
-use rhai::{Array, Engine, Map, Scope};
-use std::path::Path;
+use super::kmod::{kernel_release, FIRMWARE_DIR, MODULES_DIR};
+use super::lock::SourceRecord;
+use crate::types::SourceInfo;
+use crate::util::is_safe_name;
+use anyhow::anyhow;
+use clap::Args;
+use rhai::module_resolvers::DummyModuleResolver;
+use rhai::serde::to_dynamic;
+use rhai::EvalAltResult::{self, *};
+use rhai::{Array, Dynamic, Engine, Map, Scope};
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::{copy, create_dir_all, read_to_string};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
 macro_rules! gen_conditional {
   ($type:ident) => {
@@ -15,20 +30,538 @@ macro_rules! gen_conditional {
   };
 }
 
-pub fn create_engine(source_dir: &Path, arch: String) -> (Engine, Scope<'static>) {
+/// Execution limits applied to every script evaluation, including calls into
+/// script functions.
+#[derive(Debug, Clone, Copy, Args)]
+pub struct Limits {
+  /// Maximum time in seconds a single script evaluation may take
+  #[arg(long = "script-timeout", value_name = "SECS", default_value_t = 30)]
+  pub timeout: u64,
+
+  /// Maximum number of operations a single script evaluation may perform
+  #[arg(
+    long = "script-max-operations",
+    value_name = "N",
+    default_value_t = 10_000_000
+  )]
+  pub max_operations: u64,
+
+  /// Maximum length of strings (in bytes) and arrays/maps (in elements)
+  /// scripts may create
+  #[arg(long = "script-max-size", value_name = "N", default_value_t = 1 << 20)]
+  pub max_size: usize,
+}
+
+impl Limits {
+  /// Command line arguments reproducing these limits.
+  pub fn to_args(self) -> [String; 6] {
+    [
+      "--script-timeout".into(),
+      self.timeout.to_string(),
+      "--script-max-operations".into(),
+      self.max_operations.to_string(),
+      "--script-max-size".into(),
+      self.max_size.to_string(),
+    ]
+  }
+}
+
+/// Turns errors caused by exceeding [`Limits`] into a clear message.
+pub fn eval_error(error: Box<EvalAltResult>) -> anyhow::Error {
+  let reason = match error.unwrap_inner() {
+    ErrorTerminated(token, _) => token.to_string(),
+    x @ (ErrorTooManyOperations(_)
+    | ErrorDataTooLarge(..)
+    | ErrorStackOverflow(_)
+    | ErrorTooManyModules(_)) => x.to_string(),
+    _ => return error.into(),
+  };
+  anyhow!("script exceeded limits ({reason})")
+}
+
+/// Restricts what a script can do, so that merely evaluating an untrusted
+/// build script cannot hang or harm the host.
+fn sandbox(engine: &mut Engine, limits: Limits) {
+  engine
+    .set_max_operations(limits.max_operations)
+    .set_max_call_levels(64)
+    .set_max_expr_depths(64, 32)
+    .set_max_string_size(limits.max_size)
+    .set_max_array_size(limits.max_size)
+    .set_max_map_size(limits.max_size)
+    .set_max_modules(0)
+    .set_module_resolver(DummyModuleResolver::new())
+    .disable_symbol("eval");
+
+  // Operations are counted from one again on every evaluation, which is when
+  // the clock restarts.
+  let timeout = Duration::from_secs(limits.timeout);
+  let start = Mutex::new(Instant::now());
+  engine.on_progress(move |ops| {
+    let mut start = start.lock().unwrap();
+    if ops == 1 {
+      *start = Instant::now();
+    } else if ops % 1024 == 0 && start.elapsed() > timeout {
+      return Some(Dynamic::from(format!(
+        "evaluation took longer than {}s",
+        timeout.as_secs()
+      )));
+    }
+    None
+  });
+}
+
+type RhaiResult<T> = Result<T, Box<EvalAltResult>>;
+
+/// A path handed to scripts. Build roots need not be valid UTF-8, so paths
+/// stay opaque until a script turns one into a string, which fails for such
+/// a path rather than mangling it. Shell snippets get the same directories in
+/// environment variables, which work either way.
+#[derive(Debug, Clone)]
+pub struct ScriptPath(pub PathBuf);
+
+thread_local! {
+  /// Rhai falls back to the type name when `to_string` fails inside string
+  /// interpolation, so the failure is kept here for [`take_path_error`].
+  static PATH_ERROR: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+impl ScriptPath {
+  fn to_str(&self) -> RhaiResult<&str> {
+    self.0.to_str().ok_or_else(|| {
+      let msg = format!(
+        "path '{}' is not valid UTF-8, use $source_dir or $package_dir in shell snippets instead",
+        self.0.display()
+      );
+      PATH_ERROR.with(|x| *x.borrow_mut() = Some(msg.clone()));
+      msg.into()
+    })
+  }
+}
+
+/// Fails if a script call on this thread turned a non-UTF-8 path into a
+/// string since the last check.
+pub fn take_path_error() -> anyhow::Result<()> {
+  match PATH_ERROR.with(|x| x.borrow_mut().take()) {
+    Some(msg) => Err(anyhow!(msg)),
+    None => Ok(()),
+  }
+}
+
+fn register_path(engine: &mut Engine) {
+  engine
+    .register_type_with_name::<ScriptPath>("Path")
+    .register_fn("to_string", |x: &mut ScriptPath| -> RhaiResult<String> {
+      x.to_str().map(Into::into)
+    })
+    .register_fn("to_debug", |x: &mut ScriptPath| format!("{:?}", x.0))
+    .register_fn("is_utf8", |x: &mut ScriptPath| x.0.to_str().is_some())
+    .register_fn("join", |x: &mut ScriptPath, rel: &str| {
+      ScriptPath(x.0.join(rel))
+    })
+    .register_fn("+", |x: ScriptPath, s: &str| -> RhaiResult<String> {
+      Ok(format!("{}{s}", x.to_str()?))
+    })
+    .register_fn("+", |s: &str, x: ScriptPath| -> RhaiResult<String> {
+      Ok(format!("{s}{}", x.to_str()?))
+    });
+}
+
+/// Resolves `path` inside `source_dir`, refusing anything that would end up
+/// outside of it.
+fn resolve_in(source_dir: &Path, path: &str) -> RhaiResult<PathBuf> {
+  if !is_safe_name(path) {
+    return Err(format!("path '{path}' is outside of source directory").into());
+  }
+  let resolve = || -> std::io::Result<_> {
+    let base = source_dir.canonicalize()?;
+    let full = base.join(path).canonicalize()?;
+    Ok(full.starts_with(base).then_some(full))
+  };
+  match resolve() {
+    Ok(Some(full)) => Ok(full),
+    Ok(None) => Err(format!("path '{path}' is outside of source directory").into()),
+    Err(e) => Err(format!("cannot access '{path}': {e}").into()),
+  }
+}
+
+fn read_file(source_dir: &Path, path: &str) -> RhaiResult<String> {
+  let full = resolve_in(source_dir, path)?;
+  read_to_string(full).map_err(|e| format!("failed to read '{path}': {e}").into())
+}
+
+fn git_describe(source_dir: &Path, dir: &str) -> RhaiResult<String> {
+  let full = resolve_in(source_dir, dir)?;
+  // Fetched repositories are not trusted with config that runs commands.
+  let output = Command::new("git")
+    .args([
+      "-c",
+      "core.fsmonitor=false",
+      "-c",
+      "core.hooksPath=/dev/null",
+    ])
+    .args(["describe", "--tags", "--always"])
+    .env("GIT_CONFIG_NOSYSTEM", "1")
+    .current_dir(full)
+    .output()
+    .map_err(|e| format!("failed to run git: {e}"))?;
+  if !output.status.success() {
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    return Err(format!("git describe failed: {}", stderr.trim()).into());
+  }
+  Ok(String::from_utf8_lossy(&output.stdout).trim().into())
+}
+
+/// Finds `#define <name> <value>` in a C header, returning the value with
+/// surrounding quotes removed.
+fn read_define(source_dir: &Path, path: &str, name: &str) -> RhaiResult<String> {
+  let content = read_file(source_dir, path)?;
+  content
+    .lines()
+    .filter_map(|line| line.trim().strip_prefix('#'))
+    .filter_map(|line| line.trim_start().strip_prefix("define"))
+    .filter_map(|line| {
+      let (key, value) = line.trim().split_once(char::is_whitespace)?;
+      (key == name).then(|| value.trim().trim_matches('"').to_string())
+    })
+    .next()
+    .ok_or_else(|| format!("'{name}' is not defined in '{path}'").into())
+}
+
+fn register_builtins(engine: &mut Engine, source_dir: &Path) {
+  let dir = source_dir.to_path_buf();
+  engine.register_fn("read_file", move |path: &str| read_file(&dir, path));
+  let dir = source_dir.to_path_buf();
+  engine.register_fn("git_describe", move || git_describe(&dir, "."));
+  let dir = source_dir.to_path_buf();
+  engine.register_fn("git_describe", move |path: &str| git_describe(&dir, path));
+  let dir = source_dir.to_path_buf();
+  engine.register_fn("read_define", move |path: &str, name: &str| {
+    read_define(&dir, path, name)
+  });
+}
+
+/// Makes the evaluated source metadata available to script functions as a
+/// read-only `source` map, with the file names of its sources under `files`.
+pub fn expose_source(engine: &mut Engine, info: &SourceInfo) -> anyhow::Result<()> {
+  let mut map: Map = to_dynamic(info)?.cast();
+  let files = (info.source.iter())
+    .map(|x| Dynamic::from(x.file_name().to_string()))
+    .collect::<Array>();
+  map.insert("files".into(), files.into());
+  let value = Dynamic::from_map(map).into_read_only();
+  // `on_var` is only marked as volatile, not actually deprecated.
+  #[allow(deprecated)]
+  engine.on_var(move |name, _, _| match name {
+    "source" => Ok(Some(value.clone())),
+    _ => Ok(None),
+  });
+  Ok(())
+}
+
+/// Registers `srcdir_of(file)`, which returns where the source `file` ended up
+/// after fetching: the top-level directory of an extracted archive, or the
+/// file itself.
+pub fn expose_srcdirs(
+  engine: &mut Engine,
+  source_dir: &Path,
+  sources: &BTreeMap<Box<str>, SourceRecord>,
+) {
+  let paths = (sources.iter())
+    .map(|(name, record)| {
+      let path = match &record.extracted {
+        Some(dir) => source_dir.join(dir),
+        None => source_dir.join(&**name),
+      };
+      (name.clone(), ScriptPath(path))
+    })
+    .collect::<BTreeMap<_, _>>();
+  engine.register_fn("srcdir_of", move |file: &str| -> RhaiResult<ScriptPath> {
+    (paths.get(file).cloned()).ok_or_else(|| format!("no source named '{file}'").into())
+  });
+}
+
+/// Package currently being packed, which builtins like `install_license`
+/// install files into.
+#[derive(Debug, Clone)]
+pub struct PackTarget {
+  pub name: String,
+  pub dir: PathBuf,
+}
+
+pub type CurrentPackage = Arc<Mutex<Option<PackTarget>>>;
+
+fn install_license(source_dir: &Path, target: &PackTarget, path: &str) -> RhaiResult<()> {
+  let src = resolve_in(source_dir, path)?;
+  let name = src.file_name().unwrap_or_default();
+  let dir = (target.dir).join("usr/share/licenses").join(&target.name);
+  create_dir_all(&dir)
+    .and_then(|_| copy(&src, dir.join(name)))
+    .map_err(|e| format!("failed to install license '{path}': {e}"))?;
+  Ok(())
+}
+
+fn install_module(source_dir: &Path, target: &PackTarget, path: &str) -> RhaiResult<()> {
+  let src = resolve_in(source_dir, path)?;
+  let release = kernel_release(&src)
+    .map_err(|e| format!("failed to read module '{path}': {e}"))?
+    .ok_or_else(|| format!("'{path}' is not a kernel module with a vermagic"))?;
+  let name = src.file_name().unwrap_or_default();
+  let dir = (target.dir).join(MODULES_DIR).join(release).join("extra");
+  create_dir_all(&dir)
+    .and_then(|_| copy(&src, dir.join(name)))
+    .map_err(|e| format!("failed to install module '{path}': {e}"))?;
+  Ok(())
+}
+
+fn install_firmware(
+  source_dir: &Path,
+  target: &PackTarget,
+  path: &str,
+  dest: Option<&str>,
+) -> RhaiResult<()> {
+  let src = resolve_in(source_dir, path)?;
+  let dest = match dest {
+    Some(dest) if is_safe_name(dest) => PathBuf::from(dest),
+    Some(dest) => return Err(format!("invalid firmware path '{dest}'").into()),
+    None => src.file_name().unwrap_or_default().into(),
+  };
+  let dest = target.dir.join(FIRMWARE_DIR).join(dest);
+  (dest.parent())
+    .map_or(Ok(()), create_dir_all)
+    .and_then(|_| copy(&src, dest))
+    .map_err(|e| format!("failed to install firmware '{path}': {e}"))?;
+  Ok(())
+}
+
+/// Registers builtins that install files into the package being packed. They
+/// act on whatever the returned handle is set to.
+pub fn expose_packing(engine: &mut Engine, source_dir: &Path) -> CurrentPackage {
+  let current = CurrentPackage::default();
+  let dir = source_dir.to_path_buf();
+  let current2 = current.clone();
+  engine.register_fn("install_license", move |path: &str| {
+    match &*current2.lock().unwrap() {
+      Some(target) => install_license(&dir, target, path),
+      None => Err("install_license() can only be called while packing".into()),
+    }
+  });
+  let dir = source_dir.to_path_buf();
+  let current2 = current.clone();
+  engine.register_fn("install_module", move |path: &str| {
+    match &*current2.lock().unwrap() {
+      Some(target) => install_module(&dir, target, path),
+      None => Err("install_module() can only be called while packing".into()),
+    }
+  });
+  let dir = source_dir.to_path_buf();
+  let current2 = current.clone();
+  engine.register_fn("install_firmware", move |path: &str| {
+    match &*current2.lock().unwrap() {
+      Some(target) => install_firmware(&dir, target, path, None),
+      None => Err("install_firmware() can only be called while packing".into()),
+    }
+  });
+  let dir = source_dir.to_path_buf();
+  let current2 = current.clone();
+  engine.register_fn(
+    "install_firmware",
+    move |path: &str, dest: &str| match &*current2.lock().unwrap() {
+      Some(target) => install_firmware(&dir, target, path, Some(dest)),
+      None => Err("install_firmware() can only be called while packing".into()),
+    },
+  );
+  current
+}
+
+/// What step functions asked builtins for while traced.
+#[derive(Debug, Clone, Default)]
+pub struct BuiltinCalls {
+  /// Files looked up with `srcdir_of`.
+  pub srcdirs: BTreeSet<String>,
+  /// Paths given to `install_license`, `install_module` and
+  /// `install_firmware`.
+  pub installed: BTreeSet<String>,
+}
+
+/// Registers stand-ins for the builtins needing fetched sources or a package
+/// being packed, which only note what they are called with. `srcdir_of`
+/// returns where the file would be in `source_dir`.
+pub fn expose_tracing(engine: &mut Engine, source_dir: &Path) -> Arc<Mutex<BuiltinCalls>> {
+  let calls = Arc::new(Mutex::new(BuiltinCalls::default()));
+  let dir = source_dir.to_path_buf();
+  let calls2 = calls.clone();
+  engine.register_fn("srcdir_of", move |file: &str| {
+    calls2.lock().unwrap().srcdirs.insert(file.into());
+    ScriptPath(dir.join(file))
+  });
+  let install = |calls: &Arc<Mutex<BuiltinCalls>>| {
+    let calls = calls.clone();
+    move |path: &str| {
+      calls.lock().unwrap().installed.insert(path.into());
+    }
+  };
+  engine.register_fn("install_license", install(&calls));
+  engine.register_fn("install_module", install(&calls));
+  engine.register_fn("install_firmware", install(&calls));
+  let install = install(&calls);
+  engine.register_fn("install_firmware", move |path: &str, _: &str| install(path));
+  calls
+}
+
+/// Calls to builtins running commands or reading sources that a script made,
+/// like `read_file("VERSION")`, see [`intercept_side_effects`].
+pub type SideEffects = Arc<Mutex<Vec<String>>>;
+
+/// Replaces the builtins running commands or reading sources with stand-ins
+/// that note the call and fail, for evaluating scripts for their metadata
+/// only. Sources are not fetched then, so scripts doing such work while
+/// being evaluated only get metadata that depends on more than the script.
+pub fn intercept_side_effects(engine: &mut Engine) -> SideEffects {
+  let effects = SideEffects::default();
+  let intercept = |effects: &SideEffects| {
+    let effects = effects.clone();
+    move |name: &str, call: String| -> RhaiResult<String> {
+      effects.lock().unwrap().push(call);
+      Err(format!("{name}() is not available when only evaluating metadata").into())
+    }
+  };
+  let f = intercept(&effects);
+  engine.register_fn("read_file", move |path: &str| {
+    f("read_file", format!("read_file({path:?})"))
+  });
+  let f = intercept(&effects);
+  engine.register_fn("git_describe", move || {
+    f("git_describe", "git_describe()".into())
+  });
+  let f = intercept(&effects);
+  engine.register_fn("git_describe", move |path: &str| {
+    f("git_describe", format!("git_describe({path:?})"))
+  });
+  let f = intercept(&effects);
+  engine.register_fn("read_define", move |path: &str, name: &str| {
+    f("read_define", format!("read_define({path:?}, {name:?})"))
+  });
+  effects
+}
+
+pub fn create_engine(
+  source_dir: &Path,
+  arch: String,
+  bootstrap: bool,
+  limits: Limits,
+) -> (Engine, Scope<'static>) {
   let mut engine = Engine::new();
+  sandbox(&mut engine, limits);
   engine
     .register_fn("conditional", gen_conditional!(Array))
     .register_fn("conditional", gen_conditional!(Map));
-
-  let source_dir_path = source_dir
-    .to_str()
-    .expect("tempdir path is not UTF-8")
-    .to_string();
+  register_builtins(&mut engine, source_dir);
+  register_path(&mut engine);
+  engine.register_fn("srcdir_of", |_: &str| -> RhaiResult<ScriptPath> {
+    Err("sources are not fetched yet".into())
+  });
+  engine.register_fn("install_license", |_: &str| -> RhaiResult<()> {
+    Err("install_license() can only be called while packing".into())
+  });
+  engine.register_fn("install_module", |_: &str| -> RhaiResult<()> {
+    Err("install_module() can only be called while packing".into())
+  });
+  engine.register_fn("install_firmware", |_: &str| -> RhaiResult<()> {
+    Err("install_firmware() can only be called while packing".into())
+  });
+  engine.register_fn("install_firmware", |_: &str, _: &str| -> RhaiResult<()> {
+    Err("install_firmware() can only be called while packing".into())
+  });
 
   let mut scope = Scope::new();
-  scope.push("source_dir", source_dir_path);
+  scope.push("source_dir", ScriptPath(source_dir.into()));
   scope.push("arch", arch);
+  scope.push_constant("bootstrap", bootstrap);
+  // Placeholder so closures can capture `source`; its real value is only
+  // known after evaluation, see `expose_source`.
+  scope.push_constant("source", ());
 
   (engine, scope)
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::fs::write;
+  use tempfile::tempdir;
+
+  #[test]
+  fn test_builtins_stay_in_source_dir() {
+    let dir = tempdir().unwrap();
+    write(dir.path().join("a"), "hello").unwrap();
+    assert_eq!(read_file(dir.path(), "a").unwrap(), "hello");
+    assert!(read_file(dir.path(), "../a").is_err());
+    assert!(read_file(dir.path(), "/etc/passwd").is_err());
+  }
+
+  #[test]
+  fn test_read_define() {
+    let dir = tempdir().unwrap();
+    let header = "#ifndef FOO_H\n#  define FOO_VERSION_MAJOR 2\n#define FOO_VERSION \"2.3.4\"\n";
+    write(dir.path().join("foo.h"), header).unwrap();
+    assert_eq!(
+      read_define(dir.path(), "foo.h", "FOO_VERSION").unwrap(),
+      "2.3.4"
+    );
+    assert_eq!(
+      read_define(dir.path(), "foo.h", "FOO_VERSION_MAJOR").unwrap(),
+      "2"
+    );
+    assert!(read_define(dir.path(), "foo.h", "FOO").is_err());
+  }
+
+  #[test]
+  fn test_intercept_side_effects() {
+    let limits = Limits {
+      timeout: 30,
+      max_operations: 10_000,
+      max_size: 1024,
+    };
+    let (mut engine, mut scope) =
+      create_engine(Path::new("/tmp/x"), "x86_64".into(), false, limits);
+    let effects = intercept_side_effects(&mut engine);
+    let script = r#"let v = "0"; try { v = git_describe(); } catch {} v + read_define("a.h", "B")"#;
+    assert!(engine
+      .eval_with_scope::<String>(&mut scope, script)
+      .is_err());
+    let effects = effects.lock().unwrap();
+    assert_eq!(*effects, ["git_describe()", r#"read_define("a.h", "B")"#]);
+  }
+
+  #[test]
+  fn test_paths() {
+    let limits = Limits {
+      timeout: 30,
+      max_operations: 10_000,
+      max_size: 1024,
+    };
+    let script = "`cd ${source_dir.join(\"a\")} && ls ` + source_dir + \"/b\"";
+    let (engine, mut scope) = create_engine(Path::new("/tmp/x"), "x86_64".into(), false, limits);
+    let result = engine.eval_with_scope::<String>(&mut scope, script);
+    assert_eq!(result.unwrap(), "cd /tmp/x/a && ls /tmp/x/b");
+
+    #[cfg(unix)]
+    {
+      use std::ffi::OsStr;
+      use std::os::unix::ffi::OsStrExt;
+      let dir = Path::new(OsStr::from_bytes(b"/tmp/\xff"));
+      let (engine, mut scope) = create_engine(dir, "x86_64".into(), false, limits);
+      let result = engine.eval_with_scope::<String>(&mut scope, script);
+      assert!(result.unwrap_err().to_string().contains("not valid UTF-8"));
+      take_path_error().unwrap_err();
+      let result = engine.eval_with_scope::<String>(&mut scope, "`ls ${source_dir}`");
+      assert_eq!(result.unwrap(), "ls Path");
+      assert!(take_path_error()
+        .unwrap_err()
+        .to_string()
+        .contains("not valid UTF-8"));
+      take_path_error().unwrap();
+    }
+  }
+}
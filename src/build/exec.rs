@@ -0,0 +1,307 @@
+use crate::util::asyncify;
+use crate::util::config_dir;
+use crate::warning;
+use anyhow::{anyhow, bail, Context};
+use futures::future::{pending, select};
+use futures::try_join;
+use indicatif::{HumanBytes, HumanDuration, MultiProgress};
+use serde::Deserialize;
+use std::collections::HashSet;
+use std::fs::{self, File, Metadata};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::pin::pin;
+use std::process::{ExitStatus, Stdio};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncBufReadExt, AsyncRead, BufReader};
+use tokio::process::Command;
+use tokio::runtime::Builder as RtBuilder;
+use tokio::time::{sleep, timeout};
+
+/// Copies `pipe` to our stdout or stderr a whole line at a time, above the
+/// bars of `progress`.
+async fn forward(
+  pipe: impl AsyncRead + Unpin,
+  stderr: bool,
+  progress: &MultiProgress,
+) -> io::Result<()> {
+  let mut reader = BufReader::new(pipe);
+  let mut line = Vec::new();
+  while reader.read_until(b'\n', &mut line).await? > 0 {
+    progress.suspend(|| -> io::Result<()> {
+      if stderr {
+        io::stderr().lock().write_all(&line)
+      } else {
+        let mut stdout = io::stdout().lock();
+        stdout.write_all(&line)?;
+        stdout.flush()
+      }
+    })?;
+    line.clear();
+  }
+  Ok(())
+}
+
+/// Disk space `meta` takes up.
+#[cfg(unix)]
+pub(super) fn allocated(meta: &Metadata) -> u64 {
+  use std::os::unix::fs::MetadataExt;
+  meta.blocks() * 512
+}
+
+#[cfg(not(unix))]
+pub(super) fn allocated(meta: &Metadata) -> u64 {
+  meta.len()
+}
+
+/// Disk space taken up by everything below `dir`, counting hard links once
+/// and skipping what disappears while walking it.
+pub(super) fn disk_usage(dir: &Path) -> io::Result<u64> {
+  let gone = |e: &io::Error| e.kind() == io::ErrorKind::NotFound;
+  #[cfg(unix)]
+  let mut seen = HashSet::new();
+  let mut total = 0;
+  let mut stack = vec![dir.to_path_buf()];
+  while let Some(dir) = stack.pop() {
+    let entries = match fs::read_dir(&dir) {
+      Ok(entries) => entries,
+      Err(e) if gone(&e) => continue,
+      Err(e) => return Err(e),
+    };
+    for entry in entries {
+      let meta = match entry.and_then(|x| Ok((x.path(), x.metadata()?))) {
+        Ok(x) => x,
+        Err(e) if gone(&e) => continue,
+        Err(e) => return Err(e),
+      };
+      let (path, meta) = meta;
+      if meta.is_dir() {
+        stack.push(path);
+      }
+      #[cfg(unix)]
+      {
+        use std::os::unix::fs::MetadataExt;
+        if meta.nlink() > 1 && !meta.is_dir() && !seen.insert((meta.dev(), meta.ino())) {
+          continue;
+        }
+      }
+      total += allocated(&meta);
+    }
+  }
+  Ok(total)
+}
+
+/// Most disk space shell commands may take up in some directories together,
+/// checked every few seconds while they run.
+#[derive(Debug, Clone)]
+pub struct DiskQuota {
+  pub dirs: Vec<PathBuf>,
+  pub bytes: u64,
+}
+
+impl DiskQuota {
+  /// Returns once the directories take up more than the quota, with an error
+  /// saying so.
+  async fn exceeded(&self) -> anyhow::Error {
+    // Walking huge trees takes a while, so they are walked less often.
+    const INTERVAL: Duration = Duration::from_secs(5);
+    loop {
+      let start = Instant::now();
+      let dirs = self.dirs.clone();
+      let used = asyncify(move || dirs.iter().map(|x| disk_usage(x)).sum::<io::Result<u64>>());
+      match used.await {
+        Ok(used) if used > self.bytes => {
+          return anyhow!(
+            "build directory grew to {}, over the disk quota of {}",
+            HumanBytes(used),
+            HumanBytes(self.bytes)
+          )
+        }
+        Ok(_) => {}
+        Err(e) => return anyhow!("cannot measure the build directory: {e}"),
+      }
+      sleep(INTERVAL.max(start.elapsed() * 10)).await;
+    }
+  }
+}
+
+/// Runs `command` with its output forwarded line by line, so that commands
+/// running side by side and the bars of `progress` do not garble each other.
+/// The command is killed once it runs longer than `limit`, goes over `quota`,
+/// or when the returned future is dropped.
+pub async fn run(
+  mut command: Command,
+  limit: Option<Duration>,
+  quota: Option<&DiskQuota>,
+  progress: &MultiProgress,
+) -> anyhow::Result<ExitStatus> {
+  command
+    .stdout(Stdio::piped())
+    .stderr(Stdio::piped())
+    .kill_on_drop(true);
+  let mut child = command.spawn()?;
+  let stdout = child.stdout.take().expect("stdout should be piped");
+  let stderr = child.stderr.take().expect("stderr should be piped");
+  let output = async {
+    try_join!(
+      forward(stdout, false, progress),
+      forward(stderr, true, progress)
+    )
+  };
+  let exited = async {
+    output.await?;
+    anyhow::Ok(child.wait().await?)
+  };
+  let exceeded = async {
+    match quota {
+      Some(quota) => Err(quota.exceeded().await),
+      None => pending().await,
+    }
+  };
+  let finished = async {
+    let (result, _) = select(pin!(exited), pin!(exceeded)).await.factor_first();
+    result
+  };
+  match limit {
+    Some(limit) => match timeout(limit, finished).await {
+      Ok(status) => Ok(status?),
+      Err(_) => bail!("command timed out after {}", HumanDuration(limit)),
+    },
+    None => Ok(finished.await?),
+  }
+}
+
+/// Like [`run`], blocking until the command exits.
+pub fn run_blocking(
+  command: std::process::Command,
+  limit: Option<Duration>,
+  quota: Option<&DiskQuota>,
+) -> anyhow::Result<ExitStatus> {
+  let rt = RtBuilder::new_current_thread()
+    .enable_io()
+    .enable_time()
+    .build()?;
+  rt.block_on(run(command.into(), limit, quota, &MultiProgress::new()))
+}
+
+/// I/O scheduling class of build commands, see ionice(1).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum IoClass {
+  Realtime = 1,
+  BestEffort = 2,
+  Idle = 3,
+}
+
+/// CPU scheduling policy of build commands, see sched(7).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SchedPolicy {
+  Other,
+  Batch,
+  Idle,
+}
+
+/// How much of the machine shell commands of build scripts get, from
+/// `priority.json` of the config directory, so that builds in the background
+/// leave a desktop usable:
+///
+/// ```json
+/// { "nice": 10, "ionice": "best-effort", "ionice_level": 7, "policy": "batch" }
+/// ```
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Priority {
+  /// Niceness, from -20 to 19.
+  nice: Option<i32>,
+  ionice: Option<IoClass>,
+  /// Level within the realtime and best-effort classes, from 0 (highest) to
+  /// 7, 4 by default.
+  ionice_level: Option<u8>,
+  policy: Option<SchedPolicy>,
+}
+
+impl Priority {
+  fn path() -> Option<PathBuf> {
+    Some(config_dir()?.join("priority.json"))
+  }
+
+  /// Loads the configured priority, with `nice` instead of its niceness if
+  /// given.
+  pub fn load(nice: Option<i32>) -> anyhow::Result<Self> {
+    let mut priority = match Self::path().filter(|x| x.exists()) {
+      Some(path) => {
+        let f = File::open(&path)?;
+        (serde_json::from_reader::<_, Self>(io::BufReader::new(f)))
+          .with_context(|| format!("invalid priority config {}", path.display()))?
+      }
+      None => Self::default(),
+    };
+    priority.nice = nice.or(priority.nice);
+    if let Some(nice) = priority.nice.filter(|x| !(-20..=19).contains(x)) {
+      bail!("niceness {nice} is not between -20 and 19");
+    }
+    if let Some(level) = priority.ionice_level.filter(|x| *x > 7) {
+      bail!("ionice level {level} is not between 0 and 7");
+    }
+    if cfg!(not(target_os = "linux")) && priority.is_set() {
+      warning!("build priorities are only supported on Linux, ignoring them");
+      priority = Self::default();
+    }
+    Ok(priority)
+  }
+
+  fn is_set(&self) -> bool {
+    self.nice.is_some() || self.ionice.is_some() || self.policy.is_some()
+  }
+
+  /// Makes `command` run at this priority.
+  pub fn apply(&self, command: &mut std::process::Command) {
+    #[cfg(target_os = "linux")]
+    if self.is_set() {
+      use std::os::unix::process::CommandExt;
+      let priority = self.clone();
+      // SAFETY: only makes system calls, which are async-signal-safe.
+      unsafe {
+        command.pre_exec(move || priority.set());
+      }
+    }
+    #[cfg(not(target_os = "linux"))]
+    let _ = command;
+  }
+
+  /// Sets the priority of the calling process.
+  #[cfg(target_os = "linux")]
+  fn set(&self) -> io::Result<()> {
+    const IOPRIO_WHO_PROCESS: libc::c_int = 1;
+    const IOPRIO_CLASS_SHIFT: libc::c_int = 13;
+    let check = |ret: libc::c_long| match ret {
+      -1 => Err(io::Error::last_os_error()),
+      _ => Ok(()),
+    };
+    if let Some(policy) = self.policy {
+      let policy = match policy {
+        SchedPolicy::Other => libc::SCHED_OTHER,
+        SchedPolicy::Batch => libc::SCHED_BATCH,
+        SchedPolicy::Idle => libc::SCHED_IDLE,
+      };
+      let param = libc::sched_param { sched_priority: 0 };
+      // SAFETY: `param` outlives the call.
+      check(unsafe { libc::sched_setscheduler(0, policy, &param) }.into())?;
+    }
+    if let Some(nice) = self.nice {
+      // SAFETY: takes no pointers.
+      check(unsafe { libc::setpriority(libc::PRIO_PROCESS, 0, nice) }.into())?;
+    }
+    if let Some(class) = self.ionice {
+      let level = match class {
+        IoClass::Idle => 0,
+        _ => self.ionice_level.unwrap_or(4),
+      };
+      let ioprio = (class as libc::c_int) << IOPRIO_CLASS_SHIFT | libc::c_int::from(level);
+      // SAFETY: takes no pointers.
+      check(unsafe { libc::syscall(libc::SYS_ioprio_set, IOPRIO_WHO_PROCESS, 0, ioprio) })?;
+    }
+    Ok(())
+  }
+}
@@ -1,21 +1,51 @@
-use crate::types::{SourceFile, SourceLocation};
-use crate::util::{asyncify, tempfile_async, PB_STYLE_BYTES};
-use anyhow::bail;
+use super::cache::{EntryMeta, Partial, SourceCache, Validators};
+use super::git::{self, RevKind};
+use super::hashing::{hash_file, HashSample, ParallelHasher, CHUNK_SIZE};
+use super::lock::{Lockfile, SourceRecord};
+use crate::mirror::Mirrors;
+use crate::sign::{DigestBackend, SigningConfig};
+use crate::stats::Stats;
+use crate::types::{ChecksumKind, Hash, SourceFile, SourceLocation};
+use crate::util::{
+  asyncify, config_dir, file_mode, is_enclosed, is_safe_name, set_file_mode, PB_STYLE_BYTES,
+};
+use crate::warning;
+use anyhow::{bail, Context};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine as _;
+use bytes::Bytes;
 use bzip2::read::BzDecoder;
+use clap::Args;
 use flate2::read::GzDecoder;
-use futures::stream::FuturesUnordered;
-use futures::{TryFutureExt, TryStreamExt};
+use futures::channel::mpsc;
+use futures::executor::block_on;
+use futures::stream::{self, FuturesUnordered};
+use futures::{join, select, SinkExt, StreamExt, TryFutureExt, TryStreamExt};
+use httpdate::parse_http_date;
 use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
-use openssl::error::ErrorStack;
-use reqwest::{Client, Url};
-use std::fs::{create_dir_all, remove_file, File, Permissions};
-use std::io::{self, Read, Seek};
-use std::os::unix::prelude::PermissionsExt;
-use std::path::{Component, Path};
+use openssl::sha::{sha256, Sha256};
+use percent_encoding::percent_decode_str;
+use reqwest::header::{
+  HeaderMap, HeaderValue, CONTENT_LENGTH, CONTENT_RANGE, IF_MODIFIED_SINCE, IF_NONE_MATCH,
+  IF_RANGE, LOCATION, RANGE, RETRY_AFTER,
+};
+use reqwest::redirect::Policy;
+use reqwest::{Client, ClientBuilder, Method, NoProxy, Proxy, Response, StatusCode, Url};
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fs::{create_dir_all, read_link, remove_dir_all, remove_file, rename, File};
+use std::io::{self, BufReader, Read, Seek, Write};
+use std::mem::replace;
+use std::path::{Path, PathBuf};
 use std::str::from_utf8;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tempfile::{NamedTempFile, TempPath};
 use tokio::fs::{copy, metadata, File as AsyncFile};
-use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWrite, AsyncWriteExt};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use tokio::runtime::Builder as RtBuilder;
+use tokio::time::{sleep, sleep_until, Instant};
+use url::Position;
 use xz2::read::XzDecoder;
 use zip::ZipArchive;
 use zstd::stream::read::Decoder as ZstDecoder;
@@ -53,6 +83,10 @@ impl ArchiveKind {
     };
     Some((kind, &name[..name.len() - ext_len]))
   }
+
+  fn is_tar(self) -> bool {
+    !matches!(self, Self::Zip | Self::Deb | Self::Ar)
+  }
 }
 
 struct FlowMeter<R: Read> {
@@ -80,52 +114,176 @@ impl<R: Read + Seek> Seek for FlowMeter<R> {
   }
 }
 
-// Taken from ZipArchive::enclosed_name
-fn is_safe_name(name: &str) -> bool {
-  if name.contains('\0') {
-    return false;
+// Caps on what extracting a single archive may produce, against
+// decompression bombs.
+const MAX_EXTRACTED_SIZE: u64 = 64 << 30;
+const MAX_EXTRACTED_FILES: u64 = 1 << 20;
+
+/// Keeps track of what an archive extracted so far, refusing anything
+/// dangerous.
+struct ExtractGuard {
+  allow_special_files: bool,
+  files: u64,
+  size: u64,
+}
+
+impl ExtractGuard {
+  fn new(allow_special_files: bool) -> Self {
+    Self {
+      allow_special_files,
+      files: 0,
+      size: 0,
+    }
+  }
+
+  /// Accounts for a new entry of `size` bytes.
+  fn add(&mut self, size: u64) -> io::Result<()> {
+    self.files += 1;
+    if self.files > MAX_EXTRACTED_FILES {
+      return Err(io::Error::other(format!(
+        "archive contains more than {MAX_EXTRACTED_FILES} entries"
+      )));
+    }
+    self.grow(size)
   }
-  let path = Path::new(name);
-  let mut depth = 0usize;
-  for component in path.components() {
-    match component {
-      Component::Prefix(_) | Component::RootDir => return false,
-      Component::ParentDir => {
-        if depth == 0 {
-          return false;
-        }
-        depth -= 1;
+
+  fn grow(&mut self, size: u64) -> io::Result<()> {
+    self.size = self.size.saturating_add(size);
+    if self.size > MAX_EXTRACTED_SIZE {
+      return Err(io::Error::other(format!(
+        "archive extracts to more than {} GiB",
+        MAX_EXTRACTED_SIZE >> 30
+      )));
+    }
+    Ok(())
+  }
+
+  /// Copies an entry whose size is not known beforehand.
+  fn copy(&mut self, mut src: impl Read, mut dst: impl Write) -> io::Result<()> {
+    let mut buf = [0; 8192];
+    loop {
+      let bytes = src.read(&mut buf)?;
+      if bytes == 0 {
+        return Ok(());
+      }
+      self.grow(bytes as _)?;
+      dst.write_all(&buf[..bytes])?;
+    }
+  }
+
+  fn check_tar_entry<R: Read>(&mut self, entry: &tar::Entry<R>) -> io::Result<()> {
+    self.add(entry.size())?;
+    let kind = entry.header().entry_type();
+    let path = entry.path()?;
+    let is_special = kind.is_character_special() || kind.is_block_special() || kind.is_fifo();
+    if is_special && !self.allow_special_files {
+      return Err(io::Error::other(format!(
+        "refusing to extract special file '{}'",
+        path.display()
+      )));
+    }
+    if kind.is_symlink() || kind.is_hard_link() {
+      let target = entry.link_name()?.unwrap_or_default();
+      // Symlinks are relative to where they are, hard links to the archive
+      // root.
+      let resolved = if kind.is_symlink() {
+        path.parent().unwrap_or(Path::new("")).join(&target)
+      } else {
+        target.to_path_buf()
+      };
+      if !is_enclosed(&resolved) {
+        return Err(io::Error::other(format!(
+          "link '{}' points outside of the archive: '{}'",
+          path.display(),
+          target.display()
+        )));
+      }
+    }
+    Ok(())
+  }
+}
+
+fn unpack_tar<R: Read>(
+  ar: &mut tar::Archive<R>,
+  dst: &Path,
+  guard: &mut ExtractGuard,
+) -> io::Result<()> {
+  create_dir_all(dst)?;
+  let dst = &dst.canonicalize()?;
+  let mut dirs = Vec::new();
+  for entry in ar.entries()? {
+    let mut entry = entry?;
+    guard.check_tar_entry(&entry)?;
+    if entry.header().entry_type().is_dir() {
+      dirs.push(entry);
+    } else {
+      entry.unpack_in(dst)?;
+    }
+  }
+  // Like `tar::Archive::unpack`, create directories last and deepest first, so
+  // that their permissions do not get in the way.
+  dirs.sort_by(|a, b| b.path_bytes().cmp(&a.path_bytes()));
+  for mut dir in dirs {
+    dir.unpack_in(dst)?;
+  }
+  Ok(())
+}
+
+fn unpack_zip(
+  mut ar: ZipArchive<impl Read + Seek>,
+  dst: &Path,
+  guard: &mut ExtractGuard,
+) -> io::Result<()> {
+  for i in 0..ar.len() {
+    let mut file = ar.by_index(i)?;
+    guard.add(0)?;
+    let Some(name) = file.enclosed_name().map(Path::to_path_buf) else {
+      return Err(io::Error::other(format!(
+        "invalid file path '{}'",
+        file.name()
+      )));
+    };
+    let path = dst.join(name);
+    if file.is_dir() {
+      create_dir_all(&path)?;
+    } else {
+      if let Some(parent) = path.parent() {
+        create_dir_all(parent)?;
       }
-      Component::Normal(_) => depth += 1,
-      Component::CurDir => {}
+      guard.copy(&mut file, File::create(&path)?)?;
+    }
+    if let Some(mode) = file.unix_mode() {
+      set_file_mode(&path, mode)?;
     }
   }
-  true
+  Ok(())
 }
 
-fn extract_ar(src: impl Read + Seek, dst: &Path) -> io::Result<()> {
+fn extract_ar(src: impl Read + Seek, dst: &Path, guard: &mut ExtractGuard) -> io::Result<()> {
   let mut ar = ar::Archive::new(src);
   while let Some(mut entry) = ar.next_entry().transpose()? {
-    let name = from_utf8(entry.header().identifier())
-      .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+    let name = from_utf8(entry.header().identifier()).map_err(io::Error::other)?;
     if !is_safe_name(name) {
       continue;
     }
+    guard.add(entry.header().size())?;
     let path = dst.join(name);
     let parent = path.parent().expect("path parent should exist now");
     if !parent.exists() {
       create_dir_all(parent)?;
     }
-    let mut f = File::create(path)?;
-    io::copy(&mut entry, &mut f)?;
-    let perm = Permissions::from_mode(entry.header().mode());
-    f.set_permissions(perm)?;
+    io::copy(&mut entry, &mut File::create(&path)?)?;
+    set_file_mode(&path, entry.header().mode())?;
   }
   Ok(())
 }
 
-fn extract_deb(mut src: FlowMeter<impl Read + Seek>, dst: &Path) -> io::Result<()> {
-  extract_ar(&mut src, dst)?;
+fn extract_deb(
+  mut src: FlowMeter<impl Read + Seek>,
+  dst: &Path,
+  guard: &mut ExtractGuard,
+) -> io::Result<()> {
+  extract_ar(&mut src, dst, guard)?;
   let mut pb = src.pb;
   let orig_len = pb.length();
 
@@ -136,7 +294,7 @@ fn extract_deb(mut src: FlowMeter<impl Read + Seek>, dst: &Path) -> io::Result<(
     pb.set_length(f.metadata()?.len());
     let f = FlowMeter::new(f, pb);
     let mut ar = tar::Archive::new(XzDecoder::new(f));
-    ar.unpack(dst.join(x))?;
+    unpack_tar(&mut ar, &dst.join(x), guard)?;
     remove_file(control_path)?;
     pb = ar.into_inner().into_inner().pb;
   }
@@ -152,89 +310,902 @@ fn extract(
   src: impl Read + Seek,
   dst: impl AsRef<Path>,
   pb: ProgressBar,
+  allow_special_files: bool,
 ) -> io::Result<()> {
   use ArchiveKind::*;
   pb.set_prefix("extracting");
   let src = FlowMeter::new(src, pb);
+  let dst = dst.as_ref();
+  let guard = &mut ExtractGuard::new(allow_special_files);
   match kind {
-    Tar => tar::Archive::new(src).unpack(dst)?,
-    TarGz => tar::Archive::new(GzDecoder::new(src)).unpack(dst)?,
-    TarXz => tar::Archive::new(XzDecoder::new(src)).unpack(dst)?,
-    TarBz2 => tar::Archive::new(BzDecoder::new(src)).unpack(dst)?,
-    TarZst => tar::Archive::new(ZstDecoder::new(src)?).unpack(dst)?,
-    Zip => ZipArchive::new(src)?.extract(dst)?,
-    Ar => extract_ar(src, dst.as_ref())?,
-    Deb => extract_deb(src, dst.as_ref())?,
+    Zip => unpack_zip(ZipArchive::new(src)?, dst, guard)?,
+    Ar => extract_ar(src, dst, guard)?,
+    Deb => extract_deb(src, dst, guard)?,
+    _ => extract_tar(kind, src, dst, guard)?,
   }
   Ok(())
 }
 
-async fn download(
-  client: &Client,
-  url: Url,
-  mut dst: impl AsyncWrite + Unpin,
+/// Extracts a tar-based archive, which unlike the other kinds can be read
+/// sequentially.
+fn extract_tar(
+  kind: ArchiveKind,
+  src: impl Read,
+  dst: &Path,
+  guard: &mut ExtractGuard,
+) -> io::Result<()> {
+  use ArchiveKind::*;
+  match kind {
+    Tar => unpack_tar(&mut tar::Archive::new(src), dst, guard),
+    TarGz => unpack_tar(&mut tar::Archive::new(GzDecoder::new(src)), dst, guard),
+    TarXz => unpack_tar(&mut tar::Archive::new(XzDecoder::new(src)), dst, guard),
+    TarBz2 => unpack_tar(&mut tar::Archive::new(BzDecoder::new(src)), dst, guard),
+    TarZst => unpack_tar(&mut tar::Archive::new(ZstDecoder::new(src)?), dst, guard),
+    Zip | Ar | Deb => unreachable!("{kind:?} is not a tar archive"),
+  }
+}
+
+/// Blocking reader over chunks sent from async code, so that an archive can be
+/// extracted while it is still being downloaded.
+struct ChunkReader {
+  rx: mpsc::Receiver<Bytes>,
+  chunk: Bytes,
+}
+
+impl Read for ChunkReader {
+  fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+    while self.chunk.is_empty() {
+      match block_on(self.rx.next()) {
+        Some(chunk) => self.chunk = chunk,
+        None => return Ok(0),
+      }
+    }
+    let len = buf.len().min(self.chunk.len());
+    buf[..len].copy_from_slice(&self.chunk.split_to(len));
+    Ok(len)
+  }
+}
+
+/// Options for talking to the servers sources are fetched from.
+#[derive(Debug, Clone, Args)]
+pub struct FetchOptions {
+  /// User-Agent header sent with every request
+  #[arg(
+    long,
+    value_name = "AGENT",
+    default_value = concat!("ewepkg/", env!("CARGO_PKG_VERSION"), " (+https://eweos.org)")
+  )]
+  pub user_agent: String,
+
+  /// Minimum time in milliseconds between two requests to the same host
+  #[arg(long, value_name = "MS", default_value_t = 0)]
+  pub host_delay: u64,
+
+  /// Download sources again instead of reusing cached copies, and fetch Git
+  /// mirrors even for tags and commits they have; what is downloaded still
+  /// replaces the cached copies
+  #[arg(long)]
+  pub no_cache: bool,
+
+  /// How many times to retry a download failing with a connection error, a
+  /// timeout or a server error, before trying the next mirror or giving up
+  #[arg(long, value_name = "N", default_value_t = 3)]
+  pub retries: u32,
+
+  /// Time in milliseconds to wait before retrying a download, doubled with
+  /// each retry
+  #[arg(long, value_name = "MS", default_value_t = 1000)]
+  pub retry_delay: u64,
+}
+
+impl FetchOptions {
+  /// Command line arguments reproducing these options.
+  pub fn to_args(&self) -> Vec<String> {
+    let mut args = vec![
+      "--user-agent".into(),
+      self.user_agent.clone(),
+      "--host-delay".into(),
+      self.host_delay.to_string(),
+      "--retries".into(),
+      self.retries.to_string(),
+      "--retry-delay".into(),
+      self.retry_delay.to_string(),
+    ];
+    if self.no_cache {
+      args.push("--no-cache".into());
+    }
+    args
+  }
+}
+
+const MAX_REDIRECTS: usize = 10;
+// How often and how long we are willing to wait when a server answers 429 or
+// 503 with a Retry-After header.
+const MAX_RETRIES: usize = 3;
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(300);
+// Longest wait between two tries of a download failing on its own.
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(60);
+// Unanswered connection attempts fail, and get retried, after this long.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Parses a Retry-After header, which holds either seconds or an HTTP date.
+fn retry_after(resp: &Response) -> Option<Duration> {
+  let value = resp.headers().get(RETRY_AFTER)?.to_str().ok()?;
+  match value.trim().parse() {
+    Ok(secs) => Some(Duration::from_secs(secs)),
+    Err(_) => {
+      let date = parse_http_date(value).ok()?;
+      Some(date.duration_since(SystemTime::now()).unwrap_or_default())
+    }
+  }
+}
+
+/// Proxies to fetch sources through, from `proxy.json` of the config
+/// directory, overriding `HTTP_PROXY`, `HTTPS_PROXY`, `ALL_PROXY` and
+/// `NO_PROXY`:
+///
+/// ```json
+/// { "https": "http://proxy:3128", "all": "socks5h://proxy:1080", "no_proxy": "localhost,.lan" }
+/// ```
+///
+/// Proxies for a scheme come before `all`. Git sources are fetched by git,
+/// which only follows the environment.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ProxyConfig {
+  http: Option<Url>,
+  https: Option<Url>,
+  all: Option<Url>,
+  /// Hosts, domains and networks to reach directly, comma-separated.
+  no_proxy: Option<String>,
+}
+
+impl ProxyConfig {
+  fn path() -> Option<PathBuf> {
+    Some(config_dir()?.join("proxy.json"))
+  }
+
+  fn load() -> anyhow::Result<Option<Self>> {
+    let Some(path) = Self::path().filter(|x| x.exists()) else {
+      return Ok(None);
+    };
+    let f = File::open(&path)?;
+    (serde_json::from_reader(BufReader::new(f)))
+      .with_context(|| format!("invalid proxy config {}", path.display()))
+      .map(Some)
+  }
+
+  /// Sets up `builder` with these proxies instead of the environment's.
+  fn apply(self, mut builder: ClientBuilder) -> anyhow::Result<ClientBuilder> {
+    let no_proxy = (self.no_proxy.as_deref()).and_then(NoProxy::from_string);
+    builder = builder.no_proxy();
+    for (url, proxy) in [
+      (self.http, Proxy::http as fn(Url) -> reqwest::Result<Proxy>),
+      (self.https, Proxy::https),
+      (self.all, Proxy::all),
+    ] {
+      if let Some(url) = url {
+        let proxy = proxy(url.clone()).with_context(|| format!("invalid proxy {url}"))?;
+        builder = builder.proxy(proxy.no_proxy(no_proxy.clone()));
+      }
+    }
+    Ok(builder)
+  }
+}
+
+/// HTTP client shared by all downloads, spacing out requests to the same host
+/// so that fetching many sources does not get us blocked by mirrors.
+struct HttpClient {
+  client: Client,
+  delay: Duration,
+  /// Earliest time the next request to each host may be sent.
+  next: Mutex<HashMap<String, Instant>>,
+  /// Completed downloads as their host, size and duration, for statistics.
+  downloads: Mutex<Vec<(String, u64, Duration)>>,
+  /// Time spent verifying checksums, for statistics.
+  hashes: Mutex<Vec<HashSample>>,
+  mirrors: Mirrors,
+  digest: DigestBackend,
+  /// Whether to ignore cached copies, see [`FetchOptions::no_cache`].
+  no_cache: bool,
+  /// Whether the package tree requires checksums, so that a source pinned
+  /// only in the lockfile must not change even with `skip_checksum`.
+  require_checksums: bool,
+  retries: u32,
+  retry_delay: Duration,
+}
+
+impl HttpClient {
+  fn new(options: &FetchOptions) -> anyhow::Result<Self> {
+    let mut builder = Client::builder()
+      .redirect(Policy::none())
+      .user_agent(&options.user_agent)
+      .connect_timeout(CONNECT_TIMEOUT);
+    if let Some(config) = ProxyConfig::load()? {
+      builder = config.apply(builder)?;
+    }
+    let client = builder.build()?;
+    Ok(Self {
+      client,
+      delay: Duration::from_millis(options.host_delay),
+      next: Mutex::new(HashMap::new()),
+      downloads: Mutex::new(Vec::new()),
+      hashes: Mutex::new(Vec::new()),
+      mirrors: Mirrors::default(),
+      digest: DigestBackend::default(),
+      no_cache: options.no_cache,
+      require_checksums: false,
+      retries: options.retries,
+      retry_delay: Duration::from_millis(options.retry_delay),
+    })
+  }
+
+  /// Notes a download of `bytes` from the host of `url` taking `elapsed`.
+  /// Small files tell more about latency than speed, so they are left out.
+  fn record(&self, url: &Url, bytes: u64, elapsed: Duration) {
+    const MIN_BYTES: u64 = 1 << 20;
+    if let (Some(host), true) = (url.host_str(), bytes >= MIN_BYTES) {
+      let mut downloads = self.downloads.lock().unwrap();
+      downloads.push((host.into(), bytes, elapsed));
+    }
+  }
+
+  /// Adds the downloads and checksum verifications so far to the
+  /// statistics.
+  fn save_stats(&self) {
+    let downloads = std::mem::take(&mut *self.downloads.lock().unwrap());
+    let hashes = std::mem::take(&mut *self.hashes.lock().unwrap());
+    if !downloads.is_empty() || !hashes.is_empty() {
+      Stats::update(|stats| {
+        for (host, bytes, elapsed) in downloads {
+          stats.record_download(&host, bytes, elapsed);
+        }
+        for x in hashes {
+          stats.record_hash(x.kind, x.bytes, x.elapsed);
+        }
+      });
+    }
+  }
+
+  /// Waits until a request to the host of `url` may be sent, pushing back the
+  /// turn of the next one.
+  async fn wait_turn(&self, url: &Url) {
+    let host = url.host_str().unwrap_or_default();
+    let turn = {
+      let mut next = self.next.lock().unwrap();
+      let now = Instant::now();
+      let turn = next.get(host).map_or(now, |x| now.max(*x));
+      next.insert(host.into(), turn + self.delay);
+      turn
+    };
+    sleep_until(turn).await;
+  }
+
+  /// Keeps requests to the host of `url` from being sent before `until`.
+  fn defer(&self, url: &Url, until: Instant) {
+    let host = url.host_str().unwrap_or_default();
+    let mut next = self.next.lock().unwrap();
+    let turn = next.entry(host.into()).or_insert(until);
+    *turn = until.max(*turn);
+  }
+
+  /// Sends a request to `url`, following redirects by hand so that the chain
+  /// can be recorded. Returns the final response, whatever its status, and
+  /// every URL that redirected along with how.
+  async fn send(
+    &self,
+    method: Method,
+    mut url: Url,
+    headers: HeaderMap,
+  ) -> anyhow::Result<(Response, Vec<(Url, StatusCode)>)> {
+    let mut redirects = Vec::new();
+    let mut retries = 0;
+    loop {
+      let req = (self.client.request(method.clone(), url.clone())).headers(headers.clone());
+      self.wait_turn(&url).await;
+      let resp = req.send().await?;
+
+      let status = resp.status();
+      if status == StatusCode::TOO_MANY_REQUESTS || status == StatusCode::SERVICE_UNAVAILABLE {
+        if let Some(wait) = retry_after(&resp) {
+          if retries == MAX_RETRIES || wait > MAX_RETRY_AFTER {
+            bail!("{status}, server asked to retry after {}s", wait.as_secs());
+          }
+          retries += 1;
+          self.defer(&url, Instant::now() + wait);
+          continue;
+        }
+      }
+
+      let location = (status.is_redirection())
+        .then(|| resp.headers().get(LOCATION))
+        .flatten();
+      let Some(location) = location else {
+        return Ok((resp, redirects));
+      };
+      if redirects.len() == MAX_REDIRECTS {
+        bail!("too many redirects");
+      }
+      let next = url.join(location.to_str()?)?;
+      redirects.push((replace(&mut url, next), status));
+    }
+  }
+
+  /// Sends a GET request to `url`, conditional if `validators` are given.
+  /// With `resume` as the size and `If-Range` validator of what was
+  /// downloaded before, only the rest is asked for, which the server may
+  /// still answer with all of it. Returns the final response and every URL
+  /// that redirected.
+  async fn get(
+    &self,
+    url: Url,
+    validators: Option<&Validators>,
+    resume: Option<(u64, &str)>,
+  ) -> anyhow::Result<(Response, Vec<Url>)> {
+    let mut headers = HeaderMap::new();
+    if let Some(v) = validators {
+      if let Some(etag) = &v.etag {
+        headers.insert(IF_NONE_MATCH, etag.parse()?);
+      }
+      if let Some(last_modified) = &v.last_modified {
+        headers.insert(IF_MODIFIED_SINCE, last_modified.parse()?);
+      }
+    }
+    let mut ranged = headers.clone();
+    if let Some((offset, validator)) = resume {
+      ranged.insert(RANGE, format!("bytes={offset}-").parse()?);
+      ranged.insert(IF_RANGE, validator.parse()?);
+    }
+    let (mut resp, mut redirects) = self.send(Method::GET, url.clone(), ranged).await?;
+    if resume.is_some() && resp.status() == StatusCode::RANGE_NOT_SATISFIABLE {
+      // What we have is no shorter than the file, so it is not the same one.
+      (resp, redirects) = self.send(Method::GET, url, headers).await?;
+    }
+    let redirects = redirects.into_iter().map(|(url, _)| url).collect();
+    Ok((resp.error_for_status()?, redirects))
+  }
+}
+
+/// Returns the directory an archive extracted into `dst` actually lives in,
+/// i.e. its only top-level directory if it has one.
+fn extracted_root(dst: &Path) -> io::Result<PathBuf> {
+  let mut entries = dst.read_dir()?;
+  if let (Some(entry), None) = (entries.next().transpose()?, entries.next()) {
+    if entry.file_type()?.is_dir() {
+      return Ok(entry.path());
+    }
+  }
+  Ok(dst.to_path_buf())
+}
+
+/// Computes a Merkle-style digest of the tree at `path`: files hash their
+/// content and executable bit, symlinks their target, and directories the
+/// sorted names and digests of their entries.
+pub fn tree_digest(path: &Path) -> io::Result<[u8; 32]> {
+  let meta = path.symlink_metadata()?;
+  let mut hasher = Sha256::new();
+  if meta.is_dir() {
+    let mut names = (path.read_dir()?)
+      .map(|x| x.map(|x| x.file_name()))
+      .collect::<io::Result<Vec<_>>>()?;
+    names.sort();
+    hasher.update(b"d");
+    for name in names {
+      hasher.update(name.as_encoded_bytes());
+      hasher.update(b"\0");
+      hasher.update(&tree_digest(&path.join(name))?);
+    }
+  } else if meta.is_symlink() {
+    hasher.update(b"l");
+    hasher.update(read_link(path)?.as_os_str().as_encoded_bytes());
+  } else {
+    hasher.update(if file_mode(&meta) & 0o111 != 0 {
+      b"x"
+    } else {
+      b"f"
+    });
+    let mut f = File::open(path)?;
+    let mut buf = [0; 8192];
+    loop {
+      let bytes = f.read(&mut buf)?;
+      if bytes == 0 {
+        break;
+      }
+      hasher.update(&buf[..bytes]);
+    }
+  }
+  Ok(hasher.finish())
+}
+
+/// Newest modification time of the files in the tree at `path`, in seconds
+/// since the Unix epoch. Directories are left out, as those not in the
+/// archive are made when extracting it.
+fn newest_mtime(path: &Path) -> io::Result<Option<u64>> {
+  let meta = path.symlink_metadata()?;
+  if !meta.is_dir() {
+    let mtime = meta.modified()?.duration_since(UNIX_EPOCH);
+    return Ok(mtime.ok().map(|x| x.as_secs()));
+  }
+  let mut newest = None;
+  for entry in path.read_dir()? {
+    newest = newest.max(newest_mtime(&entry?.path())?);
+  }
+  Ok(newest)
+}
+
+/// Running checksums of a source file, compared against the expected ones
+/// once all of it went through. Time spent on them goes to the statistics of
+/// `client`.
+struct Checker<'a> {
+  file: &'a SourceFile,
+  client: &'a HttpClient,
+  hasher: ParallelHasher,
+}
+
+impl<'a> Checker<'a> {
+  fn new(file: &'a SourceFile, client: &'a HttpClient) -> Self {
+    // Files without checksums are hashed for the lockfile instead.
+    let hasher = match file.checksums.is_empty() {
+      true => ParallelHasher::new(client.digest, [&ChecksumKind::Sha256]),
+      false => ParallelHasher::new(client.digest, file.checksums.keys()),
+    };
+    Self {
+      file,
+      client,
+      hasher,
+    }
+  }
+
+  fn update(&mut self, data: &Bytes) {
+    self.hasher.update(data);
+  }
+
+  /// Verifies the checksums of the file, or returns its SHA-256 digest if it
+  /// has none.
+  fn finish(self) -> anyhow::Result<Option<Hash>> {
+    let (sums, samples): (Vec<_>, Vec<_>) = self.hasher.finish().into_iter().unzip();
+    self.client.hashes.lock().unwrap().extend(samples);
+    if self.file.checksums.is_empty() {
+      return Ok(sums.into_iter().next().map(Into::into));
+    }
+    for ((kind, expected_sum), sum) in self.file.checksums.iter().zip(sums) {
+      if sum != **expected_sum {
+        bail!(
+          "{} checksum for '{}' does not correspond:\n\texpected: {}\n\tgot:      {}",
+          kind.name(),
+          self.file.location,
+          hex::encode(expected_sum),
+          hex::encode(sum)
+        );
+      }
+    }
+    Ok(None)
+  }
+}
+
+/// Writes the body of `resp` into `dst`, feeding it to `checker` and, if
+/// given, to `tee` on the way. The first `offset` bytes of `dst`, received
+/// before, go through them first. Returns the size of the body.
+async fn receive(
+  resp: Response,
+  mut dst: impl AsyncRead + AsyncWrite + Unpin,
+  offset: u64,
+  checker: &mut Checker<'_>,
+  mut tee: Option<mpsc::Sender<Bytes>>,
   pb: &ProgressBar,
-) -> anyhow::Result<()> {
-  let resp = client.get(url.clone()).send().await?.error_for_status()?;
+) -> anyhow::Result<u64> {
   if let Some(len) = resp.content_length() {
-    pb.set_length(len);
+    pb.set_length(offset + len);
   }
+  let mut replayed = 0;
+  while replayed < offset {
+    let mut buf = Vec::with_capacity(CHUNK_SIZE);
+    let len = (&mut dst)
+      .take((offset - replayed).min(CHUNK_SIZE as u64))
+      .read_to_end(&mut buf)
+      .await?;
+    if len == 0 {
+      bail!("partial download is shorter than expected");
+    }
+    replayed += len as u64;
+    let bytes = Bytes::from(buf);
+    checker.update(&bytes);
+    if let Some(tx) = &mut tee {
+      if tx.send(bytes).await.is_err() {
+        tee = None;
+      }
+    }
+    pb.inc(len as _);
+  }
+  let mut size = 0;
   let mut stream = resp.bytes_stream();
   while let Some(bytes) = stream.try_next().await? {
+    size += bytes.len() as u64;
     dst.write_all(&bytes).await?;
+    checker.update(&bytes);
+    if let Some(tx) = &mut tee {
+      // The reader may stop early, e.g. before trailing padding or because it
+      // failed; either way, its own result tells.
+      if tx.send(bytes.clone()).await.is_err() {
+        tee = None;
+      }
+    }
     pb.inc(bytes.len() as _);
   }
-  Ok(())
+  dst.flush().await?;
+  Ok(size)
 }
 
-async fn verify(file: &SourceFile, f: &mut AsyncFile, pb: &ProgressBar) -> anyhow::Result<()> {
+async fn verify(
+  file: &SourceFile,
+  f: &mut AsyncFile,
+  client: &HttpClient,
+  pb: &ProgressBar,
+) -> anyhow::Result<()> {
   pb.set_prefix("verifying");
-  let mut checksums = file
-    .checksums
-    .iter()
-    .map(|(kind, sum)| Ok::<_, ErrorStack>((kind, kind.new_hasher()?, sum)))
-    .collect::<Result<Vec<_>, _>>()?;
-  let mut buf = [0; 8192];
+  let mut checker = Checker::new(file, client);
   loop {
-    let bytes = f.read(&mut buf).await?;
+    let mut buf = Vec::with_capacity(CHUNK_SIZE);
+    let bytes = (&mut *f)
+      .take(CHUNK_SIZE as u64)
+      .read_to_end(&mut buf)
+      .await?;
     if bytes == 0 {
       break;
     }
     pb.inc(bytes as _);
-    for (_, hasher, _) in checksums.iter_mut() {
-      hasher.update(&buf[..bytes])?;
-    }
-  }
-  for (kind, mut hasher, expected_sum) in checksums {
-    let sum = hasher.finish()?;
-    if *sum != **expected_sum {
-      bail!(
-        "{} checksum for '{}' does not correspond:\n\texpected: {}\n\tgot:      {}",
-        kind.name(),
-        file.location,
-        hex::encode(expected_sum),
-        hex::encode(sum)
-      );
-    }
+    checker.update(&buf.into());
   }
+  checker.finish()?;
   Ok(())
 }
 
-async fn fetch_single_source_inner(
-  source_dir: &Path,
+/// A tar-based archive to extract while it is being downloaded.
+#[derive(Clone)]
+struct StreamTarget {
+  kind: ArchiveKind,
+  dst: PathBuf,
+  allow_special_files: bool,
+}
+
+struct Cached {
+  path: PathBuf,
+  meta: EntryMeta,
+  /// Whether the file was already in the cache instead of downloaded anew.
+  reused: bool,
+  /// Whether the file was extracted into its [`StreamTarget`] already.
+  extracted: bool,
+  /// SHA-256 digest of a download without checksums, computed while it was
+  /// received.
+  sha256: Option<Hash>,
+}
+
+/// Offset the body of a ranged response starts at.
+fn range_start(resp: &Response) -> Option<u64> {
+  let range = resp.headers().get(CONTENT_RANGE)?.to_str().ok()?;
+  let (start, _) = range.strip_prefix("bytes ")?.split_once('-')?;
+  start.parse().ok()
+}
+
+/// Makes sure an up-to-date copy of `url` is in the source cache, downloading
+/// it from `from`, which is `url` or a mirror of it. The cached file is
+/// verified if `file` has any checksum. If it has to be downloaded and
+/// `stream` is given, it is extracted on the fly as well. Interrupted
+/// downloads are kept and resumed where the server allows.
+async fn fetch_cached(
   file: &SourceFile,
-  client: Client,
-  mp: MultiProgress,
-) -> anyhow::Result<()> {
-  let ar_kind = if file.extract {
-    file
-      .location
-      .file_name()
-      .and_then(ArchiveKind::from_file_name)
-  } else {
-    None
+  url: &Url,
+  from: &Url,
+  client: &HttpClient,
+  cache: &SourceCache,
+  stream: Option<StreamTarget>,
+  pb: &ProgressBar,
+) -> anyhow::Result<Cached> {
+  let entry = cache.entry(url);
+  let mut validators = None;
+  if entry.exists() && !client.no_cache {
+    let meta = entry.meta();
+    if file.checksums.is_empty() {
+      // Without checksums we cannot tell whether the cached copy is still the
+      // right one, so ask the server instead.
+      // Validators of one server mean nothing to another.
+      validators = Some(meta.validators).filter(|x| !x.is_empty() && from == url);
+    } else {
+      let mut f = AsyncFile::open(entry.path()).await?;
+      pb.set_length(f.metadata().await?.len());
+      if verify(file, &mut f, client, pb).await.is_ok() {
+        return Ok(Cached {
+          path: entry.path().into(),
+          meta,
+          reused: true,
+          extracted: false,
+          sha256: None,
+        });
+      }
+      pb.reset();
+    }
+  }
+
+  pb.set_prefix("downloading");
+  let resumed = match validators {
+    None if !client.no_cache => entry.take_partial(from),
+    _ => None,
+  };
+  let (temp, partial) = match resumed {
+    Some((temp, partial)) => (temp, Some(partial)),
+    None => (cache.tempfile()?, None),
+  };
+  let offset = temp.as_file().metadata()?.len();
+  let resume = (partial.as_ref())
+    .and_then(|x| x.validators.if_range())
+    .filter(|_| offset > 0)
+    .map(|x| (offset, x));
+  let start = Instant::now();
+  let (resp, redirects) = match client.get(from.clone(), validators.as_ref(), resume).await {
+    Ok(x) => x,
+    Err(e) => {
+      if let (Some(partial), Some(_)) = (&partial, resume) {
+        let _ = entry.keep_partial(temp, partial);
+      }
+      return Err(e);
+    }
+  };
+  let mut meta = EntryMeta {
+    validators: Validators::from_headers(resp.headers()),
+    redirects,
+    final_url: Some(resp.url().clone()),
   };
+  if from != url {
+    // Where mirrors redirect to says nothing about the upstream URL.
+    meta.redirects.clear();
+    meta.final_url = None;
+  }
+  if resp.status() == StatusCode::NOT_MODIFIED {
+    meta.validators = validators.unwrap_or_default();
+    entry.set_meta(&meta)?;
+    return Ok(Cached {
+      path: entry.path().into(),
+      meta,
+      reused: true,
+      extracted: false,
+      sha256: None,
+    });
+  }
+
+  let offset = match resp.status() {
+    StatusCode::PARTIAL_CONTENT if resume.is_some() => {
+      if range_start(&resp) != Some(offset) {
+        bail!("server resumed the download at the wrong offset");
+      }
+      offset
+    }
+    _ => {
+      temp.as_file().set_len(0)?;
+      0
+    }
+  };
+  // Keeps what was received when the download breaks off, so that it can be
+  // resumed next time, provided the server can tell if the file changed.
+  let partial = (meta.validators.if_range().is_some()).then(|| Partial {
+    from: from.clone(),
+    validators: meta.validators.clone(),
+  });
+  let interrupted = |e: anyhow::Error, temp: NamedTempFile| {
+    let received = temp.as_file().metadata().is_ok_and(|x| x.len() > 0);
+    if let Some(partial) = partial.as_ref().filter(|_| received) {
+      let _ = entry.keep_partial(temp, partial);
+    }
+    e
+  };
+
+  let final_url = resp.url().clone();
+  let mut f = AsyncFile::from_std(temp.reopen()?);
+  let mut checker = Checker::new(file, client);
+  let (extracted, sha256) = match stream {
+    Some(StreamTarget {
+      kind,
+      dst,
+      allow_special_files,
+    }) => {
+      let (tx, rx) = mpsc::channel(16);
+      let reader = ChunkReader {
+        rx,
+        chunk: Bytes::new(),
+      };
+      // Extracted next to `dst`, and moved there only once verified, so that
+      // nothing from an unverified archive is left where sources go.
+      let parent = dst.parent().unwrap_or(Path::new("."));
+      let mut staging = tempfile::Builder::new();
+      staging.prefix(".extracting-");
+      // The staging directory becomes the extracted tree itself, so its mode
+      // is not left to `tempfile`: 0755 less the umask.
+      #[cfg(unix)]
+      {
+        use std::fs::Permissions;
+        use std::os::unix::fs::PermissionsExt;
+        staging.permissions(Permissions::from_mode(0o755));
+      }
+      let staging = staging.tempdir_in(parent)?;
+      let staged = staging.path().to_path_buf();
+      let unpack = asyncify(move || {
+        extract_tar(
+          kind,
+          reader,
+          &staged,
+          &mut ExtractGuard::new(allow_special_files),
+        )
+      });
+      let (received, unpacked) = join!(
+        receive(resp, &mut f, offset, &mut checker, Some(tx), pb),
+        unpack
+      );
+      let size = match received {
+        Ok(size) => size,
+        Err(e) => {
+          // Report why extraction stopped too, with the download error still
+          // underneath to tell whether it is worth retrying.
+          let e = match unpacked {
+            Ok(()) => e,
+            Err(unpack_error) => e.context(unpack_error),
+          };
+          return Err(interrupted(e, temp));
+        }
+      };
+      unpacked?;
+      client.record(&final_url, size, start.elapsed());
+      let sha256 = checker.finish()?;
+      // Whatever an earlier fetch left at `dst` is stale by now.
+      match dst.symlink_metadata() {
+        Ok(x) if x.is_dir() => remove_dir_all(&dst)?,
+        Ok(_) => remove_file(&dst)?,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e.into()),
+      }
+      rename(staging.path(), &dst)?;
+      (true, sha256)
+    }
+    None => {
+      let size = match receive(resp, &mut f, offset, &mut checker, None, pb).await {
+        Ok(size) => size,
+        Err(e) => return Err(interrupted(e, temp)),
+      };
+      client.record(&final_url, size, start.elapsed());
+      (false, checker.finish()?)
+    }
+  };
+  drop(f);
+  entry.store(temp, &meta)?;
+  Ok(Cached {
+    path: entry.path().into(),
+    meta,
+    reused: false,
+    extracted,
+    sha256,
+  })
+}
+
+/// Whether a download failing with `e` may well succeed if tried again.
+fn is_transient(e: &anyhow::Error) -> bool {
+  e.chain().any(|e| {
+    if let Some(e) = e.downcast_ref::<reqwest::Error>() {
+      let server_error = e.status().is_some_and(|x| x.is_server_error());
+      server_error || e.is_connect() || e.is_timeout() || e.is_request() || e.is_body()
+    } else if let Some(e) = e.downcast_ref::<io::Error>() {
+      use io::ErrorKind::*;
+      matches!(
+        e.kind(),
+        ConnectionReset | ConnectionAborted | BrokenPipe | TimedOut
+      )
+    } else {
+      false
+    }
+  })
+}
+
+/// Like [`fetch_cached`], but retrying transient failures with exponential
+/// backoff. Since interrupted downloads are kept, retries pick up where the
+/// previous try stopped if the server allows.
+#[allow(clippy::too_many_arguments)]
+async fn fetch_retrying(
+  file: &SourceFile,
+  url: &Url,
+  from: &Url,
+  client: &HttpClient,
+  cache: &SourceCache,
+  stream: Option<StreamTarget>,
+  pb: &ProgressBar,
+  mp: &MultiProgress,
+) -> anyhow::Result<Cached> {
+  let mut delay = client.retry_delay;
+  for _ in 0..client.retries {
+    match fetch_cached(file, url, from, client, cache, stream.clone(), pb).await {
+      Err(e) if is_transient(&e) => {
+        mp.suspend(|| {
+          warning!("{from}: {e}, retrying in {:.1}s", delay.as_secs_f64());
+        });
+        pb.reset();
+        sleep(delay).await;
+        delay = (delay * 2).min(MAX_RETRY_DELAY);
+      }
+      result => return result,
+    }
+  }
+  fetch_cached(file, url, from, client, cache, stream, pb).await
+}
+
+/// A source file that was fetched and verified, waiting to be extracted or
+/// copied into the source directory.
+struct Fetched<'a> {
+  index: usize,
+  file: &'a SourceFile,
+  path: PathBuf,
+  /// Whether a cached download was reused.
+  reused: bool,
+  /// Whether the archive was already extracted while downloading.
+  extracted: bool,
+  /// Keeps decoded inline content around until it is unpacked.
+  _temp: Option<TempPath>,
+  record: SourceRecord,
+  pb: ProgressBar,
+}
+
+/// Decodes the content of a `data:` URL.
+fn decode_data_url(url: &Url) -> anyhow::Result<Vec<u8>> {
+  let content = &url[Position::BeforePath..Position::AfterQuery];
+  let Some((meta, data)) = content.split_once(',') else {
+    bail!("data URL has no content");
+  };
+  let data = percent_decode_str(data).collect::<Vec<_>>();
+  if meta.to_ascii_lowercase().ends_with(";base64") {
+    let data = (data.into_iter())
+      .filter(|x| !x.is_ascii_whitespace())
+      .collect::<Vec<_>>();
+    Ok(STANDARD.decode(data)?)
+  } else {
+    Ok(data)
+  }
+}
+
+/// Returns the kind of archive `file` is and the name of the directory it
+/// should be extracted to in the source directory, unless it should not be.
+fn extract_name(file: &SourceFile) -> Option<(ArchiveKind, &str)> {
+  if !file.extract || matches!(file.location, SourceLocation::Git { .. }) {
+    return None;
+  }
+  let (kind, dir_name) = (file.location.file_name()).and_then(ArchiveKind::from_file_name)?;
+  Some((kind, file.rename.as_deref().unwrap_or(dir_name)))
+}
+
+/// Name of the directory `file` is extracted to, if it is an archive to
+/// extract.
+pub fn extract_dir_name(file: &SourceFile) -> Option<&str> {
+  extract_name(file).map(|(_, name)| name)
+}
+
+/// SHA-256 of a download the build script has no checksum for, pinned in the
+/// lockfile instead.
+async fn sha256_unchecked(path: &Path, backend: DigestBackend) -> io::Result<Hash> {
+  let path = path.to_path_buf();
+  let (digest, _) = asyncify(move || hash_file(&path, backend, &ChecksumKind::Sha256)).await?;
+  Ok(digest.into())
+}
+
+/// Returns the kind of archive `file` is and where it should be extracted to,
+/// unless it should not be.
+fn extract_target(file: &SourceFile, source_dir: &Path) -> Option<(ArchiveKind, PathBuf)> {
+  let (kind, dir_name) = extract_name(file)?;
+  Some((kind, source_dir.join(dir_name)))
+}
 
+async fn acquire_inner<'a>(
+  index: usize,
+  file: &'a SourceFile,
+  source_dir: &Path,
+  lock: &Lockfile,
+  client: &HttpClient,
+  cache: &SourceCache,
+  mp: MultiProgress,
+) -> anyhow::Result<Fetched<'a>> {
   let pb = mp.add(ProgressBar::new(1));
   let style = ProgressStyle::with_template(PB_STYLE_BYTES)
     .unwrap()
@@ -242,133 +1213,673 @@ async fn fetch_single_source_inner(
   pb.set_style(style);
   pb.set_message(file.file_name().to_string());
 
-  match &file.location {
+  let mut record = SourceRecord::new(file.location.clone());
+  let (path, reused, extracted, temp) = match &file.location {
     SourceLocation::Http(url) => {
-      pb.set_prefix("downloading");
-      let url = url.clone();
-      if let Some((ar_kind, dir_name)) = ar_kind {
-        let dir_name = file.rename.as_deref().unwrap_or(dir_name);
-        let dst = source_dir.join(dir_name);
-        let mut f = tempfile_async().await?;
-        download(&client, url, &mut f, &pb).await?;
-        pb.reset();
-
-        if !file.checksums.is_empty() {
-          f.rewind().await?;
-          verify(file, &mut f, &pb).await?;
-          pb.reset();
+      let stream = (extract_target(file, source_dir))
+        .filter(|(kind, _)| kind.is_tar())
+        .map(|(kind, dst)| StreamTarget {
+          kind,
+          dst,
+          allow_special_files: file.allow_special_files,
+        });
+      // Mirrors listed with the source come after the configured ones.
+      let mut candidates = Vec::new();
+      for url in std::iter::once(url).chain(&file.mirrors) {
+        for x in client.mirrors.candidates(url) {
+          if !candidates.contains(&x) {
+            candidates.push(x);
+          }
         }
-
-        let mut f = match f.try_into_std() {
-          Ok(f) => f,
-          Err(f) => f
-            .try_clone()
-            .await?
-            .try_into_std()
-            .expect("file should be ready once cloned"),
+      }
+      let mut cached = None;
+      for (i, from) in candidates.iter().enumerate() {
+        let result = fetch_retrying(file, url, from, client, cache, stream.clone(), &pb, &mp).await;
+        match result {
+          Ok(x) => {
+            cached = Some(x);
+            break;
+          }
+          Err(e) if i + 1 < candidates.len() => {
+            mp.suspend(|| {
+              warning!("{from}: {e}, trying the next mirror");
+            });
+            pb.reset();
+          }
+          Err(e) => return Err(e),
+        }
+      }
+      let Cached {
+        path,
+        meta,
+        reused,
+        extracted,
+        sha256,
+      } = cached.expect("there is always a candidate");
+      record.redirects = meta.redirects;
+      record.final_url = meta.final_url;
+      if file.checksums.is_empty() {
+        // Only a reused copy still has to be hashed.
+        let digest = match sha256 {
+          Some(digest) => digest,
+          None => sha256_unchecked(&path, client.digest).await?,
         };
-        let pb2 = pb.clone();
-        asyncify(move || {
-          f.rewind()?;
-          extract(ar_kind, f, dst, pb2)
-        })
-        .await?;
-      } else {
-        let dst = source_dir.join(file.file_name());
-        let mut f = AsyncFile::create(dst).await?;
-        download(&client, url, &mut f, &pb).await?;
-
-        if !file.checksums.is_empty() {
-          pb.reset();
-          f.rewind().await?;
-          verify(file, &mut f, &pb).await?;
+        let locked = (lock.get(file.file_name(), &file.location)).and_then(|x| x.sha256.as_ref());
+        if let Some(locked) = locked.filter(|x| ***x != *digest) {
+          if !file.skip_checksum || client.require_checksums {
+            bail!(
+              "content of '{}' changed since it was locked:\n\texpected: {}\n\tgot:      {}\n\
+               remove it from the lockfile if this is expected, or better, add a checksum",
+              file.location,
+              hex::encode(locked),
+              hex::encode(&digest)
+            );
+          }
+          mp.suspend(|| {
+            warning!(
+              "content of '{}' changed since it was locked, pinning the new one:\n\t\
+               expected: {}\n\tgot:      {}",
+              file.location,
+              hex::encode(locked),
+              hex::encode(&digest)
+            );
+          });
         }
+        record.sha256 = Some(digest);
       }
+      (path, reused, extracted, None)
     }
     SourceLocation::Local(path) => {
-      pb.set_length(metadata(path).await?.len());
-
-      let mut f = AsyncFile::open(path).await?;
       if !file.checksums.is_empty() {
-        verify(file, &mut f, &pb).await?;
-        pb.reset();
+        let mut f = AsyncFile::open(path).await?;
+        pb.set_length(f.metadata().await?.len());
+        verify(file, &mut f, client, &pb).await?;
+      }
+      (path.to_path_buf(), false, false, None)
+    }
+    SourceLocation::Data(url) => {
+      let data = decode_data_url(url)?;
+      let mut checker = Checker::new(file, client);
+      checker.update(&data.clone().into());
+      let digest = checker.finish()?;
+      record.sha256 = Some(digest.unwrap_or_else(|| sha256(&data).to_vec().into()));
+      let mut temp = NamedTempFile::new()?;
+      temp.write_all(&data)?;
+      let temp = temp.into_temp_path();
+      (temp.to_path_buf(), false, false, Some(temp))
+    }
+    SourceLocation::Git { url, rev } => {
+      pb.set_prefix("fetching");
+      let git_dir = cache.git_dir(url);
+      let (commit, kind) = git::update_mirror(url, &git_dir, rev, client.no_cache).await?;
+      let locked = (lock.get(file.file_name(), &file.location)).and_then(|x| x.commit.as_deref());
+      if let Some(locked) = locked.filter(|x| kind == RevKind::Tag && **x != *commit) {
+        bail!("tag `{rev}` of {url} moved from {locked} to {commit}");
       }
+      record.commit = Some(commit.into());
+      (git_dir, false, false, None)
+    }
+  };
+  if record.commit.is_none() {
+    record.size = Some(metadata(&path).await?.len());
+  }
 
-      if let Some((ar_kind, dir_name)) = ar_kind {
-        let dir_name = file.rename.as_deref().unwrap_or(dir_name);
-        let dst = source_dir.join(dir_name);
-
-        let f = match f.try_into_std() {
-          Ok(f) => f,
-          Err(f) => f
-            .try_clone()
-            .await?
-            .try_into_std()
-            .expect("file should be ready once cloned"),
-        };
-        let pb2 = pb.clone();
-        asyncify(move || extract(ar_kind, f, dst, pb2)).await?;
-      } else {
-        drop(f);
-        let dst = source_dir.join(file.file_name());
-        pb.set_prefix("copying");
-        copy(path, dst).await?;
+  pb.reset();
+  pb.set_prefix("queued");
+  Ok(Fetched {
+    index,
+    file,
+    path,
+    reused,
+    extracted,
+    _temp: temp,
+    record,
+    pb,
+  })
+}
+
+async fn unpack_inner(
+  fetched: Fetched<'_>,
+  source_dir: &Path,
+  lock: &Lockfile,
+) -> anyhow::Result<(usize, SourceRecord)> {
+  let Fetched {
+    index,
+    file,
+    path,
+    reused,
+    extracted,
+    _temp,
+    mut record,
+    pb,
+  } = fetched;
+
+  if let Some((ar_kind, dst)) = extract_target(file, source_dir) {
+    let f = File::open(&path)?;
+    pb.set_length(f.metadata()?.len());
+    let pb2 = pb.clone();
+    let tree_digest = file.tree_digest;
+    let allow_special_files = file.allow_special_files;
+    let (root, digest, released) = asyncify(move || {
+      if !extracted {
+        extract(ar_kind, f, &dst, pb2, allow_special_files)?;
+      }
+      let digest = tree_digest.then(|| self::tree_digest(&dst)).transpose()?;
+      // Other archives are extracted without their modification times.
+      let released = match ar_kind.is_tar() {
+        true => newest_mtime(&dst)?,
+        false => None,
+      };
+      Ok((extracted_root(&dst)?, digest, released))
+    })
+    .await?;
+    let root = root.strip_prefix(source_dir).unwrap_or(&root);
+    record.extracted = Some(root.into());
+    record.released = released;
+
+    if let Some(digest) = digest {
+      let expected = (lock.get(file.file_name(), &file.location))
+        .and_then(|x| x.tree_sha256.as_ref())
+        .filter(|_| reused);
+      if let Some(expected) = expected {
+        if **expected != digest {
+          bail!(
+            "extracted content of '{}' changed:\n\texpected: {}\n\tgot:      {}",
+            file.location,
+            hex::encode(expected),
+            hex::encode(digest)
+          );
+        }
       }
+      record.tree_sha256 = Some(digest.to_vec().into());
     }
+  } else if let Some(commit) = &record.commit {
+    pb.set_prefix("checking out");
+    let dst = source_dir.join(file.file_name());
+    git::checkout(&path, commit, &dst).await?;
+    record.released = Some(git::commit_time(&path, commit).await?);
+    if let (true, SourceLocation::Git { url, .. }) = (file.submodules, &file.location) {
+      pb.set_prefix("submodules");
+      git::update_submodules(url, &dst).await?;
+    }
+    record.extracted = Some(Path::new(file.file_name()).into());
+  } else {
+    let dst = source_dir.join(file.file_name());
+    pb.set_prefix("copying");
+    copy(path, dst).await?;
   }
   pb.set_prefix("done");
   pb.finish();
-  Ok(())
+  Ok((index, record))
+}
+
+fn fetch_context(file: &SourceFile) -> impl FnOnce(anyhow::Error) -> anyhow::Error + '_ {
+  move |e| e.context(format!("failed to fetch '{}'", file.file_name()))
 }
 
-async fn fetch_single_source(
+/// Downloads (or locates) and verifies a single source file. Tar archives that
+/// have to be downloaded are extracted straight from the network.
+async fn acquire<'a>(
+  index: usize,
+  file: &'a SourceFile,
   source_dir: &Path,
-  file: &SourceFile,
-  client: Client,
+  lock: &Lockfile,
+  client: &HttpClient,
+  cache: &SourceCache,
   mp: MultiProgress,
-) -> anyhow::Result<()> {
-  fetch_single_source_inner(source_dir, file, client, mp)
-    .map_err(|e| e.context(format!("failed to fetch '{}'", file.file_name())))
+) -> anyhow::Result<Fetched<'a>> {
+  acquire_inner(index, file, source_dir, lock, client, cache, mp)
+    .map_err(fetch_context(file))
     .await
 }
 
-async fn fetch_source_inner(source_dir: &Path, files: &[SourceFile]) -> anyhow::Result<()> {
+/// Extracts or copies a fetched source file into the source directory.
+async fn unpack(
+  fetched: Fetched<'_>,
+  source_dir: &Path,
+  lock: &Lockfile,
+) -> anyhow::Result<(usize, SourceRecord)> {
+  let file = fetched.file;
+  unpack_inner(fetched, source_dir, lock)
+    .map_err(fetch_context(file))
+    .await
+}
+
+async fn fetch_source_inner(
+  source_dir: &Path,
+  files: &[SourceFile],
+  lock: &Lockfile,
+  options: &FetchOptions,
+  require_checksums: bool,
+) -> anyhow::Result<Vec<SourceRecord>> {
   if files.is_empty() {
     println!("No source specified, skipping");
   }
 
-  const PARALLEL: usize = 5;
-  let mut iter = files.iter();
-  let mut pool = FuturesUnordered::new();
-  let client = Client::new();
-  let mp = MultiProgress::new();
+  // Downloads and extractions run in separate pools, so that a huge archive
+  // being extracted does not keep the network idle. Fetched files wait in a
+  // queue in between; downloads pause once too many files are pending.
+  const PARALLEL_DOWNLOADS: usize = 5;
+  const PARALLEL_EXTRACTIONS: usize = 2;
+  const MAX_PENDING: usize = 10;
 
-  for file in iter.by_ref().take(PARALLEL) {
-    pool.push(fetch_single_source(
-      source_dir,
-      file,
-      client.clone(),
-      mp.clone(),
-    ));
-  }
+  let cache = SourceCache::new()?;
+  let mut client = HttpClient::new(options)?;
+  client.mirrors = Mirrors::load()?;
+  client.digest = SigningConfig::load()?.digest;
+  client.require_checksums = require_checksums;
+  let mp = MultiProgress::new();
+  let mut iter = files.iter().enumerate();
+  let mut downloads = FuturesUnordered::new();
+  let mut extractions = FuturesUnordered::new();
+  let mut queue = VecDeque::new();
+  let mut records = vec![None; files.len()];
 
-  while let Some(()) = pool.try_next().await? {
-    if let Some(file) = iter.next() {
-      pool.push(fetch_single_source(
-        source_dir,
+  loop {
+    while downloads.len() < PARALLEL_DOWNLOADS && queue.len() + downloads.len() < MAX_PENDING {
+      let Some((i, file)) = iter.next() else {
+        break;
+      };
+      downloads.push(acquire(
+        i,
         file,
-        client.clone(),
+        source_dir,
+        lock,
+        &client,
+        &cache,
         mp.clone(),
       ));
     }
+    while extractions.len() < PARALLEL_EXTRACTIONS {
+      let Some(fetched) = queue.pop_front() else {
+        break;
+      };
+      extractions.push(unpack(fetched, source_dir, lock));
+    }
+    if downloads.is_empty() && extractions.is_empty() {
+      break;
+    }
+
+    select! {
+      fetched = downloads.select_next_some() => queue.push_back(fetched?),
+      result = extractions.select_next_some() => {
+        let (i, record) = result?;
+        records[i] = Some(record);
+      }
+    }
   }
-  Ok(())
+  client.save_stats();
+  Ok(records.into_iter().flatten().collect())
+}
+
+/// Computes the digests of `files` with each of `kinds`, e.g. for sources
+/// without checksums yet, downloading them into the cache as needed. Git
+/// sources get `None`.
+pub fn compute_checksums(
+  files: &[SourceFile],
+  kinds: &[ChecksumKind],
+  options: &FetchOptions,
+) -> anyhow::Result<Vec<Option<BTreeMap<ChecksumKind, Hash>>>> {
+  let rt = RtBuilder::new_current_thread()
+    .enable_io()
+    .enable_time()
+    .build()?;
+  rt.block_on(compute_checksums_inner(files, kinds, options))
+}
+
+async fn compute_checksums_inner(
+  files: &[SourceFile],
+  kinds: &[ChecksumKind],
+  options: &FetchOptions,
+) -> anyhow::Result<Vec<Option<BTreeMap<ChecksumKind, Hash>>>> {
+  let cache = SourceCache::new()?;
+  let mut client = HttpClient::new(options)?;
+  client.mirrors = Mirrors::load()?;
+  client.digest = SigningConfig::load()?.digest;
+  let mp = MultiProgress::new();
+  let lock = Lockfile::default();
+  let unchecked = (files.iter())
+    .map(|x| SourceFile {
+      checksums: BTreeMap::new(),
+      extract: false,
+      ..x.clone()
+    })
+    .collect::<Vec<_>>();
+
+  let fetched = stream::iter(unchecked.iter().enumerate())
+    .filter(|(_, file)| std::future::ready(!matches!(file.location, SourceLocation::Git { .. })))
+    .map(|(i, file)| acquire(i, file, Path::new(""), &lock, &client, &cache, mp.clone()))
+    .buffer_unordered(5)
+    .try_collect::<Vec<_>>()
+    .await?;
+  client.save_stats();
+
+  let mut result = vec![None; files.len()];
+  for fetched in fetched {
+    let path = fetched.path.clone();
+    let backend = client.digest;
+    let kinds = kinds.to_vec();
+    // Fetching already hashed remote and inline sources with SHA-256.
+    let sha256 = fetched.record.sha256.clone();
+    let checksums = asyncify(move || {
+      (kinds.into_iter())
+        .map(|kind| match (&kind, &sha256) {
+          (ChecksumKind::Sha256, Some(digest)) => Ok((kind, digest.clone())),
+          _ => {
+            let (digest, _) = hash_file(&path, backend, &kind)?;
+            Ok((kind, digest.into()))
+          }
+        })
+        .collect::<io::Result<BTreeMap<_, _>>>()
+    })
+    .await?;
+    fetched.pb.finish_and_clear();
+    result[fetched.index] = Some(checksums);
+  }
+  Ok(result)
+}
+
+/// What a source URL currently points to, found out without downloading it.
+#[derive(Debug, Clone, Default)]
+pub struct UrlHealth {
+  /// Why the URL cannot be fetched, if it cannot.
+  pub error: Option<String>,
+  pub size: Option<u64>,
+  /// Where the URL permanently redirects to, if it does.
+  pub moved_to: Option<Url>,
+}
+
+/// Size of the whole file, from either a full or a ranged response.
+fn content_size(resp: &Response) -> Option<u64> {
+  let header = |name| resp.headers().get(name)?.to_str().ok();
+  if resp.status() == StatusCode::PARTIAL_CONTENT {
+    header(CONTENT_RANGE)?.rsplit_once('/')?.1.parse().ok()
+  } else {
+    header(CONTENT_LENGTH)?.parse().ok()
+  }
+}
+
+async fn check_url(client: &HttpClient, url: &Url) -> UrlHealth {
+  let result = async {
+    let (mut resp, mut redirects) =
+      (client.send(Method::HEAD, url.clone(), HeaderMap::new())).await?;
+    if !resp.status().is_success() {
+      // Plenty of servers do not like HEAD, so ask for the first byte instead.
+      let mut headers = HeaderMap::new();
+      headers.insert(RANGE, HeaderValue::from_static("bytes=0-0"));
+      (resp, redirects) = client.send(Method::GET, url.clone(), headers).await?;
+    }
+    anyhow::Ok((resp.error_for_status()?, redirects))
+  };
+  match result.await {
+    Ok((resp, redirects)) => {
+      // Only a chain of permanent redirects means the URL itself moved.
+      let mut moved_to = None;
+      for (i, (_, status)) in redirects.iter().enumerate() {
+        if !matches!(
+          *status,
+          StatusCode::MOVED_PERMANENTLY | StatusCode::PERMANENT_REDIRECT
+        ) {
+          break;
+        }
+        moved_to = Some(redirects.get(i + 1).map_or(resp.url(), |x| &x.0).clone());
+      }
+      UrlHealth {
+        error: None,
+        size: content_size(&resp),
+        moved_to,
+      }
+    }
+    Err(e) => UrlHealth {
+      error: Some(e.to_string()),
+      ..Default::default()
+    },
+  }
+}
+
+/// How quickly a URL answers, and how fast it serves its content.
+#[derive(Debug, Clone)]
+pub struct UrlSpeed {
+  /// Time until the response headers arrived.
+  pub latency: Duration,
+  pub bytes_per_sec: Option<f64>,
+}
+
+async fn measure_url(client: &HttpClient, url: &Url) -> anyhow::Result<UrlSpeed> {
+  // Enough to get past TCP slow start without taking ages on slow mirrors.
+  const MAX_BYTES: u64 = 8 << 20;
+  let start = Instant::now();
+  let (resp, _) = client
+    .send(Method::GET, url.clone(), HeaderMap::new())
+    .await?;
+  let resp = resp.error_for_status()?;
+  let latency = start.elapsed();
+  let mut size = 0;
+  let mut stream = resp.bytes_stream();
+  while size < MAX_BYTES {
+    let Some(bytes) = stream.try_next().await? else {
+      break;
+    };
+    size += bytes.len() as u64;
+  }
+  let secs = (start.elapsed() - latency).as_secs_f64();
+  Ok(UrlSpeed {
+    latency,
+    bytes_per_sec: (size > 0 && secs > 0.0).then(|| size as f64 / secs),
+  })
+}
+
+/// Measures `urls` one after another, so that they do not compete for
+/// bandwidth.
+pub fn measure_urls(
+  urls: &[Url],
+  options: &FetchOptions,
+) -> anyhow::Result<Vec<anyhow::Result<UrlSpeed>>> {
+  let rt = RtBuilder::new_current_thread()
+    .enable_io()
+    .enable_time()
+    .build()?;
+  rt.block_on(async {
+    let client = HttpClient::new(options)?;
+    let mut speeds = Vec::new();
+    for url in urls {
+      speeds.push(measure_url(&client, url).await);
+    }
+    Ok(speeds)
+  })
 }
 
-pub fn fetch_source(source_dir: &Path, files: &[SourceFile]) -> anyhow::Result<()> {
+/// Checks whether `urls` can still be fetched, without downloading them.
+pub fn check_urls(urls: &[Url], options: &FetchOptions) -> anyhow::Result<Vec<UrlHealth>> {
+  const PARALLEL_CHECKS: usize = 8;
   let rt = RtBuilder::new_current_thread()
     .enable_io()
     .enable_time()
     .build()?;
-  rt.block_on(fetch_source_inner(source_dir, files))
+  rt.block_on(async {
+    let client = HttpClient::new(options)?;
+    let checks = stream::iter(urls).map(|url| check_url(&client, url));
+    Ok(checks.buffered(PARALLEL_CHECKS).collect().await)
+  })
+}
+
+/// Downloads `url` into `dst`, returning false if the server does not have
+/// it.
+pub fn download(url: &Url, dst: &Path, options: &FetchOptions) -> anyhow::Result<bool> {
+  let rt = RtBuilder::new_current_thread()
+    .enable_io()
+    .enable_time()
+    .build()?;
+  rt.block_on(async {
+    let client = HttpClient::new(options)?;
+    let (resp, _) = client
+      .send(Method::GET, url.clone(), HeaderMap::new())
+      .await?;
+    match resp.status() {
+      StatusCode::NOT_FOUND => return Ok(false),
+      x if !x.is_success() => bail!("{url}: {x}"),
+      _ => {}
+    }
+    let mut f = AsyncFile::create(dst).await?;
+    let mut stream = resp.bytes_stream();
+    while let Some(bytes) = stream.try_next().await? {
+      f.write_all(&bytes).await?;
+    }
+    f.flush().await?;
+    Ok(true)
+  })
+}
+
+/// Fetches, verifies and extracts `files` into `source_dir`, returning what
+/// was fetched for each of them in the same order. `lock` holds the records
+/// of the previous fetch; sources pinned only there may change if they set
+/// `skip_checksum`, unless `require_checksums` is set.
+pub fn fetch_source(
+  source_dir: &Path,
+  files: &[SourceFile],
+  lock: &Lockfile,
+  options: &FetchOptions,
+  require_checksums: bool,
+) -> anyhow::Result<Vec<SourceRecord>> {
+  let rt = RtBuilder::new_current_thread()
+    .enable_io()
+    .enable_time()
+    .build()?;
+  rt.block_on(fetch_source_inner(
+    source_dir,
+    files,
+    lock,
+    options,
+    require_checksums,
+  ))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use indicatif::ProgressDrawTarget;
+  use serde_json::json;
+  use std::fs;
+  use std::net::TcpListener;
+  use std::sync::Arc;
+  use std::thread;
+
+  /// Serves whatever `body` holds at the time of each request as `name`.
+  fn serve(body: Arc<Mutex<Vec<u8>>>, name: &str) -> Url {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/{name}", listener.local_addr().unwrap());
+    thread::spawn(move || {
+      for stream in listener.incoming() {
+        let mut stream = stream.unwrap();
+        let mut request = Vec::new();
+        let mut byte = [0];
+        while !request.ends_with(b"\r\n\r\n") && stream.read(&mut byte).unwrap() > 0 {
+          request.push(byte[0]);
+        }
+        let body = body.lock().unwrap().clone();
+        let head = format!(
+          "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+          body.len()
+        );
+        stream.write_all(head.as_bytes()).unwrap();
+        stream.write_all(&body).unwrap();
+      }
+    });
+    url.parse().unwrap()
+  }
+
+  fn acquire_record(
+    file: &SourceFile,
+    source_dir: &Path,
+    lock: &Lockfile,
+    cache: &SourceCache,
+    require_checksums: bool,
+  ) -> anyhow::Result<SourceRecord> {
+    let options = FetchOptions {
+      user_agent: "ewepkg-test".into(),
+      host_delay: 0,
+      no_cache: false,
+      retries: 0,
+      retry_delay: 0,
+    };
+    let mut client = HttpClient::new(&options)?;
+    client.require_checksums = require_checksums;
+    let mp = MultiProgress::with_draw_target(ProgressDrawTarget::hidden());
+    let rt = RtBuilder::new_current_thread()
+      .enable_io()
+      .enable_time()
+      .build()?;
+    let fetched = rt.block_on(acquire(0, file, source_dir, lock, &client, cache, mp))?;
+    Ok(fetched.record)
+  }
+
+  #[test]
+  fn test_changed_skip_checksum_source() {
+    let body = Arc::new(Mutex::new(b"first".to_vec()));
+    let url = serve(body.clone(), "file.txt");
+    let cache_dir = tempfile::tempdir().unwrap();
+    let cache = SourceCache::in_dir(cache_dir.path().into()).unwrap();
+    let file: SourceFile =
+      serde_json::from_value(json!({ "url": url, "skip_checksum": true })).unwrap();
+
+    let first = acquire_record(&file, Path::new(""), &Lockfile::default(), &cache, false).unwrap();
+    assert_eq!(first.sha256.as_deref(), Some(&sha256(b"first")[..]));
+    let mut lock = Lockfile::default();
+    lock.sources.insert(file.file_name().into(), first);
+
+    *body.lock().unwrap() = b"second".to_vec();
+    let pinned = SourceFile {
+      skip_checksum: false,
+      ..file.clone()
+    };
+    assert!(acquire_record(&pinned, Path::new(""), &lock, &cache, false).is_err());
+    assert!(acquire_record(&file, Path::new(""), &lock, &cache, true).is_err());
+    let second = acquire_record(&file, Path::new(""), &lock, &cache, false).unwrap();
+    assert_eq!(second.sha256.as_deref(), Some(&sha256(b"second")[..]));
+  }
+
+  #[test]
+  #[cfg(unix)]
+  fn test_streamed_extraction() {
+    use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+
+    let mut archive = tar::Builder::new(flate2::write::GzEncoder::new(
+      Vec::new(),
+      flate2::Compression::fast(),
+    ));
+    let mut header = tar::Header::new_gnu();
+    header.set_size(6);
+    header.set_mode(0o644);
+    header.set_cksum();
+    archive
+      .append_data(&mut header, "hello", &b"hello\n"[..])
+      .unwrap();
+    let body = archive.into_inner().unwrap().finish().unwrap();
+    let url = serve(Arc::new(Mutex::new(body)), "a-1.tar.gz");
+    let cache_dir = tempfile::tempdir().unwrap();
+    let cache = SourceCache::in_dir(cache_dir.path().into()).unwrap();
+    let file: SourceFile =
+      serde_json::from_value(json!({ "url": url, "skip_checksum": true })).unwrap();
+
+    let source_dir = tempfile::tempdir().unwrap();
+    let dst = source_dir.path().join("a-1");
+    fs::create_dir(&dst).unwrap();
+    fs::write(dst.join("stale"), "").unwrap();
+    let record = acquire_record(
+      &file,
+      source_dir.path(),
+      &Lockfile::default(),
+      &cache,
+      false,
+    );
+    record.unwrap();
+    assert!(dst.join("hello").is_file());
+    assert!(!dst.join("stale").exists());
+
+    // What the umask leaves of 0755.
+    let probe = source_dir.path().join("probe");
+    fs::DirBuilder::new().mode(0o755).create(&probe).unwrap();
+    let mode = |x: &Path| x.metadata().unwrap().permissions().mode() & 0o7777;
+    assert_eq!(mode(&dst), mode(&probe));
+  }
 }
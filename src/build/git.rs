@@ -0,0 +1,222 @@
+use anyhow::{bail, Context};
+use std::path::Path;
+use std::process::Stdio;
+use tokio::fs::remove_dir_all;
+use tokio::process::Command;
+use url::Url;
+
+/// What the `rev` of a Git source turned out to be.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RevKind {
+  Tag,
+  Branch,
+  Commit,
+}
+
+/// A git command working on the repository at `git_dir`, if given.
+fn git(git_dir: Option<&Path>) -> Command {
+  let mut command = Command::new("git");
+  if let Some(dir) = git_dir {
+    command.arg("--git-dir").arg(dir);
+  }
+  // Fail instead of waiting for credentials nobody is going to type.
+  command.env("GIT_TERMINAL_PROMPT", "0").stdin(Stdio::null());
+  command
+}
+
+/// Runs `command`, returning its trimmed stdout.
+async fn run(command: &mut Command) -> anyhow::Result<String> {
+  let output = command.output().await.context("cannot run git")?;
+  if !output.status.success() {
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    bail!("git failed: {}", stderr.trim());
+  }
+  Ok(String::from_utf8_lossy(&output.stdout).trim().into())
+}
+
+/// Commit `spec` points to in the repository at `git_dir`, if any.
+async fn rev_parse(git_dir: &Path, spec: &str) -> Option<String> {
+  let spec = format!("{spec}^{{commit}}");
+  let mut command = git(Some(git_dir));
+  command.args(["rev-parse", "--verify", "--quiet", &spec]);
+  run(&mut command).await.ok().filter(|x| !x.is_empty())
+}
+
+/// Finds the commit `rev` names in the repository at `git_dir`, trying tags
+/// first, then branches, then commit hashes.
+pub async fn resolve(git_dir: &Path, rev: &str) -> Option<(String, RevKind)> {
+  if let Some(commit) = rev_parse(git_dir, &format!("refs/tags/{rev}")).await {
+    return Some((commit, RevKind::Tag));
+  }
+  if let Some(commit) = rev_parse(git_dir, &format!("refs/heads/{rev}")).await {
+    return Some((commit, RevKind::Branch));
+  }
+  let is_hash = rev.len() >= 7 && rev.bytes().all(|x| x.is_ascii_hexdigit());
+  if is_hash {
+    if let Some(commit) = rev_parse(git_dir, rev).await {
+      return Some((commit, RevKind::Commit));
+    }
+  }
+  None
+}
+
+/// Makes sure the bare mirror of `url` at `git_dir` has `rev`, and returns
+/// the commit it is at. Unless `refresh` is set, tags and commits already
+/// there are taken as they are, while branches are always brought up to date.
+pub async fn update_mirror(
+  url: &Url,
+  git_dir: &Path,
+  rev: &str,
+  refresh: bool,
+) -> anyhow::Result<(String, RevKind)> {
+  if git_dir.exists() {
+    match resolve(git_dir, rev).await {
+      Some((commit, kind)) if kind != RevKind::Branch && !refresh => return Ok((commit, kind)),
+      _ => {}
+    }
+    run(git(Some(git_dir)).args(["fetch", "--quiet", "--prune", "origin"])).await?;
+  } else {
+    let mut clone = git(None);
+    clone.args(["clone", "--quiet", "--mirror", url.as_str()]);
+    if let Err(e) = run(clone.arg(git_dir)).await {
+      // Do not take a partial clone for a mirror next time.
+      let _ = remove_dir_all(git_dir).await;
+      return Err(e);
+    }
+  }
+  match resolve(git_dir, rev).await {
+    Some(x) => Ok(x),
+    None => bail!("`{rev}` is neither a tag, a branch nor a commit of {url}"),
+  }
+}
+
+/// Checks `commit` out of the mirror at `git_dir` into the new directory
+/// `dst`, keeping its history so that `git describe` works in builds.
+pub async fn checkout(git_dir: &Path, commit: &str, dst: &Path) -> anyhow::Result<()> {
+  run(
+    git(None)
+      .args(["clone", "--quiet", "--no-checkout"])
+      .arg(git_dir)
+      .arg(dst),
+  )
+  .await?;
+  let mut command = git(None);
+  command.arg("-C").arg(dst);
+  run(command.args(["checkout", "--quiet", "--detach", commit])).await?;
+  Ok(())
+}
+
+/// Commit time of `commit` in the repository at `git_dir`, in seconds since
+/// the Unix epoch.
+pub async fn commit_time(git_dir: &Path, commit: &str) -> anyhow::Result<u64> {
+  let mut command = git(Some(git_dir));
+  let time = run(command.args(["show", "--no-patch", "--format=%ct", commit])).await?;
+  (time.parse()).with_context(|| format!("invalid commit time `{time}`"))
+}
+
+/// Checks out the submodules of the checkout at `dir`, recursively, at the
+/// commits it records. They are fetched from their own remotes every time,
+/// with relative URLs taken relative to `url` rather than to the mirror.
+pub async fn update_submodules(url: &Url, dir: &Path) -> anyhow::Result<()> {
+  let mut command = git(None);
+  command.arg("-C").arg(dir);
+  run(command.args(["remote", "set-url", "origin", url.as_str()])).await?;
+  let mut command = git(None);
+  // Git refuses local submodules by default, lest a remote repository point
+  // into the file system. A local superproject is trusted with that already.
+  if url.scheme() == "file" {
+    command.args(["-c", "protocol.file.allow=always"]);
+  }
+  command.arg("-C").arg(dir);
+  command.args(["submodule", "--quiet", "update", "--init", "--recursive"]);
+  run(&mut command).await?;
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::fs;
+  use tempfile::tempdir;
+  use tokio::runtime::Builder as RtBuilder;
+
+  #[test]
+  fn test_mirror() {
+    let dir = tempdir().unwrap();
+    let upstream = dir.path().join("upstream");
+    let run_git = |args: &[&str]| {
+      let status = std::process::Command::new("git")
+        .arg("-C")
+        .arg(&upstream)
+        .args(["-c", "user.name=a", "-c", "user.email=a@localhost"])
+        .args(args)
+        .status()
+        .unwrap();
+      assert!(status.success());
+    };
+    let commit = |message: &str| {
+      fs::write(upstream.join("file"), message).unwrap();
+      run_git(&["add", "file"]);
+      run_git(&["commit", "-qm", message]);
+    };
+    fs::create_dir(&upstream).unwrap();
+    run_git(&["init", "-q", "-b", "main"]);
+    commit("one");
+    run_git(&["tag", "v1"]);
+
+    let url = Url::from_directory_path(&upstream).unwrap();
+    let mirror = dir.path().join("mirror.git");
+    let rt = RtBuilder::new_current_thread().enable_io().build().unwrap();
+    rt.block_on(async {
+      let (v1, kind) = update_mirror(&url, &mirror, "v1", false).await.unwrap();
+      assert_eq!(kind, RevKind::Tag);
+      commit("two");
+      let (main, kind) = update_mirror(&url, &mirror, "main", false).await.unwrap();
+      assert_eq!(kind, RevKind::Branch);
+      assert_ne!(main, v1);
+      let short = resolve(&mirror, &v1[..10]).await.unwrap();
+      assert_eq!(short, (v1.clone(), RevKind::Commit));
+      assert!(commit_time(&mirror, &v1).await.unwrap() > 0);
+      assert!(update_mirror(&url, &mirror, "v2", false).await.is_err());
+
+      let dst = dir.path().join("src");
+      checkout(&mirror, &v1, &dst).await.unwrap();
+      assert_eq!(fs::read_to_string(dst.join("file")).unwrap(), "one");
+
+      // Relative to the upstream, where the mirror has no such neighbour.
+      let lib = dir.path().join("lib");
+      fs::create_dir(&lib).unwrap();
+      fs::write(lib.join("lib.c"), "").unwrap();
+      for args in [
+        &["init", "-q"][..],
+        &["add", "lib.c"],
+        &["commit", "-qm", "lib"],
+      ] {
+        let status = std::process::Command::new("git")
+          .arg("-C")
+          .arg(&lib)
+          .args(["-c", "user.name=a", "-c", "user.email=a@localhost"])
+          .args(args)
+          .status()
+          .unwrap();
+        assert!(status.success());
+      }
+      run_git(&[
+        "-c",
+        "protocol.file.allow=always",
+        "submodule",
+        "-q",
+        "add",
+        "../lib",
+        "lib",
+      ]);
+      run_git(&["commit", "-qm", "three"]);
+      let (main, _) = update_mirror(&url, &mirror, "main", false).await.unwrap();
+      let dst = dir.path().join("src2");
+      checkout(&mirror, &main, &dst).await.unwrap();
+      assert!(!dst.join("lib/lib.c").exists());
+      update_submodules(&url, &dst).await.unwrap();
+      assert!(dst.join("lib/lib.c").exists());
+    });
+  }
+}
@@ -0,0 +1,135 @@
+use crate::sign::DigestBackend;
+use crate::types::ChecksumKind;
+use bytes::Bytes;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+use std::sync::mpsc::{sync_channel, SyncSender};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// Size of the chunks files are read in for hashing.
+pub const CHUNK_SIZE: usize = 1 << 20;
+
+/// Chunks waiting for each digest before feeding more blocks.
+const QUEUE_LEN: usize = 16;
+
+/// Time one digest spent on some data, for statistics.
+#[derive(Debug, Clone)]
+pub struct HashSample {
+  pub kind: &'static str,
+  pub bytes: u64,
+  pub elapsed: Duration,
+}
+
+struct Worker {
+  kind: &'static str,
+  tx: SyncSender<Bytes>,
+  handle: JoinHandle<(Vec<u8>, Duration)>,
+}
+
+impl Worker {
+  fn spawn(backend: DigestBackend, kind: &ChecksumKind) -> Self {
+    let mut hasher = backend.digester(kind);
+    let (tx, rx) = sync_channel::<Bytes>(QUEUE_LEN);
+    let handle = thread::spawn(move || {
+      let mut elapsed = Duration::ZERO;
+      for chunk in rx {
+        let start = Instant::now();
+        hasher.update(&chunk);
+        elapsed += start.elapsed();
+      }
+      (hasher.finish(), elapsed)
+    });
+    Self {
+      kind: kind.name(),
+      tx,
+      handle,
+    }
+  }
+}
+
+/// Computes several digests of the same data side by side, each on a thread
+/// of its own, while the caller goes on reading or downloading the next
+/// chunk.
+pub struct ParallelHasher {
+  workers: Vec<Worker>,
+  bytes: u64,
+}
+
+impl ParallelHasher {
+  pub fn new<'a>(
+    backend: DigestBackend,
+    kinds: impl IntoIterator<Item = &'a ChecksumKind>,
+  ) -> Self {
+    let workers = (kinds.into_iter())
+      .map(|x| Worker::spawn(backend, x))
+      .collect();
+    Self { workers, bytes: 0 }
+  }
+
+  pub fn update(&mut self, chunk: &Bytes) {
+    self.bytes += chunk.len() as u64;
+    for worker in &self.workers {
+      // A worker only stops early by panicking, which `finish` reports.
+      let _ = worker.tx.send(chunk.clone());
+    }
+  }
+
+  /// Returns the digests in the order of the kinds given to [`Self::new`].
+  pub fn finish(self) -> Vec<(Vec<u8>, HashSample)> {
+    let bytes = self.bytes;
+    (self.workers.into_iter())
+      .map(|Worker { kind, tx, handle }| {
+        drop(tx);
+        let (digest, elapsed) = handle.join().expect("hashing thread panicked");
+        let sample = HashSample {
+          kind,
+          bytes,
+          elapsed,
+        };
+        (digest, sample)
+      })
+      .collect()
+  }
+}
+
+/// Hashes the file at `path` with `kind`, reading it while hashing what was
+/// read before.
+pub fn hash_file(
+  path: &Path,
+  backend: DigestBackend,
+  kind: &ChecksumKind,
+) -> io::Result<(Vec<u8>, HashSample)> {
+  let mut hasher = ParallelHasher::new(backend, [kind]);
+  let mut f = File::open(path)?;
+  loop {
+    let mut buf = Vec::with_capacity(CHUNK_SIZE);
+    if (&mut f).take(CHUNK_SIZE as u64).read_to_end(&mut buf)? == 0 {
+      break;
+    }
+    hasher.update(&buf.into());
+  }
+  Ok(hasher.finish().pop().expect("there is one digest"))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use openssl::sha::{sha256, sha512};
+
+  #[test]
+  fn test_parallel_hasher() {
+    let data = (0..3 * CHUNK_SIZE / 2).map(|x| x as u8).collect::<Vec<_>>();
+    let kinds = [ChecksumKind::Sha256, ChecksumKind::Sha512];
+    let mut hasher = ParallelHasher::new(DigestBackend::RustCrypto, &kinds);
+    for chunk in data.chunks(CHUNK_SIZE) {
+      hasher.update(&Bytes::copy_from_slice(chunk));
+    }
+    let digests = hasher.finish();
+    assert_eq!(digests[0].0, sha256(&data));
+    assert_eq!(digests[1].0, sha512(&data));
+    assert_eq!(digests[1].1.kind, "SHA-512");
+    assert_eq!(digests[1].1.bytes, data.len() as u64);
+  }
+}
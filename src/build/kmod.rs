@@ -0,0 +1,155 @@
+use crate::util::scan_reader;
+use crate::util::walk;
+use crate::warning;
+use memchr::memmem;
+use std::collections::BTreeSet;
+use std::ffi::OsStr;
+use std::fs::{self, File};
+use std::io::{self, BufReader};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use zstd::stream::read::Decoder as ZstDecoder;
+
+pub const MODULES_DIR: &str = "usr/lib/modules";
+pub const FIRMWARE_DIR: &str = "usr/lib/firmware";
+
+/// Written by the kernel's own `modules_install`, but not for out-of-tree
+/// modules.
+const MODULES_ORDER: &str = "modules.order";
+
+/// Files generated by depmod.
+const DEPMOD_FILES: &[&str] = &[
+  "modules.alias",
+  "modules.alias.bin",
+  "modules.builtin.alias.bin",
+  "modules.builtin.bin",
+  "modules.dep",
+  "modules.dep.bin",
+  "modules.devname",
+  "modules.softdep",
+  "modules.symbols",
+  "modules.symbols.bin",
+  "modules.weakdep",
+];
+
+const VERMAGIC: &[u8] = b"\0vermagic=";
+const MAX_RELEASE_LEN: usize = 128;
+
+pub fn is_module(file: &Path) -> bool {
+  let name = file.file_name().unwrap_or_default().to_string_lossy();
+  [".ko", ".ko.zst", ".ko.xz", ".ko.gz"]
+    .iter()
+    .any(|x| name.ends_with(x))
+}
+
+/// The kernel release directory `file`, relative to the package root, is
+/// installed in.
+pub fn release_dir(file: &Path) -> Option<&str> {
+  let rest = file.strip_prefix(MODULES_DIR).ok()?;
+  let mut components = rest.components();
+  let release = components.next()?.as_os_str().to_str()?;
+  components.next().map(|_| release)
+}
+
+/// The kernel release a module was built for, taken from its `vermagic`.
+/// Returns `None` if there is none, or the module is compressed with
+/// something other than zstd.
+pub fn kernel_release(path: &Path) -> io::Result<Option<String>> {
+  let file = BufReader::new(File::open(path)?);
+  let mut release = None;
+  let find = |chunk: &[u8]| {
+    if release.is_some() {
+      return;
+    }
+    for pos in memmem::find_iter(chunk, VERMAGIC) {
+      let rest = &chunk[pos + VERMAGIC.len()..];
+      // Releases cut off at the end of a chunk show up again in the next one.
+      if let Some(len) = rest.iter().position(|x| *x == b' ' || *x == 0) {
+        release = Some(String::from_utf8_lossy(&rest[..len]).into_owned());
+        return;
+      }
+    }
+  };
+  let overlap = VERMAGIC.len() + MAX_RELEASE_LEN;
+  let name = path.file_name().unwrap_or_default().to_string_lossy();
+  if name.ends_with(".ko") {
+    scan_reader(file, overlap, find)?;
+  } else if name.ends_with(".ko.zst") {
+    scan_reader(ZstDecoder::new(file)?, overlap, find)?;
+  }
+  Ok(release)
+}
+
+/// Compresses the uncompressed kernel modules in the package with zstd, and
+/// generates depmod metadata for full kernel trees.
+pub fn process_modules(package_dir: &Path) -> io::Result<()> {
+  let modules_dir = package_dir.join(MODULES_DIR);
+  if !modules_dir.is_dir() {
+    return Ok(());
+  }
+  for file in walk(&modules_dir)? {
+    let path = modules_dir.join(&file);
+    let meta = path.symlink_metadata()?;
+    if !meta.is_file() || path.extension() != Some(OsStr::new("ko")) {
+      continue;
+    }
+    let mut compressed = path.clone().into_os_string();
+    compressed.push(".zst");
+    let dst = File::create(&compressed)?;
+    zstd::stream::copy_encode(File::open(&path)?, &dst, 0)?;
+    dst.set_permissions(meta.permissions())?;
+    fs::remove_file(&path)?;
+  }
+
+  for entry in modules_dir.read_dir()? {
+    let dir = entry?.path();
+    if !dir.join(MODULES_ORDER).is_file() {
+      continue;
+    }
+    let release = dir.file_name().unwrap().to_string_lossy();
+    let status = Command::new("depmod")
+      .arg("-b")
+      .arg(package_dir)
+      .arg(&*release)
+      .status();
+    match status {
+      Ok(x) if x.success() => {}
+      Ok(x) => {
+        warning!("depmod for {release} exited with {x}");
+      }
+      Err(e) => {
+        warning!("failed to run depmod for {release}: {e}");
+      }
+    }
+  }
+  Ok(())
+}
+
+/// Kernel releases the modules among `files` are installed for.
+pub fn kernel_releases(files: &[PathBuf]) -> BTreeSet<Box<str>> {
+  (files.iter())
+    .filter(|x| is_module(x))
+    .filter_map(|x| release_dir(x))
+    .map(Into::into)
+    .collect()
+}
+
+/// Whether the modules for `release` come from a full kernel tree, rather than
+/// being built out of tree.
+pub fn is_kernel_tree(package_dir: &Path, release: &str) -> bool {
+  (package_dir.join(MODULES_DIR).join(release))
+    .join(MODULES_ORDER)
+    .is_file()
+}
+
+pub fn is_depmod_output(file: &Path) -> bool {
+  release_dir(file).is_some()
+    && file.parent().and_then(|x| x.parent()) == Some(Path::new(MODULES_DIR))
+    && (file.file_name().and_then(|x| x.to_str())).is_some_and(|x| DEPMOD_FILES.contains(&x))
+}
+
+/// Out-of-tree modules need depmod to run on the target system once they are
+/// installed.
+pub fn needs_depmod(package_dir: &Path, files: &[PathBuf]) -> bool {
+  (kernel_releases(files).iter()).any(|x| !is_kernel_tree(package_dir, x))
+}
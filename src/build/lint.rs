@@ -0,0 +1,1510 @@
+use super::fetch::{check_urls, extract_dir_name, FetchOptions};
+use super::qa::Severity;
+use super::script::StepTrace;
+use super::types::{Execution, Package, Source};
+use crate::tree::{Groups, GROUPS_FILE};
+use crate::types::{SourceFile, SourceLocation};
+use crate::util::glob_match;
+use anyhow::{bail, Context as _};
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use url::Url;
+
+/// Name of the file at the root of a package tree setting lint policies.
+pub const POLICY_FILE: &str = ".ewe-lint.json";
+
+/// A problem found in a build script.
+#[derive(Debug, Clone)]
+pub struct Finding {
+  /// Line in the build script, if known.
+  pub line: Option<usize>,
+  pub severity: Severity,
+  pub message: String,
+}
+
+/// Where each snippet starts in the script. Snippets are the result of
+/// evaluation, so this is only a best guess based on their first line.
+fn locate(script: &str, snippet: &str) -> Option<usize> {
+  let (first, needle) = (snippet.lines().enumerate()).find(|(_, x)| !x.trim().is_empty())?;
+  let found = script.lines().position(|x| x.contains(needle.trim()))?;
+  (found + 1).checked_sub(first)
+}
+
+#[derive(Deserialize)]
+struct ShellcheckOutput {
+  comments: Vec<ShellcheckComment>,
+}
+
+#[derive(Deserialize)]
+struct ShellcheckComment {
+  line: usize,
+  level: String,
+  code: u32,
+  message: String,
+}
+
+/// Runs shellcheck on `snippet`, returning `None` if it is not installed.
+fn shellcheck(snippet: &str) -> Option<io::Result<Vec<(usize, Severity, String)>>> {
+  let child = Command::new("shellcheck")
+    .args(["--shell=sh", "--format=json1", "-"])
+    .stdin(Stdio::piped())
+    .stdout(Stdio::piped())
+    .stderr(Stdio::null())
+    .spawn();
+  let mut child = match child {
+    Ok(child) => child,
+    Err(e) if e.kind() == io::ErrorKind::NotFound => return None,
+    Err(e) => return Some(Err(e)),
+  };
+  let run = || {
+    child.stdin.take().unwrap().write_all(snippet.as_bytes())?;
+    let output = child.wait_with_output()?;
+    let output: ShellcheckOutput = serde_json::from_slice(&output.stdout)?;
+    Ok(
+      (output.comments.into_iter())
+        .map(|x| {
+          let severity = match &*x.level {
+            "error" => Severity::Error,
+            _ => Severity::Warning,
+          };
+          (x.line, severity, format!("{} [SC{}]", x.message, x.code))
+        })
+        .collect(),
+    )
+  };
+  Some(run())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Context {
+  Double,
+  Subst,
+}
+
+fn is_name(x: &str) -> bool {
+  let mut chars = x.chars();
+  (chars.next()).is_some_and(|x| x.is_ascii_alphabetic() || x == '_')
+    && chars.all(|x| x.is_ascii_alphanumeric() || x == '_')
+}
+
+/// A small subset of shellcheck for when it is not installed: unterminated
+/// quotes and unquoted expansions.
+fn check_shell(snippet: &str) -> Vec<(usize, Severity, String)> {
+  let mut findings = Vec::new();
+  let chars = snippet.chars().collect::<Vec<_>>();
+  let mut stack = Vec::new();
+  let mut line = 1;
+  // Words seen on the current line outside quotes, for context.
+  let mut word = String::new();
+  let mut prev_word = String::new();
+  let mut in_test = false;
+  let mut heredocs = Vec::new();
+  let mut i = 0;
+  while i < chars.len() {
+    let c = chars[i];
+    let quoted = stack.last() == Some(&Context::Double);
+    match c {
+      '\n' => {
+        line += 1;
+        in_test = false;
+        word.clear();
+        prev_word.clear();
+        // Heredoc bodies are not shell code.
+        for delim in std::mem::take(&mut heredocs) {
+          while i + 1 < chars.len() {
+            let end = (chars[i + 1..].iter())
+              .position(|x| *x == '\n')
+              .map_or(chars.len(), |x| i + 1 + x);
+            let body_line = chars[i + 1..end].iter().collect::<String>();
+            i = end;
+            line += 1;
+            if body_line.trim() == delim {
+              break;
+            }
+          }
+        }
+      }
+      '\\' => {
+        i += 1;
+        if chars.get(i) == Some(&'\n') {
+          line += 1;
+        }
+      }
+      '"' if quoted => {
+        stack.pop();
+      }
+      '"' => stack.push(Context::Double),
+      '\'' if !quoted => {
+        let start = line;
+        match chars[i + 1..].iter().position(|x| *x == '\'') {
+          Some(len) => {
+            line += chars[i + 1..i + 1 + len]
+              .iter()
+              .filter(|x| **x == '\n')
+              .count();
+            i += len + 1;
+          }
+          None => {
+            findings.push((start, Severity::Error, "unterminated single quote".into()));
+            return findings;
+          }
+        }
+      }
+      '#' if !quoted && word.is_empty() => {
+        while i + 1 < chars.len() && chars[i + 1] != '\n' {
+          i += 1;
+        }
+      }
+      ')' if stack.last() == Some(&Context::Subst) => {
+        stack.pop();
+      }
+      '<' if !quoted && chars.get(i + 1) == Some(&'<') && chars.get(i + 2) != Some(&'<') => {
+        let rest = chars[i + 2..].iter().collect::<String>();
+        let rest = rest.trim_start_matches('-').trim_start();
+        let delim = (rest.split(|x: char| x.is_whitespace() || x == ';'))
+          .next()
+          .unwrap_or_default()
+          .trim_matches(['\'', '"']);
+        if !delim.is_empty() {
+          heredocs.push(delim.to_string());
+        }
+        i += 1;
+      }
+      '$' => {
+        let next = chars.get(i + 1).copied().unwrap_or_default();
+        if next == '(' && chars.get(i + 2) == Some(&'(') {
+          // Arithmetic expansion, where splitting does not happen.
+          let end = chars[i..].windows(2).position(|x| x == [')', ')']);
+          i += end.map_or(chars.len() - i, |x| x + 1);
+        } else if next == '(' {
+          stack.push(Context::Subst);
+          i += 1;
+        } else {
+          let name = if next == '{' {
+            let len = chars[i + 2..].iter().position(|x| *x == '}');
+            len.map(|len| chars[i + 2..i + 2 + len].iter().collect::<String>())
+          } else if next == '@' || next == '*' {
+            Some(next.to_string())
+          } else {
+            let len = (chars[i + 1..].iter())
+              .take_while(|x| x.is_ascii_alphanumeric() || **x == '_')
+              .count();
+            Some(chars[i + 1..i + 1 + len].iter().collect())
+          };
+          let assignment = word.split_once('=').is_some_and(|(x, _)| is_name(x));
+          if let Some(name) = name.filter(|x| !x.is_empty()) {
+            let plain = name.split(['#', ':', '%', '/']).next().unwrap_or_default();
+            let splits = is_name(plain) || plain == "@" || plain == "*";
+            if !quoted && splits && !assignment && !in_test && prev_word != "case" {
+              findings.push((
+                line,
+                Severity::Warning,
+                format!("unquoted `${name}` is subject to word splitting and globbing"),
+              ));
+            }
+            i += if next == '{' {
+              name.chars().count() + 2
+            } else {
+              name.chars().count()
+            };
+          }
+        }
+      }
+      c if !quoted && (c.is_whitespace() || ";&|".contains(c)) => {
+        if word == "[[" {
+          in_test = true;
+        } else if word == "]]" {
+          in_test = false;
+        }
+        if !word.is_empty() {
+          prev_word = std::mem::take(&mut word);
+        }
+      }
+      _ => {}
+    }
+    if !quoted && !c.is_whitespace() && !";&|".contains(c) {
+      word.push(c);
+    }
+    i += 1;
+  }
+  if stack.contains(&Context::Double) {
+    findings.push((line, Severity::Error, "unterminated double quote".into()));
+  }
+  findings
+}
+
+/// Checks the shell snippets of `source`, evaluated from the build script
+/// `script`.
+fn check_snippets(script: &str, source: &Source) -> io::Result<Vec<Finding>> {
+  let snippets = [
+    ("prepare", &source.prepare),
+    ("build", &source.build),
+    ("check", &source.check),
+    ("test", &source.test),
+  ];
+  let mut findings = Vec::new();
+  for (name, exec) in snippets {
+    let Some(Execution::Shell(snippet)) = exec else {
+      continue;
+    };
+    let results = match shellcheck(snippet) {
+      Some(results) => results?,
+      None => check_shell(snippet),
+    };
+    let base = locate(script, snippet);
+    for (line, severity, message) in results {
+      findings.push(Finding {
+        line: base.map(|x| x + line - 1),
+        severity,
+        message: format!("`{name}`: {message}"),
+      });
+    }
+  }
+  Ok(findings)
+}
+
+/// Naming convention of one kind of split package, like `-dev`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SplitRule {
+  pub suffix: Box<str>,
+  /// Suffixes meaning the same that should not be used instead.
+  #[serde(default)]
+  pub aliases: Vec<Box<str>>,
+  /// Whether such a package has to depend on the package it is split from.
+  #[serde(default)]
+  pub depends_on_base: bool,
+}
+
+impl SplitRule {
+  fn new(suffix: &str, aliases: &[&str], depends_on_base: bool) -> Self {
+    Self {
+      suffix: suffix.into(),
+      aliases: aliases.iter().map(|x| (*x).into()).collect(),
+      depends_on_base,
+    }
+  }
+}
+
+fn default_split_rules() -> Vec<SplitRule> {
+  vec![
+    SplitRule::new("-dev", &["-devel", "-headers"], true),
+    SplitRule::new("-doc", &["-docs", "-man"], false),
+    SplitRule::new("-dbg", &["-debug", "-dbgsym"], true),
+    SplitRule::new("-locale", &["-lang", "-l10n", "-i18n"], false),
+  ]
+}
+
+fn default_max_length() -> usize {
+  80
+}
+
+/// What descriptions of packages should look like.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DescriptionPolicy {
+  /// Maximum length in characters.
+  #[serde(default = "default_max_length")]
+  pub max_length: usize,
+
+  /// File listing the words descriptions are spelled with, one per line,
+  /// relative to the policy. Without it, spelling is not checked.
+  #[serde(default)]
+  pub wordlist: Option<PathBuf>,
+
+  /// Words of the list, lowercase.
+  #[serde(skip)]
+  pub words: Option<BTreeSet<Box<str>>>,
+}
+
+impl Default for DescriptionPolicy {
+  fn default() -> Self {
+    Self {
+      max_length: default_max_length(),
+      wordlist: None,
+      words: None,
+    }
+  }
+}
+
+/// Lint policies of a package tree, from [`POLICY_FILE`]:
+///
+/// ```json
+/// {
+///   "split_packages": [{ "suffix": "-dev", "aliases": ["-devel"], "depends_on_base": true }],
+///   "description": { "max_length": 72, "wordlist": "words.txt" },
+///   "flaky_hosts": ["*.sourceforge.net"],
+///   "rules": { "shell": "error", "split-package-names": "off" }
+/// }
+/// ```
+///
+/// Without the file, or without `split_packages` in it, the conventional
+/// `-dev`, `-doc`, `-dbg` and `-locale` rules apply.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LintPolicy {
+  #[serde(default = "default_split_rules")]
+  pub split_packages: Vec<SplitRule>,
+
+  #[serde(default)]
+  pub description: DescriptionPolicy,
+
+  /// Host globs, like `*.sourceforge.net`, whose URLs are not reported when
+  /// they fail to answer with `--network`.
+  #[serde(default)]
+  pub flaky_hosts: Vec<Box<str>>,
+
+  /// Rules turned off or reported at another severity, by ID.
+  #[serde(default)]
+  pub rules: BTreeMap<Box<str>, RuleLevel>,
+}
+
+impl Default for LintPolicy {
+  fn default() -> Self {
+    Self {
+      split_packages: default_split_rules(),
+      description: DescriptionPolicy::default(),
+      flaky_hosts: Vec::new(),
+      rules: BTreeMap::new(),
+    }
+  }
+}
+
+/// How findings of a rule are reported, overriding the rule itself.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RuleLevel {
+  Off,
+  Warning,
+  Error,
+}
+
+impl LintPolicy {
+  /// Finds the policy file of the tree the build script at `path` is in, by
+  /// looking through its parent directories.
+  pub fn locate(path: &Path) -> io::Result<Option<PathBuf>> {
+    let path = path.canonicalize()?;
+    let found = (path.ancestors().skip(1))
+      .map(|x| x.join(POLICY_FILE))
+      .find(|x| x.is_file());
+    Ok(found)
+  }
+
+  /// Loads the policy file at `path`, along with its wordlist.
+  pub fn load(path: &Path) -> anyhow::Result<Self> {
+    let data = fs::read(path)?;
+    let mut policy: Self = (serde_json::from_slice(&data))
+      .with_context(|| format!("invalid lint policy {}", path.display()))?;
+    if let Some(wordlist) = &policy.description.wordlist {
+      let wordlist = path.parent().unwrap_or(Path::new(".")).join(wordlist);
+      let words = (fs::read_to_string(&wordlist))
+        .with_context(|| format!("cannot read wordlist {}", wordlist.display()))?;
+      let words = words.lines().map(|x| x.trim().to_lowercase().into());
+      policy.description.words = Some(words.collect());
+    }
+    Ok(policy)
+  }
+}
+
+/// Split packages of `source` following or resembling a convention of
+/// `policy`, with the convention, the package they are split from, and the
+/// alias they use instead of the suffix, if any.
+fn split_packages<'a>(
+  source: &'a Source,
+  policy: &'a LintPolicy,
+) -> impl Iterator<Item = (&'a Package, &'a SplitRule, &'a str, Option<&'a str>)> {
+  (source.packages.iter())
+    .filter(|x| x.info.name != source.info.name)
+    .filter_map(|package| {
+      let name = &*package.info.name;
+      policy.split_packages.iter().find_map(|rule| {
+        if let Some(alias) = (rule.aliases.iter()).find(|x| name.ends_with(&***x)) {
+          return Some((
+            package,
+            rule,
+            &name[..name.len() - alias.len()],
+            Some(&**alias),
+          ));
+        }
+        let base = name.strip_suffix(&*rule.suffix).filter(|x| !x.is_empty())?;
+        Some((package, rule, base, None))
+      })
+    })
+}
+
+/// Line of the build script `script` naming the package `name`, if any.
+fn locate_package(script: &str, name: &str) -> Option<usize> {
+  let quoted = format!("\"{name}\"");
+  script
+    .lines()
+    .position(|x| x.contains(&quoted))
+    .map(|x| x + 1)
+}
+
+/// What a lint rule is given to check.
+pub struct LintContext<'a> {
+  /// Text of the build script.
+  pub script: &'a str,
+  /// The script, evaluated.
+  pub source: &'a Source,
+  /// Its steps, evaluated.
+  pub trace: &'a StepTrace,
+  pub policy: &'a LintPolicy,
+  /// Groups of the package tree, if it has a [`GROUPS_FILE`].
+  pub groups: Option<&'a Groups>,
+  /// How to reach servers, if rules may.
+  pub network: Option<&'a FetchOptions>,
+}
+
+/// A check of build scripts, turned off or reported at another severity
+/// through the `rules` of the [`LintPolicy`].
+pub trait LintRule: Send + Sync {
+  /// Stable identifier, like `shell`, used in output and in policies.
+  fn id(&self) -> &'static str;
+
+  fn check(&self, cx: &LintContext) -> anyhow::Result<Vec<Finding>>;
+}
+
+/// Shell snippets are checked with shellcheck, or a small subset of it when
+/// it is not installed.
+struct ShellRule;
+
+impl LintRule for ShellRule {
+  fn id(&self) -> &'static str {
+    "shell"
+  }
+
+  fn check(&self, cx: &LintContext) -> anyhow::Result<Vec<Finding>> {
+    Ok(check_snippets(cx.script, cx.source)?)
+  }
+}
+
+/// Split packages are named after the conventions of the policy, and are
+/// split from a package of the same script.
+struct SplitNamesRule;
+
+impl LintRule for SplitNamesRule {
+  fn id(&self) -> &'static str {
+    "split-package-names"
+  }
+
+  fn check(&self, cx: &LintContext) -> anyhow::Result<Vec<Finding>> {
+    let mut findings = Vec::new();
+    for (package, rule, base, alias) in split_packages(cx.source, cx.policy) {
+      let name = &package.info.name;
+      let known =
+        base == &*cx.source.info.name || (cx.source.packages.iter()).any(|x| &*x.info.name == base);
+      let message = match alias {
+        Some(_) => format!(
+          "split package `{name}` should be named `{base}{}`",
+          rule.suffix
+        ),
+        None if !known => format!("split package `{name}` has no `{base}` to go with it"),
+        None => continue,
+      };
+      findings.push(Finding {
+        line: locate_package(cx.script, name),
+        severity: Severity::Warning,
+        message,
+      });
+    }
+    Ok(findings)
+  }
+}
+
+/// Split packages depend on the package they are split from, where the
+/// policy says so.
+struct SplitDependsRule;
+
+impl LintRule for SplitDependsRule {
+  fn id(&self) -> &'static str {
+    "split-package-depends"
+  }
+
+  fn check(&self, cx: &LintContext) -> anyhow::Result<Vec<Finding>> {
+    Ok(
+      (split_packages(cx.source, cx.policy))
+        .filter(|(package, rule, base, alias)| {
+          alias.is_none() && rule.depends_on_base && !package.info.depends.contains(*base)
+        })
+        .map(|(package, _, base, _)| Finding {
+          line: locate_package(cx.script, &package.info.name),
+          severity: Severity::Error,
+          message: format!(
+            "split package `{}` should depend on `{base}`",
+            package.info.name
+          ),
+        })
+        .collect(),
+    )
+  }
+}
+
+/// Fields of packages, read from build scripts and from each entry of their
+/// `packages`.
+const PACKAGE_FIELDS: [&str; 21] = [
+  "name",
+  "description",
+  "version",
+  "architecture",
+  "homepage",
+  "bugs",
+  "repository",
+  "license",
+  "groups",
+  "keywords",
+  "provides",
+  "conflicts",
+  "depends",
+  "optional_depends",
+  "triggers",
+  "permitted_setuid",
+  "permitted_world_writable",
+  "rpath",
+  "extra",
+  "compression",
+  "pack",
+];
+
+/// Fields only build scripts themselves have.
+const SCRIPT_FIELDS: [&str; 9] = [
+  "build_depends",
+  "source",
+  "options",
+  "source_date_epoch",
+  "prepare",
+  "build",
+  "check",
+  "test",
+  "packages",
+];
+
+/// Fields listing names, where listing one twice does nothing.
+const LIST_FIELDS: [&str; 7] = [
+  "architecture", "groups", "keywords", "provides", "conflicts", "depends", "build_depends",
+];
+
+/// The script as evaluated, then each entry of its `packages`, with what to
+/// call them in findings.
+fn raw_packages(source: &Source) -> Vec<(String, &Map<String, Value>)> {
+  let Some(script) = source.raw.as_object() else {
+    return Vec::new();
+  };
+  let mut maps = vec![("the script".to_string(), script)];
+  let packages = (script.get("packages").and_then(Value::as_array))
+    .into_iter()
+    .flatten()
+    .filter_map(Value::as_object);
+  for package in packages {
+    let name = package.get("name").and_then(Value::as_str);
+    maps.push((format!("package `{}`", name.unwrap_or("?")), package));
+  }
+  maps
+}
+
+/// Number of single-character edits turning `a` into `b`.
+fn edit_distance(a: &str, b: &str) -> usize {
+  let b = b.chars().collect::<Vec<_>>();
+  let mut row = (0..=b.len()).collect::<Vec<_>>();
+  for (i, x) in a.chars().enumerate() {
+    let mut diagonal = row[0];
+    row[0] = i + 1;
+    for (j, y) in b.iter().enumerate() {
+      let substituted = diagonal + usize::from(x != *y);
+      diagonal = row[j + 1];
+      row[j + 1] = substituted.min(row[j] + 1).min(diagonal + 1);
+    }
+  }
+  row[b.len()]
+}
+
+/// Fields are all read by ewepkg, as misspelled ones are left out silently.
+struct UnknownFieldsRule;
+
+impl LintRule for UnknownFieldsRule {
+  fn id(&self) -> &'static str {
+    "unknown-fields"
+  }
+
+  fn check(&self, cx: &LintContext) -> anyhow::Result<Vec<Finding>> {
+    let maps = (raw_packages(cx.source).into_iter())
+      .enumerate()
+      .map(|(i, (what, map))| {
+        let known = match i {
+          0 => [&PACKAGE_FIELDS[..], &SCRIPT_FIELDS].concat(),
+          _ => PACKAGE_FIELDS.to_vec(),
+        };
+        (what, map, known)
+      })
+      .collect::<Vec<_>>();
+
+    let mut findings = Vec::new();
+    for (what, map, known) in maps {
+      for key in map.keys().filter(|x| !known.contains(&&***x)) {
+        let similar = (known.iter()).find(|x| key.len() > 3 && edit_distance(key, x) <= 2);
+        let hint = similar
+          .map(|x| format!(", did you mean `{x}`?"))
+          .unwrap_or_default();
+        findings.push(Finding {
+          line: locate_text(cx.script, &format!("{key}:")),
+          severity: Severity::Warning,
+          message: format!("{what} has a field `{key}` that is not used{hint}"),
+        });
+      }
+    }
+    Ok(findings)
+  }
+}
+
+/// Names are listed once in each field.
+struct DuplicateEntriesRule;
+
+impl LintRule for DuplicateEntriesRule {
+  fn id(&self) -> &'static str {
+    "duplicate-entries"
+  }
+
+  fn check(&self, cx: &LintContext) -> anyhow::Result<Vec<Finding>> {
+    let mut findings = Vec::new();
+    for (what, map) in raw_packages(cx.source) {
+      for field in LIST_FIELDS {
+        let Some(list) = map.get(field).and_then(Value::as_array) else {
+          continue;
+        };
+        let mut seen = BTreeSet::new();
+        let mut reported = BTreeSet::new();
+        for name in list.iter().filter_map(Value::as_str) {
+          if !seen.insert(name) && reported.insert(name) {
+            findings.push(Finding {
+              line: locate_text(cx.script, &format!("\"{name}\"")),
+              severity: Severity::Warning,
+              message: format!("{what} lists `{name}` twice in `{field}`"),
+            });
+          }
+        }
+      }
+    }
+    Ok(findings)
+  }
+}
+
+/// Packages do not depend on or conflict with themselves, by name or
+/// through what they provide.
+struct SelfDependsRule;
+
+impl LintRule for SelfDependsRule {
+  fn id(&self) -> &'static str {
+    "self-depends"
+  }
+
+  fn check(&self, cx: &LintContext) -> anyhow::Result<Vec<Finding>> {
+    let mut findings = Vec::new();
+    for package in &cx.source.packages {
+      let info = &package.info;
+      let names = std::iter::once(&info.name).chain(&info.provides);
+      for name in names {
+        for (field, list) in [
+          ("depends on", &info.depends),
+          ("conflicts with", &info.conflicts),
+        ] {
+          if list.contains(name) {
+            let via = match *name == info.name {
+              true => String::new(),
+              false => format!(" through `{name}`"),
+            };
+            findings.push(Finding {
+              line: locate_package(cx.script, &info.name),
+              severity: Severity::Error,
+              message: format!("`{}` {field} itself{via}", info.name),
+            });
+          }
+        }
+      }
+    }
+    Ok(findings)
+  }
+}
+
+/// Architecture lists do not mix `any` or `all` with specific architectures,
+/// which are ignored or rejected next to them.
+struct ArchitectureRule;
+
+impl LintRule for ArchitectureRule {
+  fn id(&self) -> &'static str {
+    "architecture"
+  }
+
+  fn check(&self, cx: &LintContext) -> anyhow::Result<Vec<Finding>> {
+    let mut findings = Vec::new();
+    for (what, map) in raw_packages(cx.source) {
+      let Some(list) = map.get("architecture").and_then(Value::as_array) else {
+        continue;
+      };
+      let arches = list
+        .iter()
+        .filter_map(Value::as_str)
+        .collect::<BTreeSet<_>>();
+      let specific = (arches.iter()).filter(|x| !matches!(**x, "any" | "all"));
+      let specific = specific.map(|x| format!("`{x}`")).collect::<Vec<_>>();
+      for generic in ["any", "all"].into_iter().filter(|x| arches.contains(x)) {
+        if specific.is_empty() {
+          continue;
+        }
+        findings.push(Finding {
+          line: locate_text(cx.script, &format!("\"{generic}\"")),
+          severity: Severity::Error,
+          message: format!(
+            "{what} lists `{generic}` along with {}",
+            specific.join(", ")
+          ),
+        });
+      }
+    }
+    Ok(findings)
+  }
+}
+
+/// Distinct descriptions of `source` and its split packages, with the name
+/// of the first package having each.
+fn descriptions(source: &Source) -> Vec<(&str, &str)> {
+  let mut seen = BTreeSet::new();
+  (std::iter::once(&source.info.inner))
+    .chain(source.packages.iter().map(|x| &x.info))
+    .filter(|x| seen.insert(&*x.description))
+    .map(|x| (&*x.name, &*x.description))
+    .collect()
+}
+
+/// Line of the build script `script` holding the string `text`, if any.
+fn locate_text(script: &str, text: &str) -> Option<usize> {
+  let text = text.lines().next().unwrap_or_default();
+  let found = script
+    .lines()
+    .position(|x| !text.is_empty() && x.contains(text));
+  found.map(|x| x + 1)
+}
+
+/// Runs `check` on each distinct description, reporting what it returns at
+/// `severity`.
+fn check_descriptions(
+  cx: &LintContext,
+  severity: Severity,
+  check: impl Fn(&str, &str) -> Option<String>,
+) -> Vec<Finding> {
+  (descriptions(cx.source).into_iter())
+    .filter_map(|(name, description)| {
+      Some(Finding {
+        line: locate_text(cx.script, description),
+        severity,
+        message: check(name, description)?,
+      })
+    })
+    .collect()
+}
+
+/// Descriptions are there.
+struct DescriptionEmptyRule;
+
+impl LintRule for DescriptionEmptyRule {
+  fn id(&self) -> &'static str {
+    "description-empty"
+  }
+
+  fn check(&self, cx: &LintContext) -> anyhow::Result<Vec<Finding>> {
+    Ok(check_descriptions(cx, Severity::Error, |name, x| {
+      (x.trim().is_empty()).then(|| format!("`{name}` has an empty description"))
+    }))
+  }
+}
+
+/// Descriptions fit the length of the policy.
+struct DescriptionLengthRule;
+
+impl LintRule for DescriptionLengthRule {
+  fn id(&self) -> &'static str {
+    "description-length"
+  }
+
+  fn check(&self, cx: &LintContext) -> anyhow::Result<Vec<Finding>> {
+    let max = cx.policy.description.max_length;
+    Ok(check_descriptions(cx, Severity::Warning, |name, x| {
+      let len = x.chars().count();
+      (len > max).then(|| format!("description of `{name}` is {len} characters long, over {max}"))
+    }))
+  }
+}
+
+/// Descriptions say what a package is, not repeat its name.
+struct DescriptionNameRule;
+
+impl LintRule for DescriptionNameRule {
+  fn id(&self) -> &'static str {
+    "description-name"
+  }
+
+  fn check(&self, cx: &LintContext) -> anyhow::Result<Vec<Finding>> {
+    Ok(check_descriptions(cx, Severity::Warning, |name, x| {
+      let first = x
+        .split(|c: char| c.is_whitespace() || c == ',' || c == ':')
+        .next()?;
+      (first.eq_ignore_ascii_case(name))
+        .then(|| format!("description of `{name}` starts with the package name"))
+    }))
+  }
+}
+
+/// Descriptions use words of the wordlist of the policy, if it has one.
+/// Words with digits or capitals past the first letter, like `GTK` or `x86`,
+/// are taken as names.
+struct DescriptionSpellingRule;
+
+impl LintRule for DescriptionSpellingRule {
+  fn id(&self) -> &'static str {
+    "description-spelling"
+  }
+
+  fn check(&self, cx: &LintContext) -> anyhow::Result<Vec<Finding>> {
+    let Some(words) = &cx.policy.description.words else {
+      return Ok(Vec::new());
+    };
+    let names = (cx.source.packages.iter())
+      .map(|x| x.info.name.to_lowercase())
+      .collect::<BTreeSet<_>>();
+    Ok(check_descriptions(cx, Severity::Warning, |name, x| {
+      let unknown = (x.split(|c: char| !c.is_alphanumeric() && c != '\'' && c != '-'))
+        .map(|x| x.trim_matches(['\'', '-']))
+        .filter(|x| !x.is_empty())
+        .filter(|x| x.chars().all(char::is_alphabetic))
+        .filter(|x| !x.chars().skip(1).any(char::is_uppercase))
+        .map(|x| x.to_lowercase())
+        .filter(|x| !words.contains(&**x) && !names.contains(x))
+        .collect::<Vec<_>>();
+      (!unknown.is_empty()).then(|| {
+        format!(
+          "description of `{name}` has unknown words: {}",
+          unknown.join(", ")
+        )
+      })
+    }))
+  }
+}
+
+/// HTTP source URLs of `source` and their mirrors, then its homepages, bug
+/// trackers and repositories on the web, with what each is, leaving out
+/// those of flaky hosts.
+fn urls<'a>(source: &'a Source, policy: &LintPolicy) -> Vec<(&'a Url, &'static str)> {
+  let infos =
+    || (std::iter::once(&source.info.inner)).chain(source.packages.iter().map(|x| &x.info));
+  let pages = (infos().filter_map(|x| Some((x.homepage.as_ref()?, "homepage"))))
+    .chain(infos().filter_map(|x| Some((x.bugs.as_ref()?, "bug tracker"))))
+    .chain(infos().filter_map(|x| Some((x.repository.as_ref()?, "repository"))))
+    .filter(|(url, _)| matches!(url.scheme(), "http" | "https"));
+  let sources = (source.info.source.iter()).filter_map(|x| match &x.location {
+    SourceLocation::Http(url) => Some((url, "source")),
+    _ => None,
+  });
+  let mirrors = (source.info.source.iter()).flat_map(|x| x.mirrors.iter().map(|x| (x, "mirror")));
+  let mut seen = BTreeSet::new();
+  (sources.chain(mirrors).chain(pages))
+    .filter(|(url, _)| seen.insert(*url))
+    .filter(|(url, _)| {
+      let host = url.host_str().unwrap_or_default();
+      !(policy.flaky_hosts.iter()).any(|x| glob_match(x, host))
+    })
+    .collect()
+}
+
+/// Source URLs and web pages answer, with `--network`. Dead sources are
+/// errors, dead mirrors and pages warnings.
+struct DeadUrlsRule;
+
+impl LintRule for DeadUrlsRule {
+  fn id(&self) -> &'static str {
+    "dead-urls"
+  }
+
+  fn check(&self, cx: &LintContext) -> anyhow::Result<Vec<Finding>> {
+    let Some(options) = cx.network else {
+      return Ok(Vec::new());
+    };
+    let urls = urls(cx.source, cx.policy);
+    let checked = urls.iter().map(|(x, _)| (*x).clone()).collect::<Vec<_>>();
+    let health = check_urls(&checked, options)?;
+    Ok(
+      (urls.iter().zip(health))
+        .filter_map(|((url, what), health)| {
+          let severity = match *what {
+            "source" => Severity::Error,
+            _ => Severity::Warning,
+          };
+          Some(Finding {
+            line: locate_text(cx.script, url.as_str()),
+            severity,
+            message: format!("{what} {url} is dead ({})", health.error?),
+          })
+        })
+        .collect(),
+    )
+  }
+}
+
+/// Web pages of packages are web pages, bugs go to one or to a `mailto:`
+/// address, and repositories may also be cloned over git or SSH.
+struct UrlSchemesRule;
+
+impl LintRule for UrlSchemesRule {
+  fn id(&self) -> &'static str {
+    "url-schemes"
+  }
+
+  fn check(&self, cx: &LintContext) -> anyhow::Result<Vec<Finding>> {
+    let mut reported = BTreeSet::new();
+    let mut findings = Vec::new();
+    let infos =
+      (std::iter::once(&cx.source.info.inner)).chain(cx.source.packages.iter().map(|x| &x.info));
+    for info in infos {
+      let fields = [
+        ("homepage", &info.homepage),
+        ("bugs", &info.bugs),
+        ("repository", &info.repository),
+      ];
+      for (field, url) in fields {
+        let Some(url) = url else {
+          continue;
+        };
+        let valid = match url.scheme() {
+          "http" | "https" => url.host().is_some(),
+          "mailto" => field == "bugs",
+          "git" | "ssh" | "git+ssh" => field == "repository",
+          _ => false,
+        };
+        if !valid && reported.insert(url) {
+          findings.push(Finding {
+            line: locate_text(cx.script, url.as_str()),
+            severity: Severity::Warning,
+            message: format!(
+              "`{field}` of `{}` has an unexpected scheme: {url}",
+              info.name
+            ),
+          });
+        }
+      }
+    }
+    Ok(findings)
+  }
+}
+
+/// `http://` source URLs and web pages are not used where `https://` works,
+/// with `--network`.
+struct InsecureUrlsRule;
+
+impl LintRule for InsecureUrlsRule {
+  fn id(&self) -> &'static str {
+    "insecure-urls"
+  }
+
+  fn check(&self, cx: &LintContext) -> anyhow::Result<Vec<Finding>> {
+    let Some(options) = cx.network else {
+      return Ok(Vec::new());
+    };
+    let (urls, secure): (Vec<_>, Vec<_>) = (urls(cx.source, cx.policy).into_iter())
+      .filter(|(x, _)| x.scheme() == "http")
+      .filter_map(|(url, _)| {
+        let mut secure = url.clone();
+        secure.set_scheme("https").ok()?;
+        secure.set_port(None).ok()?;
+        Some((url, secure))
+      })
+      .unzip();
+    let health = check_urls(&secure, options)?;
+    Ok(
+      (urls.iter().zip(secure).zip(health))
+        .filter(|(_, health)| health.error.is_none())
+        .map(|((url, secure), _)| Finding {
+          line: locate_text(cx.script, url.as_str()),
+          severity: Severity::Warning,
+          message: format!("{url} also works as {secure}"),
+        })
+        .collect(),
+    )
+  }
+}
+
+/// Downloaded sources are pinned by a checksum.
+struct ChecksumsRule;
+
+impl LintRule for ChecksumsRule {
+  fn id(&self) -> &'static str {
+    "checksums"
+  }
+
+  fn check(&self, cx: &LintContext) -> anyhow::Result<Vec<Finding>> {
+    let findings = (cx.source.info.source.iter())
+      .filter(|x| matches!(x.location, SourceLocation::Http(_)) && x.checksums.is_empty())
+      .filter(|x| !x.skip_checksum)
+      .map(|file| Finding {
+        line: locate_text(cx.script, file.file_name()),
+        severity: Severity::Warning,
+        message: format!(
+          "source `{}` has no checksum, see `ewe checksum`, or set `skip_checksum`",
+          file.file_name()
+        ),
+      })
+      .collect();
+    Ok(findings)
+  }
+}
+
+/// Names a source goes by in the source directory: its file name and, for
+/// archives, the directory it is extracted to.
+fn source_names(file: &SourceFile) -> impl Iterator<Item = &str> {
+  std::iter::once(file.file_name()).chain(extract_dir_name(file))
+}
+
+/// Whether `text` mentions `name` as a whole word or path component.
+fn mentions(text: &str, name: &str) -> bool {
+  let is_part = |c: char| c.is_ascii_alphanumeric() || "._+-".contains(c);
+  (text.match_indices(name))
+    .any(|(i, _)| !text[..i].ends_with(is_part) && !text[i + name.len()..].starts_with(is_part))
+}
+
+/// Declared sources are used by some step, through its extracted directory,
+/// its file or a builtin.
+struct UnusedSourcesRule;
+
+impl LintRule for UnusedSourcesRule {
+  fn id(&self) -> &'static str {
+    "unused-sources"
+  }
+
+  fn check(&self, cx: &LintContext) -> anyhow::Result<Vec<Finding>> {
+    let trace = cx.trace;
+    // Going through `source.files` uses them all, and steps that failed to
+    // evaluate may use any.
+    if !trace.failed.is_empty() || cx.script.contains("source.files") {
+      return Ok(Vec::new());
+    }
+    let texts = || (trace.snippets.iter().map(|(_, x)| x)).chain(&trace.calls.installed);
+    let is_used = |file: &SourceFile| {
+      trace.calls.srcdirs.contains(file.file_name())
+        || texts().any(|x| source_names(file).any(|name| mentions(x, name)))
+    };
+    let findings = (cx.source.info.source.iter())
+      .filter(|file| !is_used(file))
+      .map(|file| Finding {
+        line: locate_text(cx.script, file.file_name()),
+        severity: Severity::Warning,
+        message: format!("source `{}` is not used by any step", file.file_name()),
+      })
+      .collect();
+    Ok(findings)
+  }
+}
+
+/// Steps only use declared sources: `srcdir_of` is not asked for others,
+/// and `prepare` refers to no other patches or files in the source
+/// directory.
+struct UndeclaredSourcesRule;
+
+impl LintRule for UndeclaredSourcesRule {
+  fn id(&self) -> &'static str {
+    "undeclared-sources"
+  }
+
+  fn check(&self, cx: &LintContext) -> anyhow::Result<Vec<Finding>> {
+    let declared = (cx.source.info.source.iter())
+      .flat_map(source_names)
+      .collect::<BTreeSet<_>>();
+    let mut findings = Vec::new();
+    for name in &cx.trace.calls.srcdirs {
+      if !declared.contains(&**name) {
+        findings.push(Finding {
+          line: locate_text(cx.script, &format!("\"{name}\"")),
+          severity: Severity::Error,
+          message: format!("`srcdir_of` is asked for `{name}`, which is not a source"),
+        });
+      }
+    }
+    let mut reported = BTreeSet::new();
+    let prepare = (cx.trace.snippets.iter()).filter(|(step, _)| step == "prepare");
+    for (_, snippet) in prepare {
+      let words = snippet.split(|c: char| c.is_whitespace() || ";|&<>()'\"`=".contains(c));
+      for word in words {
+        let in_source_dir = ["$source_dir/", "${source_dir}/"]
+          .into_iter()
+          .find_map(|x| word.strip_prefix(x));
+        // Only what looks like files, since steps make directories of their
+        // own too.
+        let name = match in_source_dir {
+          Some(path) => path.split('/').next().unwrap_or_default(),
+          None => word.trim_start_matches("../"),
+        };
+        let is_file = match in_source_dir {
+          Some(_) => name.contains('.'),
+          None => !name.contains('/') && (name.ends_with(".patch") || name.ends_with(".diff")),
+        };
+        let is_literal = !name.contains(['$', '*', '?', '[']);
+        if is_file && is_literal && !declared.contains(name) && reported.insert(name) {
+          findings.push(Finding {
+            line: locate_text(cx.script, name),
+            severity: Severity::Warning,
+            message: format!("`prepare` refers to `{name}`, which is not a source"),
+          });
+        }
+      }
+    }
+    Ok(findings)
+  }
+}
+
+/// Scripts do no work outside of steps: what they run or read then is not
+/// there yet, and makes their metadata depend on more than the script.
+struct SideEffectsRule;
+
+impl LintRule for SideEffectsRule {
+  fn id(&self) -> &'static str {
+    "side-effects"
+  }
+
+  fn check(&self, cx: &LintContext) -> anyhow::Result<Vec<Finding>> {
+    let findings = (cx.trace.side_effects.iter())
+      .map(|call| Finding {
+        line: locate_text(cx.script, call.split('(').next().unwrap_or_default()),
+        severity: Severity::Warning,
+        message: format!("`{call}` is called outside of steps, before sources are fetched"),
+      })
+      .collect();
+    Ok(findings)
+  }
+}
+
+/// Packages are only in groups the package tree lists.
+struct GroupsRule;
+
+impl LintRule for GroupsRule {
+  fn id(&self) -> &'static str {
+    "groups"
+  }
+
+  fn check(&self, cx: &LintContext) -> anyhow::Result<Vec<Finding>> {
+    let mut findings = Vec::new();
+    for package in &cx.source.packages {
+      let info = &package.info;
+      let Some(groups) = cx.groups else {
+        if !info.groups.is_empty() {
+          findings.push(Finding {
+            line: locate_package(cx.script, &info.name),
+            severity: Severity::Warning,
+            message: format!(
+              "`{}` is in groups, but there is no {GROUPS_FILE}",
+              info.name
+            ),
+          });
+        }
+        continue;
+      };
+      for group in groups.unknown(info) {
+        findings.push(Finding {
+          line: locate_text(cx.script, &format!("\"{group}\"")),
+          severity: Severity::Error,
+          message: format!(
+            "`{}` is in `{group}`, which is not in {GROUPS_FILE}",
+            info.name
+          ),
+        });
+      }
+    }
+    Ok(findings)
+  }
+}
+
+/// The lint rules to run, the built-in ones to begin with.
+pub struct Linter {
+  rules: Vec<Box<dyn LintRule>>,
+  network: Option<FetchOptions>,
+}
+
+impl Default for Linter {
+  fn default() -> Self {
+    Self {
+      rules: vec![
+        Box::new(ShellRule),
+        Box::new(UnknownFieldsRule),
+        Box::new(DuplicateEntriesRule),
+        Box::new(SelfDependsRule),
+        Box::new(ArchitectureRule),
+        Box::new(SplitNamesRule),
+        Box::new(SplitDependsRule),
+        Box::new(DescriptionEmptyRule),
+        Box::new(DescriptionLengthRule),
+        Box::new(DescriptionNameRule),
+        Box::new(DescriptionSpellingRule),
+        Box::new(UrlSchemesRule),
+        Box::new(DeadUrlsRule),
+        Box::new(InsecureUrlsRule),
+        Box::new(ChecksumsRule),
+        Box::new(UnusedSourcesRule),
+        Box::new(UndeclaredSourcesRule),
+        Box::new(SideEffectsRule),
+        Box::new(GroupsRule),
+      ],
+      network: None,
+    }
+  }
+}
+
+impl Linter {
+  /// Lets rules reach servers with `options`, for `--network`.
+  pub fn with_network(mut self, options: FetchOptions) -> Self {
+    self.network = Some(options);
+    self
+  }
+
+  /// Adds a rule, to be configured like the built-in ones.
+  #[allow(unused)]
+  pub fn register(&mut self, rule: Box<dyn LintRule>) {
+    self.rules.push(rule);
+  }
+
+  /// Runs the rules on the build script `script` evaluated to `source` and
+  /// `trace`, in a tree with `groups` and as configured by `policy`,
+  /// prefixing findings with their rule.
+  pub fn run(
+    &self,
+    script: &str,
+    source: &Source,
+    trace: &StepTrace,
+    groups: Option<&Groups>,
+    policy: &LintPolicy,
+  ) -> anyhow::Result<Vec<Finding>> {
+    if let Some(id) =
+      (policy.rules.keys()).find(|x| self.rules.iter().all(|rule| rule.id() != &***x))
+    {
+      bail!("unknown lint rule `{id}` in the policy");
+    }
+    let cx = LintContext {
+      script,
+      source,
+      trace,
+      policy,
+      groups,
+      network: self.network.as_ref(),
+    };
+    let mut findings = Vec::new();
+    for rule in &self.rules {
+      let level = policy.rules.get(rule.id()).copied();
+      if level == Some(RuleLevel::Off) {
+        continue;
+      }
+      for mut finding in rule.check(&cx)? {
+        finding.severity = match level {
+          Some(RuleLevel::Warning) => Severity::Warning,
+          Some(RuleLevel::Error) => Severity::Error,
+          _ => finding.severity,
+        };
+        finding.message = format!("{}: {}", rule.id(), finding.message);
+        findings.push(finding);
+      }
+    }
+    Ok(findings)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_check_shell() {
+    let lines = |x: &str| {
+      (check_shell(x).into_iter())
+        .map(|(line, _, message)| format!("{line}: {message}"))
+        .collect::<Vec<_>>()
+    };
+    assert_eq!(
+      lines("cd $dir\nrm -rf \"$dir\"/x ${a}\n"),
+      [
+        "1: unquoted `$dir` is subject to word splitting and globbing",
+        "2: unquoted `$a` is subject to word splitting and globbing"
+      ]
+    );
+    assert_eq!(
+      lines("x=$y; [[ -n $x ]]\ncase $x in *) ;; esac\necho $((1 + $n)) \"$(ls $d)\"\n"),
+      ["3: unquoted `$d` is subject to word splitting and globbing"]
+    );
+    assert!(lines("cat <<EOF\nit's $x\nEOF\necho 'a\n b' # don't\n").is_empty());
+    assert_eq!(lines("echo 'b\n"), ["1: unterminated single quote"]);
+    assert_eq!(lines("echo \"a\n"), ["2: unterminated double quote"]);
+    assert!(lines("echo \"${x:-é}\" \"$((é + 1))\" 'b'\n").is_empty());
+  }
+
+  #[test]
+  fn test_check_split_packages() {
+    let script = r#"#{
+      name: "foo",
+      description: "Frobnicator",
+      version: "1",
+      architecture: ["any"],
+      packages: [
+        #{ name: "foo" },
+        #{ name: "foo-dev" },
+        #{ name: "foo-dbg", depends: ["foo"] },
+        #{ name: "foo-docs" },
+        #{ name: "bar-locale" },
+      ],
+    }"#;
+    let mut value = rhai::Engine::new().eval(script).unwrap();
+    let source = Source::from_dynamic(&mut value).unwrap();
+    let linter = Linter::default();
+    let lines = |policy: &str| {
+      let policy: LintPolicy = serde_json::from_str(policy).unwrap();
+      (linter
+        .run(script, &source, &StepTrace::default(), None, &policy)
+        .unwrap()
+        .into_iter())
+      .map(|x| format!("{}: {:?}: {}", x.line.unwrap(), x.severity, x.message))
+      .collect::<Vec<_>>()
+    };
+    assert_eq!(
+      lines("{}"),
+      [
+        "11: Warning: split-package-names: split package `bar-locale` has no `bar` to go with it",
+        "10: Warning: split-package-names: split package `foo-docs` should be named `foo-doc`",
+        "8: Error: split-package-depends: split package `foo-dev` should depend on `foo`",
+      ]
+    );
+    assert!(lines(r#"{ "split_packages": [{ "suffix": "-docs" }] }"#).is_empty());
+    assert_eq!(
+      lines(r#"{ "rules": { "split-package-names": "off", "split-package-depends": "warning" } }"#),
+      ["8: Warning: split-package-depends: split package `foo-dev` should depend on `foo`"]
+    );
+    let policy = serde_json::from_str(r#"{ "rules": { "nope": "off" } }"#).unwrap();
+    assert!(linter
+      .run(script, &source, &StepTrace::default(), None, &policy)
+      .is_err());
+  }
+
+  #[test]
+  fn test_descriptions() {
+    let script = r#"#{
+      name: "foo",
+      description: "Foo, a tool for GTK frobnication",
+      version: "1",
+      architecture: ["any"],
+      packages: [
+        #{ name: "foo" },
+        #{ name: "foo-doc", description: "" },
+      ],
+    }"#;
+    let mut value = rhai::Engine::new().eval(script).unwrap();
+    let source = Source::from_dynamic(&mut value).unwrap();
+    let mut policy = LintPolicy::default();
+    policy.description.max_length = 20;
+    policy.description.words = Some(["a", "tool", "for"].map(Into::into).into());
+    let messages = (Linter::default()
+      .run(script, &source, &StepTrace::default(), None, &policy)
+      .unwrap()
+      .into_iter())
+    .map(|x| x.message)
+    .collect::<Vec<_>>();
+    assert_eq!(
+      messages,
+      [
+        "description-empty: `foo-doc` has an empty description",
+        "description-length: description of `foo` is 32 characters long, over 20",
+        "description-name: description of `foo` starts with the package name",
+        "description-spelling: description of `foo` has unknown words: frobnication",
+      ]
+    );
+  }
+
+  #[test]
+  fn test_source_usage() {
+    let script = r#"#{
+      name: "foo",
+      description: "Frobnicator",
+      version: "1",
+      architecture: ["any"],
+      source: [
+        #{ url: "https://example.org/foo-1.0.tar.gz" },
+        #{ url: "https://example.org/fix.patch" },
+        #{ url: "https://example.org/stale.patch" },
+        #{ url: "https://example.org/COPYING" },
+      ],
+    }"#;
+    let mut value = rhai::Engine::new().eval(script).unwrap();
+    let source = Source::from_dynamic(&mut value).unwrap();
+    let mut trace = StepTrace::default();
+    let prepare = "cd foo-1.0\npatch -Np1 -i ../fix.patch\npatch -Np1 < ../gone.patch\n";
+    trace.snippets.push(("prepare".into(), prepare.into()));
+    trace
+      .snippets
+      .push(("build".into(), "make -C foo-1.0.old".into()));
+    trace.calls.installed.insert("COPYING".into());
+    trace.calls.srcdirs.insert("bar".into());
+    let mut policy = LintPolicy::default();
+    policy.rules.insert("checksums".into(), RuleLevel::Off);
+    let messages = (Linter::default()
+      .run(script, &source, &trace, None, &policy)
+      .unwrap())
+    .into_iter()
+    .map(|x| x.message)
+    .collect::<Vec<_>>();
+    assert_eq!(
+      messages,
+      [
+        "unused-sources: source `stale.patch` is not used by any step",
+        "undeclared-sources: `srcdir_of` is asked for `bar`, which is not a source",
+        "undeclared-sources: `prepare` refers to `gone.patch`, which is not a source",
+      ]
+    );
+    assert!(!mentions("make -C foo-1.0.old", "foo-1.0"));
+  }
+
+  #[test]
+  fn test_fields() {
+    let script = r#"#{
+      name: "foo",
+      description: "Frobnicator",
+      version: "1",
+      architecture: ["any", "x86_64"],
+      homepage: "ftp://example.org/foo",
+      depends: ["bar", "bar"],
+      build_depend: ["baz"],
+      source: [#{ url: "https://example.org/foo.tar.gz" }],
+      packages: [
+        #{ name: "foo", provides: ["libfoo"], conflicts: ["libfoo"] },
+        #{ name: "foo-doc", architecture: ["all", "x86_64"], extras: #{} },
+      ],
+    }"#;
+    let mut value = rhai::Engine::new().eval(script).unwrap();
+    let source = Source::from_dynamic(&mut value).unwrap();
+    let mut policy = LintPolicy::default();
+    policy.rules.insert("unused-sources".into(), RuleLevel::Off);
+    let messages = (Linter::default()
+      .run(script, &source, &StepTrace::default(), None, &policy)
+      .unwrap()
+      .into_iter())
+    .map(|x| format!("{}: {}", x.line.unwrap(), x.message))
+    .collect::<Vec<_>>();
+    assert_eq!(
+      messages,
+      [
+        "8: unknown-fields: the script has a field `build_depend` that is not used, did you mean `build_depends`?",
+        "12: unknown-fields: package `foo-doc` has a field `extras` that is not used, did you mean `extra`?",
+        "7: duplicate-entries: the script lists `bar` twice in `depends`",
+        "2: self-depends: `foo` conflicts with itself through `libfoo`",
+        "5: architecture: the script lists `any` along with `x86_64`",
+        "12: architecture: package `foo-doc` lists `all` along with `x86_64`",
+        "6: url-schemes: `homepage` of `foo` has an unexpected scheme: ftp://example.org/foo",
+        "9: checksums: source `foo.tar.gz` has no checksum, see `ewe checksum`, or set `skip_checksum`",
+      ]
+    );
+    assert_eq!(edit_distance("kitten", "sitting"), 3);
+  }
+}
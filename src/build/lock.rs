@@ -0,0 +1,112 @@
+use crate::types::{Hash, SourceLocation};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use url::Url;
+
+/// What was actually fetched for a source file.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SourceRecord {
+  #[serde(flatten)]
+  pub location: SourceLocation,
+
+  /// Every URL that answered with a redirect, in order.
+  #[serde(default, skip_serializing_if = "Vec::is_empty")]
+  pub redirects: Vec<Url>,
+
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub final_url: Option<Url>,
+
+  /// Size of the fetched file in bytes.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub size: Option<u64>,
+
+  /// Digest of inline content, or of a download without checksums in the
+  /// script, which later downloads have to match.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub sha256: Option<Hash>,
+
+  /// Directory the source was extracted into, relative to the source
+  /// directory.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub extracted: Option<Box<Path>>,
+
+  /// Digest of the extracted tree, see `tree_digest`.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub tree_sha256: Option<Hash>,
+
+  /// Commit the `rev` of a Git source was at.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub commit: Option<Box<str>>,
+
+  /// When the source was released, in seconds since the Unix epoch: the
+  /// newest modification time in a tar archive, or the commit time of a Git
+  /// source.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub released: Option<u64>,
+}
+
+impl SourceRecord {
+  pub fn new(location: SourceLocation) -> Self {
+    Self {
+      location,
+      redirects: Vec::new(),
+      final_url: None,
+      size: None,
+      sha256: None,
+      extracted: None,
+      tree_sha256: None,
+      commit: None,
+      released: None,
+    }
+  }
+
+  pub fn final_host(&self) -> Option<&str> {
+    self.final_url.as_ref()?.host_str()
+  }
+}
+
+/// Records of fetched sources, kept next to the build script.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Lockfile {
+  #[serde(default)]
+  pub sources: BTreeMap<Box<str>, SourceRecord>,
+
+  /// `SOURCE_DATE_EPOCH` of builds: the one the script pins, or else when
+  /// the newest of its sources was released.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub source_date_epoch: Option<u64>,
+}
+
+impl Lockfile {
+  /// Returns the record of source `name`, if it was fetched from the same
+  /// location.
+  pub fn get(&self, name: &str, location: &SourceLocation) -> Option<&SourceRecord> {
+    (self.sources.get(name)).filter(|x| x.location == *location)
+  }
+
+  pub fn path_for(script: &Path) -> PathBuf {
+    let mut name = script.file_name().unwrap_or_default().to_os_string();
+    name.push(".lock");
+    script.with_file_name(name)
+  }
+
+  /// Reads the lockfile at `path`, returning an empty one if it does not
+  /// exist yet.
+  pub fn load(path: &Path) -> anyhow::Result<Self> {
+    match File::open(path) {
+      Ok(f) => Ok(serde_json::from_reader(BufReader::new(f))?),
+      Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Self::default()),
+      Err(e) => Err(e.into()),
+    }
+  }
+
+  pub fn save(&self, path: &Path) -> anyhow::Result<()> {
+    let mut f = BufWriter::new(File::create(path)?);
+    serde_json::to_writer_pretty(&mut f, self)?;
+    f.write_all(b"\n")?;
+    Ok(())
+  }
+}
@@ -0,0 +1,59 @@
+use super::lock::SourceRecord;
+use crate::types::PackageName;
+use crate::version::PackageVersion;
+use serde::{Deserialize, Serialize};
+use smartstring::{LazyCompact, SmartString};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+/// Summary of a single build, written next to the produced packages.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BuildManifest {
+  pub name: PackageName,
+  pub version: PackageVersion,
+  pub architecture: SmartString<LazyCompact>,
+
+  /// Identifies the inputs of the build in the build cache.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub build_key: Option<String>,
+
+  #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+  pub sources: BTreeMap<Box<str>, SourceRecord>,
+
+  /// File names of the produced package archives.
+  #[serde(default)]
+  pub packages: Vec<Box<str>>,
+
+  /// Packages in the repository linking against sonames the build dropped.
+  #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+  pub rebuilds: BTreeMap<PackageName, BTreeSet<Box<str>>>,
+
+  /// Outcome of the script's `test` against the packages, if run.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub smoke_test: Option<SmokeTest>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SmokeTest {
+  Passed,
+  Failed,
+}
+
+impl BuildManifest {
+  pub fn file_name(&self) -> String {
+    format!(
+      "{}_{}_{}.manifest.json",
+      self.name, self.version, self.architecture
+    )
+  }
+
+  pub fn save(&self, dir: &Path) -> anyhow::Result<()> {
+    let mut f = BufWriter::new(File::create(dir.join(self.file_name()))?);
+    serde_json::to_writer_pretty(&mut f, self)?;
+    f.write_all(b"\n")?;
+    Ok(())
+  }
+}
@@ -0,0 +1,162 @@
+use super::build_cache::BuildCacheOptions;
+use super::fetch::FetchOptions;
+use super::manifest::BuildManifest;
+use super::qemu::{QemuOptions, QEMU_HOST};
+use super::remote::{self, Builder, RemoteOptions};
+use super::{build_local, host_arch, load_source, BuildOptions, Limits};
+use crate::types::PackageName;
+use crate::version::PackageVersion;
+use crate::{segment_info, warning};
+use anyhow::bail;
+use clap::Args;
+use console::style;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+/// Options for building one script for several architectures.
+#[derive(Debug, Clone, Args)]
+pub struct MatrixOptions {
+  /// Architectures to build for, e.g. `x86_64,aarch64`. Those not in the
+  /// script's architecture list are skipped, and those without a builder are
+  /// cross-built on this machine
+  #[arg(long, value_name = "ARCH", value_delimiter = ',')]
+  pub arch: Vec<String>,
+
+  /// Builder for an architecture other than this machine's, as `ARCH=HOST`
+  /// for an SSH host, `ARCH=qemu` for emulation or `ARCH=local` for cross
+  /// builds; may be repeated
+  #[arg(long = "builder", value_name = "ARCH=HOST")]
+  pub builders: Vec<Builder>,
+
+  #[command(flatten)]
+  pub qemu: QemuOptions,
+}
+
+/// Manifests of the builds of one script for each architecture.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MatrixManifest {
+  pub name: PackageName,
+  pub version: PackageVersion,
+  pub builds: BTreeMap<Box<str>, BuildManifest>,
+}
+
+impl MatrixManifest {
+  pub fn file_name(&self) -> String {
+    format!("{}_{}.manifest.json", self.name, self.version)
+  }
+
+  pub fn save(&self, dir: &Path) -> anyhow::Result<()> {
+    let mut f = BufWriter::new(File::create(dir.join(self.file_name()))?);
+    serde_json::to_writer_pretty(&mut f, self)?;
+    f.write_all(b"\n")?;
+    Ok(())
+  }
+}
+
+/// Builds the script at `path` for each of the requested architectures, on
+/// this machine or their builder, and writes a combined manifest into
+/// `output`. Returns the manifests of the builds that succeeded.
+#[allow(clippy::too_many_arguments)]
+pub fn run(
+  path: PathBuf,
+  limits: Limits,
+  fetch: &FetchOptions,
+  options: BuildOptions,
+  cache: &BuildCacheOptions,
+  remote: &RemoteOptions,
+  matrix: MatrixOptions,
+  output: &Path,
+) -> anyhow::Result<Vec<BuildManifest>> {
+  let source = load_source(&path, limits)?.info;
+  let host = host_arch()?;
+  let arches = if source.architecture.contains_all() {
+    // Architecture-independent packages are the same everywhere.
+    vec![host.clone()]
+  } else {
+    (matrix.arch.iter())
+      .filter(|x| {
+        let contained = source.architecture.contains(x);
+        if !contained {
+          warning!("skipping {x}, not in the architectures of {}", source.name);
+        }
+        contained
+      })
+      .cloned()
+      .collect()
+  };
+
+  let mut combined = MatrixManifest {
+    name: source.name.clone(),
+    version: source.version.clone(),
+    builds: BTreeMap::new(),
+  };
+  let mut failed = 0;
+  for arch in &arches {
+    segment_info!("Building for", "{arch}");
+    let builder = (matrix.builders.iter()).find(|x| x.arch == *arch);
+    let result = match builder.and_then(|x| x.host.as_deref()) {
+      _ if *arch == host => build_local(
+        path.clone(),
+        limits,
+        fetch,
+        options.clone(),
+        cache,
+        arch,
+        None,
+        output,
+      ),
+      Some(QEMU_HOST) => build_local(
+        path.clone(),
+        limits,
+        fetch,
+        options.clone(),
+        cache,
+        arch,
+        Some(&matrix.qemu),
+        output,
+      ),
+      Some(host) => remote::build(
+        host,
+        remote,
+        path.clone(),
+        limits,
+        fetch,
+        &options,
+        cache,
+        output,
+      ),
+      None => {
+        segment_info!("Cross-building", "for {arch} on this machine");
+        build_local(
+          path.clone(),
+          limits,
+          fetch,
+          options.clone(),
+          cache,
+          arch,
+          None,
+          output,
+        )
+      }
+    };
+    match result {
+      Ok(manifest) => {
+        combined
+          .builds
+          .insert(manifest.architecture.as_str().into(), manifest);
+      }
+      Err(e) => {
+        failed += 1;
+        eprintln!("{} {arch}: {e}", style("error:").red().bold());
+      }
+    }
+  }
+  combined.save(output)?;
+  if failed > 0 {
+    bail!("{failed} of {} architecture(s) failed", arches.len());
+  }
+  Ok(combined.builds.into_values().collect())
+}
@@ -0,0 +1,103 @@
+use super::service::Services;
+use crate::types::PackageInfo;
+use anyhow::{bail, Context};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use smartstring::{LazyCompact, SmartString};
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::Read;
+
+/// Version of the `metadata.json` schema written by this ewepkg. Adding
+/// optional fields keeps the version, as readers ignore fields they do not
+/// know; it only changes when existing fields change shape or meaning, along
+/// with an upgrade step in [`upgrade`].
+pub const SCHEMA_VERSION: u64 = 1;
+
+/// Contents of `metadata.json` in package archives.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PackageMeta {
+  /// Missing in archives from before versioning, which are version 0.
+  #[serde(default)]
+  pub schema_version: u64,
+  pub architecture: SmartString<LazyCompact>,
+  pub info: PackageInfo,
+  /// Tags of a variant build, like `asan`.
+  #[serde(default, skip_serializing_if = "Vec::is_empty")]
+  pub variant: Vec<Box<str>>,
+  #[serde(default, skip_serializing_if = "Services::is_empty")]
+  pub(super) services: Services,
+  /// System caches to refresh after installing the package.
+  #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+  pub trigger_hints: BTreeSet<Box<str>>,
+  /// Kernel releases the packaged modules are built for.
+  #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+  pub kernel_releases: BTreeSet<Box<str>>,
+  /// From the MAINTAINERS file of the package tree.
+  #[serde(default, skip_serializing_if = "Vec::is_empty")]
+  pub(super) maintainers: Vec<Box<str>>,
+  /// Sonames of the shared libraries in the package.
+  #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+  pub sonames: BTreeSet<Box<str>>,
+  /// Sonames the package links against without providing them itself.
+  #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+  pub needed_sonames: BTreeSet<Box<str>>,
+  /// Custom key/values declared by the build script.
+  #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+  pub extra: BTreeMap<Box<str>, Box<str>>,
+}
+
+/// Brings `metadata` of schema `version` to the next one.
+fn upgrade(version: u64, metadata: &mut Value) {
+  match version {
+    // Version 1 only started recording the version.
+    0 => {}
+    _ => unreachable!("no upgrade from schema version {version}"),
+  }
+  metadata["schema_version"] = (version + 1).into();
+}
+
+impl PackageMeta {
+  /// Reads `metadata.json` of any schema version up to ours.
+  pub fn from_reader(reader: impl Read) -> anyhow::Result<Self> {
+    let mut metadata: Value = serde_json::from_reader(reader)?;
+    let version = match metadata.get("schema_version") {
+      None => 0,
+      Some(x) => x.as_u64().context("invalid schema_version")?,
+    };
+    if version > SCHEMA_VERSION {
+      bail!("metadata schema version {version} is newer than {SCHEMA_VERSION}, update ewepkg to read it");
+    }
+    for version in version..SCHEMA_VERSION {
+      upgrade(version, &mut metadata);
+    }
+    Ok(serde_json::from_value(metadata)?)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_schema_versions() {
+    let info = r#""info": { "name": "foo", "description": "Foo", "version": "1.0", "architecture": ["any"] }"#;
+    let v0 = format!(r#"{{ "architecture": "x86_64", {info} }}"#);
+    let meta = PackageMeta::from_reader(v0.as_bytes()).unwrap();
+    assert_eq!(meta.schema_version, SCHEMA_VERSION);
+    assert_eq!(&*meta.info.name, "foo");
+
+    let v1 = format!(
+      r#"{{ "schema_version": 1, "architecture": "x86_64", {info},
+        "sonames": ["libfoo.so.1"], "added_later": true }}"#
+    );
+    let meta = PackageMeta::from_reader(v1.as_bytes()).unwrap();
+    assert!(meta.sonames.contains("libfoo.so.1"));
+
+    let written = serde_json::to_vec(&meta).unwrap();
+    let meta = PackageMeta::from_reader(&*written).unwrap();
+    assert_eq!(meta.schema_version, SCHEMA_VERSION);
+
+    let future = format!(r#"{{ "schema_version": 999, "architecture": "x86_64", {info} }}"#);
+    assert!(PackageMeta::from_reader(future.as_bytes()).is_err());
+  }
+}
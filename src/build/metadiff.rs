@@ -0,0 +1,145 @@
+use super::types::Source;
+use serde_json::Value;
+use std::collections::{BTreeMap, BTreeSet};
+
+fn show(value: &Value) -> String {
+  match value {
+    Value::String(x) => x.clone(),
+    x => x.to_string(),
+  }
+}
+
+fn is_scalar(value: &Value) -> bool {
+  !value.is_array() && !value.is_object()
+}
+
+/// Items of an array of objects keyed by their `name`, or by index if they
+/// have none.
+fn keyed(items: &[Value]) -> BTreeMap<String, &Value> {
+  (items.iter().enumerate())
+    .map(|(i, x)| match x.get("name").and_then(Value::as_str) {
+      Some(name) => (name.to_string(), x),
+      None => (i.to_string(), x),
+    })
+    .collect()
+}
+
+fn join(path: &str, key: &str) -> String {
+  if path.is_empty() {
+    key.into()
+  } else {
+    format!("{path}.{key}")
+  }
+}
+
+/// Appends a line per difference between `old` and `new` to `out`.
+fn diff(path: &str, old: &Value, new: &Value, out: &mut Vec<String>) {
+  match (old, new) {
+    (Value::Object(old), Value::Object(new)) => {
+      for key in old.keys().chain(new.keys()).collect::<BTreeSet<_>>() {
+        let path = join(path, key);
+        match (old.get(key), new.get(key)) {
+          (Some(old), Some(new)) => diff(&path, old, new, out),
+          (Some(old), None) => out.push(format!("{path}: removed {}", show(old))),
+          (None, Some(new)) => out.push(format!("{path}: added {}", show(new))),
+          (None, None) => unreachable!(),
+        }
+      }
+    }
+    (Value::Array(old), Value::Array(new)) if old.iter().chain(new).all(is_scalar) => {
+      let old_set = old.iter().map(show).collect::<BTreeSet<_>>();
+      let new_set = new.iter().map(show).collect::<BTreeSet<_>>();
+      for x in old_set.difference(&new_set) {
+        out.push(format!("{path}: - {x}"));
+      }
+      for x in new_set.difference(&old_set) {
+        out.push(format!("{path}: + {x}"));
+      }
+    }
+    (Value::Array(old), Value::Array(new)) => {
+      let (old, new) = (keyed(old), keyed(new));
+      for key in old.keys().chain(new.keys()).collect::<BTreeSet<_>>() {
+        let path = format!("{path}[{key}]");
+        match (old.get(key), new.get(key)) {
+          (Some(old), Some(new)) => diff(&path, old, new, out),
+          (Some(_), None) => out.push(format!("{path}: removed")),
+          (None, Some(_)) => out.push(format!("{path}: added")),
+          (None, None) => unreachable!(),
+        }
+      }
+    }
+    (old, new) if old != new => out.push(format!("{path}: {} -> {}", show(old), show(new))),
+    _ => {}
+  }
+}
+
+/// Field-level differences between two evaluated build scripts, one per line.
+/// Changes to packages that only follow the same change of the source are
+/// left out.
+pub fn metadiff(old: &Source, new: &Source) -> serde_json::Result<Vec<String>> {
+  let mut lines = Vec::new();
+  diff(
+    "",
+    &serde_json::to_value(&old.info)?,
+    &serde_json::to_value(&new.info)?,
+    &mut lines,
+  );
+  let inherited = lines.iter().cloned().collect::<BTreeSet<_>>();
+
+  let packages = |x: &Source| {
+    (x.packages.iter())
+      .map(|x| Ok((x.info.name.to_string(), serde_json::to_value(&x.info)?)))
+      .collect::<serde_json::Result<BTreeMap<_, _>>>()
+  };
+  let (old, new) = (packages(old)?, packages(new)?);
+  for name in old.keys().chain(new.keys()).collect::<BTreeSet<_>>() {
+    let path = format!("packages.{name}");
+    match (old.get(name), new.get(name)) {
+      (Some(old), Some(new)) => {
+        let mut package_lines = Vec::new();
+        diff("", old, new, &mut package_lines);
+        (package_lines.into_iter())
+          .filter(|x| !inherited.contains(x))
+          .for_each(|x| lines.push(format!("{path}.{x}")));
+      }
+      (Some(_), None) => lines.push(format!("{path}: removed")),
+      (None, Some(_)) => lines.push(format!("{path}: added")),
+      (None, None) => unreachable!(),
+    }
+  }
+  Ok(lines)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use serde_json::json;
+
+  #[test]
+  fn test_diff() {
+    let old = json!({
+      "version": "1.0", "depends": ["a", "b"], "homepage": "https://a",
+      "source": [{ "url": "https://a/1.0.tgz" }],
+      "optional_depends": [{ "name": "x" }, { "name": "y", "description": "old" }],
+    });
+    let new = json!({
+      "version": "1.1", "depends": ["b", "c"],
+      "source": [{ "url": "https://a/1.1.tgz" }],
+      "optional_depends": [{ "name": "y", "description": "new" }],
+    });
+    let mut lines = Vec::new();
+    diff("", &old, &new, &mut lines);
+    assert_eq!(
+      lines,
+      [
+        "depends: - a",
+        "depends: + c",
+        "homepage: removed https://a",
+        "optional_depends[x]: removed",
+        "optional_depends[y].description: old -> new",
+        "source[0].url: https://a/1.0.tgz -> https://a/1.1.tgz",
+        "version: 1.0 -> 1.1",
+      ]
+    );
+  }
+}
@@ -1,38 +1,1020 @@
+mod build_cache;
+mod cache;
+mod clean;
+mod coredump;
+mod desktop;
+mod devel;
+mod elf;
 mod engine;
+mod exec;
 mod fetch;
+mod git;
+mod hashing;
+mod kmod;
+mod lint;
+mod lock;
+mod manifest;
+mod matrix;
+mod meta;
+mod metadiff;
+mod qa;
+mod qemu;
+mod remote;
+mod scaffold;
 mod script;
+mod script_test;
+mod service;
+mod tarball;
 mod types;
+mod vuln;
+mod writer;
 
-use crate::segment_info;
-use crate::types::PackageInfo;
-use anyhow::bail;
-use script::{BuildScript, PackScript};
+pub use build_cache::BuildCacheOptions;
+pub use clean::{clean, CleanOptions};
+pub use engine::Limits;
+pub use fetch::{check_urls, measure_urls, FetchOptions};
+pub use hashing::hash_file;
+pub use manifest::BuildManifest;
+pub use matrix::MatrixOptions;
+pub use meta::PackageMeta;
+pub use qemu::{QemuOptions, QEMU_HOST};
+pub use remote::{serve_remote, Builder, RemoteOptions};
+pub use scaffold::new_script;
+pub use script::{host_arch, load_source};
+
+use crate::repo::{self, RepoIndex};
+use crate::stats::Stats;
+use crate::tree::Groups;
+use crate::types::{ChecksumKind, PackageInfo, SourceInfo, SourceLocation};
+use crate::util::{config_dir, confirm, is_interactive, par_map};
+use crate::{segment_info, warning};
+use anyhow::{bail, Context};
+use build_cache::BuildCache;
+use clap::Args;
+use console::style;
+use exec::DiskQuota;
+use fetch::compute_checksums;
+use lock::Lockfile;
+use manifest::SmokeTest;
+use qa::Severity;
+use qemu::Sysroot;
+use script::{archive_name, load_source_for, BuildScript, PackScript};
 use serde::{Deserialize, Serialize};
-use smartstring::{LazyCompact, SmartString};
-use std::path::PathBuf;
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::fmt::Display;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use types::Source;
+
+/// Options affecting how packages are built.
+#[derive(Debug, Clone, Default, Args)]
+pub struct BuildOptions {
+  /// Pass -ffile-prefix-map to C and C++ compilers, so that binaries do not
+  /// embed the temporary build directory
+  #[arg(long)]
+  pub map_build_paths: bool,
+
+  /// Build with AddressSanitizer and UndefinedBehaviorSanitizer for
+  /// debugging, into packages tagged `+asan`
+  #[arg(long)]
+  pub asan: bool,
+
+  /// Build a reduced-feature stage1 variant, for scripts with the
+  /// `bootstrap` option, into packages tagged `+bootstrap` that repositories
+  /// leave out
+  #[arg(long)]
+  pub bootstrap: bool,
+
+  /// Unpack the packages after building and run the script's `test` against
+  /// them, recording whether it passed in the manifest
+  #[arg(long)]
+  pub smoke_test: bool,
+
+  /// Warn about packaged copies of libraries with known vulnerabilities, as
+  /// listed in this OSV database (a JSON file or directory of them)
+  #[arg(long, value_name = "PATH")]
+  pub vuln_db: Option<PathBuf>,
+
+  /// Kill shell commands of the script that run longer than this many
+  /// seconds
+  #[arg(long, value_name = "SECS")]
+  pub command_timeout: Option<u64>,
+
+  /// Run shell commands of the script at this niceness, from -20 to 19,
+  /// instead of the one in priority.json
+  #[arg(long, value_name = "N", allow_hyphen_values = true)]
+  pub nice: Option<i32>,
+
+  /// Kill shell commands of the script once the build directory takes up
+  /// more than this many GiB
+  #[arg(long, value_name = "GIB")]
+  pub disk_quota: Option<u64>,
+
+  /// Keep the build directory after building, for inspection; `ewe clean`
+  /// removes it later
+  #[arg(long)]
+  pub keep_build: bool,
+
+  /// Developer build, exempt from the POLICY of the package tree, which may
+  /// forbid sources without checksums
+  #[arg(long)]
+  pub dev: bool,
+}
+
+impl BuildOptions {
+  /// Command line arguments reproducing these options.
+  pub fn to_args(&self) -> Vec<OsString> {
+    let mut args = Vec::new();
+    if self.map_build_paths {
+      args.push("--map-build-paths".into());
+    }
+    if self.asan {
+      args.push("--asan".into());
+    }
+    if self.bootstrap {
+      args.push("--bootstrap".into());
+    }
+    if self.smoke_test {
+      args.push("--smoke-test".into());
+    }
+    if let Some(path) = &self.vuln_db {
+      args.push("--vuln-db".into());
+      args.push(path.into());
+    }
+    if let Some(secs) = self.command_timeout {
+      args.push("--command-timeout".into());
+      args.push(secs.to_string().into());
+    }
+    if let Some(nice) = self.nice {
+      args.push(format!("--nice={nice}").into());
+    }
+    if let Some(gib) = self.disk_quota {
+      args.push("--disk-quota".into());
+      args.push(gib.to_string().into());
+    }
+    if self.keep_build {
+      args.push("--keep-build".into());
+    }
+    if self.dev {
+      args.push("--dev".into());
+    }
+    args
+  }
+
+  /// How much shell commands of the script may leave in `dirs`.
+  pub fn disk_quota(&self, dirs: &[&Path]) -> Option<DiskQuota> {
+    Some(DiskQuota {
+      dirs: dirs.iter().map(|x| x.to_path_buf()).collect(),
+      bytes: self.disk_quota?.saturating_mul(1 << 30),
+    })
+  }
+
+  /// Time shell commands of the script may take.
+  pub fn command_timeout(&self) -> Option<Duration> {
+    self.command_timeout.map(Duration::from_secs)
+  }
+
+  /// Options changing what gets built, as part of build keys.
+  pub fn profile(&self) -> String {
+    let mut profile = Vec::new();
+    if self.map_build_paths {
+      profile.push("map-build-paths");
+    }
+    profile.extend(self.variant());
+    profile.join(",")
+  }
+
+  /// Name of [`Self::profile`] in the paths of caches and build directories.
+  pub fn profile_dir(&self) -> String {
+    let profile = self.profile();
+    match profile.is_empty() {
+      true => "default".into(),
+      false => profile,
+    }
+  }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-struct PackageMeta {
-  architecture: SmartString<LazyCompact>,
-  info: PackageInfo,
+  /// Tags of packages built differently from their regular ones.
+  pub fn variant(&self) -> Vec<&'static str> {
+    let tags = [(self.bootstrap, "bootstrap"), (self.asan, "asan")];
+    tags.iter().filter(|x| x.0).map(|x| x.1).collect()
+  }
+
+  /// QA rules not applying to packages built with these options.
+  fn relaxed_rules(&self) -> &'static [&'static str] {
+    if self.asan {
+      // Debug info embeds the build directory.
+      &["provenance"]
+    } else {
+      &[]
+    }
+  }
 }
 
-pub fn run(path: PathBuf) -> anyhow::Result<()> {
-  let script = BuildScript::new(path)?;
+#[allow(clippy::too_many_arguments)]
+pub fn run(
+  path: PathBuf,
+  limits: Limits,
+  fetch: FetchOptions,
+  options: BuildOptions,
+  cache: BuildCacheOptions,
+  remote: RemoteOptions,
+  matrix: MatrixOptions,
+  output_dir: Option<PathBuf>,
+  repo_index: Option<PathBuf>,
+) -> anyhow::Result<()> {
+  let output = &BuildConfig::load()?.output_dir(output_dir)?;
+  if !matrix.arch.is_empty() {
+    let manifests = matrix::run(
+      path, limits, &fetch, options, &cache, &remote, matrix, output,
+    )?;
+    if let Some(index) = repo_index {
+      for mut manifest in manifests {
+        report_rebuilds(&mut manifest, &index, output)?;
+      }
+    }
+    return Ok(());
+  }
+  let mut manifest = match &remote.remote {
+    Some(host) => remote::build(
+      host, &remote, path, limits, &fetch, &options, &cache, output,
+    )?,
+    None => {
+      let arch = host_arch()?;
+      build_local(path, limits, &fetch, options, &cache, &arch, None, output)?
+    }
+  };
+  if let Some(index) = repo_index {
+    report_rebuilds(&mut manifest, &index, output)?;
+  }
+  Ok(())
+}
+
+/// Defaults of `ewe build`, read from `build.json` in the config directory:
+///
+/// ```json
+/// { "output_dir": "/srv/packages" }
+/// ```
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct BuildConfig {
+  /// Directory receiving packages and manifests, relative to the current
+  /// one unless absolute.
+  output_dir: Option<PathBuf>,
+}
+
+impl BuildConfig {
+  fn path() -> Option<PathBuf> {
+    Some(config_dir()?.join("build.json"))
+  }
+
+  fn load() -> anyhow::Result<Self> {
+    match Self::path().filter(|x| x.exists()) {
+      Some(path) => {
+        let f = fs::File::open(&path)?;
+        (serde_json::from_reader(io::BufReader::new(f)))
+          .with_context(|| format!("invalid build config {}", path.display()))
+      }
+      None => Ok(Self::default()),
+    }
+  }
+
+  /// `dir`, or else the configured output directory, or else the current
+  /// one, created if missing.
+  fn output_dir(self, dir: Option<PathBuf>) -> anyhow::Result<PathBuf> {
+    let dir = (dir.or(self.output_dir)).unwrap_or_else(|| ".".into());
+    fs::create_dir_all(&dir)
+      .with_context(|| format!("failed to create output directory {}", dir.display()))?;
+    Ok(dir)
+  }
+}
+
+/// Lists the packages in the repository at `index` that need rebuilding
+/// against the packages of `manifest`, and records them in it.
+fn report_rebuilds(
+  manifest: &mut BuildManifest,
+  index: &Path,
+  output: &Path,
+) -> anyhow::Result<()> {
+  let index = RepoIndex::load(index)?;
+  let built = (manifest.packages.iter())
+    .map(|x| repo::read_metadata(&output.join(&**x)))
+    .collect::<anyhow::Result<Vec<_>>>()?;
+  manifest.rebuilds = repo::rebuild_impact(&index, &built);
+  if !manifest.rebuilds.is_empty() {
+    segment_info!("Packages needing rebuilds:");
+    for (name, sonames) in &manifest.rebuilds {
+      let sonames = sonames.iter().map(|x| &**x).collect::<Vec<_>>();
+      println!("  {name} (links {})", sonames.join(", "));
+    }
+  }
+  manifest.save(output)
+}
+
+/// Builds the script at `path` on this machine, leaving the packages and the
+/// manifest in `output`.
+/// Packages are made for `arch`, which when foreign is cross-built with the
+/// toolchain the script picks, or with `emulate`, built under QEMU.
+#[allow(clippy::too_many_arguments)]
+fn build_local(
+  path: PathBuf,
+  limits: Limits,
+  fetch: &FetchOptions,
+  options: BuildOptions,
+  cache: &BuildCacheOptions,
+  arch: &str,
+  emulate: Option<&QemuOptions>,
+  output: &Path,
+) -> anyhow::Result<BuildManifest> {
+  let mut script = BuildScript::new(path, arch, limits, options)?;
   let source = &script.source().info;
   segment_info!("Starting building:", "{} {}", source.name, source.version);
-  script.prepare()?;
+  let cache = BuildCache::new(cache, &script.cache_namespace())?;
+  if let (Some(cache), Some(key)) = (&cache, script.build_key()?) {
+    if let Some(manifest) = cache.restore(&key, output, fetch)? {
+      segment_info!("Reusing cached build", "{key}");
+      manifest.save(output)?;
+      return Ok(manifest);
+    }
+  }
+  let start = Instant::now();
+  if let Some(qemu) = emulate {
+    let info = &script.source().info;
+    let packages = (info.build_depends.iter()).chain(&info.depends).cloned();
+    let sysroot = Sysroot::assemble(arch, qemu, packages.collect::<Vec<_>>())?;
+    script.use_sysroot(sysroot);
+  }
+  let lock = script.prepare(fetch)?;
   script.build()?;
-  script.pack()?;
+  script.pack(output)?;
+  let mut manifest = script.manifest(lock);
+  manifest.build_key = script.build_key()?;
+  manifest.smoke_test = script.smoke_test(output, &manifest.packages)?;
+  manifest.save(output)?;
+  if manifest.smoke_test == Some(SmokeTest::Failed) {
+    bail!("smoke test failed");
+  }
+  Stats::update(|stats| stats.record_build(&manifest.name, start.elapsed()));
+  if let (Some(cache), Some(key)) = (&cache, &manifest.build_key) {
+    cache.store(key, &manifest, output)?;
+  }
+  Ok(manifest)
+}
+
+pub fn run_package(
+  path: PathBuf,
+  source_dir: PathBuf,
+  arch: String,
+  output_dir: PathBuf,
+  limits: Limits,
+  options: BuildOptions,
+) -> anyhow::Result<()> {
+  if !is_root() {
+    bail!("not running in fakeroot/root environment");
+  }
+  let script = PackScript::new(path, &source_dir, arch, limits, &options)?;
+  script.pack(&output_dir)?;
   Ok(())
 }
 
-pub fn run_package(path: PathBuf, source_dir: PathBuf, arch: String) -> anyhow::Result<()> {
+#[cfg(unix)]
+fn is_root() -> bool {
   // SAFETY: only gets current user's UID
-  if unsafe { libc::getuid() } != 0 {
-    bail!("not running in fakeroot/root environment");
+  unsafe { libc::getuid() == 0 }
+}
+
+/// Packing relies on fakeroot, which only exists on Unix hosts.
+#[cfg(not(unix))]
+fn is_root() -> bool {
+  false
+}
+
+/// Collects the build scripts at `path`: the file itself, or every file named
+/// `ewebuild` below a directory.
+pub fn find_scripts(path: &Path, scripts: &mut Vec<PathBuf>) -> io::Result<()> {
+  if !path.is_dir() {
+    scripts.push(path.into());
+    return Ok(());
+  }
+  let mut entries = path.read_dir()?.collect::<io::Result<Vec<_>>>()?;
+  entries.sort_by_key(|x| x.file_name());
+  for entry in entries {
+    let name = entry.file_name();
+    if name.as_encoded_bytes().starts_with(b".") {
+      continue;
+    }
+    if entry.file_type()?.is_dir() {
+      find_scripts(&entry.path(), scripts)?;
+    } else if name == "ewebuild" {
+      scripts.push(entry.path());
+    }
+  }
+  Ok(())
+}
+
+/// Reports problems in every build script in `paths` without building them,
+/// evaluating up to `jobs` scripts at once.
+pub fn lint(
+  paths: Vec<PathBuf>,
+  limits: Limits,
+  jobs: usize,
+  network: Option<FetchOptions>,
+) -> anyhow::Result<()> {
+  let mut scripts = Vec::new();
+  for path in paths {
+    find_scripts(&path, &mut scripts)?;
+  }
+  let mut linter = lint::Linter::default();
+  if let Some(options) = network {
+    linter = linter.with_network(options);
+  }
+  // Scripts of a tree share its policy, wordlist included.
+  let policies = Mutex::new(BTreeMap::new());
+  let results = par_map(&scripts, jobs, |path| {
+    match script::trace_source(path, limits) {
+      Ok((source, trace)) => {
+        let file = lint::LintPolicy::locate(path)?;
+        let policy = {
+          let mut policies = policies.lock().unwrap();
+          match policies.get(&file) {
+            Some(policy) => Arc::clone(policy),
+            None => {
+              let policy = match &file {
+                Some(file) => Arc::new(lint::LintPolicy::load(file)?),
+                None => Arc::default(),
+              };
+              policies.insert(file, Arc::clone(&policy));
+              policy
+            }
+          }
+        };
+        let groups = Groups::find(path)?;
+        let script = fs::read_to_string(path)?;
+        linter.run(&script, &source, &trace, groups.as_ref(), &policy)
+      }
+      Err(e) => Ok(vec![lint::Finding {
+        line: None,
+        severity: Severity::Error,
+        message: format!("failed to evaluate: {e}"),
+      }]),
+    }
+  });
+  let mut errors = 0;
+  for (path, findings) in scripts.iter().zip(results) {
+    for finding in findings? {
+      let location = match finding.line {
+        Some(line) => format!("{}:{line}", path.display()),
+        None => path.display().to_string(),
+      };
+      match finding.severity {
+        Severity::Warning => {
+          warning!("{location}: {}", finding.message);
+        }
+        Severity::Error => {
+          errors += 1;
+          eprintln!(
+            "{} {location}: {}",
+            style("error:").red().bold(),
+            finding.message
+          );
+        }
+      }
+    }
+  }
+  if errors > 0 {
+    bail!(
+      "found {errors} error(s) in {} build script(s)",
+      scripts.len()
+    );
   }
-  let script = PackScript::new(path, &source_dir, arch)?;
-  script.pack()?;
   Ok(())
 }
+
+/// Runs the `test_*` functions of the shared script modules in `paths`,
+/// failing if any of them fails.
+pub fn script_test(paths: Vec<PathBuf>, limits: Limits) -> anyhow::Result<()> {
+  let (mut passed, mut failed) = (0, 0);
+  for path in paths {
+    segment_info!("Testing", "{}", path.display());
+    for (name, error) in script_test::run_tests(&path, limits)? {
+      match error {
+        None => {
+          passed += 1;
+          println!("{} {name}", style("ok").green().bold());
+        }
+        Some(e) => {
+          failed += 1;
+          println!("{} {name}: {e}", style("FAILED").red().bold());
+        }
+      }
+    }
+  }
+  segment_info!("Finished", "{passed} passed, {failed} failed");
+  if failed > 0 {
+    bail!("{failed} test(s) failed");
+  }
+  Ok(())
+}
+
+/// What `ewe info --json` prints about a build script.
+#[derive(Serialize)]
+struct ScriptInfo<'a> {
+  source: &'a SourceInfo,
+  packages: Vec<PackageOutput<'a>>,
+}
+
+#[derive(Serialize)]
+struct PackageOutput<'a> {
+  #[serde(flatten)]
+  info: &'a PackageInfo,
+  /// File name of the archive the package is built into.
+  archive: String,
+}
+
+/// Packages of `source` built for `arch`, with the names of their archives.
+fn outputs<'a>(source: &'a Source, arch: &str) -> Vec<(&'a PackageInfo, String)> {
+  let arch = match source.info.architecture.contains_all() {
+    true => "all",
+    false => arch,
+  };
+  (source.packages.iter())
+    .map(|x| {
+      (
+        &x.info,
+        archive_name(&x.info, arch, &BuildOptions::default()),
+      )
+    })
+    .collect()
+}
+
+/// Renders what `ewe info --json` prints about `source` built for `arch`.
+fn info_json(source: &Source, arch: &str) -> serde_json::Result<String> {
+  let info = ScriptInfo {
+    source: &source.info,
+    packages: (outputs(source, arch).into_iter())
+      .map(|(info, archive)| PackageOutput { info, archive })
+      .collect(),
+  };
+  serde_json::to_string_pretty(&info)
+}
+
+/// Prints `name` and the items of `values`, if there are any.
+fn print_field<T: Display>(name: &str, values: impl IntoIterator<Item = T>) {
+  let values = values
+    .into_iter()
+    .map(|x| x.to_string())
+    .collect::<Vec<_>>();
+  if !values.is_empty() {
+    println!("  {:<18}{}", format!("{name}:"), values.join(" "));
+  }
+}
+
+/// Prints the metadata of the build script at `path` as evaluated for
+/// `arch`, or this machine's, and the packages it builds, without building
+/// anything.
+pub fn info(path: PathBuf, arch: Option<String>, json: bool, limits: Limits) -> anyhow::Result<()> {
+  let arch = match arch {
+    Some(arch) => arch,
+    None => host_arch()?,
+  };
+  let script = load_source_for(&path, &arch, limits)?;
+  let source = &script.info;
+  if !source.architecture.contains_all() && !source.architecture.contains(&arch) {
+    warning!("{} is not built for {arch}", source.name);
+  }
+  if json {
+    println!("{}", info_json(&script, &arch)?);
+    return Ok(());
+  }
+
+  segment_info!("Source", "{} {}", source.name, source.version);
+  print_field("Description", [&source.description]);
+  print_field("Architecture", source.architecture.iter());
+  print_field("Homepage", &source.homepage);
+  print_field("License", &source.license);
+  print_field("Build depends", &source.build_depends);
+  print_field("Source epoch", source.source_date_epoch);
+  for file in &source.source {
+    print_field(
+      "Source",
+      [format!("{} ({})", file.file_name(), file.location)],
+    );
+  }
+  for (info, archive) in outputs(&script, &arch) {
+    segment_info!("Package", "{} {} ({archive})", info.name, info.version);
+    if info.description != source.description {
+      print_field("Description", [&info.description]);
+    }
+    print_field("Depends", &info.depends);
+    print_field(
+      "Optional depends",
+      info.optional_depends.iter().map(|x| &x.name),
+    );
+    print_field("Provides", &info.provides);
+    print_field("Conflicts", &info.conflicts);
+    print_field("Groups", &info.groups);
+    print_field("Keywords", &info.keywords);
+  }
+  Ok(())
+}
+
+/// Prints what changed in the metadata of a build script between two of its
+/// revisions.
+pub fn metadiff(old: PathBuf, new: PathBuf, limits: Limits) -> anyhow::Result<()> {
+  let old_source = load_source(&old, limits)?;
+  let new_source = load_source(&new, limits)?;
+  let lines = metadiff::metadiff(&old_source, &new_source)?;
+  if lines.is_empty() {
+    println!("no changes");
+  }
+  for line in lines {
+    println!("{line}");
+  }
+  Ok(())
+}
+
+/// Fetches and verifies the sources of every build script in `paths` for
+/// `arch`, or this machine's, into the cache without building anything, so
+/// that builds can then go without network access.
+pub fn fetch_sources(
+  paths: Vec<PathBuf>,
+  arch: Option<String>,
+  limits: Limits,
+  fetch: FetchOptions,
+) -> anyhow::Result<()> {
+  let mut scripts = Vec::new();
+  for path in paths {
+    find_scripts(&path, &mut scripts)?;
+  }
+  let arch = match arch {
+    Some(arch) => arch,
+    None => host_arch()?,
+  };
+  let mut failed = 0;
+  for path in &scripts {
+    segment_info!("Fetching sources of", "{}", path.display());
+    let result = BuildScript::new(path.clone(), &arch, limits, BuildOptions::default())
+      .and_then(|mut script| script.fetch(&fetch));
+    if let Err(e) = result {
+      failed += 1;
+      eprintln!("{} {}: {e}", style("error:").red().bold(), path.display());
+    }
+  }
+  if failed > 0 {
+    bail!("failed to fetch sources of {failed} build script(s)");
+  }
+  segment_info!("Fetched", "sources of {} build script(s)", scripts.len());
+  Ok(())
+}
+
+/// Downloads the sources of the build script at `path` and prints their
+/// checksums, or with `write` replaces the outdated ones in the script.
+/// Sources without checksums get `kind` ones, which are written into the
+/// script once confirmed at the terminal.
+pub fn checksum(
+  path: PathBuf,
+  write: bool,
+  kind: ChecksumKind,
+  limits: Limits,
+  fetch: FetchOptions,
+) -> anyhow::Result<()> {
+  let source = load_source(&path, limits)?;
+  let files = &source.info.source;
+  let mut kinds = (files.iter())
+    .flat_map(|x| x.checksums.keys().cloned())
+    .collect::<Vec<_>>();
+  kinds.push(kind.clone());
+  kinds.sort();
+  kinds.dedup();
+
+  segment_info!("Downloading sources of", "{}", path.display());
+  let computed = compute_checksums(files, &kinds, &fetch)?;
+  let interactive = is_interactive();
+  let mut changes = Vec::new();
+  let mut additions = Vec::new();
+  for (i, (file, computed)) in files.iter().zip(computed).enumerate() {
+    let Some(computed) = computed else {
+      continue;
+    };
+    let name = file.file_name();
+    if file.checksums.is_empty() {
+      let hex = hex::encode(&computed[&kind]);
+      if file.skip_checksum {
+        println!(
+          "{name}: {} \"{hex}\" {}",
+          kind.field(),
+          style("skipped").yellow()
+        );
+      } else if write && !interactive {
+        warning!(
+          "{name} has no checksum to replace, add `{}: \"{hex}\"`",
+          kind.field()
+        );
+      } else {
+        println!("{name}: {} \"{hex}\"", kind.field());
+        additions.push((i, format!("{}: \"{hex}\"", kind.field())));
+      }
+      continue;
+    }
+    for (kind, expected) in &file.checksums {
+      let hex = hex::encode(&computed[kind]);
+      if **expected == *computed[kind] {
+        println!("{name}: {} \"{hex}\" {}", kind.field(), style("ok").green());
+      } else {
+        println!(
+          "{name}: {} \"{hex}\" {}",
+          kind.field(),
+          style("changed").yellow().bold()
+        );
+        changes.push((hex::encode(expected), hex));
+      }
+    }
+  }
+
+  if write && !changes.is_empty() {
+    let text = fs::read_to_string(&path)?;
+    fs::write(&path, replace_checksums(&text, &changes)?)?;
+    segment_info!(
+      "Updated",
+      "{} checksum(s) in {}",
+      changes.len(),
+      path.display()
+    );
+  }
+  let question = format!("Add {} checksum(s) to {}?", additions.len(), path.display());
+  if interactive && !additions.is_empty() && confirm(&question)? {
+    let text = fs::read_to_string(&path)?;
+    fs::write(&path, add_checksums(&text, files.len(), &additions)?)?;
+    segment_info!(
+      "Added",
+      "{} checksum(s) to {}",
+      additions.len(),
+      path.display()
+    );
+  }
+  Ok(())
+}
+
+/// Adds each field, given with the index of its source, to the maps of the
+/// `count` sources written out in the `source` array of a build script.
+fn add_checksums(
+  text: &str,
+  count: usize,
+  additions: &[(usize, String)],
+) -> anyhow::Result<String> {
+  let maps = source_maps(text)
+    .filter(|x| x.len() == count)
+    .context("sources are not written out one by one in the script, add their checksums by hand")?;
+  let mut text = text.to_string();
+  for (i, field) in additions.iter().rev() {
+    let (start, end) = maps[*i];
+    let last = start + text[start..end].trim_end().len();
+    let comma = if text[..last].ends_with(',') { "" } else { "," };
+    if text[last..end].contains('\n') {
+      let line = text[..last].rfind('\n').map_or(0, |x| x + 1);
+      let indent = &text[line..line + text[line..].len() - text[line..].trim_start().len()];
+      let insert = format!("{comma}\n{indent}{field},");
+      text.insert_str(last, &insert);
+    } else {
+      text.insert_str(last, &format!("{comma} {field}"));
+    }
+  }
+  Ok(text)
+}
+
+/// Byte ranges of the maps in the `source` array of a build script, from
+/// after their `#{` to their `}`. Strings and comments are skipped, but not
+/// interpolations nesting strings.
+fn source_maps(text: &str) -> Option<Vec<(usize, usize)>> {
+  let field = (text.match_indices("source"))
+    .map(|(i, x)| i + x.len())
+    .find(|&i| {
+      let before = text[..i - "source".len()].chars().next_back();
+      !before.is_some_and(|x| x.is_alphanumeric() || x == '_')
+        && text[i..].trim_start().starts_with(':')
+    })?;
+  let rest = text[field..].trim_start()[1..].trim_start();
+  let open = text.len() - rest.len();
+  if !rest.starts_with('[') {
+    return None;
+  }
+  let bytes = text.as_bytes();
+  let mut maps = Vec::new();
+  let mut starts = Vec::new();
+  let mut depth = 0;
+  let mut i = open;
+  while i < bytes.len() {
+    match bytes[i] {
+      quote @ (b'"' | b'`' | b'\'') => {
+        i += 1;
+        while i < bytes.len() && bytes[i] != quote {
+          i += if bytes[i] == b'\\' { 2 } else { 1 };
+        }
+      }
+      b'/' if bytes.get(i + 1) == Some(&b'/') => {
+        i += text[i..].find('\n').unwrap_or(text.len() - i);
+      }
+      b'[' | b'(' | b'{' => {
+        depth += 1;
+        if depth == 2 && bytes[i] == b'{' && i > 0 && bytes[i - 1] == b'#' {
+          starts.push(i + 1);
+        }
+      }
+      b']' | b')' | b'}' => {
+        depth -= 1;
+        if depth == 1 && bytes[i] == b'}' {
+          maps.push((starts.pop()?, i));
+        }
+        if depth == 0 {
+          return (starts.is_empty()).then_some(maps);
+        }
+      }
+      _ => {}
+    }
+    i += 1;
+  }
+  None
+}
+
+/// Replaces each old checksum written in the text of a build script, in hex
+/// of either case, with its new value.
+fn replace_checksums(text: &str, changes: &[(String, String)]) -> anyhow::Result<String> {
+  let mut text = text.to_string();
+  for (old, new) in changes {
+    let lower = text.to_ascii_lowercase();
+    let is_hex = |i: usize| (lower.as_bytes().get(i)).is_some_and(u8::is_ascii_hexdigit);
+    let found = (lower.match_indices(&**old))
+      .map(|(i, _)| i)
+      .filter(|&i| (i == 0 || !is_hex(i - 1)) && !is_hex(i + old.len()))
+      .collect::<Vec<_>>();
+    if found.is_empty() {
+      bail!("checksum {old} is not written out in the script, update it by hand");
+    }
+    for i in found.into_iter().rev() {
+      text.replace_range(i..i + old.len(), new);
+    }
+  }
+  Ok(text)
+}
+
+/// Checks the source URLs of every build script in `paths` without
+/// downloading them, reporting dead links, size changes against the lockfile
+/// and permanent redirects.
+pub fn check_sources(
+  paths: Vec<PathBuf>,
+  limits: Limits,
+  fetch: FetchOptions,
+) -> anyhow::Result<()> {
+  let mut scripts = Vec::new();
+  for path in paths {
+    find_scripts(&path, &mut scripts)?;
+  }
+
+  // (script, file name, URL, size in lockfile)
+  let mut sources = Vec::new();
+  for path in &scripts {
+    let source = match load_source(path, limits) {
+      Ok(source) => source,
+      Err(e) => {
+        warning!("skipping {}: {e}", path.display());
+        continue;
+      }
+    };
+    let lock = Lockfile::load(&Lockfile::path_for(path))?;
+    for file in source.info.source {
+      if let SourceLocation::Http(url) = &file.location {
+        let size = (lock.get(file.file_name(), &file.location)).and_then(|x| x.size);
+        sources.push((path, file.file_name().to_string(), url.clone(), size));
+      }
+    }
+  }
+
+  let urls = sources.iter().map(|x| x.2.clone()).collect::<Vec<_>>();
+  let health = check_urls(&urls, &fetch)?;
+  let mut dead = 0;
+  let mut last_script = None;
+  for ((script, name, _, locked_size), health) in sources.iter().zip(health) {
+    if last_script != Some(script) {
+      segment_info!("Checking", "{}", script.display());
+      last_script = Some(script);
+    }
+    if let Some(error) = health.error {
+      dead += 1;
+      println!("{name}: {} ({error})", style("dead").red().bold());
+      continue;
+    }
+    let mut ok = true;
+    if let (Some(before), Some(now)) = (locked_size, health.size) {
+      if *before != now {
+        ok = false;
+        println!(
+          "{name}: {} from {before} to {now} bytes",
+          style("size changed").yellow().bold()
+        );
+      }
+    }
+    if let Some(url) = health.moved_to {
+      ok = false;
+      println!(
+        "{name}: {} to {url}",
+        style("moved permanently").yellow().bold()
+      );
+    }
+    if ok {
+      println!("{name}: {}", style("ok").green());
+    }
+  }
+  if dead > 0 {
+    bail!("{dead} of {} source URLs are dead", sources.len());
+  }
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_replace_checksums() {
+    let text = "sha256sum: \"AB12\", sha512sum: \"ab12ab12\"";
+    let changes = [("ab12".to_string(), "cd34".to_string())];
+    let replaced = replace_checksums(text, &changes).unwrap();
+    assert_eq!(replaced, "sha256sum: \"cd34\", sha512sum: \"ab12ab12\"");
+    assert!(replace_checksums(&replaced, &changes).is_err());
+  }
+
+  #[test]
+  fn test_info_for_arch() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("ewebuild");
+    let script = r#"#{
+      name: "foo",
+      description: "d",
+      version: "1",
+      architecture: ["x86_64", "aarch64", "riscv64"],
+      license: "MIT",
+      depends: [`lib-${arch}`],
+      pack: |dir| `touch ${dir}/foo`,
+    }"#;
+    fs::write(&path, script).unwrap();
+    let limits = Limits {
+      timeout: 30,
+      max_operations: 10_000,
+      max_size: 1024 * 1024,
+    };
+    let arch = match &*host_arch().unwrap() {
+      "riscv64" => "aarch64",
+      _ => "riscv64",
+    };
+    let source = load_source_for(&path, arch, limits).unwrap();
+    let outputs = outputs(&source, arch);
+    assert_eq!(outputs[0].1, format!("foo_1_{arch}.tar.zst"));
+    let depends = outputs[0].0.depends.first().unwrap();
+    assert_eq!(depends.to_string(), format!("lib-{arch}"));
+    let json = info_json(&source, arch).unwrap();
+    let json = serde_json::from_str::<serde_json::Value>(&json).unwrap();
+    assert_eq!(json["source"]["name"], "foo");
+    let archive = format!("foo_1_{arch}.tar.zst");
+    assert_eq!(json["packages"][0]["archive"], *archive);
+  }
+
+  #[test]
+  fn test_add_checksums() {
+    let text = r#"#{
+  name: "a",
+  source: [
+    #{ url: "https://example.org/a-1.tar.gz" },
+    #{
+      url: `https://example.org/{b}-${version}.tar.gz`,
+      rename: "b.tar.gz"
+    },
+    #{ path: "c.patch", sha256sum: "ab12", },
+  ],
+}"#;
+    let additions = [
+      (0, "sha256sum: \"cd34\"".to_string()),
+      (1, "sha256sum: \"ef56\"".to_string()),
+    ];
+    let added = add_checksums(text, 3, &additions).unwrap();
+    assert_eq!(
+      added,
+      r#"#{
+  name: "a",
+  source: [
+    #{ url: "https://example.org/a-1.tar.gz", sha256sum: "cd34" },
+    #{
+      url: `https://example.org/{b}-${version}.tar.gz`,
+      rename: "b.tar.gz",
+      sha256sum: "ef56",
+    },
+    #{ path: "c.patch", sha256sum: "ab12", },
+  ],
+}"#
+    );
+    assert!(add_checksums(text, 2, &additions).is_err());
+    assert!(add_checksums("#{ source: sources }", 1, &additions).is_err());
+  }
+}
@@ -0,0 +1,582 @@
+use super::desktop::{has_icon, has_system_icon, is_desktop_entry, DesktopEntry};
+use super::devel::{self, BuildConfig, PkgConfig};
+use super::elf;
+use super::kmod;
+use super::service::{check_init_script, classify, ServiceKind, Unit};
+use super::types::{Package, QaPolicy, RpathMode};
+use super::vuln::{detect_libraries, VulnDb};
+use crate::types::PackageInfo;
+use crate::util::{file_mode, scan_file, set_file_mode};
+use crate::warning;
+use anyhow::bail;
+use console::style;
+use memchr::memmem::Finder;
+use std::collections::BTreeSet;
+use std::fs;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+
+/// What a QA rule gets to look at.
+pub struct QaContext<'a> {
+  pub info: &'a PackageInfo,
+  pub policy: &'a QaPolicy,
+  /// Root of the package tree about to be packed.
+  pub package_dir: &'a Path,
+  pub source_dir: &'a Path,
+  /// Everything in the package tree, relative to its root. Directories come
+  /// before their content.
+  pub files: &'a [PathBuf],
+  pub vuln_db: Option<&'a VulnDb>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+  Warning,
+  Error,
+}
+
+#[derive(Debug, Clone)]
+pub struct Issue {
+  pub severity: Severity,
+  pub message: String,
+}
+
+impl Issue {
+  pub fn warning(message: impl Into<String>) -> Self {
+    Self {
+      severity: Severity::Warning,
+      message: message.into(),
+    }
+  }
+
+  pub fn error(message: impl Into<String>) -> Self {
+    Self {
+      severity: Severity::Error,
+      message: message.into(),
+    }
+  }
+}
+
+type Rule = fn(&QaContext) -> io::Result<Vec<Issue>>;
+
+const RULES: &[(&str, Rule)] = &[
+  ("license", check_license),
+  ("provenance", check_provenance),
+  ("vulnerable-libraries", check_vulnerable_libraries),
+  ("permissions", check_permissions),
+  ("services", check_services),
+  ("desktop-entries", check_desktop_entries),
+  ("rpath", check_rpaths),
+  ("build-configs", check_build_configs),
+  ("kernel-modules", check_kernel_modules),
+];
+
+/// Packages have to ship their license text, unless their license is
+/// explicitly marked `custom`.
+fn check_license(cx: &QaContext) -> io::Result<Vec<Issue>> {
+  let Some(license) = &cx.info.license else {
+    return Ok(vec![Issue::error("no license declared")]);
+  };
+  if &**license == "custom" {
+    return Ok(vec![]);
+  }
+  let dir = (cx.package_dir)
+    .join("usr/share/licenses")
+    .join(&*cx.info.name);
+  let has_file = match dir.read_dir() {
+    Ok(entries) => (entries.collect::<io::Result<Vec<_>>>()?)
+      .iter()
+      .any(|x| x.path().is_file()),
+    Err(e) if e.kind() == io::ErrorKind::NotFound => false,
+    Err(e) => return Err(e),
+  };
+  if has_file {
+    return Ok(vec![]);
+  }
+  Ok(vec![Issue::error(format!(
+    "no license file in /usr/share/licenses/{}, use install_license() or declare the license as `custom`",
+    cx.info.name
+  ))])
+}
+
+#[cfg(unix)]
+fn hostname() -> Option<String> {
+  let mut buf = [0u8; 256];
+  // SAFETY: the buffer is large enough for any hostname and stays
+  // NUL-terminated
+  if unsafe { libc::gethostname(buf.as_mut_ptr().cast(), buf.len() - 1) } != 0 {
+    return None;
+  }
+  let name = std::ffi::CStr::from_bytes_until_nul(&buf).ok()?;
+  Some(name.to_string_lossy().into_owned())
+}
+
+#[cfg(not(unix))]
+fn hostname() -> Option<String> {
+  std::env::var("COMPUTERNAME").ok()
+}
+
+/// Returns which of `needles` occur in the file at `path`.
+fn find_in_file<'a>(path: &Path, needles: &'a [(&str, Finder)]) -> io::Result<Vec<&'a str>> {
+  let overlap = (needles.iter())
+    .map(|(_, x)| x.needle().len())
+    .max()
+    .unwrap_or(0);
+  let mut found = vec![false; needles.len()];
+  scan_file(path, overlap, |chunk| {
+    for (found, (_, finder)) in found.iter_mut().zip(needles) {
+      *found = *found || finder.find(chunk).is_some();
+    }
+  })?;
+  Ok(
+    (needles.iter().zip(found))
+      .filter(|(_, found)| *found)
+      .map(|((name, _), _)| *name)
+      .collect(),
+  )
+}
+
+/// Packaged files should not embed the build directory, or details of the
+/// machine they were built on.
+fn check_provenance(cx: &QaContext) -> io::Result<Vec<Issue>> {
+  let mut needles = vec![(
+    "the build directory",
+    Finder::new(cx.source_dir.as_os_str().as_encoded_bytes()).into_owned(),
+  )];
+  if let Some(home) = std::env::var_os("HOME").filter(|x| x.len() > 1) {
+    needles.push(("$HOME", Finder::new(home.as_encoded_bytes()).into_owned()));
+  }
+  // Short hostnames would turn up everywhere by chance.
+  if let Some(host) = hostname().filter(|x| x.len() >= 6 && x != "localhost") {
+    needles.push(("the hostname", Finder::new(host.as_bytes()).into_owned()));
+  }
+
+  let mut issues = Vec::new();
+  for file in cx.files {
+    let path = cx.package_dir.join(file);
+    if !path.symlink_metadata()?.is_file() {
+      continue;
+    }
+    let found = find_in_file(&path, &needles)?;
+    if !found.is_empty() {
+      issues.push(Issue::warning(format!(
+        "/{} embeds {}",
+        file.display(),
+        found.join(", ")
+      )));
+    }
+  }
+  Ok(issues)
+}
+
+/// Packaged copies of libraries should not have known vulnerabilities. Only
+/// checked if a vulnerability database was given.
+fn check_vulnerable_libraries(cx: &QaContext) -> io::Result<Vec<Issue>> {
+  let Some(db) = cx.vuln_db else {
+    return Ok(vec![]);
+  };
+  let mut issues = Vec::new();
+  for file in cx.files {
+    let path = cx.package_dir.join(file);
+    if !path.symlink_metadata()?.is_file() {
+      continue;
+    }
+    for (library, version) in detect_libraries(&path)? {
+      let vulns = (db.query(library, &version))
+        .map(|x| x.name())
+        .collect::<Vec<_>>();
+      if !vulns.is_empty() {
+        issues.push(Issue::warning(format!(
+          "/{} contains {library} {version}, affected by {}",
+          file.display(),
+          vulns.join(", ")
+        )));
+      }
+    }
+  }
+  Ok(issues)
+}
+
+/// Setuid/setgid files and world-writable paths are only allowed if declared
+/// in `permitted_setuid` and `permitted_world_writable`.
+fn check_permissions(cx: &QaContext) -> io::Result<Vec<Issue>> {
+  let is = |declared: &str, file: &Path| Path::new(declared.trim_start_matches('/')) == file;
+  let declared = |list: &BTreeSet<Box<str>>, file: &Path| list.iter().any(|x| is(x, file));
+  let mut issues = Vec::new();
+  for file in cx.files {
+    let meta = cx.package_dir.join(file).symlink_metadata()?;
+    if meta.is_symlink() {
+      continue;
+    }
+    let mode = file_mode(&meta);
+    if mode & 0o6000 != 0 && !declared(&cx.policy.permitted_setuid, file) {
+      issues.push(Issue::error(format!(
+        "/{} is setuid or setgid, but not in `permitted_setuid`",
+        file.display()
+      )));
+    }
+    if mode & 0o002 != 0 && !declared(&cx.policy.permitted_world_writable, file) {
+      issues.push(Issue::error(format!(
+        "/{} is world-writable, but not in `permitted_world_writable`",
+        file.display()
+      )));
+    }
+  }
+  let lists = [
+    ("permitted_setuid", &cx.policy.permitted_setuid),
+    (
+      "permitted_world_writable",
+      &cx.policy.permitted_world_writable,
+    ),
+  ];
+  for (name, list) in lists {
+    for path in list {
+      if !cx.files.iter().any(|x| is(path, x)) {
+        issues.push(Issue::warning(format!(
+          "`{name}` lists {path}, which is not in the package"
+        )));
+      }
+    }
+  }
+  Ok(issues)
+}
+
+/// Service definitions have to be loadable by the service manager.
+fn check_services(cx: &QaContext) -> io::Result<Vec<Issue>> {
+  let mut issues = Vec::new();
+  for file in cx.files {
+    let Some(kind) = classify(file) else {
+      continue;
+    };
+    let path = cx.package_dir.join(file);
+    if !path.symlink_metadata()?.is_file() {
+      continue;
+    }
+    let problems = match kind {
+      ServiceKind::Systemd { .. } => match fs::read_to_string(&path) {
+        Ok(text) => match Unit::parse(&text) {
+          Ok(unit) => unit.check(&file.file_name().unwrap().to_string_lossy()),
+          Err(e) => vec![e],
+        },
+        Err(e) if e.kind() == io::ErrorKind::InvalidData => vec!["not UTF-8".into()],
+        Err(e) => return Err(e),
+      },
+      ServiceKind::OpenRc => check_init_script(&path)?,
+    };
+    for problem in problems {
+      issues.push(Issue::error(format!("/{}: {problem}", file.display())));
+    }
+  }
+  Ok(issues)
+}
+
+/// Desktop entries have to follow the spec, and their icons should be
+/// installed by the package or its dependencies.
+fn check_desktop_entries(cx: &QaContext) -> io::Result<Vec<Issue>> {
+  let mut issues = Vec::new();
+  for file in cx.files {
+    let path = cx.package_dir.join(file);
+    if !is_desktop_entry(file) || !path.symlink_metadata()?.is_file() {
+      continue;
+    }
+    let entry = match fs::read_to_string(&path) {
+      Ok(text) => DesktopEntry::parse(&text),
+      Err(e) if e.kind() == io::ErrorKind::InvalidData => Err("not UTF-8".into()),
+      Err(e) => return Err(e),
+    };
+    let entry = match entry {
+      Ok(entry) => entry,
+      Err(e) => {
+        issues.push(Issue::error(format!("/{}: {e}", file.display())));
+        continue;
+      }
+    };
+    for problem in entry.check() {
+      issues.push(Issue::error(format!("/{}: {problem}", file.display())));
+    }
+    let Some(icon) = entry.get("Icon").filter(|x| !x.is_empty()) else {
+      continue;
+    };
+    let found = match icon.strip_prefix('/') {
+      Some(rel) => cx.files.iter().any(|x| x == Path::new(rel)) || Path::new(icon).exists(),
+      None => has_icon(cx.files, icon) || has_system_icon(icon),
+    };
+    if !found {
+      issues.push(Issue::warning(format!(
+        "/{}: icon `{icon}` is neither in the package nor installed",
+        file.display()
+      )));
+    }
+  }
+  Ok(issues)
+}
+
+const TEMP_DIRS: &[&str] = &["/tmp", "/var/tmp", "/dev/shm"];
+
+/// What is wrong with a build configuration pointing other builds at `path`.
+fn config_path_issue(cx: &QaContext, path: &str) -> Option<Issue> {
+  let path = Path::new(path);
+  if (TEMP_DIRS.iter()).any(|x| path.starts_with(x))
+    || path.starts_with(cx.source_dir)
+    || path.starts_with(cx.package_dir)
+  {
+    Some(Issue::error(format!(
+      "points at `{}`, a temporary or build directory",
+      path.display()
+    )))
+  } else if path.starts_with("/usr/local") {
+    Some(Issue::warning(format!(
+      "points at `{}`, outside of /usr",
+      path.display()
+    )))
+  } else {
+    let rel = path.strip_prefix("/").ok()?;
+    let found = cx.files.iter().any(|x| x == rel) || path.exists();
+    (!found).then(|| Issue::warning(format!("points at missing `{}`", path.display())))
+  }
+}
+
+/// pkg-config files and CMake package configurations have to point other
+/// builds at where the package installs its files.
+fn check_build_configs(cx: &QaContext) -> io::Result<Vec<Issue>> {
+  let mut issues = Vec::new();
+  for file in cx.files {
+    let Some(kind) = devel::classify(file) else {
+      continue;
+    };
+    let path = cx.package_dir.join(file);
+    if !path.symlink_metadata()?.is_file() {
+      continue;
+    }
+    let text = match fs::read_to_string(&path) {
+      Ok(text) => text,
+      Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+        issues.push(Issue::error(format!("/{}: not UTF-8", file.display())));
+        continue;
+      }
+      Err(e) => return Err(e),
+    };
+    let mut found = Vec::new();
+    let paths: Vec<String> = match kind {
+      BuildConfig::PkgConfig => match PkgConfig::parse(&text) {
+        Ok(pc) => {
+          let prefix = pc.variables.get("prefix").map(|x| &**x);
+          if prefix.is_some_and(|x| x != "/usr") {
+            found.push(Issue::warning(format!(
+              "prefix is `{}`, not /usr",
+              prefix.unwrap()
+            )));
+          }
+          let libdir = pc.variables.get("libdir").map(|x| &**x);
+          if libdir.is_some_and(|x| !DEFAULT_LIB_DIRS.contains(&x)) {
+            let libdir = libdir.unwrap();
+            found.push(Issue::warning(format!(
+              "libdir `{libdir}` is not a library directory"
+            )));
+          }
+          pc.paths().into_iter().map(String::from).collect()
+        }
+        Err(e) => {
+          issues.push(Issue::error(format!("/{}: {e}", file.display())));
+          continue;
+        }
+      },
+      BuildConfig::Cmake => devel::cmake_paths(&text)
+        .into_iter()
+        .map(String::from)
+        .collect(),
+    };
+    let mut seen = BTreeSet::new();
+    for path in paths.iter().filter(|x| seen.insert(*x)) {
+      found.extend(config_path_issue(cx, path));
+    }
+    for issue in found {
+      issues.push(Issue {
+        message: format!("/{}: {}", file.display(), issue.message),
+        ..issue
+      });
+    }
+  }
+  Ok(issues)
+}
+const DEFAULT_LIB_DIRS: &[&str] = &["/lib", "/lib64", "/usr/lib", "/usr/lib64"];
+
+fn is_origin_relative(entry: &str) -> bool {
+  entry.starts_with("$ORIGIN") || entry.starts_with("${ORIGIN}")
+}
+
+/// Rewrites RPATH and RUNPATH entries of the ELF files among `files` as
+/// `mode` says. Entries pointing at temporary or build directories are left
+/// for [`check_rpaths`] to fail on, as they hint at a broken build.
+pub fn clean_rpaths(package_dir: &Path, files: &[PathBuf], mode: RpathMode) -> io::Result<()> {
+  if mode == RpathMode::Keep {
+    return Ok(());
+  }
+  for file in files {
+    let path = package_dir.join(file);
+    let meta = path.symlink_metadata()?;
+    if !meta.is_file() {
+      continue;
+    }
+    let Some(mut elf) = elf::open(&path)? else {
+      continue;
+    };
+    let mut seen = BTreeSet::new();
+    let changed = elf.retain_run_paths(|entry| {
+      let entry = entry.trim_end_matches('/');
+      mode == RpathMode::Clean
+        && (entry.starts_with('/') || is_origin_relative(entry))
+        && !DEFAULT_LIB_DIRS.contains(&entry)
+        && seen.insert(entry.to_string())
+    });
+    if changed {
+      // Packaged files are often read-only.
+      set_file_mode(&path, file_mode(&meta) | 0o200)?;
+      fs::write(&path, elf.into_bytes())?;
+      fs::set_permissions(&path, meta.permissions())?;
+      println!("Cleaned RPATH of /{}", file.display());
+    }
+  }
+  Ok(())
+}
+
+/// ELF files must not look for libraries in temporary or build directories,
+/// and should not in directories relative to the working directory.
+fn check_rpaths(cx: &QaContext) -> io::Result<Vec<Issue>> {
+  let mut issues = Vec::new();
+  for file in cx.files {
+    let path = cx.package_dir.join(file);
+    if !path.symlink_metadata()?.is_file() {
+      continue;
+    }
+    let Some(elf) = elf::open(&path)? else {
+      continue;
+    };
+    for run_path in elf.run_paths() {
+      for entry in run_path.split(':') {
+        let entry_path = Path::new(entry);
+        if (TEMP_DIRS.iter()).any(|x| entry_path.starts_with(x))
+          || entry_path.starts_with(cx.source_dir)
+          || entry_path.starts_with(cx.package_dir)
+        {
+          issues.push(Issue::error(format!(
+            "/{} has RPATH `{entry}`, pointing at a temporary or build directory",
+            file.display()
+          )));
+        } else if !entry.starts_with('/') && !is_origin_relative(entry) {
+          issues.push(Issue::warning(format!(
+            "/{} has RPATH `{entry}`, relative to the working directory",
+            file.display()
+          )));
+        }
+      }
+    }
+  }
+  Ok(issues)
+}
+
+/// Kernel modules have to be installed for the kernel release they were built
+/// for, and depmod output belongs to the kernel package only.
+fn check_kernel_modules(cx: &QaContext) -> io::Result<Vec<Issue>> {
+  let mut issues = Vec::new();
+  for file in cx.files {
+    let path = cx.package_dir.join(file);
+    if !path.symlink_metadata()?.is_file() {
+      continue;
+    }
+    if kmod::is_depmod_output(file) {
+      let release = kmod::release_dir(file).unwrap_or_default();
+      if !kmod::is_kernel_tree(cx.package_dir, release) {
+        issues.push(Issue::error(format!(
+          "/{} is generated by depmod on the target system",
+          file.display()
+        )));
+      }
+      continue;
+    }
+    if !kmod::is_module(file) {
+      continue;
+    }
+    let Some(dir) = kmod::release_dir(file) else {
+      issues.push(Issue::error(format!(
+        "/{} is outside of /{}/<release>",
+        file.display(),
+        kmod::MODULES_DIR
+      )));
+      continue;
+    };
+    match kmod::kernel_release(&path)? {
+      Some(release) if release != dir => issues.push(Issue::error(format!(
+        "/{} is built for kernel {release}, but installed for {dir}",
+        file.display()
+      ))),
+      Some(_) => {}
+      None => issues.push(Issue::warning(format!(
+        "/{} has no readable vermagic",
+        file.display()
+      ))),
+    }
+  }
+  Ok(issues)
+}
+
+/// Removes the static libraries among `files` from the package tree, and
+/// returns them.
+pub fn drop_static_libs(package_dir: &Path, files: &[PathBuf]) -> io::Result<Vec<PathBuf>> {
+  let mut dropped = Vec::new();
+  for file in files {
+    let path = package_dir.join(file);
+    if file.extension().is_some_and(|x| x == "a") && path.symlink_metadata()?.is_file() {
+      let mut magic = [0; 8];
+      let is_archive = fs::File::open(&path)?.read_exact(&mut magic).is_ok();
+      if is_archive && magic == *b"!<arch>\n" {
+        fs::remove_file(&path)?;
+        dropped.push(file.clone());
+      }
+    }
+  }
+  Ok(dropped)
+}
+
+/// Runs every QA rule but the `relaxed` ones on a package tree, failing if
+/// any of them found an error.
+pub fn run(
+  package: &Package,
+  package_dir: &Path,
+  files: &[PathBuf],
+  source_dir: &Path,
+  vuln_db: Option<&VulnDb>,
+  relaxed: &[&str],
+) -> anyhow::Result<()> {
+  let info = &package.info;
+  let cx = &QaContext {
+    info,
+    policy: &package.policy,
+    package_dir,
+    source_dir,
+    files,
+    vuln_db,
+  };
+  let mut errors = 0;
+  for (name, rule) in RULES.iter().filter(|(x, _)| !relaxed.contains(x)) {
+    for issue in rule(cx)? {
+      match issue.severity {
+        Severity::Warning => {
+          warning!("{name}: {}", issue.message);
+        }
+        Severity::Error => {
+          errors += 1;
+          eprintln!(
+            "{} {name}: {}",
+            style("QA error:").red().bold(),
+            issue.message
+          );
+        }
+      }
+    }
+  }
+  if errors > 0 {
+    bail!("{} failed {errors} QA check(s)", cx.info.name);
+  }
+  Ok(())
+}
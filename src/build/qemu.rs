@@ -0,0 +1,146 @@
+use crate::repo::{self, Repo};
+use crate::segment_info;
+use crate::sign::SigningConfig;
+use crate::types::PackageName;
+use anyhow::{anyhow, bail, Context};
+use clap::Args;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use tempfile::{tempdir, TempDir};
+
+/// Builder host standing for QEMU user-mode emulation on this machine.
+pub const QEMU_HOST: &str = "qemu";
+
+/// Mounts the build directory `$2` and `/dev` into the root `$1`, then runs
+/// the script `$3` chrooted in there.
+const CHROOT_SCRIPT: &str = r#"set -e
+mkdir -p "$1$2" "$1/dev"
+mount --rbind /dev "$1/dev"
+mount --rbind "$2" "$1$2"
+exec chroot "$1" /bin/sh -c 'cd "$1" && eval "$2"' sh "$2" "$3""#;
+
+/// Options for emulated builds of foreign architectures.
+#[derive(Debug, Clone, Args)]
+pub struct QemuOptions {
+  /// Repository of the target architecture to assemble build roots from, as
+  /// a directory with an `index.json` from `ewe repo index`
+  #[arg(long, value_name = "DIR")]
+  pub qemu_repo: Option<PathBuf>,
+
+  /// Packages installed into build roots besides the build dependencies
+  #[arg(
+    long,
+    value_name = "PKG",
+    value_delimiter = ',',
+    default_value = "base"
+  )]
+  pub qemu_base: Vec<PackageName>,
+}
+
+impl QemuOptions {
+  /// Command line arguments reproducing these options.
+  pub fn to_args(&self) -> Vec<String> {
+    let mut args = Vec::new();
+    if let Some(repo) = &self.qemu_repo {
+      args.push("--qemu-repo".into());
+      args.push(repo.display().to_string());
+    }
+    let base = self.qemu_base.iter().map(|x| x.to_string());
+    args.push(format!(
+      "--qemu-base={}",
+      base.collect::<Vec<_>>().join(",")
+    ));
+    args
+  }
+}
+
+/// Name QEMU uses for `arch` in its binaries and binfmt handlers.
+fn qemu_name(arch: &str) -> &str {
+  match arch {
+    "i486" | "i586" | "i686" => "i386",
+    "armv6l" | "armv7l" | "armv7h" => "arm",
+    x => x,
+  }
+}
+
+/// Checks that binaries of `arch` are run through QEMU, with the interpreter
+/// loaded up front so that it keeps working inside a chroot.
+fn check_binfmt(arch: &str) -> anyhow::Result<()> {
+  let name = format!("qemu-{}", qemu_name(arch));
+  let path = Path::new("/proc/sys/fs/binfmt_misc").join(&name);
+  let status = fs::read_to_string(&path).map_err(|_| {
+    anyhow!("{name} is not registered with binfmt_misc, install qemu-user-static and its binfmt configuration")
+  })?;
+  if !status.starts_with("enabled") {
+    bail!(
+      "{name} is disabled in binfmt_misc, enable it with `echo 1 > {}`",
+      path.display()
+    );
+  }
+  let fixed = (status.lines())
+    .filter_map(|x| x.strip_prefix("flags:"))
+    .any(|x| x.contains('F'));
+  if !fixed {
+    bail!("{name} must be registered with the F flag to work in build roots");
+  }
+  Ok(())
+}
+
+/// A root file system of a foreign architecture that build commands run in.
+#[derive(Debug)]
+pub struct Sysroot {
+  root: TempDir,
+}
+
+impl Sysroot {
+  /// Assembles a root for `arch` from the configured repository, with the
+  /// base packages, `extra` ones and everything they depend on.
+  pub fn assemble(
+    arch: &str,
+    options: &QemuOptions,
+    extra: impl IntoIterator<Item = PackageName>,
+  ) -> anyhow::Result<Self> {
+    check_binfmt(arch)?;
+    let repo = (options.qemu_repo.as_deref())
+      .with_context(|| format!("--qemu-repo is needed to build for {arch} under QEMU"))?;
+    let repos = [Repo::open(repo)?];
+    let digest = SigningConfig::load()?.digest;
+
+    segment_info!("Assembling build root for", "{arch}");
+    let root = tempdir()?;
+    let names = (options.qemu_base.iter().cloned())
+      .chain(extra)
+      .collect::<Vec<_>>();
+    let packages = repo::resolve(&repos, &names)?;
+    for package in &packages {
+      // Indexes from before checksums were recorded cannot be checked.
+      if package.entry.sha256.is_some() {
+        package.entry.verify(&package.repo.dir, digest)?;
+      }
+      repo::unpack(&package.archive(), root.path())?;
+    }
+    println!("Installed {} package(s)", packages.len());
+    Ok(Self { root })
+  }
+
+  /// Command running the shell script `script` in `dir` inside this root, as
+  /// root in a new user namespace.
+  pub fn command(&self, dir: &Path, script: &str) -> Command {
+    let mut command = Command::new("unshare");
+    command
+      .args([
+        "--user",
+        "--map-root-user",
+        "--mount",
+        "sh",
+        "-c",
+        CHROOT_SCRIPT,
+        "sh",
+      ])
+      .arg(self.root.path())
+      .arg(dir)
+      .arg(script);
+    command
+  }
+}
@@ -0,0 +1,245 @@
+use super::build_cache::BuildCacheOptions;
+use super::fetch::FetchOptions;
+use super::lock::Lockfile;
+use super::manifest::BuildManifest;
+use super::{build_local, host_arch, load_source, BuildOptions, Limits};
+use crate::segment_info;
+use crate::types::SourceLocation;
+use crate::util::{is_enclosed, is_safe_name};
+use anyhow::{bail, Context};
+use clap::Args;
+use std::ffi::OsString;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::str::FromStr;
+use std::{env, thread};
+use tempfile::tempdir;
+
+/// Name of the internal command serving a build on the remote host.
+const SERVE_COMMAND: &str = "__internal_remote_build";
+
+/// Entry of the returned archive holding the updated lockfile.
+const LOCK_ENTRY: &str = "lock.json";
+
+/// Directory of the returned archive holding packages and the manifest.
+const OUTPUT_DIR: &str = "out";
+
+/// Options for offloading builds to another machine.
+#[derive(Debug, Clone, Args)]
+pub struct RemoteOptions {
+  /// Build on this host over SSH instead of locally, e.g.
+  /// `builder@arm64.example.org`
+  #[arg(long, value_name = "HOST")]
+  pub remote: Option<String>,
+
+  /// ewepkg executable on the remote host
+  #[arg(long, value_name = "PATH", default_value = "ewe")]
+  pub remote_ewe: String,
+}
+
+/// A machine that builds run on, one at a time.
+#[derive(Debug, Clone)]
+pub struct Builder {
+  pub arch: String,
+  /// SSH host, `qemu` for emulation on this machine, or `None` for this
+  /// machine.
+  pub host: Option<String>,
+}
+
+impl FromStr for Builder {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let (arch, host) = (s.split_once('='))
+      .filter(|(arch, host)| !arch.is_empty() && !host.is_empty())
+      .ok_or_else(|| format!("expected ARCH=HOST, got `{s}`"))?;
+    Ok(Self {
+      arch: arch.into(),
+      host: (host != "local").then(|| host.into()),
+    })
+  }
+}
+
+impl Builder {
+  pub fn name(&self) -> &str {
+    self.host.as_deref().unwrap_or("local")
+  }
+}
+
+fn shell_quote(arg: &str) -> String {
+  format!("'{}'", arg.replace('\'', r"'\''"))
+}
+
+/// Files the remote build needs: the script, its lockfile and local sources.
+fn inputs(path: &Path, limits: Limits) -> anyhow::Result<Vec<PathBuf>> {
+  let source = load_source(path, limits)?;
+  let mut files = vec![path.to_path_buf()];
+  let lock = Lockfile::path_for(path);
+  if lock.is_file() {
+    files.push(lock);
+  }
+  for file in &source.info.source {
+    if let SourceLocation::Local(path) = &file.location {
+      files.push(path.to_path_buf());
+    }
+  }
+  for file in &files {
+    if !is_enclosed(file) {
+      bail!(
+        "{} must be a relative path below the current directory to build remotely",
+        file.display()
+      );
+    }
+  }
+  Ok(files)
+}
+
+/// Sends the script at `path` and its inputs to `host`, builds it there
+/// with the same options, and retrieves the packages and manifest into
+/// `output`.
+#[allow(clippy::too_many_arguments)]
+pub fn build(
+  host: &str,
+  remote: &RemoteOptions,
+  path: PathBuf,
+  limits: Limits,
+  fetch: &FetchOptions,
+  options: &BuildOptions,
+  cache: &BuildCacheOptions,
+  output: &Path,
+) -> anyhow::Result<BuildManifest> {
+  let files = inputs(&path, limits)?;
+  segment_info!("Starting remote build on", "{host}");
+
+  let mut args = vec![OsString::from(SERVE_COMMAND), path.clone().into()];
+  args.extend(limits.to_args().map(Into::into));
+  args.extend(fetch.to_args().into_iter().map(Into::into));
+  args.extend(options.to_args());
+  args.extend(cache.to_args().into_iter().map(Into::into));
+  let command = (std::iter::once(remote.remote_ewe.clone()))
+    .chain(args.iter().map(|x| x.to_string_lossy().into_owned()))
+    .map(|x| shell_quote(&x))
+    .collect::<Vec<_>>()
+    .join(" ");
+  let mut child = Command::new("ssh")
+    .args(["-o", "BatchMode=yes", "-e", "none", host, "--", &command])
+    .stdin(Stdio::piped())
+    .stdout(Stdio::piped())
+    .spawn()
+    .context("failed to run ssh")?;
+
+  // The bundle is written while the remote side may already log, so it gets
+  // its own thread.
+  let stdin = child.stdin.take().unwrap();
+  let upload = thread::spawn(move || -> io::Result<()> {
+    let mut bundle = tar::Builder::new(stdin);
+    bundle.follow_symlinks(false);
+    for file in &files {
+      if file.is_dir() {
+        bundle.append_dir_all(file, file)?;
+      } else {
+        bundle.append_path(file)?;
+      }
+    }
+    bundle.into_inner()?.flush()
+  });
+
+  let mut manifest = None;
+  let mut results = tar::Archive::new(child.stdout.take().unwrap());
+  for entry in results.entries()? {
+    let mut entry = entry?;
+    let name = entry.path()?.to_string_lossy().into_owned();
+    if name == LOCK_ENTRY {
+      entry.unpack(Lockfile::path_for(&path))?;
+      continue;
+    }
+    let Some(file) = name.strip_prefix(&format!("{OUTPUT_DIR}/")) else {
+      continue;
+    };
+    if !is_safe_name(file) || file.contains('/') {
+      bail!("remote build returned invalid file `{file}`");
+    }
+    if file.ends_with(".manifest.json") {
+      let mut data = Vec::new();
+      entry.read_to_end(&mut data)?;
+      manifest = Some(serde_json::from_slice::<BuildManifest>(&data)?);
+      File::create(output.join(file))?.write_all(&data)?;
+    } else {
+      segment_info!("Retrieving", "{file}");
+      entry.unpack(output.join(file))?;
+    }
+  }
+
+  let status = child.wait()?;
+  let uploaded = upload.join().expect("upload thread panicked");
+  if !status.success() {
+    bail!("remote build on {host} failed ({status})");
+  }
+  uploaded.context("failed to upload build inputs")?;
+  manifest.with_context(|| format!("remote build on {host} returned no manifest"))
+}
+
+/// Moves stdout to a new descriptor, which is returned, and points the
+/// original one to stderr.
+#[cfg(unix)]
+fn redirect_stdout() -> io::Result<File> {
+  use std::os::fd::FromRawFd;
+  // SAFETY: only duplicates the standard file descriptors.
+  unsafe {
+    let fd = libc::dup(1);
+    if fd < 0 || libc::dup2(2, 1) < 0 {
+      return Err(io::Error::last_os_error());
+    }
+    Ok(File::from_raw_fd(fd))
+  }
+}
+
+#[cfg(not(unix))]
+fn redirect_stdout() -> io::Result<File> {
+  Err(io::Error::new(
+    io::ErrorKind::Unsupported,
+    "serving builds needs a Unix host",
+  ))
+}
+
+/// Serves a build requested by [`build`]: reads the inputs from stdin, builds
+/// them, and writes the results to stdout. Logs go to stderr.
+pub fn serve_remote(
+  path: PathBuf,
+  limits: Limits,
+  fetch: FetchOptions,
+  options: BuildOptions,
+  cache: BuildCacheOptions,
+) -> anyhow::Result<()> {
+  // Keep stdout for the results, and send everything else printed by us or
+  // the build to stderr.
+  let stdout = redirect_stdout()?;
+
+  let dir = tempdir()?;
+  tar::Archive::new(io::stdin().lock()).unpack(dir.path())?;
+  env::set_current_dir(dir.path())?;
+  let manifest = build_local(
+    path.clone(),
+    limits,
+    &fetch,
+    options,
+    &cache,
+    &host_arch()?,
+    None,
+    Path::new("."),
+  )?;
+
+  let mut output = tar::Builder::new(stdout);
+  let lock = Lockfile::path_for(&path);
+  if lock.is_file() {
+    output.append_path_with_name(lock, LOCK_ENTRY)?;
+  }
+  let files = (manifest.packages.iter().map(|x| x.to_string())).chain([manifest.file_name()]);
+  for file in files {
+    output.append_path_with_name(&file, Path::new(OUTPUT_DIR).join(&file))?;
+  }
+  output.into_inner()?.flush()?;
+  Ok(())
+}
@@ -0,0 +1,95 @@
+use crate::segment_info;
+use crate::types::PackageName;
+use anyhow::bail;
+use std::fs;
+use std::path::PathBuf;
+use url::Url;
+
+/// Build script with the standard fields and steps of a package built with
+/// `./configure && make`.
+fn skeleton(name: &str, version: &str, url: &str) -> String {
+  format!(
+    r#"// Fill in the description, license and source of {name}, then add the
+// checksums of its sources with `ewe checksum` and check it with `ewe lint`.
+
+let version = "{version}";
+
+#{{
+  name: "{name}",
+  description: "",
+  version: `${{version}}-1`,
+  architecture: ["any"],
+  license: "",
+  build_depends: [],
+  source: [#{{
+    url: `{url}`,
+  }}],
+
+  prepare: `
+    cd {name}-${{version}}
+  `,
+
+  build: `
+    cd {name}-${{version}}
+    ./configure --prefix=/usr
+    make
+  `,
+
+  packages: [#{{
+    pack: |package_dir| `
+      cd {name}-${{version}}
+      make DESTDIR="${{package_dir}}" install
+    `,
+  }}],
+}}
+"#
+  )
+}
+
+/// Writes a skeleton build script for `name` into `dir`, by default a new
+/// directory named after it. Occurrences of `version` in `url` refer to the
+/// version variable of the script.
+pub fn new_script(
+  name: PackageName,
+  dir: Option<PathBuf>,
+  version: String,
+  url: Option<Url>,
+) -> anyhow::Result<()> {
+  let dir = dir.unwrap_or_else(|| PathBuf::from(&*name));
+  let path = dir.join("ewebuild");
+  if path.exists() {
+    bail!("{} already exists", path.display());
+  }
+  let url = match url {
+    Some(url) => url.as_str().replace(&version, "${version}"),
+    None => format!("https://example.org/{name}-${{version}}.tar.gz"),
+  };
+  fs::create_dir_all(&dir)?;
+  fs::write(&path, skeleton(&name, &version, &url))?;
+  segment_info!("Created", "{}", path.display());
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::build::{load_source, Limits};
+  use tempfile::tempdir;
+
+  #[test]
+  fn test_skeleton() {
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("ewebuild");
+    let url = "https://example.org/foo-${version}.tar.gz";
+    fs::write(&path, skeleton("foo", "1.2", url)).unwrap();
+    let limits = Limits {
+      timeout: 30,
+      max_operations: 10_000,
+      max_size: 1024 * 1024,
+    };
+    let source = load_source(&path, limits).unwrap();
+    assert_eq!(&*source.info.name, "foo");
+    assert_eq!(source.info.version.to_string(), "1.2-1");
+    assert_eq!(source.packages.len(), 1);
+  }
+}
@@ -1,20 +1,48 @@
-use super::engine::create_engine;
+use super::build_cache::build_key;
+use super::clean::BuildDir;
+use super::coredump::CoreDumps;
+use super::desktop;
+use super::elf;
+use super::engine::{
+  create_engine, eval_error, expose_packing, expose_source, expose_srcdirs, expose_tracing,
+  intercept_side_effects, take_path_error, BuiltinCalls, CurrentPackage, Limits, PackTarget,
+  ScriptPath,
+};
+use super::exec::{run_blocking, Priority};
+use super::kmod;
+use super::lock::{Lockfile, SourceRecord};
+use super::manifest::{BuildManifest, SmokeTest};
+use super::meta::SCHEMA_VERSION;
+use super::qa;
+use super::qemu::Sysroot;
+use super::service;
 use super::types::{Execution, Package, Source};
-use crate::build::fetch::fetch_source;
-use crate::build::PackageMeta;
-use crate::segment_info;
-use crate::util::PB_STYLE;
+use super::vuln::VulnDb;
+use super::writer::{PackageWriter, TarZstWriter};
+use crate::build::fetch::{fetch_source, FetchOptions};
+use crate::build::{BuildOptions, PackageMeta};
+use crate::repo;
+use crate::tree::{Groups, Owners, Policy, GROUPS_FILE, POLICY_FILE};
+use crate::types::{PackageInfo, ScriptOption, SourceFile, SourceLocation};
+use crate::util::{walk, Walk, PB_STYLE};
+use crate::{segment_info, warning};
 use anyhow::bail;
 use indicatif::{ProgressBar, ProgressStyle};
-use rhai::{Dynamic, Engine, FnPtr, FuncArgs, AST};
+use rhai::{Dynamic, Engine, FnPtr, FuncArgs, Scope, AST};
 use smartstring::{LazyCompact, SmartString};
-use std::collections::BTreeSet;
-use std::fs::File;
+use std::collections::{BTreeMap, BTreeSet};
+use std::env::{join_paths, split_paths, var_os};
+use std::mem::take;
 use std::path::{Path, PathBuf};
 use std::process::Command;
 use std::str::from_utf8;
 use tempfile::{tempdir, TempDir};
-use zstd::stream::Encoder as ZstEncoder;
+
+pub(super) fn archive_name(info: &PackageInfo, arch: &str, options: &BuildOptions) -> String {
+  let tag = (options.variant().into_iter()).fold(String::new(), |x, tag| x + "+" + tag);
+  let extension = TarZstWriter::EXTENSION;
+  format!("{}{tag}_{}_{}.{extension}", info.name, info.version, arch)
+}
 
 #[derive(Debug)]
 pub struct BuildScript {
@@ -22,26 +50,183 @@ pub struct BuildScript {
   ast: AST,
   path: Box<Path>,
   source: Source,
-  source_dir: TempDir,
+  source_dir: BuildDir,
   arch: SmartString<LazyCompact>,
+  limits: Limits,
+  options: BuildOptions,
+  /// Foreign root that build commands are emulated in.
+  sysroot: Option<Sysroot>,
+  /// From the lockfile, once sources are fetched.
+  source_date_epoch: Option<u64>,
+  priority: Priority,
 }
 
-impl BuildScript {
-  pub fn new(path: PathBuf) -> anyhow::Result<Self> {
-    let source_dir = tempdir()?;
-    let arch = Command::new("uname").arg("-m").output()?.stdout;
-    let mut arch = from_utf8(&arch)?.trim();
-    let (engine, mut scope) = create_engine(source_dir.path(), arch.to_string());
+pub fn host_arch() -> anyhow::Result<String> {
+  let arch = Command::new("uname").arg("-m").output()?.stdout;
+  Ok(from_utf8(&arch)?.trim().into())
+}
 
-    let ast = engine.compile_file_with_scope(&scope, path.clone())?;
-    let mut value = engine.eval_ast_with_scope(&mut scope, &ast)?;
-    let source = Source::from_dynamic(&mut value)?;
+fn eval_script(engine: &Engine, scope: &mut Scope, path: &Path) -> anyhow::Result<(AST, Source)> {
+  let ast = engine.compile_file_with_scope(scope, path.to_path_buf())?;
+  let mut value = (engine.eval_ast_with_scope(scope, &ast)).map_err(eval_error)?;
+  let source = Source::from_dynamic(&mut value)?;
+  Ok((ast, source))
+}
+
+fn evaluate(
+  path: &Path,
+  source_dir: &Path,
+  arch: &str,
+  bootstrap: bool,
+  limits: Limits,
+) -> anyhow::Result<(Engine, AST, Source)> {
+  let (engine, mut scope) = create_engine(source_dir, arch.to_string(), bootstrap, limits);
+  let (ast, source) = eval_script(&engine, &mut scope, path)?;
+  Ok((engine, ast, source))
+}
+
+/// Like [`evaluate`] for this host, with side effects intercepted, see
+/// [`intercept_side_effects`]. Also returns the calls the script made.
+fn evaluate_metadata(
+  path: &Path,
+  source_dir: &Path,
+  arch: &str,
+  limits: Limits,
+) -> anyhow::Result<(Engine, AST, Source, Vec<String>)> {
+  let (mut engine, mut scope) = create_engine(source_dir, arch.into(), false, limits);
+  let effects = intercept_side_effects(&mut engine);
+  let (ast, source) = eval_script(&engine, &mut scope, path)?;
+  let effects = take(&mut *effects.lock().unwrap());
+  Ok((engine, ast, source, effects))
+}
+
+/// Evaluates the build script at `path` for its metadata only, regardless of
+/// whether it can be built on this host, warning about side effects it
+/// tried.
+pub fn load_source(path: &Path, limits: Limits) -> anyhow::Result<Source> {
+  load_source_for(path, &host_arch()?, limits)
+}
+
+/// Like [`load_source`], evaluating the script for `arch`.
+pub fn load_source_for(path: &Path, arch: &str, limits: Limits) -> anyhow::Result<Source> {
+  let source_dir = tempdir()?;
+  let (_, _, source, effects) = evaluate_metadata(path, source_dir.path(), arch, limits)?;
+  for call in effects {
+    warning!(
+      "{}: calls `{call}` outside of steps, before sources are fetched",
+      path.display()
+    );
+  }
+  Ok(source)
+}
+
+/// What the steps of a build script evaluate to, found by calling their
+/// functions with builtins that only note what they are asked for.
+#[derive(Debug, Clone, Default)]
+pub struct StepTrace {
+  /// Shell snippets of the steps by name, with the source directory written
+  /// as `$source_dir`.
+  pub snippets: Vec<(String, String)>,
+  pub calls: BuiltinCalls,
+  /// Steps that failed to evaluate, so that what they do is unknown.
+  pub failed: Vec<String>,
+  /// Side effects tried outside of steps, see [`intercept_side_effects`].
+  pub side_effects: Vec<String>,
+}
+
+/// Evaluates the build script at `path` like [`load_source`], along with its
+/// steps, without running anything.
+pub fn trace_source(path: &Path, limits: Limits) -> anyhow::Result<(Source, StepTrace)> {
+  let source_dir = tempdir()?;
+  let package_dir = tempdir()?;
+  let (mut engine, ast, source, side_effects) =
+    evaluate_metadata(path, source_dir.path(), &host_arch()?, limits)?;
+  expose_source(&mut engine, &source.info)?;
+  let calls = expose_tracing(&mut engine, source_dir.path());
+
+  let dir = source_dir.path().to_string_lossy();
+  let mut trace = StepTrace {
+    side_effects,
+    ..Default::default()
+  };
+  let mut add = |name: &str, result: anyhow::Result<Dynamic>| match result {
+    Ok(x) => {
+      if let Ok(x) = x.into_string() {
+        trace
+          .snippets
+          .push((name.into(), x.replace(&*dir, "$source_dir")));
+      }
+    }
+    Err(_) => trace.failed.push(name.into()),
+  };
+  let call = |f: &FnPtr, args: Vec<Dynamic>| -> anyhow::Result<Dynamic> {
+    let result = f.call(&engine, &ast, args).map_err(eval_error)?;
+    take_path_error()?;
+    Ok(result)
+  };
+  let steps = [
+    ("prepare", &source.prepare, false),
+    ("build", &source.build, false),
+    ("check", &source.check, false),
+    ("test", &source.test, true),
+  ];
+  for (name, exec, takes_dir) in steps {
+    match exec {
+      Some(Execution::Shell(x)) => add(name, Ok(x.to_string().into())),
+      Some(Execution::Fn(f)) => {
+        let args =
+          (takes_dir.then(|| Dynamic::from(ScriptPath(package_dir.path().into())))).into_iter();
+        add(name, call(f, args.collect()));
+      }
+      None => {}
+    }
+  }
+  for f in source.packages.iter().filter_map(|x| x.pack.as_ref()) {
+    let arg = Dynamic::from(ScriptPath(package_dir.path().into()));
+    add("pack", call(f, vec![arg]));
+  }
+  trace.calls = calls.lock().unwrap().clone();
+  Ok((source, trace))
+}
+
+impl BuildScript {
+  /// Loads the script at `path` to build for `arch`.
+  pub fn new(
+    path: PathBuf,
+    arch: &str,
+    limits: Limits,
+    options: BuildOptions,
+  ) -> anyhow::Result<Self> {
+    let source_dir = BuildDir::new(options.keep_build, arch, &options.profile_dir())?;
+    let mut arch = arch;
+    let (mut engine, ast, source) =
+      evaluate(&path, source_dir.path(), arch, options.bootstrap, limits)?;
+    if options.bootstrap && !source.info.options.contains(&ScriptOption::Bootstrap) {
+      bail!(
+        "{} has no bootstrap build, add `bootstrap` to its options",
+        source.info.name
+      );
+    }
 
     if source.info.architecture.contains_all() {
       arch = "all"
     } else if !source.info.architecture.contains(arch) {
       bail!("source architecture does not contain `{arch}`")
     }
+    if let Some(groups) = Groups::find(&path)? {
+      for package in &source.packages {
+        let unknown = groups.unknown(&package.info).collect::<Vec<_>>();
+        if !unknown.is_empty() {
+          bail!(
+            "{}: unknown groups {}, see {GROUPS_FILE}",
+            package.info.name,
+            unknown.join(", ")
+          );
+        }
+      }
+    }
+    expose_source(&mut engine, &source.info)?;
+    let priority = Priority::load(options.nice)?;
 
     Ok(Self {
       engine,
@@ -50,6 +235,11 @@ impl BuildScript {
       source,
       source_dir,
       arch: arch.into(),
+      limits,
+      options,
+      sysroot: None,
+      source_date_epoch: None,
+      priority,
     })
   }
 
@@ -57,19 +247,92 @@ impl BuildScript {
     &self.source
   }
 
+  /// Runs build commands inside `sysroot` from now on.
+  pub fn use_sysroot(&mut self, sysroot: Sysroot) {
+    self.sysroot = Some(sysroot);
+  }
+
+  pub fn build_key(&self) -> anyhow::Result<Option<String>> {
+    let profile = self.options.profile();
+    build_key(&self.path, &self.source.info, &profile, &self.arch)
+  }
+
+  /// Where builds for this architecture and profile are kept in caches, as
+  /// `<arch>/<profile>`.
+  pub fn cache_namespace(&self) -> PathBuf {
+    Path::new(&*self.arch).join(self.options.profile_dir())
+  }
+
+  /// Environment variables set for build commands.
+  fn build_env(&self) -> Vec<(&'static str, String)> {
+    let mut cflags = Vec::new();
+    let mut ldflags = Vec::new();
+    if self.options.map_build_paths {
+      cflags.push(format!(
+        "-ffile-prefix-map={}=/usr/src/{}",
+        self.source_dir.path().display(),
+        self.source.info.name
+      ));
+    }
+    if self.options.asan {
+      let sanitize = "-fsanitize=address,undefined";
+      cflags.extend([sanitize, "-fno-omit-frame-pointer", "-g"].map(Into::into));
+      ldflags.push(sanitize.into());
+    }
+
+    let mut env = Vec::new();
+    let flags = [
+      ("CFLAGS", &cflags),
+      ("CXXFLAGS", &cflags),
+      ("LDFLAGS", &ldflags),
+    ];
+    for (var, flags) in flags {
+      if flags.is_empty() {
+        continue;
+      }
+      let value = (var_os(var).map(|x| x.to_string_lossy().into_owned()))
+        .into_iter()
+        .chain(flags.iter().cloned())
+        .collect::<Vec<_>>();
+      env.push((var, value.join(" ")));
+    }
+    if self.options.asan {
+      // Keep debug info from build systems stripping on install.
+      env.push(("STRIP", "true".into()));
+    }
+    if let Some(epoch) = self.source_date_epoch {
+      env.push(("SOURCE_DATE_EPOCH", epoch.to_string()));
+    }
+    env
+  }
+
   fn exec_shell(&self, dir: impl AsRef<Path>, x: &str) -> anyhow::Result<()> {
-    let status = Command::new("sh")
-      .args(["-c", &format!("set -e\n{x}")])
-      .current_dir(dir)
-      .status()?;
+    let script = format!("set -e\n{x}");
+    let mut command = match &self.sysroot {
+      Some(sysroot) => sysroot.command(dir.as_ref(), &script),
+      None => {
+        let mut command = Command::new("sh");
+        command.args(["-c", &script]);
+        command
+      }
+    };
+    command.env("source_dir", self.source_dir.path());
+    command.current_dir(dir).envs(self.build_env());
+    self.priority.apply(&mut command);
+    let cores = CoreDumps::new(&[self.source_dir.path()]);
+    cores.enable(&mut command);
+    let quota = self.options.disk_quota(&[self.source_dir.path()]);
+    let status = run_blocking(command, self.options.command_timeout(), quota.as_ref())?;
     if !status.success() {
+      cores.report(status);
       bail!("shell exited with {status}");
     }
     Ok(())
   }
 
   fn exec_fn(&self, dir: impl AsRef<Path>, f: &FnPtr, args: impl FuncArgs) -> anyhow::Result<()> {
-    let result: Dynamic = f.call(&self.engine, &self.ast, args)?;
+    let result: Dynamic = f.call(&self.engine, &self.ast, args).map_err(eval_error)?;
+    take_path_error()?;
     if let Ok(x) = result.into_string() {
       self.exec_shell(dir, &x)?;
     }
@@ -83,21 +346,95 @@ impl BuildScript {
     }
   }
 
-  pub fn prepare(&self) -> anyhow::Result<()> {
+  /// Records fetched sources into the lockfile, warning about sources that
+  /// started redirecting to another host since the last time.
+  fn update_lock(&self, old: &Lockfile, records: Vec<SourceRecord>) -> anyhow::Result<Lockfile> {
+    let mut lock = Lockfile::default();
+    for (file, record) in self.source.info.source.iter().zip(records) {
+      let name = file.file_name();
+      if let Some(old) = old.get(name, &record.location) {
+        if let (Some(before), Some(now)) = (old.final_host(), record.final_host()) {
+          if before != now {
+            warning!("source '{name}' now redirects to `{now}` instead of `{before}`");
+          }
+        }
+      }
+      lock.sources.insert(name.into(), record);
+    }
+    lock.source_date_epoch = (self.source.info.source_date_epoch)
+      .or_else(|| lock.sources.values().filter_map(|x| x.released).max());
+    lock.save(&Lockfile::path_for(&self.path))?;
+    Ok(lock)
+  }
+
+  /// Sources downloaded without checksums to verify them.
+  fn unverified_sources(&self) -> impl Iterator<Item = &SourceFile> {
+    (self.source.info.source.iter())
+      .filter(|x| matches!(x.location, SourceLocation::Http(_)) && x.checksums.is_empty())
+  }
+
+  /// Fails if the policy of the package tree forbids sources without
+  /// checksums, unless this is a developer build.
+  fn check_policy(&self) -> anyhow::Result<()> {
+    let unverified = self
+      .unverified_sources()
+      .map(|x| x.file_name())
+      .collect::<Vec<_>>();
+    if unverified.is_empty() || self.options.dev || !Policy::find(&self.path)?.require_checksums {
+      return Ok(());
+    }
+    bail!(
+      "{POLICY_FILE} requires checksums, which {} lack; add them with `ewe checksum`, \
+       or build with --dev",
+      unverified.join(", ")
+    );
+  }
+
+  /// Fetches, verifies and extracts the sources into the source directory,
+  /// recording them in the lockfile.
+  pub fn fetch(&mut self, options: &FetchOptions) -> anyhow::Result<Lockfile> {
     let source_dir = self.source_dir.path();
+    segment_info!("Fetching source...");
+    for file in self.unverified_sources() {
+      if file.skip_checksum {
+        warning!(
+          "source '{}' is not verified by a checksum, pinning its download in the lockfile",
+          file.file_name()
+        );
+      } else {
+        warning!(
+          "source '{}' has no checksum, add one with `ewe checksum` or set `skip_checksum`",
+          file.file_name()
+        );
+      }
+    }
+    let old = Lockfile::load(&Lockfile::path_for(&self.path))?;
+    let require_checksums = Policy::find(&self.path)?.require_checksums;
+    let records = fetch_source(
+      source_dir,
+      &self.source.info.source,
+      &old,
+      options,
+      require_checksums,
+    )?;
+    let lock = self.update_lock(&old, records)?;
+    expose_srcdirs(&mut self.engine, source_dir, &lock.sources);
+    self.source_date_epoch = lock.source_date_epoch;
+    Ok(lock)
+  }
 
+  pub fn prepare(&mut self, options: &FetchOptions) -> anyhow::Result<Lockfile> {
     // TODO: dependency check
     segment_info!("Checking dependencies...");
     println!("Not implemented, skipping");
 
-    segment_info!("Fetching source...");
-    fetch_source(source_dir, &self.source.info.source)?;
-
+    self.check_policy()?;
+    let lock = self.fetch(options)?;
     if let Some(prepare) = &self.source.prepare {
       segment_info!("Preparing source...");
-      self.exec(source_dir, prepare, ())?;
+      self.exec(self.source_dir.path(), prepare, ())?;
     }
-    Ok(())
+    Ok(lock)
   }
 
   pub fn build(&self) -> anyhow::Result<()> {
@@ -108,7 +445,8 @@ impl BuildScript {
     Ok(())
   }
 
-  pub fn pack(&self) -> anyhow::Result<()> {
+  /// Packs the built files into packages in `output`.
+  pub fn pack(&self, output: &Path) -> anyhow::Result<()> {
     segment_info!("Entering fakeroot...");
     let exe = std::env::current_exe()?;
     let status = Command::new("fakeroot")
@@ -118,7 +456,10 @@ impl BuildScript {
         &self.path,
         self.source_dir.path(),
         Path::new(&*self.arch),
+        output,
       ])
+      .args(self.limits.to_args())
+      .args(self.options.to_args())
       .status()?;
     if !status.success() {
       bail!("fakeroot exited with {status}");
@@ -126,6 +467,92 @@ impl BuildScript {
     segment_info!("Exiting fakeroot...");
     Ok(())
   }
+
+  /// Packages the script builds, with the names of their archives.
+  pub fn outputs(&self) -> Vec<(&PackageInfo, String)> {
+    (self.source.packages.iter())
+      .map(|x| (&x.info, archive_name(&x.info, &self.arch, &self.options)))
+      .collect()
+  }
+
+  pub fn manifest(&self, lock: Lockfile) -> BuildManifest {
+    let info = &self.source.info;
+    BuildManifest {
+      name: info.name.clone(),
+      version: info.version.clone(),
+      architecture: self.arch.clone(),
+      build_key: None,
+      sources: lock.sources,
+      packages: (self.outputs().into_iter())
+        .map(|(_, x)| x.into())
+        .collect(),
+      rebuilds: BTreeMap::new(),
+      smoke_test: None,
+    }
+  }
+
+  /// With `--smoke-test`, unpacks the built `packages` in `output` into a
+  /// temporary root and runs the script's `test` in there, with the packaged
+  /// executables and libraries taking precedence.
+  pub fn smoke_test(
+    &self,
+    output: &Path,
+    packages: &[Box<str>],
+  ) -> anyhow::Result<Option<SmokeTest>> {
+    if !self.options.smoke_test {
+      return Ok(None);
+    }
+    let Some(test) = &self.source.test else {
+      warning!("no `test` in the build script, skipping smoke test");
+      return Ok(None);
+    };
+    if self.sysroot.is_some() {
+      warning!("smoke tests cannot run emulated builds yet, skipping");
+      return Ok(None);
+    }
+    if self.arch != "all" && self.arch != host_arch()? {
+      warning!("smoke tests cannot run cross builds, skipping");
+      return Ok(None);
+    }
+    segment_info!("Running smoke test...");
+    let root = tempdir()?;
+    for package in packages {
+      repo::unpack(&output.join(&**package), root.path())?;
+    }
+    let script = match test {
+      Execution::Shell(x) => Some(x.to_string()),
+      Execution::Fn(f) => {
+        let arg = [ScriptPath(root.path().into())];
+        let result: Dynamic = f.call(&self.engine, &self.ast, arg).map_err(eval_error)?;
+        take_path_error()?;
+        result.into_string().ok()
+      }
+    };
+    let passed = match script {
+      Some(x) => {
+        let dirs = |dirs: &[&str]| dirs.iter().map(|x| root.path().join(x)).collect::<Vec<_>>();
+        let mut path = dirs(&["usr/bin", "bin"]);
+        path.extend(var_os("PATH").iter().flat_map(split_paths));
+        let libs = dirs(&["usr/lib", "usr/lib64", "lib", "lib64"]);
+        let mut command = Command::new("sh");
+        command
+          .args(["-c", &format!("set -e\n{x}")])
+          .current_dir(root.path())
+          .env("PATH", join_paths(path)?)
+          .env("LD_LIBRARY_PATH", join_paths(libs)?);
+        self.priority.apply(&mut command);
+        let status = run_blocking(command, self.options.command_timeout(), None)?;
+        status.success()
+      }
+      None => true,
+    };
+    if passed {
+      println!("Smoke test passed");
+      Ok(Some(SmokeTest::Passed))
+    } else {
+      Ok(Some(SmokeTest::Failed))
+    }
+  }
 }
 
 #[derive(Debug)]
@@ -135,43 +562,170 @@ pub struct PackScript {
   packages: BTreeSet<Package>,
   source_dir: Box<Path>,
   arch: SmartString<LazyCompact>,
+  current: CurrentPackage,
+  vuln_db: Option<VulnDb>,
+  owners: Owners,
+  /// From the lockfile, exported to `pack` and the newest modification
+  /// time in packages.
+  source_date_epoch: Option<u64>,
+  options: BuildOptions,
+  static_libs: bool,
+  /// Directory of the build script, which dictionaries are relative to.
+  script_dir: Box<Path>,
+  priority: Priority,
 }
 
 impl PackScript {
-  pub fn new(path: PathBuf, source_dir: &Path, arch: String) -> anyhow::Result<Self> {
-    let (engine, mut scope) = create_engine(source_dir, arch.clone());
-    let ast = engine.compile_file_with_scope(&scope, path)?;
-    let mut value = engine.eval_ast_with_scope(&mut scope, &ast)?;
+  pub fn new(
+    path: PathBuf,
+    source_dir: &Path,
+    arch: String,
+    limits: Limits,
+    options: &BuildOptions,
+  ) -> anyhow::Result<Self> {
+    let (mut engine, mut scope) =
+      create_engine(source_dir, arch.clone(), options.bootstrap, limits);
+    let ast = engine.compile_file_with_scope(&scope, path.clone())?;
+    let mut value = (engine.eval_ast_with_scope(&mut scope, &ast)).map_err(eval_error)?;
     let source = Source::from_dynamic(&mut value)?;
+    expose_source(&mut engine, &source.info)?;
+    let lock = Lockfile::load(&Lockfile::path_for(&path))?;
+    expose_srcdirs(&mut engine, source_dir, &lock.sources);
+    let current = expose_packing(&mut engine, source_dir);
+    let vuln_db = (options.vuln_db.as_deref()).map(VulnDb::load).transpose()?;
+    let owners = Owners::find(&path)?;
+    let script_dir = path.parent().unwrap_or(Path::new(".")).into();
     Ok(Self {
       engine,
       ast,
       packages: source.packages,
       source_dir: source_dir.into(),
       arch: arch.into(),
+      current,
+      vuln_db,
+      owners,
+      source_date_epoch: lock.source_date_epoch,
+      options: options.clone(),
+      static_libs: source.info.options.contains(&ScriptOption::StaticLibs),
+      script_dir,
+      priority: Priority::load(options.nice)?,
     })
   }
 
   fn exec_shell(&self, dir: impl AsRef<Path>, x: &str) -> anyhow::Result<()> {
-    let status = Command::new("sh")
-      .args(["-c", &format!("set -e\n{x}")])
-      .current_dir(dir)
-      .status()?;
+    let mut command = Command::new("sh");
+    command.args(["-c", &format!("set -e\n{x}")]);
+    command.env("source_dir", &*self.source_dir);
+    let package_dir = (self.current.lock().unwrap().as_ref()).map(|x| x.dir.clone());
+    let mut dirs = vec![&*self.source_dir];
+    if let Some(dir) = &package_dir {
+      command.env("package_dir", dir);
+      dirs.push(dir);
+    }
+    if let Some(epoch) = self.source_date_epoch {
+      command.env("SOURCE_DATE_EPOCH", epoch.to_string());
+    }
+    command.current_dir(dir);
+    self.priority.apply(&mut command);
+    let cores = CoreDumps::new(&dirs);
+    cores.enable(&mut command);
+    let quota = self.options.disk_quota(&dirs);
+    let status = run_blocking(command, self.options.command_timeout(), quota.as_ref())?;
     if !status.success() {
+      cores.report(status);
       bail!("Shell exited with {status}");
     }
     Ok(())
   }
 
   fn exec_fn(&self, dir: impl AsRef<Path>, f: &FnPtr, args: impl FuncArgs) -> anyhow::Result<()> {
-    let result: Dynamic = f.call(&self.engine, &self.ast, args)?;
+    let result: Dynamic = f.call(&self.engine, &self.ast, args).map_err(eval_error)?;
+    take_path_error()?;
     if let Ok(x) = result.into_string() {
       self.exec_shell(dir, &x)?;
     }
     Ok(())
   }
 
-  pub fn pack(&self) -> anyhow::Result<()> {
+  /// Runs the `pack` function of `package` into a new directory, then
+  /// processes and checks what it put there. Returns the directory and the
+  /// files in it.
+  fn stage(&self, package: &Package) -> anyhow::Result<(TempDir, Vec<PathBuf>)> {
+    let package_dir = tempdir()?;
+    let path = ScriptPath(package_dir.path().into());
+    if let Some(f) = &package.pack {
+      *self.current.lock().unwrap() = Some(PackTarget {
+        name: package.info.name.to_string(),
+        dir: package_dir.path().into(),
+      });
+      let result = self.exec_fn(&self.source_dir, f, [path]);
+      *self.current.lock().unwrap() = None;
+      result?;
+    }
+
+    kmod::process_modules(package_dir.path())?;
+    let mut files = walk(package_dir.path())?;
+    if !self.static_libs {
+      let dropped = qa::drop_static_libs(package_dir.path(), &files)?;
+      if !dropped.is_empty() {
+        files.retain(|x| !dropped.contains(x));
+        let dropped = dropped.iter().map(|x| format!("/{}", x.display()));
+        warning!(
+          "static-libs: dropped {}, add `staticlibs` to the options to keep them",
+          dropped.collect::<Vec<_>>().join(", ")
+        );
+      }
+    }
+    let rpath = package.policy.rpath.unwrap_or_default();
+    qa::clean_rpaths(package_dir.path(), &files, rpath)?;
+    qa::run(
+      package,
+      package_dir.path(),
+      &files,
+      &self.source_dir,
+      self.vuln_db.as_ref(),
+      self.options.relaxed_rules(),
+    )?;
+    Ok((package_dir, files))
+  }
+
+  fn metadata(
+    &self,
+    package: &Package,
+    dir: &Path,
+    files: &[PathBuf],
+  ) -> anyhow::Result<PackageMeta> {
+    let sonames = elf::sonames(dir, files)?;
+    Ok(PackageMeta {
+      schema_version: SCHEMA_VERSION,
+      architecture: self.arch.clone(),
+      info: package.info.clone(),
+      variant: self.options.variant().into_iter().map(Into::into).collect(),
+      services: service::collect(dir, files)?,
+      trigger_hints: (desktop::trigger_hints(files).into_iter())
+        .chain(kmod::needs_depmod(dir, files).then_some("depmod"))
+        .map(Into::into)
+        .collect(),
+      kernel_releases: kmod::kernel_releases(files),
+      maintainers: self.owners.of(&package.info.name).to_vec(),
+      sonames: sonames.provided,
+      needed_sonames: sonames.needed,
+      extra: package.extra.extra.clone(),
+    })
+  }
+
+  /// Creates the writer of the package file of `package` at `path`.
+  fn writer(&self, package: &Package, path: &Path) -> anyhow::Result<Box<dyn PackageWriter>> {
+    let params = &package.compression.compression;
+    Ok(Box::new(TarZstWriter::create(
+      path,
+      params,
+      &self.script_dir,
+      self.source_date_epoch,
+    )?))
+  }
+
+  pub fn pack(&self, output: &Path) -> anyhow::Result<()> {
     for package in &self.packages {
       segment_info!(
         "Starting packing:",
@@ -179,41 +733,18 @@ impl PackScript {
         package.info.name,
         package.info.version
       );
-      let package_dir = tempdir()?;
-      let path = package_dir
-        .path()
-        .to_str()
-        .expect("tempdir path should be UTF-8")
-        .to_string();
-      if let Some(f) = &package.pack {
-        self.exec_fn(&self.source_dir, f, [path])?;
-      }
+      let (package_dir, files) = self.stage(package)?;
+      let metadata = self.metadata(package, package_dir.path(), &files)?;
+      // Packing lists the tree again as it goes, instead of keeping the
+      // whole list around while compressing.
+      let count = files.len();
+      drop(files);
 
       segment_info!("Creating tarball...");
-      let archive_name = format!(
-        "{}_{}_{}.tar.zst",
-        package.info.name, package.info.version, self.arch,
-      );
-      let mut archive = tar::Builder::new(ZstEncoder::new(File::create(&archive_name)?, 3)?);
-      archive.follow_symlinks(false);
-
-      let base = package_dir.path();
-      let mut paths = vec![];
-      let mut stack = vec![(base.to_path_buf(), true)];
-      while let Some((path, is_dir)) = stack.pop() {
-        if is_dir {
-          for entry in path.read_dir()? {
-            let entry = entry?;
-            let file_type = entry.file_type()?;
-            stack.push((entry.path(), file_type.is_dir()))
-          }
-        }
-        if path != base {
-          paths.push(path);
-        }
-      }
+      let archive_name = archive_name(&package.info, &self.arch, &self.options);
+      let mut writer = self.writer(package, &output.join(&archive_name))?;
 
-      let pb = ProgressBar::new(paths.len() as _);
+      let pb = ProgressBar::new(count as _);
       pb.set_message(archive_name);
       pb.set_prefix("packing");
       let style = ProgressStyle::with_template(PB_STYLE)
@@ -221,25 +752,9 @@ impl PackScript {
         .progress_chars("=> ");
       pb.set_style(style);
 
-      for path in paths {
-        let name = path.strip_prefix(base)?;
-        archive.append_path_with_name(&path, name)?;
-        pb.inc(1);
-      }
-
-      let metadata = PackageMeta {
-        architecture: self.arch.clone(),
-        info: package.info.clone(),
-      };
-      let metadata = serde_json::to_vec_pretty(&metadata)?;
-      let mut header = tar::Header::new_old();
-      header.set_size(metadata.len() as _);
-      header.set_path("metadata.json")?;
-      header.set_mode(0o644);
-      header.set_cksum();
-      archive.append(&header, &*metadata)?;
-
-      archive.into_inner()?.finish()?;
+      let mut files = Walk::new(package_dir.path());
+      writer.append_files(package_dir.path(), &mut files, &pb)?;
+      writer.finish(&metadata)?;
       pb.set_prefix("done");
       pb.finish();
     }
@@ -0,0 +1,97 @@
+use super::engine::{create_engine, eval_error, Limits};
+use super::script::host_arch;
+use rhai::{CallFnOptions, Dynamic, Engine, EvalAltResult};
+use std::path::Path;
+use tempfile::tempdir;
+
+type RhaiResult<T> = Result<T, Box<EvalAltResult>>;
+
+/// Values compare equal when they have the same type and representation, as
+/// script values have no general equality.
+fn same(a: &Dynamic, b: &Dynamic) -> bool {
+  a.type_name() == b.type_name() && format!("{a:?}") == format!("{b:?}")
+}
+
+fn register_asserts(engine: &mut Engine) {
+  engine
+    .register_fn("assert", |cond: bool| -> RhaiResult<()> {
+      cond.then_some(()).ok_or_else(|| "assertion failed".into())
+    })
+    .register_fn("assert", |cond: bool, message: &str| -> RhaiResult<()> {
+      cond.then_some(()).ok_or_else(|| message.into())
+    })
+    .register_fn("assert_eq", |a: Dynamic, b: Dynamic| -> RhaiResult<()> {
+      match same(&a, &b) {
+        true => Ok(()),
+        false => Err(format!("assertion failed: {a:?} == {b:?}").into()),
+      }
+    })
+    .register_fn("assert_ne", |a: Dynamic, b: Dynamic| -> RhaiResult<()> {
+      match same(&a, &b) {
+        true => Err(format!("assertion failed: {a:?} != {b:?}").into()),
+        false => Ok(()),
+      }
+    });
+}
+
+/// Runs the `test_*` functions without parameters in the script at `path`,
+/// in the same sandboxed engine as build scripts plus assert builtins.
+/// Returns each test's name and the error it failed with.
+pub fn run_tests(path: &Path, limits: Limits) -> anyhow::Result<Vec<(String, Option<String>)>> {
+  let source_dir = tempdir()?;
+  let (mut engine, mut scope) = create_engine(source_dir.path(), host_arch()?, false, limits);
+  register_asserts(&mut engine);
+  let ast = engine.compile_file_with_scope(&scope, path.into())?;
+  (engine.run_ast_with_scope(&mut scope, &ast)).map_err(eval_error)?;
+
+  let mut tests = (ast.iter_functions())
+    .filter(|x| x.name.starts_with("test_") && x.params.is_empty())
+    .map(|x| x.name.to_string())
+    .collect::<Vec<_>>();
+  tests.sort();
+  let results = (tests.into_iter())
+    .map(|name| {
+      let result = engine.call_fn_with_options::<Dynamic>(
+        CallFnOptions::new().eval_ast(false).rewind_scope(true),
+        &mut scope,
+        &ast,
+        &name,
+        (),
+      );
+      let error = result.err().map(|e| eval_error(e).to_string());
+      (name, error)
+    })
+    .collect();
+  Ok(results)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::fs::write;
+
+  #[test]
+  fn test_run_tests() {
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("helpers.rhai");
+    let module = r#"
+      fn cmake_args(prefix) { ["-DCMAKE_INSTALL_PREFIX=" + prefix] }
+      fn test_prefix() { assert_eq(cmake_args("/usr"), ["-DCMAKE_INSTALL_PREFIX=/usr"]); }
+      fn test_types() { assert_eq(1, "1"); }
+      fn test_message() { assert(false, "custom message"); }
+      fn test_with_args(x) { assert(false); }
+    "#;
+    write(&path, module).unwrap();
+    let limits = Limits {
+      timeout: 30,
+      max_operations: 10_000,
+      max_size: 1024,
+    };
+    let results = run_tests(&path, limits).unwrap();
+    let names = results.iter().map(|x| &*x.0).collect::<Vec<_>>();
+    assert_eq!(names, ["test_message", "test_prefix", "test_types"]);
+    assert!(results[0].1.as_ref().unwrap().contains("custom message"));
+    assert!(results[1].1.is_none());
+    assert!(results[2].1.is_some());
+  }
+}
@@ -0,0 +1,273 @@
+use crate::util::file_mode;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+const SYSTEMD_SYSTEM_DIRS: &[&str] = &["usr/lib/systemd/system", "etc/systemd/system"];
+const SYSTEMD_USER_DIRS: &[&str] = &["usr/lib/systemd/user", "etc/systemd/user"];
+const OPENRC_DIR: &str = "etc/init.d";
+
+/// Sections allowed in each kind of unit, besides `[Unit]`, `[Install]` and
+/// `X-` extensions.
+const UNIT_SECTIONS: &[(&str, &[&str])] = &[
+  ("service", &["Service"]),
+  ("socket", &["Socket"]),
+  ("timer", &["Timer"]),
+  ("path", &["Path"]),
+  ("mount", &["Mount"]),
+  ("automount", &["Automount"]),
+  ("swap", &["Swap"]),
+  ("slice", &["Slice"]),
+  ("scope", &["Scope"]),
+  ("target", &[]),
+];
+
+/// Services a package ships that can be enabled, recorded in its metadata for
+/// the installer.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Services {
+  #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+  pub systemd: BTreeSet<Box<str>>,
+
+  #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+  pub systemd_user: BTreeSet<Box<str>>,
+
+  #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+  pub openrc: BTreeSet<Box<str>>,
+}
+
+impl Services {
+  pub fn is_empty(&self) -> bool {
+    self.systemd.is_empty() && self.systemd_user.is_empty() && self.openrc.is_empty()
+  }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ServiceKind {
+  Systemd { user: bool },
+  OpenRc,
+}
+
+/// Tells whether `file`, relative to the package root, is a service
+/// definition. Drop-ins and other files in subdirectories are not.
+pub fn classify(file: &Path) -> Option<ServiceKind> {
+  let parent = file.parent()?.to_str()?;
+  let name = file.file_name()?.to_str()?;
+  if SYSTEMD_SYSTEM_DIRS.contains(&parent) || SYSTEMD_USER_DIRS.contains(&parent) {
+    let (_, ext) = name.rsplit_once('.')?;
+    (UNIT_SECTIONS.iter()).find(|(x, _)| *x == ext)?;
+    let user = SYSTEMD_USER_DIRS.contains(&parent);
+    Some(ServiceKind::Systemd { user })
+  } else if parent == OPENRC_DIR {
+    Some(ServiceKind::OpenRc)
+  } else {
+    None
+  }
+}
+
+/// A parsed systemd unit file.
+#[derive(Debug, Default)]
+pub struct Unit {
+  sections: Vec<(String, Vec<(String, String)>)>,
+}
+
+impl Unit {
+  /// Parses the unit file format: sections of `key=value` lines, with `#` and
+  /// `;` comments and backslash continuations.
+  pub fn parse(text: &str) -> Result<Self, String> {
+    let mut unit = Self::default();
+    let mut lines = text.lines().enumerate();
+    while let Some((i, line)) = lines.next() {
+      let mut line = line.trim().to_string();
+      if line.is_empty() || line.starts_with(['#', ';']) {
+        continue;
+      }
+      while line.ends_with('\\') {
+        line.pop();
+        line.truncate(line.trim_end().len());
+        match lines.next() {
+          Some((_, next)) if !next.trim_start().starts_with(['#', ';']) => {
+            line.push(' ');
+            line.push_str(next.trim());
+          }
+          Some(_) => {}
+          None => break,
+        }
+      }
+      let n = i + 1;
+      if let Some(name) = line.strip_prefix('[') {
+        let name = (name.strip_suffix(']'))
+          .filter(|x| !x.is_empty())
+          .ok_or_else(|| format!("line {n}: malformed section header"))?;
+        unit.sections.push((name.into(), Vec::new()));
+        continue;
+      }
+      let Some((key, value)) = line.split_once('=') else {
+        return Err(format!("line {n}: expected `key=value`"));
+      };
+      let key = key.trim();
+      if key.is_empty() || key.contains(char::is_whitespace) {
+        return Err(format!("line {n}: invalid key `{key}`"));
+      }
+      let Some((_, entries)) = unit.sections.last_mut() else {
+        return Err(format!("line {n}: assignment outside of any section"));
+      };
+      entries.push((key.into(), value.trim().into()));
+    }
+    Ok(unit)
+  }
+
+  fn section<'a>(&'a self, name: &'a str) -> impl Iterator<Item = (&'a str, &'a str)> {
+    (self.sections.iter())
+      .filter(move |(x, _)| x == name)
+      .flat_map(|(_, x)| x.iter().map(|(k, v)| (&**k, &**v)))
+  }
+
+  fn get_all<'a>(&'a self, section: &'a str, key: &'a str) -> impl Iterator<Item = &'a str> {
+    (self.section(section))
+      .filter(move |(k, _)| *k == key)
+      .map(|(_, v)| v)
+  }
+
+  /// Whether the unit can be enabled, i.e. says where to hook itself in.
+  pub fn is_installable(&self) -> bool {
+    (self.section("Install"))
+      .any(|(k, _)| ["WantedBy", "RequiredBy", "UpheldBy", "Alias", "Also"].contains(&k))
+  }
+
+  /// Looks for mistakes that would keep systemd from loading the unit `name`.
+  pub fn check(&self, name: &str) -> Vec<String> {
+    let ext = name.rsplit_once('.').map_or("", |(_, x)| x);
+    let allowed = (UNIT_SECTIONS.iter())
+      .find(|(x, _)| *x == ext)
+      .map_or(&[][..], |(_, x)| *x);
+    let mut problems = Vec::new();
+    for (section, entries) in &self.sections {
+      if !(["Unit", "Install"].contains(&&**section)
+        || allowed.contains(&&**section)
+        || section.starts_with("X-"))
+      {
+        problems.push(format!("unknown section [{section}]"));
+      }
+      for (key, value) in entries {
+        if !key.starts_with("Exec") {
+          continue;
+        }
+        // Commands may be prefixed with flags like `-` and `@`.
+        let cmd = value.trim_start_matches(['@', '-', ':', '+', '!', '|']);
+        let cmd = cmd.split_whitespace().next().unwrap_or_default();
+        if cmd.contains('/') && !cmd.starts_with('/') {
+          problems.push(format!("{key}= uses relative path `{cmd}`"));
+        }
+      }
+    }
+
+    match ext {
+      "service" => {
+        let oneshot = self.get_all("Service", "Type").last() == Some("oneshot");
+        let starts = (self.get_all("Service", "ExecStart"))
+          .filter(|x| !x.is_empty())
+          .count();
+        if starts == 0 && !oneshot {
+          problems.push("no ExecStart= in [Service]".into());
+        } else if starts > 1 && !oneshot {
+          problems.push("multiple ExecStart= are only allowed with Type=oneshot".into());
+        }
+      }
+      "socket" if !self.section("Socket").any(|(k, _)| k.starts_with("Listen")) => {
+        problems.push("no Listen*= in [Socket]".into());
+      }
+      "timer" if !self.section("Timer").any(|(k, _)| k.starts_with("On")) => {
+        problems.push("no On*= trigger in [Timer]".into());
+      }
+      _ => {}
+    }
+    problems
+  }
+}
+
+/// Looks for mistakes that would keep OpenRC from running an init script.
+pub fn check_init_script(path: &Path) -> io::Result<Vec<String>> {
+  let mut problems = Vec::new();
+  if file_mode(&fs::metadata(path)?) & 0o111 == 0 {
+    problems.push("not executable".into());
+  }
+  let content = fs::read(path)?;
+  let first_line = content.split(|x| *x == b'\n').next().unwrap_or_default();
+  let first_line = String::from_utf8_lossy(first_line);
+  if !first_line.starts_with("#!") || !first_line.contains("openrc-run") {
+    problems.push("does not start with `#!/sbin/openrc-run`".into());
+  }
+  Ok(problems)
+}
+
+/// Collects the services among `files` that can be enabled.
+pub fn collect(package_dir: &Path, files: &[PathBuf]) -> io::Result<Services> {
+  let mut services = Services::default();
+  for file in files {
+    let Some(kind) = classify(file) else {
+      continue;
+    };
+    let path = package_dir.join(file);
+    // Aliases point to units recorded by their own name.
+    if !path.symlink_metadata()?.is_file() {
+      continue;
+    }
+    let name: Box<str> = file.file_name().unwrap().to_string_lossy().into();
+    match kind {
+      ServiceKind::Systemd { user } => {
+        let Ok(unit) = Unit::parse(&fs::read_to_string(path)?) else {
+          continue;
+        };
+        if unit.is_installable() {
+          if user {
+            services.systemd_user.insert(name);
+          } else {
+            services.systemd.insert(name);
+          }
+        }
+      }
+      ServiceKind::OpenRc => {
+        services.openrc.insert(name);
+      }
+    }
+  }
+  Ok(services)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_unit() {
+    let unit = Unit::parse(
+      "# comment\n[Unit]\nDescription=Foo \\\n  daemon\n\n[Service]\nExecStart=-/usr/bin/foo\nExecStart=/usr/bin/foo -x\n[Install]\nWantedBy=multi-user.target\n",
+    )
+    .unwrap();
+    assert_eq!(
+      unit.get_all("Unit", "Description").collect::<Vec<_>>(),
+      ["Foo daemon"]
+    );
+    assert!(unit.is_installable());
+    assert_eq!(
+      unit.check("foo.service"),
+      ["multiple ExecStart= are only allowed with Type=oneshot"]
+    );
+    assert_eq!(
+      unit.check("foo.socket"),
+      ["unknown section [Service]", "no Listen*= in [Socket]"]
+    );
+
+    assert!(Unit::parse("Description=x\n").is_err());
+    assert!(Unit::parse("[Unit]\nDescription\n").is_err());
+    let unit = Unit::parse("[Service]\nType=oneshot\nExecStart=bin/foo\n").unwrap();
+    assert!(!unit.is_installable());
+    assert_eq!(
+      unit.check("foo.service"),
+      ["ExecStart= uses relative path `bin/foo`"]
+    );
+  }
+}
@@ -0,0 +1,183 @@
+use crate::util::par_map;
+use indicatif::ProgressBar;
+use std::fs::{self, read_link, File, Metadata};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::sync_channel;
+use std::thread;
+
+/// Regular files up to this size are read ahead on worker threads.
+const READ_AHEAD_MAX: u64 = 256 << 10;
+
+/// Files looked at ahead at once.
+const BATCH_LEN: usize = 64;
+
+/// Metadata of a file, and its content if it is small enough to be read ahead.
+type Prefetched = io::Result<(Metadata, Option<Vec<u8>>)>;
+
+fn prefetch(path: &Path) -> Prefetched {
+  let meta = path.symlink_metadata()?;
+  let content = match meta.is_file() && meta.len() <= READ_AHEAD_MAX {
+    true => Some(fs::read(path)?),
+    false => None,
+  };
+  Ok((meta, content))
+}
+
+/// Appends `files`, relative to `base`, to `archive` in order, as they are
+/// listed. Packing many small files is mostly spent opening and reading them,
+/// so those are read on worker threads while earlier ones are being
+/// compressed; larger files and everything else are appended as usual. Only
+/// a couple of batches are held in memory, however many files there are.
+/// Modification times after `max_mtime` are recorded as `max_mtime`.
+pub fn append_files<W: Write>(
+  archive: &mut tar::Builder<W>,
+  base: &Path,
+  files: impl Iterator<Item = io::Result<PathBuf>> + Send,
+  max_mtime: Option<u64>,
+  pb: &ProgressBar,
+) -> anyhow::Result<()> {
+  thread::scope(|s| {
+    // One batch waits while the next is read.
+    let (tx, rx) = sync_channel(1);
+    s.spawn(move || {
+      let mut files = files.peekable();
+      while files.peek().is_some() {
+        let batch = (files.by_ref().take(BATCH_LEN)).collect::<io::Result<Vec<_>>>();
+        let batch = batch.map(|names| {
+          let prefetched = par_map(&names, 0, |x| prefetch(&base.join(x)));
+          names.into_iter().zip(prefetched).collect::<Vec<_>>()
+        });
+        let failed = batch.is_err();
+        if tx.send(batch).is_err() || failed {
+          break;
+        }
+      }
+    });
+    for batch in rx {
+      for (name, prefetched) in batch? {
+        let (meta, data) = prefetched?;
+        let mut header = tar::Header::new_gnu();
+        header.set_metadata(&meta);
+        if let Some(max) = max_mtime {
+          header.set_mtime(header.mtime()?.min(max));
+        }
+        let path = base.join(&name);
+        match data {
+          Some(data) => {
+            header.set_size(data.len() as _);
+            archive.append_data(&mut header, name, &*data)?;
+          }
+          None if meta.is_file() => archive.append_data(&mut header, name, File::open(path)?)?,
+          None if meta.is_symlink() => archive.append_link(&mut header, name, read_link(path)?)?,
+          None if meta.is_dir() => archive.append_data(&mut header, name, io::empty())?,
+          None => archive.append_path_with_name(path, name)?,
+        }
+        pb.inc(1);
+      }
+    }
+    Ok(())
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::io::Read;
+  use std::sync::atomic::{AtomicU64, Ordering};
+  use std::time::Instant;
+  use tempfile::tempdir;
+
+  fn tree(files: usize) -> (tempfile::TempDir, Vec<PathBuf>) {
+    let dir = tempdir().unwrap();
+    let mut list = vec![PathBuf::from("d")];
+    fs::create_dir(dir.path().join("d")).unwrap();
+    for i in 0..files {
+      let name = PathBuf::from(format!("d/f{i}"));
+      fs::write(dir.path().join(&name), format!("file {i}\n")).unwrap();
+      list.push(name);
+    }
+    (dir, list)
+  }
+
+  #[test]
+  #[cfg(unix)]
+  fn test_append_files() {
+    let (dir, mut files) = tree(200);
+    let big = vec![7; READ_AHEAD_MAX as usize + 1];
+    fs::write(dir.path().join("big"), &big).unwrap();
+    std::os::unix::fs::symlink("d/f1", dir.path().join("link")).unwrap();
+    files.extend(["big", "link"].map(PathBuf::from));
+
+    let mut archive = tar::Builder::new(Vec::new());
+    archive.follow_symlinks(false);
+    let pb = ProgressBar::hidden();
+    let list = files.iter().cloned().map(Ok);
+    append_files(&mut archive, dir.path(), list, Some(1), &pb).unwrap();
+    let data = archive.into_inner().unwrap();
+
+    let mut archive = tar::Archive::new(&*data);
+    let mut names = Vec::new();
+    for entry in archive.entries().unwrap() {
+      let mut entry = entry.unwrap();
+      let name = entry.path().unwrap().into_owned();
+      assert_eq!(entry.header().mtime().unwrap(), 1);
+      let mut content = Vec::new();
+      entry.read_to_end(&mut content).unwrap();
+      match name.to_str().unwrap() {
+        "d/f42" => assert_eq!(content, b"file 42\n"),
+        "big" => assert_eq!(content, big),
+        "link" => assert_eq!(entry.link_name().unwrap().unwrap(), Path::new("d/f1")),
+        _ => {}
+      }
+      names.push(name);
+    }
+    assert_eq!(names, files);
+  }
+
+  /// However many files there are, only a few batches of them are listed
+  /// ahead of those already appended.
+  #[test]
+  fn test_append_files_bounded() {
+    const COUNT: u64 = 5_000;
+    let (dir, _) = tree(1);
+    let pb = ProgressBar::hidden();
+    let listed = AtomicU64::new(0);
+    let ahead = AtomicU64::new(0);
+    let files = (0..COUNT).map(|_| {
+      let listed = listed.fetch_add(1, Ordering::Relaxed) + 1;
+      ahead.fetch_max(listed - pb.position(), Ordering::Relaxed);
+      Ok(PathBuf::from("d/f0"))
+    });
+    let mut archive = tar::Builder::new(io::sink());
+    append_files(&mut archive, dir.path(), files, None, &pb).unwrap();
+    assert_eq!(pb.position(), COUNT);
+    let ahead = ahead.into_inner();
+    assert!(ahead <= 3 * BATCH_LEN as u64, "{ahead} files listed ahead");
+  }
+
+  /// Packing time should grow linearly with the number of files. Run alone,
+  /// as other tests disturb it.
+  #[test]
+  #[ignore = "benchmark, run with --ignored"]
+  fn bench_append_files() {
+    let mut per_file = Vec::new();
+    for count in [5_000, 20_000] {
+      let (dir, files) = tree(count);
+      let start = Instant::now();
+      let encoder = zstd::stream::Encoder::new(io::sink(), 3).unwrap();
+      let mut archive = tar::Builder::new(encoder);
+      append_files(
+        &mut archive,
+        dir.path(),
+        files.into_iter().map(Ok),
+        None,
+        &ProgressBar::hidden(),
+      )
+      .unwrap();
+      archive.into_inner().unwrap().finish().unwrap();
+      per_file.push(start.elapsed() / count as u32);
+    }
+    assert!(per_file[1] < per_file[0] * 3, "{per_file:?}");
+  }
+}
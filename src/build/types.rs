@@ -1,12 +1,10 @@
-use crate::types::{ArchList, OptionalDepends, PackageInfo, PackageName, SourceInfo};
-use crate::version::PackageVersion;
+use crate::types::{PackageInfo, PackageInfoDelta, SourceInfo};
 use anyhow::bail;
-use reqwest::Url;
 use rhai::serde::from_dynamic;
 use rhai::EvalAltResult::ErrorMismatchDataType;
 use rhai::{Dynamic, EvalAltResult, FnPtr, Map, Position};
 use serde::Deserialize;
-use std::collections::BTreeSet;
+use std::collections::{BTreeMap, BTreeSet};
 use std::fmt;
 use std::fmt::{Debug, Formatter};
 use std::ops::Deref;
@@ -53,44 +51,94 @@ impl Execution {
   }
 }
 
-#[derive(Debug, Deserialize)]
-struct PackageInfoDelta {
-  name: Option<PackageName>,
-  description: Option<Box<str>>,
-  version: Option<PackageVersion>,
-  architecture: Option<ArchList>,
-  homepage: Option<Url>,
+/// What to do with RPATH and RUNPATH entries of packaged ELF files.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RpathMode {
+  /// Leave them as they are.
+  Keep,
+  /// Drop empty, relative, duplicate and default library directories.
+  #[default]
+  Clean,
+  /// Remove them altogether.
+  Strip,
+}
 
+/// Exceptions to QA rules a package has to declare explicitly. Paths are
+/// absolute, as installed.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct QaPolicy {
+  /// Files that may be setuid or setgid.
   #[serde(default)]
-  provides: Option<BTreeSet<PackageName>>,
+  pub permitted_setuid: BTreeSet<Box<str>>,
 
+  /// Paths that may be writable by everyone.
   #[serde(default)]
-  conflicts: Option<BTreeSet<PackageName>>,
+  pub permitted_world_writable: BTreeSet<Box<str>>,
 
   #[serde(default)]
-  depends: Option<BTreeSet<PackageName>>,
+  pub rpath: Option<RpathMode>,
+}
+
+impl QaPolicy {
+  fn merge(mut self, other: &Self) -> Self {
+    (self.permitted_setuid).extend(other.permitted_setuid.iter().cloned());
+    (self.permitted_world_writable).extend(other.permitted_world_writable.iter().cloned());
+    self.rpath = self.rpath.or(other.rpath);
+    self
+  }
+}
 
+/// Custom metadata carried verbatim into `metadata.json` for downstream
+/// tools, under its `extra` key so it cannot collide with official fields.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct Extra {
   #[serde(default)]
-  optional_depends: Option<BTreeSet<OptionalDepends>>,
-}
-
-impl PackageInfoDelta {
-  fn merge_into(self, info: &PackageInfo) -> PackageInfo {
-    PackageInfo {
-      name: self.name.unwrap_or_else(|| info.name.clone()),
-      description: self.description.unwrap_or_else(|| info.description.clone()),
-      version: self.version.unwrap_or_else(|| info.version.clone()),
-      architecture: self
-        .architecture
-        .unwrap_or_else(|| info.architecture.clone()),
-      homepage: self.homepage.or_else(|| info.homepage.clone()),
-      provides: self.provides.unwrap_or_else(|| info.provides.clone()),
-      conflicts: self.conflicts.unwrap_or_else(|| info.conflicts.clone()),
-      depends: self.depends.unwrap_or_else(|| info.depends.clone()),
-      optional_depends: self
-        .optional_depends
-        .unwrap_or_else(|| info.optional_depends.clone()),
+  pub extra: BTreeMap<Box<str>, Box<str>>,
+}
+
+impl Extra {
+  fn merge(mut self, other: &Self) -> Self {
+    for (key, value) in &other.extra {
+      self
+        .extra
+        .entry(key.clone())
+        .or_insert_with(|| value.clone());
     }
+    self
+  }
+}
+
+/// Zstd parameters for the package archive.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ZstdParams {
+  /// Compression level, 3 by default.
+  pub level: Option<i32>,
+
+  /// Long distance matching over a 128 MiB window, for large packages such
+  /// as kernels and firmware.
+  pub long: Option<bool>,
+
+  /// Dictionary trained with `zstd --train`, relative to the build script,
+  /// for small text-heavy packages. Readers need it as well, so it is copied
+  /// to `dictionaries/` next to the archive.
+  pub dictionary: Option<Box<str>>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct Compression {
+  #[serde(default)]
+  pub compression: ZstdParams,
+}
+
+impl Compression {
+  fn merge(mut self, other: &Self) -> Self {
+    let (x, other) = (&mut self.compression, &other.compression);
+    x.level = x.level.or(other.level);
+    x.long = x.long.or(other.long);
+    x.dictionary = x.dictionary.take().or_else(|| other.dictionary.clone());
+    self
   }
 }
 
@@ -98,12 +146,18 @@ impl PackageInfoDelta {
 pub struct Package {
   pub info: PackageInfo,
   pub pack: Option<FnPtr>,
+  pub policy: QaPolicy,
+  pub extra: Extra,
+  pub compression: Compression,
 }
 
 impl Package {
   pub fn from_dynamic_delta(
     value: &mut Dynamic,
     fallback: &PackageInfo,
+    policy: &QaPolicy,
+    extra: &Extra,
+    compression: &Compression,
   ) -> Result<Self, Box<EvalAltResult>> {
     let type_name = value.type_name();
     let mut map = value.write_lock::<Map>().ok_or_else(|| {
@@ -117,7 +171,16 @@ impl Package {
     drop(map);
     let delta: PackageInfoDelta = from_dynamic(value)?;
     let info = delta.merge_into(fallback);
-    Ok(Self { info, pack })
+    let policy = from_dynamic::<QaPolicy>(value)?.merge(policy);
+    let extra = from_dynamic::<Extra>(value)?.merge(extra);
+    let compression = from_dynamic::<Compression>(value)?.merge(compression);
+    Ok(Self {
+      info,
+      pack,
+      policy,
+      extra,
+      compression,
+    })
   }
 }
 
@@ -155,11 +218,17 @@ pub struct Source {
   pub prepare: Option<Execution>,
   pub build: Option<Execution>,
   pub check: Option<Execution>,
+  /// Run against the unpacked packages with `--smoke-test`.
+  pub test: Option<Execution>,
   pub packages: BTreeSet<Package>,
+  /// The evaluated script with functions by name, for checking what does
+  /// not make it into the fields above.
+  pub raw: serde_json::Value,
 }
 
 impl Source {
   pub fn from_dynamic(value: &mut Dynamic) -> anyhow::Result<Self> {
+    let raw = serde_json::to_value(&*value).unwrap_or_default();
     let type_name = value.type_name();
     let mut map = value.write_lock::<Map>().ok_or_else(|| {
       Box::new(ErrorMismatchDataType(
@@ -168,11 +237,11 @@ impl Source {
         Position::NONE,
       ))
     })?;
-    let mut execs = [None, None, None];
-    for (i, name) in ["prepare", "build", "check"].iter().enumerate() {
+    let mut execs = [None, None, None, None];
+    for (i, name) in ["prepare", "build", "check", "test"].iter().enumerate() {
       execs[i] = map.remove(*name).map(Execution::from_dynamic).transpose()?;
     }
-    let [prepare, build, check] = execs;
+    let [prepare, build, check, test] = execs;
 
     let pack = map.remove("pack").map(fnptr_from_dynamic).transpose()?;
     let packages_repr = map
@@ -193,10 +262,18 @@ impl Source {
 
     drop(map);
     let info: SourceInfo = from_dynamic(value)?;
+    let policy: QaPolicy = from_dynamic(value)?;
+    let extra: Extra = from_dynamic(value)?;
+    let compression: Compression = from_dynamic(value)?;
+    // Packages are ordered by name alone, which the functions they hold
+    // cannot change.
+    #[allow(clippy::mutable_key_type)]
     let mut packages = BTreeSet::new();
     if let Some(packages_repr) = packages_repr {
       for mut package in packages_repr {
-        packages.insert(Package::from_dynamic_delta(&mut package, &info)?);
+        let package =
+          Package::from_dynamic_delta(&mut package, &info, &policy, &extra, &compression)?;
+        packages.insert(package);
       }
     } else {
       if !info.architecture.is_valid_for_package() {
@@ -205,6 +282,9 @@ impl Source {
       packages.insert(Package {
         info: info.inner.clone(),
         pack,
+        policy,
+        extra,
+        compression,
       });
     }
 
@@ -213,7 +293,9 @@ impl Source {
       prepare,
       build,
       check,
+      test,
       packages,
+      raw,
     })
   }
 }
@@ -0,0 +1,262 @@
+use crate::util::scan_file;
+use crate::version::cmp_version;
+use anyhow::Context;
+use memchr::memmem;
+use serde::Deserialize;
+use std::cmp::Ordering;
+use std::collections::BTreeSet;
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::path::Path;
+
+/// How a library gives away its version in binaries it is linked into.
+struct Signature {
+  library: &'static str,
+  prefix: &'static [u8],
+  /// Required right after the version, for prefixes too common on their own.
+  suffix: &'static [u8],
+}
+
+const SIGNATURES: &[Signature] = &[
+  Signature {
+    library: "zlib",
+    prefix: b" deflate ",
+    suffix: b" Copyright",
+  },
+  Signature {
+    library: "zlib",
+    prefix: b" inflate ",
+    suffix: b" Copyright",
+  },
+  Signature {
+    library: "openssl",
+    prefix: b"OpenSSL ",
+    suffix: b" ",
+  },
+  Signature {
+    library: "libpng",
+    prefix: b"libpng version ",
+    suffix: b"",
+  },
+  Signature {
+    library: "curl",
+    prefix: b"libcurl/",
+    suffix: b"",
+  },
+  Signature {
+    library: "expat",
+    prefix: b"expat_",
+    suffix: b"",
+  },
+];
+
+const MAX_VERSION_LEN: usize = 32;
+
+fn detect_in(data: &[u8], found: &mut BTreeSet<(&'static str, String)>) {
+  for sig in SIGNATURES {
+    for pos in memmem::find_iter(data, sig.prefix) {
+      let rest = &data[pos + sig.prefix.len()..];
+      let len = (rest.iter())
+        .take_while(|x| x.is_ascii_alphanumeric() || **x == b'.')
+        .count();
+      let version = &rest[..len];
+      // Versions cut off at the end of a chunk show up again in the next one.
+      if len == rest.len()
+        || !(1..=MAX_VERSION_LEN).contains(&len)
+        || !version[0].is_ascii_digit()
+        || !version.contains(&b'.')
+        || !rest[len..].starts_with(sig.suffix)
+      {
+        continue;
+      }
+      found.insert((sig.library, String::from_utf8_lossy(version).into()));
+    }
+  }
+}
+
+/// Finds versions of well-known libraries embedded in the file at `path`.
+pub fn detect_libraries(path: &Path) -> io::Result<BTreeSet<(&'static str, String)>> {
+  let overlap = (SIGNATURES.iter())
+    .map(|x| x.prefix.len() + MAX_VERSION_LEN + x.suffix.len() + 1)
+    .max()
+    .unwrap_or(0);
+  let mut found = BTreeSet::new();
+  scan_file(path, overlap, |chunk| detect_in(chunk, &mut found))?;
+  Ok(found)
+}
+
+/// The parts of an OSV record we care about, see https://ossf.github.io/osv-schema/.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Vulnerability {
+  pub id: String,
+  #[serde(default)]
+  pub aliases: Vec<String>,
+  #[serde(default)]
+  affected: Vec<Affected>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct Affected {
+  package: Option<AffectedPackage>,
+  #[serde(default)]
+  versions: Vec<String>,
+  #[serde(default)]
+  ranges: Vec<AffectedRange>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct AffectedPackage {
+  name: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct AffectedRange {
+  #[serde(rename = "type")]
+  kind: String,
+  #[serde(default)]
+  events: Vec<RangeEvent>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum RangeEvent {
+  Introduced(String),
+  Fixed(String),
+  LastAffected(String),
+  Limit(String),
+}
+
+impl AffectedRange {
+  fn contains(&self, version: &str) -> bool {
+    // Git ranges are about commits, which we cannot tell from a binary.
+    if self.kind == "GIT" {
+      return false;
+    }
+    let at_least = |x: &str| x == "0" || cmp_version(version, x) != Ordering::Less;
+    let mut introduced = None;
+    for event in &self.events {
+      match event {
+        RangeEvent::Introduced(x) => introduced = Some(x),
+        RangeEvent::Fixed(x) | RangeEvent::Limit(x) => {
+          if introduced.is_some_and(|i| at_least(i)) && cmp_version(version, x) == Ordering::Less {
+            return true;
+          }
+          introduced = None;
+        }
+        RangeEvent::LastAffected(x) => {
+          if introduced.is_some_and(|i| at_least(i)) && cmp_version(version, x) != Ordering::Greater
+          {
+            return true;
+          }
+          introduced = None;
+        }
+      }
+    }
+    introduced.is_some_and(|i| at_least(i))
+  }
+}
+
+impl Vulnerability {
+  fn affects(&self, library: &str, version: &str) -> bool {
+    (self.affected.iter())
+      .filter(|x| (x.package.as_ref()).is_some_and(|x| x.name.eq_ignore_ascii_case(library)))
+      .any(|x| {
+        x.versions.iter().any(|x| x == version) || x.ranges.iter().any(|x| x.contains(version))
+      })
+  }
+
+  /// The most recognizable name of this vulnerability.
+  pub fn name(&self) -> &str {
+    (self.aliases.iter())
+      .find(|x| x.starts_with("CVE-"))
+      .unwrap_or(&self.id)
+  }
+}
+
+/// A local vulnerability database in OSV format.
+#[derive(Debug, Clone, Default)]
+pub struct VulnDb {
+  vulns: Vec<Vulnerability>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum OneOrMany {
+  One(Box<Vulnerability>),
+  Many(Vec<Vulnerability>),
+}
+
+impl VulnDb {
+  /// Loads the database at `path`: a JSON file holding one or more records,
+  /// or a directory of such files.
+  pub fn load(path: &Path) -> anyhow::Result<Self> {
+    let mut db = Self::default();
+    if path.is_dir() {
+      for entry in path.read_dir()? {
+        let path = entry?.path();
+        if path.extension().is_some_and(|x| x == "json") {
+          db.load_file(&path)?;
+        }
+      }
+    } else {
+      db.load_file(path)?;
+    }
+    Ok(db)
+  }
+
+  fn load_file(&mut self, path: &Path) -> anyhow::Result<()> {
+    let f = BufReader::new(File::open(path)?);
+    let records = serde_json::from_reader(f)
+      .with_context(|| format!("invalid OSV record in {}", path.display()))?;
+    match records {
+      OneOrMany::One(x) => self.vulns.push(*x),
+      OneOrMany::Many(x) => self.vulns.extend(x),
+    }
+    Ok(())
+  }
+
+  pub fn query<'a>(
+    &'a self,
+    library: &'a str,
+    version: &'a str,
+  ) -> impl Iterator<Item = &'a Vulnerability> + 'a {
+    (self.vulns.iter()).filter(move |x| x.affects(library, version))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_detect_libraries() {
+    let mut found = BTreeSet::new();
+    let data = b"\0 deflate 1.2.11 Copyright 1995-2017 Jean-loup Gailly\0OpenSSL 1.1.1k  25 Mar 2021\0 deflate 1.2";
+    detect_in(data, &mut found);
+    let found = found.into_iter().collect::<Vec<_>>();
+    assert_eq!(
+      found,
+      [("openssl", "1.1.1k".into()), ("zlib", "1.2.11".into())]
+    );
+  }
+
+  #[test]
+  fn test_affected_ranges() {
+    let vuln: Vulnerability = serde_json::from_str(
+      r#"{
+        "id": "OSV-1", "aliases": ["CVE-2022-37434"],
+        "affected": [{
+          "package": { "ecosystem": "OSS-Fuzz", "name": "zlib" },
+          "ranges": [{ "type": "ECOSYSTEM", "events": [{ "introduced": "0" }, { "fixed": "1.2.12" }] }],
+          "versions": ["1.2.12"]
+        }]
+      }"#,
+    )
+    .unwrap();
+    assert_eq!(vuln.name(), "CVE-2022-37434");
+    assert!(vuln.affects("zlib", "1.2.11"));
+    assert!(vuln.affects("zlib", "1.2.12"));
+    assert!(!vuln.affects("zlib", "1.2.13"));
+    assert!(!vuln.affects("openssl", "1.2.11"));
+  }
+}
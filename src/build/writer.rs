@@ -0,0 +1,102 @@
+use super::meta::PackageMeta;
+use super::tarball;
+use super::types::ZstdParams;
+use crate::repo::DICTIONARY_DIR;
+use anyhow::{bail, Context};
+use indicatif::ProgressBar;
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+use zstd::stream::Encoder as ZstEncoder;
+
+/// Last stage of packing, writing a package directory that went through QA
+/// into a file of some package format along with its metadata. Everything
+/// before it is shared by all formats.
+pub trait PackageWriter {
+  /// Adds `files`, relative to `base`, in order, as they are listed.
+  fn append_files(
+    &mut self,
+    base: &Path,
+    files: &mut (dyn Iterator<Item = io::Result<PathBuf>> + Send),
+    pb: &ProgressBar,
+  ) -> anyhow::Result<()>;
+
+  /// Adds the metadata and completes the file.
+  fn finish(self: Box<Self>, meta: &PackageMeta) -> anyhow::Result<()>;
+}
+
+/// Tar archive compressed with zstd, with `metadata.json` as its last member,
+/// as repositories are made of.
+pub struct TarZstWriter {
+  archive: tar::Builder<ZstEncoder<'static, File>>,
+  /// Modification times after this are recorded as this.
+  max_mtime: Option<u64>,
+}
+
+impl TarZstWriter {
+  pub const EXTENSION: &'static str = "tar.zst";
+
+  /// Creates the archive at `path`, compressed as `params` say. Their
+  /// dictionary, if any, is relative to `script_dir`, and is copied to where
+  /// readers of the archive look for it. Files newer than `max_mtime` are
+  /// recorded as modified then, like `SOURCE_DATE_EPOCH` asks.
+  pub fn create(
+    path: &Path,
+    params: &ZstdParams,
+    script_dir: &Path,
+    max_mtime: Option<u64>,
+  ) -> anyhow::Result<Self> {
+    /// Window of long distance matching, the largest decoders accept without
+    /// raising their limit.
+    const LONG_WINDOW_LOG: u32 = 27;
+    let level = params.level.unwrap_or(3);
+    if !zstd::compression_level_range().contains(&level) {
+      bail!("compression level {level} is out of range");
+    }
+    let file = File::create(path)?;
+    let mut encoder = match &params.dictionary {
+      Some(dict_path) => {
+        let dict = fs::read(script_dir.join(&**dict_path))
+          .with_context(|| format!("cannot read zstd dictionary `{dict_path}`"))?;
+        let Some(id) = zstd::zstd_safe::get_dict_id(&dict) else {
+          bail!("`{dict_path}` is not a zstd dictionary, train one with `zstd --train`");
+        };
+        let dir = path.parent().unwrap_or(Path::new("")).join(DICTIONARY_DIR);
+        fs::create_dir_all(&dir)?;
+        fs::write(dir.join(format!("{id}.dict")), &dict)?;
+        ZstEncoder::with_dictionary(file, level, &dict)?
+      }
+      None => ZstEncoder::new(file, level)?,
+    };
+    if params.long == Some(true) {
+      encoder.long_distance_matching(true)?;
+      encoder.window_log(LONG_WINDOW_LOG)?;
+    }
+    let mut archive = tar::Builder::new(encoder);
+    archive.follow_symlinks(false);
+    Ok(Self { archive, max_mtime })
+  }
+}
+
+impl PackageWriter for TarZstWriter {
+  fn append_files(
+    &mut self,
+    base: &Path,
+    files: &mut (dyn Iterator<Item = io::Result<PathBuf>> + Send),
+    pb: &ProgressBar,
+  ) -> anyhow::Result<()> {
+    tarball::append_files(&mut self.archive, base, files, self.max_mtime, pb)
+  }
+
+  fn finish(mut self: Box<Self>, meta: &PackageMeta) -> anyhow::Result<()> {
+    let metadata = serde_json::to_vec_pretty(meta)?;
+    let mut header = tar::Header::new_old();
+    header.set_size(metadata.len() as _);
+    header.set_path("metadata.json")?;
+    header.set_mode(0o644);
+    header.set_cksum();
+    self.archive.append(&header, &*metadata)?;
+    self.archive.into_inner()?.finish()?;
+    Ok(())
+  }
+}
@@ -0,0 +1,115 @@
+//! C ABI of the version comparator, declared in `include/ewepkg.h`, so that
+//! installers and bindings in other languages order versions exactly like
+//! ewepkg does.
+
+use crate::version::{cmp_version, is_allowed_in_version, PackageVersion};
+use std::ffi::{c_char, c_int, CStr};
+use std::ptr;
+
+/// Reads the NUL-terminated UTF-8 string `s`, if it is one.
+///
+/// # Safety
+///
+/// `s` must be null or point to a NUL-terminated string.
+unsafe fn read_str<'a>(s: *const c_char) -> Option<&'a str> {
+  if s.is_null() {
+    return None;
+  }
+  CStr::from_ptr(s).to_str().ok()
+}
+
+/// Parses the package version `s`, like `1:2.0~rc1-3`. Returns null if it is
+/// invalid; otherwise the version is to be freed with
+/// [`ewepkg_version_free`].
+///
+/// # Safety
+///
+/// `s` must be null or point to a NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn ewepkg_version_parse(s: *const c_char) -> *mut PackageVersion {
+  match read_str(s).map(str::parse::<PackageVersion>) {
+    Some(Ok(version)) => Box::into_raw(Box::new(version)),
+    _ => ptr::null_mut(),
+  }
+}
+
+/// Frees a version returned by [`ewepkg_version_parse`].
+///
+/// # Safety
+///
+/// `version` must be null or come from [`ewepkg_version_parse`], and not have
+/// been freed yet.
+#[no_mangle]
+pub unsafe extern "C" fn ewepkg_version_free(version: *mut PackageVersion) {
+  if !version.is_null() {
+    drop(Box::from_raw(version));
+  }
+}
+
+/// Compares two parsed versions, returning -1, 0 or 1 if `a` is older than,
+/// the same as or newer than `b`.
+///
+/// # Safety
+///
+/// Both must be live versions from [`ewepkg_version_parse`].
+#[no_mangle]
+pub unsafe extern "C" fn ewepkg_version_cmp(
+  a: *const PackageVersion,
+  b: *const PackageVersion,
+) -> c_int {
+  (*a).cmp(&*b) as c_int
+}
+
+/// Compares two upstream versions or revisions, without epochs, storing -1, 0
+/// or 1 in `result`. Returns 0, or -1 without touching `result` if either is
+/// not a valid version part.
+///
+/// # Safety
+///
+/// `a` and `b` must be null or point to NUL-terminated strings, and `result`
+/// must point to an `int`.
+#[no_mangle]
+pub unsafe extern "C" fn ewepkg_cmp_version(
+  a: *const c_char,
+  b: *const c_char,
+  result: *mut c_int,
+) -> c_int {
+  let valid = |x: &&str| x.chars().all(is_allowed_in_version);
+  let (Some(a), Some(b)) = (read_str(a).filter(valid), read_str(b).filter(valid)) else {
+    return -1;
+  };
+  *result = cmp_version(a, b) as c_int;
+  0
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_capi() {
+    unsafe {
+      let a = ewepkg_version_parse(c"1.14.51~beta4-999".as_ptr());
+      let b = ewepkg_version_parse(c"1.14.51-1".as_ptr());
+      assert_eq!(ewepkg_version_cmp(a, b), -1);
+      assert_eq!(ewepkg_version_cmp(b, a), 1);
+      assert_eq!(ewepkg_version_cmp(a, a), 0);
+      ewepkg_version_free(a);
+      ewepkg_version_free(b);
+      assert!(ewepkg_version_parse(c"2.33-beta1-4".as_ptr()).is_null());
+      assert!(ewepkg_version_parse(ptr::null()).is_null());
+
+      let mut result = 2;
+      assert_eq!(
+        ewepkg_cmp_version(c"1.0".as_ptr(), c"1.0+dfsg".as_ptr(), &mut result),
+        0
+      );
+      assert_eq!(result, -1);
+      assert_eq!(
+        ewepkg_cmp_version(c"1.0".as_ptr(), c"1-0".as_ptr(), &mut result),
+        -1
+      );
+      assert_eq!(result, -1);
+    }
+  }
+}
@@ -0,0 +1,141 @@
+use crate::build::{check_urls, FetchOptions};
+use crate::segment_info;
+use crate::util::cache_dir;
+use anyhow::{anyhow, bail};
+use console::style;
+use std::fs;
+use std::process::{Command, Stdio};
+use tempfile::tempfile_in;
+use url::Url;
+use zstd::zstd_safe::{CCtx, CParameter};
+
+/// A problem found on the host, and how to fix it.
+struct Problem {
+  error: String,
+  fix: &'static str,
+}
+
+fn problem(error: impl ToString, fix: &'static str) -> Problem {
+  Problem {
+    error: error.to_string(),
+    fix,
+  }
+}
+
+/// Runs `program` with `args`, and reports its first line of output.
+fn probe(program: &str, args: &[&str]) -> anyhow::Result<String> {
+  let mut command = Command::new(program);
+  let output = (command.args(args).stdin(Stdio::null()).output())
+    .map_err(|e| anyhow!("cannot run {program}: {e}"))?;
+  if !output.status.success() {
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    bail!(
+      "{program} failed: {}",
+      stderr.lines().next().unwrap_or_default()
+    );
+  }
+  let stdout = String::from_utf8_lossy(&output.stdout);
+  Ok(stdout.lines().next().unwrap_or_default().trim().into())
+}
+
+fn tool(name: &str, fix: &'static str) -> Result<String, Problem> {
+  probe(name, &["--version"]).map_err(|e| problem(e, fix))
+}
+
+/// Like [`tool`], for programs reporting their version with `-V` on stderr.
+fn tool_v(name: &str, fix: &'static str) -> Result<String, Problem> {
+  let output = Command::new(name).arg("-V").stdin(Stdio::null()).output();
+  match output {
+    Ok(x) if x.status.success() => {
+      let stderr = String::from_utf8_lossy(&x.stderr);
+      Ok(stderr.lines().next().unwrap_or_default().trim().into())
+    }
+    Ok(x) => Err(problem(format!("{name} -V exited with {}", x.status), fix)),
+    Err(e) => Err(problem(format!("cannot run {name}: {e}"), fix)),
+  }
+}
+
+fn fakeroot() -> Result<String, Problem> {
+  probe("fakeroot", &["id", "-u"])
+    .and_then(|uid| match &*uid {
+      "0" => Ok("works".into()),
+      _ => Err(anyhow!("commands under fakeroot run as uid {uid}")),
+    })
+    .map_err(|e| problem(e, "install fakeroot, which packing runs in"))
+}
+
+fn userns() -> Result<String, Problem> {
+  let fix = "enable unprivileged user namespaces, which emulated builds run in";
+  let uid = probe("unshare", &["--user", "--map-root-user", "id", "-u"]);
+  uid.map(|_| "works".into()).map_err(|e| problem(e, fix))
+}
+
+fn cache() -> Result<String, Problem> {
+  let fix = "make the cache directory writable, or point XDG_CACHE_HOME elsewhere";
+  let dir = cache_dir().ok_or_else(|| problem("neither XDG_CACHE_HOME nor HOME is set", fix))?;
+  fs::create_dir_all(&dir)
+    .and_then(|_| tempfile_in(&dir))
+    .map(|_| dir.display().to_string())
+    .map_err(|e| problem(format!("{}: {e}", dir.display()), fix))
+}
+
+fn zstd_threads() -> Result<String, Problem> {
+  let mut ctx = CCtx::create();
+  match ctx.set_parameter(CParameter::NbWorkers(2)) {
+    Ok(_) => Ok(format!("zstd {}", zstd::zstd_safe::version_string())),
+    Err(_) => Err(problem(
+      "libzstd is built without multithreading",
+      "install a libzstd built with ZSTD_MULTITHREAD for faster packing",
+    )),
+  }
+}
+
+/// Checks that this host has what building packages needs, and suggests fixes
+/// for what it lacks. Mirrors in `urls` are checked for reachability.
+pub fn doctor(urls: Vec<Url>, fetch: FetchOptions) -> anyhow::Result<()> {
+  let git = "install git, which git sources are fetched with";
+  let patch = "install patch, which build scripts apply patches with";
+  let strip = "install binutils, which binaries are stripped with";
+  let ssh = "install an OpenSSH client for remote builds";
+  let shellcheck = "install shellcheck for `ewe lint` to check shell snippets";
+  let mut checks = vec![
+    ("fakeroot", true, fakeroot()),
+    ("git", true, tool("git", git)),
+    ("patch", true, tool("patch", patch)),
+    ("strip", true, tool("strip", strip)),
+    ("cache", true, cache()),
+    ("user namespaces", false, userns()),
+    ("ssh", false, tool_v("ssh", ssh)),
+    ("shellcheck", false, tool("shellcheck", shellcheck)),
+    ("zstd threading", false, zstd_threads()),
+  ];
+  let health = check_urls(&urls, &fetch)?;
+  for (url, health) in urls.iter().zip(health) {
+    let result = match health.error {
+      Some(e) => Err(problem(e, "check the mirror URL and the network")),
+      None => Ok("reachable".into()),
+    };
+    checks.push((url.as_str(), true, result));
+  }
+
+  segment_info!("Checking the build environment...");
+  let mut failed = 0;
+  for (name, required, result) in checks {
+    match result {
+      Ok(detail) => println!("{} {name}: {detail}", style("ok").green().bold()),
+      Err(Problem { error, fix }) => {
+        if required {
+          failed += 1;
+          println!("{} {name}: {error}", style("missing").red().bold());
+        } else {
+          println!("{} {name}: {error}", style("optional").yellow().bold());
+        }
+        println!("   {} {fix}", style("fix:").bold());
+      }
+    }
+  }
+  if failed > 0 {
+    bail!("{failed} required check(s) failed");
+  }
+  Ok(())
+}
@@ -0,0 +1,161 @@
+use crate::repo::RepoIndex;
+use crate::types::PackageName;
+use crate::util::glob_match;
+use anyhow::{bail, Context};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+/// Name of the file database in a repository directory.
+const FILES_FILE: &str = "files.json";
+
+/// Packages of a repository shipping each path, directories left out, kept
+/// next to its index.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct FileDatabase {
+  pub files: BTreeMap<PathBuf, BTreeSet<PackageName>>,
+}
+
+/// Whether `a` declares a conflict with `b`, by name or by something it
+/// provides.
+fn declares_conflict(index: &RepoIndex, a: &PackageName, b: &PackageName) -> bool {
+  let (Some(a), Some(entry)) = (index.packages.get(a), index.packages.get(b)) else {
+    return false;
+  };
+  a.conflicts.contains(b) || entry.provides.iter().any(|x| a.conflicts.contains(x))
+}
+
+impl FileDatabase {
+  /// Loads the database of the repository in `dir`, if it has one.
+  pub fn load(dir: &Path) -> anyhow::Result<Option<Self>> {
+    let path = dir.join(FILES_FILE);
+    let f = match File::open(&path) {
+      Ok(x) => x,
+      Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+      Err(e) => return Err(e.into()),
+    };
+    (serde_json::from_reader(BufReader::new(f)))
+      .with_context(|| format!("invalid file database {}", path.display()))
+  }
+
+  pub fn save(&self, dir: &Path) -> anyhow::Result<()> {
+    let mut f = BufWriter::new(File::create(dir.join(FILES_FILE))?);
+    serde_json::to_writer_pretty(&mut f, self)?;
+    f.write_all(b"\n")?;
+    Ok(())
+  }
+
+  pub fn insert(&mut self, package: &PackageName, files: impl IntoIterator<Item = PathBuf>) {
+    for file in files {
+      self.files.entry(file).or_default().insert(package.clone());
+    }
+  }
+
+  /// Paths shipped by several packages of `index`, some of which can be
+  /// installed together as none declares a conflict with the other.
+  pub fn collisions<'a>(
+    &'a self,
+    index: &'a RepoIndex,
+  ) -> impl Iterator<Item = (&'a Path, &'a BTreeSet<PackageName>)> {
+    (self.files.iter())
+      .filter(|(_, owners)| {
+        (owners.iter()).any(|a| {
+          (owners.iter())
+            .filter(|b| a < *b)
+            .any(|b| !declares_conflict(index, a, b) && !declares_conflict(index, b, a))
+        })
+      })
+      .map(|(path, owners)| (&**path, owners))
+  }
+
+  /// Paths matching `pattern`, see [`which_owns`], with their packages.
+  pub fn find<'a>(
+    &'a self,
+    pattern: &'a str,
+  ) -> impl Iterator<Item = (&'a Path, &'a BTreeSet<PackageName>)> {
+    let (pattern, whole) = match pattern.contains('/') {
+      true => (pattern.trim_start_matches('/'), true),
+      false => (pattern, false),
+    };
+    (self.files.iter())
+      .filter(move |(path, _)| {
+        let subject = match whole {
+          true => path.as_os_str(),
+          false => path.file_name().unwrap_or_default(),
+        };
+        glob_match(pattern, &subject.to_string_lossy())
+      })
+      .map(|(path, owners)| (&**path, owners))
+  }
+
+  /// Whether all of `owners` already shipped `path`, along with some other
+  /// package.
+  pub fn has_collision(&self, path: &Path, owners: &BTreeSet<PackageName>) -> bool {
+    self
+      .files
+      .get(path)
+      .is_some_and(|x| x.len() > 1 && owners.is_subset(x))
+  }
+}
+
+pub fn which_owns(pattern: String, repo: PathBuf) -> anyhow::Result<()> {
+  let Some(db) = FileDatabase::load(&repo)? else {
+    bail!(
+      "{} has no file database, make one with `ewe repo index`",
+      repo.display()
+    );
+  };
+  let mut found = false;
+  for (path, owners) in db.find(&pattern) {
+    for owner in owners {
+      println!("{owner}  /{}", path.display());
+    }
+    found = true;
+  }
+  if !found {
+    bail!("no package in {} ships `{pattern}`", repo.display());
+  }
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use serde_json::json;
+
+  #[test]
+  fn test_collisions() {
+    let index: RepoIndex = serde_json::from_value(json!({ "packages": {
+      "a": { "version": "1", "file": "a" },
+      "b": { "version": "1", "file": "b" },
+      "c": { "version": "1", "file": "c", "conflicts": ["sh"] },
+      "d": { "version": "1", "file": "d", "provides": ["sh"] },
+    }}))
+    .unwrap();
+    let name = |x: &str| x.parse::<PackageName>().unwrap();
+    let path = |x| PathBuf::from(x);
+    let mut db = FileDatabase::default();
+    db.insert(&name("a"), [path("usr/bin/a"), path("usr/bin/x")]);
+    db.insert(&name("b"), [path("usr/bin/x")]);
+    db.insert(&name("c"), [path("usr/bin/sh")]);
+    db.insert(&name("d"), [path("usr/bin/sh")]);
+    let collisions = db.collisions(&index).collect::<Vec<_>>();
+    assert_eq!(collisions.len(), 1);
+    assert_eq!(collisions[0].0, Path::new("usr/bin/x"));
+
+    let owners = BTreeSet::from([name("a"), name("b")]);
+    assert!(db.has_collision(Path::new("usr/bin/x"), &owners));
+    assert!(!db.has_collision(Path::new("usr/bin/a"), &owners));
+
+    let found = |x| db.find(x).map(|(x, _)| x).collect::<Vec<_>>();
+    assert_eq!(found("sh"), [Path::new("usr/bin/sh")]);
+    assert_eq!(
+      found("/usr/bin/?"),
+      [Path::new("usr/bin/a"), Path::new("usr/bin/x")]
+    );
+    assert!(found("bin/sh").is_empty());
+    assert_eq!(found("*/sh").len(), 1);
+  }
+}
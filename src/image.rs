@@ -0,0 +1,489 @@
+use crate::build::PackageMeta;
+use crate::repo::{self, Repo};
+use crate::sign::SigningConfig;
+use crate::types::{Hash, PackageName};
+use crate::util::walk;
+use crate::{segment_info, warning};
+use anyhow::{bail, Context};
+use clap::Subcommand;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use tempfile::tempdir_in;
+
+#[derive(Subcommand)]
+pub enum ImageCommand {
+  /// Compose a root file system from a list of packages and everything they
+  /// depend on, running their triggers in it
+  Compose {
+    /// File naming a package per line; `#` starts a comment
+    pkglist: PathBuf,
+    /// Repository to take packages from, as a directory with an index; may
+    /// be repeated, earlier ones being preferred
+    #[arg(long = "repo", value_name = "DIR", required = true)]
+    repos: Vec<PathBuf>,
+    /// Image to write, a tarball if it ends with `.tar` or `.tar.zst`, or an
+    /// erofs image, made with mkfs.erofs, if it ends with `.erofs`
+    #[arg(long, short, value_name = "PATH")]
+    output: PathBuf,
+    /// Keep the root file system in DIR instead of a temporary directory. If
+    /// it was composed before, only packages that changed since are
+    /// reinstalled, and those no longer wanted removed
+    #[arg(long, value_name = "DIR")]
+    root: Option<PathBuf>,
+  },
+}
+
+/// Where a composed root records what it holds, so that it can be updated in
+/// place.
+const MANIFEST_PATH: &str = "var/lib/ewepkg/image.json";
+
+/// Packages installed in a composed root.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct ImageManifest {
+  packages: BTreeMap<PackageName, Installed>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Installed {
+  /// Checksum of the archive it came from, if its index had one.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  sha256: Option<Hash>,
+  meta: PackageMeta,
+  /// Paths unpacked from the archive, relative to the root.
+  files: Vec<PathBuf>,
+}
+
+impl ImageManifest {
+  fn load(root: &Path) -> anyhow::Result<Option<Self>> {
+    let path = root.join(MANIFEST_PATH);
+    let f = match File::open(&path) {
+      Ok(x) => x,
+      Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+      Err(e) => return Err(e.into()),
+    };
+    (serde_json::from_reader(BufReader::new(f)))
+      .with_context(|| format!("invalid image manifest {}", path.display()))
+  }
+
+  fn save(&self, root: &Path) -> anyhow::Result<()> {
+    let path = root.join(MANIFEST_PATH);
+    fs::create_dir_all(path.parent().unwrap())?;
+    let mut f = BufWriter::new(File::create(path)?);
+    serde_json::to_writer_pretty(&mut f, self)?;
+    f.write_all(b"\n")?;
+    Ok(())
+  }
+}
+
+/// Kind of image, by the extension of its path.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ImageFormat {
+  Tar,
+  TarZst,
+  Erofs,
+}
+
+impl ImageFormat {
+  fn of(path: &Path) -> anyhow::Result<Self> {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    if name.ends_with(".tar") {
+      Ok(Self::Tar)
+    } else if name.ends_with(".tar.zst") {
+      Ok(Self::TarZst)
+    } else if name.ends_with(".erofs") {
+      Ok(Self::Erofs)
+    } else {
+      bail!("cannot tell the image format of {name}, use .tar, .tar.zst or .erofs");
+    }
+  }
+}
+
+fn read_pkglist(path: &Path) -> anyhow::Result<Vec<PackageName>> {
+  let list = fs::read_to_string(path).with_context(|| format!("cannot read {}", path.display()))?;
+  let names = (list.lines())
+    .map(|x| x.split('#').next().unwrap().trim())
+    .filter(|x| !x.is_empty())
+    .map(|x| {
+      x.parse()
+        .map_err(|e| anyhow::anyhow!("invalid package name `{x}`: {e}"))
+    })
+    .collect::<anyhow::Result<Vec<PackageName>>>()?;
+  if names.is_empty() {
+    bail!("{} lists no packages", path.display());
+  }
+  Ok(names)
+}
+
+/// Fails if any of `packages` conflicts with another one, by name or by
+/// something it provides.
+fn check_conflicts(packages: &[&PackageMeta]) -> anyhow::Result<()> {
+  for package in packages {
+    for other in packages {
+      let mut names = std::iter::once(&other.info.name).chain(&other.info.provides);
+      if let Some(name) = names.find(|x| package.info.conflicts.contains(*x)) {
+        bail!(
+          "{} conflicts with {name}, which {} brings in",
+          package.info.name,
+          other.info.name
+        );
+      }
+    }
+  }
+  Ok(())
+}
+
+/// Commands refreshing the system caches that `meta` hints at, with the tool
+/// each of them needs.
+fn hint_commands(meta: &PackageMeta) -> Vec<(&'static str, String)> {
+  let mut commands = Vec::new();
+  for hint in &meta.trigger_hints {
+    match &**hint {
+      "icon-cache" => commands.push((
+        "gtk-update-icon-cache",
+        "for x in /usr/share/icons/*/; do gtk-update-icon-cache -qtf \"$x\"; done".into(),
+      )),
+      "desktop-database" => commands.push((
+        "update-desktop-database",
+        "update-desktop-database -q /usr/share/applications".into(),
+      )),
+      "mime-database" => commands.push((
+        "update-mime-database",
+        "update-mime-database /usr/share/mime".into(),
+      )),
+      "depmod" => {
+        for release in &meta.kernel_releases {
+          commands.push(("depmod", format!("depmod -a '{release}'")));
+        }
+      }
+      _ => {
+        warning!(
+          "ignoring unknown trigger hint `{hint}` of {}",
+          meta.info.name
+        );
+      }
+    }
+  }
+  commands
+}
+
+/// Runs the shell command `script` chrooted in `root`, as root of a new user
+/// namespace without network access.
+fn run_contained(root: &Path, script: &str) -> anyhow::Result<()> {
+  let status = Command::new("unshare")
+    .args([
+      "--user",
+      "--map-root-user",
+      "--mount",
+      "--net",
+      "--ipc",
+      "--uts",
+    ])
+    .arg("chroot")
+    .arg(root)
+    .args(["/bin/sh", "-c", script])
+    .status()
+    .context("cannot run unshare")?;
+  if !status.success() {
+    bail!("`{script}` exited with {status}");
+  }
+  Ok(())
+}
+
+/// Runs the triggers of `packages` watching any of the `changed` paths, and the
+/// cache updates that the `installed` ones hint at, each command once.
+fn run_triggers(
+  root: &Path,
+  packages: &[&PackageMeta],
+  installed: &[&PackageMeta],
+  changed: &BTreeSet<PathBuf>,
+) -> anyhow::Result<usize> {
+  let mut commands = Vec::new();
+  for meta in packages {
+    for trigger in &meta.info.triggers {
+      let watched = (trigger.paths.iter())
+        .map(|x| Path::new(x.trim_start_matches('/')))
+        .any(|x| changed.iter().any(|path| path.starts_with(x)));
+      if watched {
+        commands.push(trigger.command.to_string());
+      }
+    }
+  }
+  for meta in installed {
+    for (tool, command) in hint_commands(meta) {
+      let found = ["usr/bin", "usr/sbin", "bin", "sbin"]
+        .iter()
+        .any(|x| root.join(x).join(tool).exists());
+      if found {
+        commands.push(command);
+      } else {
+        warning!("{tool} is not in the image, skipping `{command}`");
+      }
+    }
+  }
+  let mut seen = BTreeSet::new();
+  commands.retain(|x| seen.insert(x.clone()));
+  if !commands.is_empty() && !root.join("bin/sh").exists() {
+    bail!("the image has no /bin/sh to run package triggers with");
+  }
+  for command in &commands {
+    println!("Running {command}");
+    run_contained(root, command)?;
+  }
+  Ok(commands.len())
+}
+
+/// Writes everything in `root` to a tarball in `out`, owned by root as
+/// installed systems have it.
+fn write_tar<W: Write>(root: &Path, out: W) -> anyhow::Result<W> {
+  let mut archive = tar::Builder::new(out);
+  for name in walk(root)? {
+    let path = root.join(&name);
+    let meta = path.symlink_metadata()?;
+    let mut header = tar::Header::new_gnu();
+    header.set_metadata(&meta);
+    header.set_uid(0);
+    header.set_gid(0);
+    let file_type = meta.file_type();
+    if file_type.is_file() {
+      archive.append_data(&mut header, &name, File::open(&path)?)?;
+    } else if file_type.is_dir() {
+      header.set_size(0);
+      archive.append_data(&mut header, &name, io::empty())?;
+    } else if file_type.is_symlink() {
+      header.set_size(0);
+      archive.append_link(&mut header, &name, fs::read_link(&path)?)?;
+    } else {
+      warning!(
+        "leaving out /{}, which is neither a file, a directory nor a symlink",
+        name.display()
+      );
+    }
+  }
+  Ok(archive.into_inner()?)
+}
+
+fn write_image(root: &Path, output: &Path, format: ImageFormat) -> anyhow::Result<()> {
+  match format {
+    ImageFormat::Tar => {
+      write_tar(root, File::create(output)?)?.flush()?;
+    }
+    ImageFormat::TarZst => {
+      let encoder =
+        zstd::stream::Encoder::new(File::create(output)?, zstd::DEFAULT_COMPRESSION_LEVEL)?;
+      write_tar(root, encoder)?.finish()?;
+    }
+    ImageFormat::Erofs => {
+      let status = Command::new("mkfs.erofs")
+        .arg("--all-root")
+        .arg(output)
+        .arg(root)
+        .status()
+        .context("cannot run mkfs.erofs, install erofs-utils to make erofs images")?;
+      if !status.success() {
+        bail!("mkfs.erofs exited with {status}");
+      }
+    }
+  }
+  Ok(())
+}
+
+/// Removes the `files` of packages taken out of `root`, except those in `keep`,
+/// deepest first so that directories left empty go as well. Returns the paths
+/// removed.
+fn remove_files(
+  root: &Path,
+  files: BTreeSet<&Path>,
+  keep: &BTreeSet<&Path>,
+) -> anyhow::Result<Vec<PathBuf>> {
+  let mut removed = Vec::new();
+  for file in files.into_iter().rev() {
+    if keep.contains(file) {
+      continue;
+    }
+    let path = root.join(file);
+    let Ok(meta) = path.symlink_metadata() else {
+      continue;
+    };
+    if meta.is_dir() {
+      // Still holding files of other packages or made by triggers otherwise.
+      if fs::remove_dir(&path).is_ok() {
+        removed.push(file.to_owned());
+      }
+    } else {
+      fs::remove_file(&path).with_context(|| format!("cannot remove {}", path.display()))?;
+      removed.push(file.to_owned());
+    }
+  }
+  Ok(removed)
+}
+
+pub fn compose(
+  pkglist: PathBuf,
+  repos: Vec<PathBuf>,
+  output: PathBuf,
+  root: Option<PathBuf>,
+) -> anyhow::Result<()> {
+  let format = ImageFormat::of(&output)?;
+  let names = read_pkglist(&pkglist)?;
+  let repos = (repos.iter())
+    .map(|x| Repo::open(x))
+    .collect::<anyhow::Result<Vec<_>>>()?;
+  let packages = repo::resolve(&repos, &names)?;
+
+  // Next to the image rather than in a possibly small temporary directory.
+  let parent = output.parent().filter(|x| !x.as_os_str().is_empty());
+  let parent = parent.unwrap_or(Path::new("."));
+  fs::create_dir_all(parent)?;
+  let temp;
+  let root = match &root {
+    Some(dir) => dir.as_path(),
+    None => {
+      temp = tempdir_in(parent)?;
+      temp.path()
+    }
+  };
+  let mut old = match ImageManifest::load(root)? {
+    Some(x) => x,
+    None => {
+      if root.read_dir().is_ok_and(|mut x| x.next().is_some()) {
+        bail!(
+          "{} is not empty, but has no image manifest to update it by",
+          root.display()
+        );
+      }
+      fs::create_dir_all(root)?;
+      ImageManifest::default()
+    }
+  };
+  segment_info!("Composing image of", "{} packages", packages.len());
+
+  let digest = SigningConfig::load()?.digest;
+  let mut manifest = ImageManifest::default();
+  let mut install = Vec::new();
+  for package in &packages {
+    let unchanged = old.packages.get(package.name).is_some_and(|x| {
+      x.meta.info.version == package.entry.version && x.sha256 == package.entry.sha256
+    });
+    if unchanged {
+      let entry = old.packages.remove(package.name).unwrap();
+      manifest.packages.insert(package.name.clone(), entry);
+      continue;
+    }
+    // Indexes from before checksums were recorded cannot be checked.
+    if package.entry.sha256.is_some() {
+      package.entry.verify(&package.repo.dir, digest)?;
+    }
+    install.push((package, repo::read_metadata(&package.archive())?));
+  }
+  let metas = (manifest.packages.values())
+    .map(|x| &x.meta)
+    .chain(install.iter().map(|(_, meta)| meta))
+    .collect::<Vec<_>>();
+  check_conflicts(&metas)?;
+
+  // A root left half updated is not to be taken for a composed one.
+  match fs::remove_file(root.join(MANIFEST_PATH)) {
+    Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+    _ => {}
+  }
+  for (name, package) in &old.packages {
+    if !install.iter().any(|(x, _)| x.name == name) {
+      println!("Removing {name} {}", package.meta.info.version);
+    }
+  }
+  let keep = (manifest.packages.values())
+    .flat_map(|x| &x.files)
+    .map(|x| &**x)
+    .collect();
+  let stale = (old.packages.values())
+    .flat_map(|x| &x.files)
+    .map(|x| &**x)
+    .collect();
+  let mut changed = remove_files(root, stale, &keep)?
+    .into_iter()
+    .collect::<BTreeSet<_>>();
+  let kept = manifest.packages.len();
+  let mut installed = Vec::new();
+  for (package, meta) in install {
+    println!("Installing {} {}", package.name, package.entry.version);
+    let files = repo::unpack(&package.archive(), root)?;
+    changed.extend(files.iter().cloned());
+    let entry = Installed {
+      sha256: package.entry.sha256.clone(),
+      meta,
+      files,
+    };
+    manifest.packages.insert(package.name.clone(), entry);
+    installed.push(package.name);
+  }
+  if kept > 0 {
+    println!("Kept {kept} unchanged package(s)");
+  }
+
+  let metas = manifest
+    .packages
+    .values()
+    .map(|x| &x.meta)
+    .collect::<Vec<_>>();
+  let installed = (installed.iter())
+    .map(|x| &manifest.packages[*x].meta)
+    .collect::<Vec<_>>();
+  let ran = run_triggers(root, &metas, &installed, &changed)?;
+  if ran > 0 {
+    println!("Ran {ran} trigger(s)");
+  }
+  manifest.save(root)?;
+
+  segment_info!("Writing", "{}", output.display());
+  write_image(root, &output, format)?;
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_image_format() {
+    assert_eq!(
+      ImageFormat::of(Path::new("a/root.tar")).unwrap(),
+      ImageFormat::Tar
+    );
+    assert_eq!(
+      ImageFormat::of(Path::new("root.tar.zst")).unwrap(),
+      ImageFormat::TarZst
+    );
+    assert_eq!(
+      ImageFormat::of(Path::new("root.erofs")).unwrap(),
+      ImageFormat::Erofs
+    );
+    assert!(ImageFormat::of(Path::new("root.img")).is_err());
+  }
+
+  #[test]
+  fn test_remove_files() {
+    let root = tempfile::tempdir().unwrap();
+    let root = root.path();
+    fs::create_dir_all(root.join("usr/share/a")).unwrap();
+    fs::create_dir_all(root.join("usr/share/b")).unwrap();
+    fs::write(root.join("usr/share/a/x"), "").unwrap();
+    fs::write(root.join("usr/share/b/y"), "").unwrap();
+    let stale = ["usr", "usr/share", "usr/share/a", "usr/share/a/x"];
+    let keep = ["usr", "usr/share", "usr/share/b", "usr/share/b/y"];
+    let removed = remove_files(
+      root,
+      stale.into_iter().map(Path::new).collect(),
+      &keep.into_iter().map(Path::new).collect(),
+    )
+    .unwrap();
+    assert_eq!(
+      removed,
+      [Path::new("usr/share/a/x"), Path::new("usr/share/a")]
+    );
+    assert!(root.join("usr/share/b/y").exists());
+  }
+}
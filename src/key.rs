@@ -0,0 +1,287 @@
+use crate::sign::{key_id, SigningConfig};
+use crate::util::config_dir;
+use crate::warning;
+use anyhow::{bail, Context};
+use clap::Subcommand;
+use openssl::pkey::{PKey, Public};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::{self, DirBuilder, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+#[derive(Subcommand)]
+pub enum KeyCommand {
+  /// Generate an Ed25519 signing key, trusting its public key too
+  Generate,
+  /// Print the public key of a signing or trusted key
+  Export {
+    id: String,
+    /// Write the private key instead, to a new file only readable by you
+    #[arg(long, requires = "output")]
+    private: bool,
+    /// File to write the key to instead of stdout
+    #[arg(long, short, value_name = "PATH")]
+    output: Option<PathBuf>,
+  },
+  /// Trust a public key, or add a private key to sign with
+  Import { path: PathBuf },
+  /// List signing keys and trusted keys
+  List,
+}
+
+/// Keys kept in `keys` of the config directory: private signing keys as
+/// `private/<id>.pem`, only accessible by their owner, and public keys trusted
+/// for verification as `trusted/<id>.pem`.
+pub struct Keyring {
+  dir: PathBuf,
+}
+
+/// Creates `dir` and its parents, keeping it to ourselves on Unix.
+fn create_private_dir(dir: &Path) -> io::Result<()> {
+  let mut builder = DirBuilder::new();
+  builder.recursive(true);
+  #[cfg(unix)]
+  std::os::unix::fs::DirBuilderExt::mode(&mut builder, 0o700);
+  builder.create(dir)
+}
+
+/// Writes `data` to the new file `path`, only readable by us on Unix.
+fn write_private(path: &Path, data: &[u8]) -> io::Result<()> {
+  let mut options = OpenOptions::new();
+  options.write(true).create_new(true);
+  #[cfg(unix)]
+  std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+  options.open(path)?.write_all(data)
+}
+
+/// Outcome of [`Keyring::import`].
+#[derive(Debug, PartialEq, Eq)]
+pub struct Imported {
+  pub id: String,
+  /// Whether it is a signing key rather than only a trusted one.
+  pub private: bool,
+  /// Whether the keyring did not have it yet.
+  pub new: bool,
+}
+
+fn check_id(id: &str) -> anyhow::Result<()> {
+  if id.len() != 16 || !id.bytes().all(|x| matches!(x, b'0'..=b'9' | b'a'..=b'f')) {
+    bail!("invalid key ID `{id}`, expecting 16 lowercase hex digits as shown by `ewe key list`");
+  }
+  Ok(())
+}
+
+impl Keyring {
+  pub fn open() -> anyhow::Result<Self> {
+    let dir = config_dir().context("neither XDG_CONFIG_HOME nor HOME is set")?;
+    Ok(Self {
+      dir: dir.join("keys"),
+    })
+  }
+
+  pub fn private_path(&self, id: &str) -> anyhow::Result<PathBuf> {
+    check_id(id)?;
+    Ok(self.dir.join("private").join(format!("{id}.pem")))
+  }
+
+  fn trusted_path(&self, id: &str) -> anyhow::Result<PathBuf> {
+    check_id(id)?;
+    Ok(self.dir.join("trusted").join(format!("{id}.pem")))
+  }
+
+  /// IDs of the keys in `sub`, by their file names.
+  fn ids(&self, sub: &str) -> anyhow::Result<Vec<String>> {
+    let entries = match self.dir.join(sub).read_dir() {
+      Ok(x) => x,
+      Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+      Err(e) => return Err(e.into()),
+    };
+    let mut ids = Vec::new();
+    for entry in entries {
+      let name = entry?.file_name();
+      let Some(id) = name.to_str().and_then(|x| x.strip_suffix(".pem")) else {
+        continue;
+      };
+      if check_id(id).is_ok() {
+        ids.push(id.to_owned());
+      }
+    }
+    ids.sort();
+    Ok(ids)
+  }
+
+  /// Public keys trusted for verification, by ID. Keys whose file is not
+  /// named after them are left out.
+  pub fn trusted(&self) -> anyhow::Result<BTreeMap<String, PKey<Public>>> {
+    let mut keys = BTreeMap::new();
+    for id in self.ids("trusted")? {
+      let path = self.trusted_path(&id)?;
+      let key = PKey::public_key_from_pem(&fs::read(&path)?)
+        .with_context(|| format!("invalid public key {}", path.display()))?;
+      match key_id(&key) {
+        Ok(x) if x == id => {
+          keys.insert(id, key);
+        }
+        _ => {
+          warning!("ignoring {}, which is not the key {id}", path.display());
+        }
+      }
+    }
+    Ok(keys)
+  }
+
+  fn trust(&self, key: &PKey<Public>) -> anyhow::Result<(String, bool)> {
+    let id = key_id(key)?;
+    let path = self.trusted_path(&id)?;
+    if path.exists() {
+      return Ok((id, false));
+    }
+    create_private_dir(path.parent().unwrap())?;
+    fs::write(&path, key.public_key_to_pem()?)?;
+    Ok((id, true))
+  }
+
+  /// Stores the private key in `pem`, trusting its public key as well.
+  fn add_private(&self, pem: &[u8]) -> anyhow::Result<(String, bool)> {
+    let key = PKey::private_key_from_pem(pem)?;
+    let public = PKey::public_key_from_der(&key.public_key_to_der()?)?;
+    let (id, _) = self.trust(&public)?;
+    let path = self.private_path(&id)?;
+    if path.exists() {
+      return Ok((id, false));
+    }
+    create_private_dir(path.parent().unwrap())?;
+    write_private(&path, &key.private_key_to_pem_pkcs8()?)?;
+    Ok((id, true))
+  }
+
+  pub fn generate(&self) -> anyhow::Result<String> {
+    let key = PKey::generate_ed25519()?;
+    let (id, _) = self.add_private(&key.private_key_to_pem_pkcs8()?)?;
+    Ok(id)
+  }
+
+  /// Imports the public or private key in the PEM file at `path`.
+  pub fn import(&self, path: &Path) -> anyhow::Result<Imported> {
+    let pem = fs::read(path).with_context(|| format!("cannot read {}", path.display()))?;
+    let (private, result) = match PKey::public_key_from_pem(&pem) {
+      Ok(key) => (false, self.trust(&key)),
+      Err(_) => (true, self.add_private(&pem)),
+    };
+    let (id, new) = result.with_context(|| format!("cannot import {}", path.display()))?;
+    Ok(Imported { id, private, new })
+  }
+
+  pub fn export(&self, id: &str, private: bool) -> anyhow::Result<Vec<u8>> {
+    let path = match private {
+      true => self.private_path(id)?,
+      false => self.trusted_path(id)?,
+    };
+    match fs::read(path) {
+      Ok(x) => Ok(x),
+      Err(e) if e.kind() == io::ErrorKind::NotFound => match private {
+        true => bail!("no signing key {id}"),
+        false => bail!("no key {id}"),
+      },
+      Err(e) => Err(e.into()),
+    }
+  }
+}
+
+pub fn generate() -> anyhow::Result<()> {
+  let id = Keyring::open()?.generate()?;
+  println!("Generated signing key {id}");
+  let config = SigningConfig::path().unwrap_or_default();
+  println!(
+    "Sign with it by setting \"signer\": {{ \"keyring\": {{ \"id\": \"{id}\" }} }} in {}",
+    config.display()
+  );
+  Ok(())
+}
+
+pub fn export(id: String, private: bool, output: Option<PathBuf>) -> anyhow::Result<()> {
+  let pem = Keyring::open()?.export(&id, private)?;
+  match (output, private) {
+    (Some(path), true) => {
+      write_private(&path, &pem).with_context(|| format!("cannot create {}", path.display()))?
+    }
+    (Some(path), false) => fs::write(path, pem)?,
+    (None, _) => io::stdout().write_all(&pem)?,
+  }
+  Ok(())
+}
+
+pub fn import(path: PathBuf) -> anyhow::Result<()> {
+  let Imported { id, private, new } = Keyring::open()?.import(&path)?;
+  let kind = match private {
+    true => "signing key",
+    false => "trusted key",
+  };
+  match new {
+    true => println!("Imported {kind} {id}"),
+    false => println!("Already have {kind} {id}"),
+  }
+  Ok(())
+}
+
+pub fn list() -> anyhow::Result<()> {
+  let keyring = Keyring::open()?;
+  let private = keyring.ids("private")?;
+  let trusted = keyring.trusted()?;
+  let ids = private
+    .iter()
+    .chain(trusted.keys())
+    .collect::<BTreeSet<_>>();
+  for id in ids {
+    let roles = [
+      private.contains(id).then_some("signing"),
+      trusted.contains_key(id).then_some("trusted"),
+    ];
+    let roles = roles.into_iter().flatten().collect::<Vec<_>>();
+    println!("{id}  {}", roles.join(", "));
+  }
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use tempfile::tempdir;
+
+  #[test]
+  fn test_keyring() {
+    let dir = tempdir().unwrap();
+    let keyring = Keyring {
+      dir: dir.path().join("keys"),
+    };
+    let id = keyring.generate().unwrap();
+    assert_eq!(keyring.ids("private").unwrap(), [&*id]);
+    assert!(keyring.trusted().unwrap().contains_key(&id));
+    #[cfg(unix)]
+    {
+      let meta = fs::metadata(keyring.private_path(&id).unwrap()).unwrap();
+      assert_eq!(crate::util::file_mode(&meta) & 0o777, 0o600);
+    }
+
+    let other = Keyring {
+      dir: dir.path().join("other"),
+    };
+    let public = dir.path().join("public.pem");
+    fs::write(&public, keyring.export(&id, false).unwrap()).unwrap();
+    let imported = |private, new| Imported {
+      id: id.clone(),
+      private,
+      new,
+    };
+    assert_eq!(other.import(&public).unwrap(), imported(false, true));
+    assert_eq!(other.import(&public).unwrap(), imported(false, false));
+    assert!(other.ids("private").unwrap().is_empty());
+    assert!(other.export(&id, true).is_err());
+
+    let private = dir.path().join("private.pem");
+    fs::write(&private, keyring.export(&id, true).unwrap()).unwrap();
+    assert_eq!(other.import(&private).unwrap(), imported(true, true));
+    assert_eq!(other.ids("private").unwrap(), [&*id]);
+    assert!(other.export("../x", false).is_err());
+  }
+}
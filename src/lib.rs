@@ -0,0 +1,10 @@
+//! Parts of ewepkg that other programs can build on, the `ewe` binary
+//! included.
+
+#[cfg(feature = "cdylib")]
+pub mod capi;
+pub mod metadata;
+pub mod types;
+pub mod version;
+#[cfg(feature = "wasm")]
+pub mod wasm;
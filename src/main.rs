@@ -1,12 +1,28 @@
 mod build;
-mod types;
+mod doctor;
+mod filedb;
+mod image;
+mod key;
+mod mirror;
+mod repo;
+mod search;
+mod sign;
+mod stats;
+mod tree;
+mod trust;
 mod util;
-mod version;
 
+use build::{BuildCacheOptions, BuildOptions, FetchOptions, Limits, MatrixOptions, RemoteOptions};
 use clap::{Parser, Subcommand};
 use console::style;
+use ewepkg::{types, version};
+use image::ImageCommand;
+use key::KeyCommand;
+use mirror::MirrorCommand;
+use repo::RepoCommand;
 use std::path::PathBuf;
 use std::process::exit;
+use tree::{BatchOptions, TreeCommand};
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
@@ -20,24 +36,354 @@ enum Command {
   Build {
     #[arg(default_value = "ewebuild")]
     path: PathBuf,
+    #[command(flatten)]
+    limits: Limits,
+    #[command(flatten)]
+    fetch: FetchOptions,
+    #[command(flatten)]
+    options: BuildOptions,
+    #[command(flatten)]
+    cache: BuildCacheOptions,
+    #[command(flatten)]
+    remote: RemoteOptions,
+    #[command(flatten)]
+    matrix: MatrixOptions,
+    /// Directory for the packages and manifests, created if missing; defaults
+    /// to `output_dir` in build.json of the config directory, or the current
+    /// directory
+    #[arg(long, value_name = "DIR")]
+    output_dir: Option<PathBuf>,
+    /// Index of the repository the packages replace, to list packages
+    /// needing rebuilds for sonames they no longer provide
+    #[arg(long, value_name = "PATH")]
+    repo_index: Option<PathBuf>,
+  },
+  /// Build every build script in a tree in dependency order, spreading them
+  /// over builders
+  BuildAll {
+    #[arg(default_value = ".")]
+    tree: PathBuf,
+    #[command(flatten)]
+    limits: Limits,
+    #[command(flatten)]
+    options: BuildOptions,
+    #[command(flatten)]
+    cache: BuildCacheOptions,
+    #[command(flatten)]
+    batch: BatchOptions,
+  },
+  /// Download and verify the sources of build scripts into the cache
+  /// without building them, for builds without network access
+  Fetch {
+    /// Build scripts, or directories to search for them
+    #[arg(default_value = "ewebuild")]
+    paths: Vec<PathBuf>,
+    /// Only check that sources can be fetched, without downloading them
+    #[arg(long)]
+    check: bool,
+    /// Architecture to evaluate build scripts for, this machine's by default
+    #[arg(long, value_name = "ARCH", conflicts_with = "check")]
+    arch: Option<String>,
+    #[command(flatten)]
+    limits: Limits,
+    #[command(flatten)]
+    fetch: FetchOptions,
+  },
+  /// Download the sources of a build script and print their checksums, or
+  /// update the ones written in it; at a terminal, offers to add the missing
+  /// ones
+  Checksum {
+    /// Build script
+    #[arg(default_value = "ewebuild")]
+    path: PathBuf,
+    /// Replace outdated checksums in the build script
+    #[arg(short, long)]
+    write: bool,
+    /// Compute SHA-512 instead of SHA-256 for sources without checksums
+    #[arg(long)]
+    sha512: bool,
+    #[command(flatten)]
+    limits: Limits,
+    #[command(flatten)]
+    fetch: FetchOptions,
+  },
+  /// Write a skeleton build script for a new package
+  New {
+    name: types::PackageName,
+    /// Directory to write the build script into, a new one named after the
+    /// package by default
+    dir: Option<PathBuf>,
+    /// Upstream version of the package
+    #[arg(long, default_value = "0.1.0")]
+    version: String,
+    /// URL of the source tarball, with the version filled in
+    #[arg(long)]
+    url: Option<url::Url>,
+  },
+  /// Remove cached downloads and builds, leftover build directories and old
+  /// packages
+  Clean {
+    #[command(flatten)]
+    options: build::CleanOptions,
+  },
+  /// Check that this host has everything building packages needs
+  Doctor {
+    /// Mirrors to check the reachability of
+    #[arg(value_name = "URL")]
+    urls: Vec<url::Url>,
+    #[command(flatten)]
+    fetch: FetchOptions,
+  },
+  /// Show download speeds, build durations and hashing speeds recorded on this
+  /// machine
+  Stats,
+  /// Report problems in build scripts without building them
+  Lint {
+    /// Build scripts, or directories to search for them
+    #[arg(default_value = "ewebuild")]
+    paths: Vec<PathBuf>,
+    #[command(flatten)]
+    limits: Limits,
+    /// Number of build scripts to evaluate at once, 0 for one per CPU
+    #[arg(long, short, value_name = "N", default_value_t = 0)]
+    jobs: usize,
+    /// Also check that homepages and source URLs answer, and whether
+    /// `http://` ones work with `https://`
+    #[arg(long)]
+    network: bool,
+    #[command(flatten)]
+    fetch: FetchOptions,
+  },
+  /// Work with the mirrors sources are fetched from
+  Mirror {
+    #[command(subcommand)]
+    cmd: MirrorCommand,
+  },
+  /// Compose system images from built packages
+  Image {
+    #[command(subcommand)]
+    cmd: ImageCommand,
+  },
+  /// Manage signing keys and keys trusted for verification
+  Key {
+    #[command(subcommand)]
+    cmd: KeyCommand,
+  },
+  /// Work with a repository of built packages
+  Repo {
+    #[command(subcommand)]
+    cmd: RepoCommand,
+  },
+  /// Find which packages of a repository ship a file
+  WhichOwns {
+    /// Path of the file, or a glob with `*` and `?`; without a `/`, only file
+    /// names are matched
+    pattern: String,
+    /// Repository directory
+    #[arg(long, value_name = "DIR", default_value = ".")]
+    repo: PathBuf,
+  },
+  /// Work with a whole tree of build scripts
+  Tree {
+    #[command(subcommand)]
+    cmd: TreeCommand,
+  },
+  /// Run the `test_*` functions of shared script modules, with `assert`,
+  /// `assert_eq` and `assert_ne` available
+  ScriptTest {
+    #[arg(required = true)]
+    modules: Vec<PathBuf>,
+    #[command(flatten)]
+    limits: Limits,
+  },
+  /// Show the metadata of a build script and the packages it builds, without
+  /// building them
+  Info {
+    #[arg(default_value = "ewebuild")]
+    path: PathBuf,
+    /// Architecture to evaluate the build script for, this machine's by
+    /// default
+    #[arg(long, value_name = "ARCH")]
+    arch: Option<String>,
+    /// Print JSON instead
+    #[arg(long)]
+    json: bool,
+    #[command(flatten)]
+    limits: Limits,
+  },
+  /// Show field-level changes of metadata between two build scripts
+  Metadiff {
+    old: PathBuf,
+    new: PathBuf,
+    #[command(flatten)]
+    limits: Limits,
+  },
+  #[command(name = "__internal_remote_build", hide = true)]
+  InternalRemoteBuild {
+    path: PathBuf,
+    #[command(flatten)]
+    limits: Limits,
+    #[command(flatten)]
+    fetch: FetchOptions,
+    #[command(flatten)]
+    options: BuildOptions,
+    #[command(flatten)]
+    cache: BuildCacheOptions,
   },
   #[command(name = "__internal_package_inside_fakeroot", hide = true)]
   InternalPackage {
     path: PathBuf,
     source_dir: PathBuf,
     arch: String,
+    output_dir: PathBuf,
+    #[command(flatten)]
+    limits: Limits,
+    #[command(flatten)]
+    options: BuildOptions,
   },
 }
 
 fn run() -> anyhow::Result<()> {
   let args = Args::parse();
   match args.cmd {
-    Command::Build { path } => build::run(path)?,
+    Command::Build {
+      path,
+      limits,
+      fetch,
+      options,
+      cache,
+      remote,
+      matrix,
+      output_dir,
+      repo_index,
+    } => build::run(
+      path, limits, fetch, options, cache, remote, matrix, output_dir, repo_index,
+    )?,
+    Command::BuildAll {
+      tree,
+      limits,
+      options,
+      cache,
+      batch,
+    } => tree::build_all(tree, limits, options, cache, batch)?,
+    Command::Fetch {
+      paths,
+      check: true,
+      limits,
+      fetch,
+      ..
+    } => build::check_sources(paths, limits, fetch)?,
+    Command::Fetch {
+      paths,
+      arch,
+      limits,
+      fetch,
+      ..
+    } => build::fetch_sources(paths, arch, limits, fetch)?,
+    Command::Checksum {
+      path,
+      write,
+      sha512,
+      limits,
+      fetch,
+    } => {
+      let kind = match sha512 {
+        true => types::ChecksumKind::Sha512,
+        false => types::ChecksumKind::Sha256,
+      };
+      build::checksum(path, write, kind, limits, fetch)?
+    }
+    Command::New {
+      name,
+      dir,
+      version,
+      url,
+    } => build::new_script(name, dir, version, url)?,
+    Command::Clean { options } => build::clean(options)?,
+    Command::Doctor { urls, fetch } => doctor::doctor(urls, fetch)?,
+    Command::Stats => stats::show()?,
+    Command::Lint {
+      paths,
+      limits,
+      jobs,
+      network,
+      fetch,
+    } => build::lint(paths, limits, jobs, network.then_some(fetch))?,
+    Command::ScriptTest { modules, limits } => build::script_test(modules, limits)?,
+    Command::Info {
+      path,
+      arch,
+      json,
+      limits,
+    } => build::info(path, arch, json, limits)?,
+    Command::Metadiff { old, new, limits } => build::metadiff(old, new, limits)?,
+    Command::Mirror { cmd } => match cmd {
+      MirrorCommand::Rank { probe, fetch } => mirror::rank(probe, fetch)?,
+    },
+    Command::Image { cmd } => match cmd {
+      ImageCommand::Compose {
+        pkglist,
+        repos,
+        output,
+        root,
+      } => image::compose(pkglist, repos, output, root)?,
+    },
+    Command::Key { cmd } => match cmd {
+      KeyCommand::Generate => key::generate()?,
+      KeyCommand::Export {
+        id,
+        private,
+        output,
+      } => key::export(id, private, output)?,
+      KeyCommand::Import { path } => key::import(path)?,
+      KeyCommand::List => key::list()?,
+    },
+    Command::Repo { cmd } => match cmd {
+      RepoCommand::Index { dir } => repo::index(dir)?,
+      RepoCommand::Verify { dir } => repo::verify(dir)?,
+      RepoCommand::Query {
+        dir,
+        provides,
+        depends,
+        group,
+      } => repo::query(dir, provides, depends, group)?,
+      RepoCommand::Search { dir, terms } => repo::search(dir, terms)?,
+      RepoCommand::Orphans { dir, allow } => repo::orphans(dir, allow)?,
+      RepoCommand::Report {
+        dir,
+        largest,
+        closures,
+        rdepends,
+        leaves,
+        top,
+      } => repo::report(dir, largest, closures, rdepends, leaves, top)?,
+      RepoCommand::Changelog { old, new, json } => repo::changelog(old, new, json)?,
+    },
+    Command::WhichOwns { pattern, repo } => filedb::which_owns(pattern, repo)?,
+    Command::Tree { cmd } => match cmd {
+      TreeCommand::Index { tree, limits, jobs } => tree::index(tree, limits, jobs)?,
+      TreeCommand::Query {
+        tree,
+        query,
+        limits,
+      } => tree::query(tree, query, limits)?,
+      TreeCommand::Owner { tree, name } => tree::owner(tree, name)?,
+    },
+    Command::InternalRemoteBuild {
+      path,
+      limits,
+      fetch,
+      options,
+      cache,
+    } => build::serve_remote(path, limits, fetch, options, cache)?,
     Command::InternalPackage {
       path,
       source_dir,
       arch,
-    } => build::run_package(path, source_dir, arch)?,
+      output_dir,
+      limits,
+      options,
+    } => build::run_package(path, source_dir, arch, output_dir, limits, options)?,
   }
   Ok(())
 }
@@ -0,0 +1,154 @@
+//! Metadata-only evaluation of build scripts, for tools that show or check
+//! them without building anything, possibly in a browser.
+//!
+//! Unlike the builder, nothing here touches the file system or runs
+//! commands: builtins reading the source tree fail, and the build steps
+//! themselves are left unevaluated.
+
+use crate::types::{PackageInfo, PackageInfoDelta, SourceInfo};
+use anyhow::bail;
+use rhai::module_resolvers::DummyModuleResolver;
+use rhai::serde::from_dynamic;
+use rhai::{Array, Dynamic, Engine, EvalAltResult, Map, Scope};
+use serde::Serialize;
+
+/// Same as the builder's default script limits.
+const MAX_OPERATIONS: u64 = 10_000_000;
+const MAX_SIZE: usize = 1 << 20;
+
+/// Fields holding build steps rather than metadata.
+const STEPS: [&str; 5] = ["prepare", "build", "check", "test", "pack"];
+
+/// What a build script declares, split packages included.
+#[derive(Debug, Clone, Serialize)]
+pub struct ScriptMetadata {
+  pub source: SourceInfo,
+  pub packages: Vec<PackageInfo>,
+}
+
+fn not_available(name: &'static str) -> impl Fn(&str) -> Result<(), Box<EvalAltResult>> {
+  move |_| Err(format!("{name}() is not available when only evaluating metadata").into())
+}
+
+fn create_engine(arch: &str) -> (Engine, Scope<'static>) {
+  let mut engine = Engine::new();
+  engine
+    .set_max_operations(MAX_OPERATIONS)
+    .set_max_call_levels(64)
+    .set_max_expr_depths(64, 32)
+    .set_max_string_size(MAX_SIZE)
+    .set_max_array_size(MAX_SIZE)
+    .set_max_map_size(MAX_SIZE)
+    .set_max_modules(0)
+    .set_module_resolver(DummyModuleResolver::new())
+    .disable_symbol("eval");
+  engine
+    .register_fn(
+      "conditional",
+      |cond: bool, x: Array| if cond { x } else { Array::new() },
+    )
+    .register_fn(
+      "conditional",
+      |cond: bool, x: Map| if cond { x } else { Map::new() },
+    );
+  engine
+    .register_fn("read_file", not_available("read_file"))
+    .register_fn("git_describe", || not_available("git_describe")(""))
+    .register_fn("git_describe", not_available("git_describe"))
+    .register_fn("read_define", |path: &str, _: &str| {
+      not_available("read_define")(path)
+    });
+
+  let mut scope = Scope::new();
+  scope.push("source_dir", ());
+  scope.push("arch", arch.to_string());
+  scope.push_constant("bootstrap", false);
+  scope.push_constant("source", ());
+  (engine, scope)
+}
+
+/// Removes the build steps of the script or package `value`, and its split
+/// packages, if any.
+fn strip_steps(value: &mut Dynamic) -> anyhow::Result<Option<Array>> {
+  let type_name = value.type_name();
+  let Some(mut map) = value.write_lock::<Map>() else {
+    bail!("expected a map, found {type_name}");
+  };
+  for name in STEPS {
+    map.remove(name);
+  }
+  match map.remove("packages") {
+    Some(x) => match x.into_array() {
+      Ok(x) => Ok(Some(x)),
+      Err(t) => bail!("field `packages` should be an array, found {t}"),
+    },
+    None => Ok(None),
+  }
+}
+
+/// Evaluates the build script `script` for `arch`, sandboxed like the
+/// builder does.
+pub fn evaluate(script: &str, arch: &str) -> anyhow::Result<ScriptMetadata> {
+  let (engine, mut scope) = create_engine(arch);
+  let ast = engine.compile_with_scope(&scope, script)?;
+  let mut value: Dynamic = engine.eval_ast_with_scope(&mut scope, &ast)?;
+  let packages = strip_steps(&mut value)?;
+  let source: SourceInfo = from_dynamic(&value)?;
+  let packages = match packages {
+    Some(packages) => {
+      let mut infos = Vec::new();
+      for mut package in packages {
+        strip_steps(&mut package)?;
+        let delta: PackageInfoDelta = from_dynamic(&package)?;
+        infos.push(delta.merge_into(&source));
+      }
+      infos
+    }
+    None => {
+      if !source.architecture.is_valid_for_package() {
+        bail!("architecture for package conflicts between `all` and other platforms");
+      }
+      vec![source.inner.clone()]
+    }
+  };
+  Ok(ScriptMetadata { source, packages })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_evaluate() {
+    let script = r#"
+      let cc = if arch == "x86_64" { "gcc" } else { "clang" };
+      #{
+        name: "foo",
+        description: "Foo",
+        version: "1.0-1",
+        architecture: ["x86_64", "aarch64"],
+        build_depends: [cc],
+        bugs: "https://example.org/foo/issues",
+        build: || "make",
+        packages: [
+          #{ name: "foo", pack: |dir| `make install DESTDIR=${dir}` },
+          #{ name: "foo-doc", architecture: ["all"] },
+        ],
+      }
+    "#;
+    let meta = evaluate(script, "aarch64").unwrap();
+    assert_eq!(meta.source.name.to_string(), "foo");
+    assert!(meta.source.build_depends.contains("clang"));
+    let names = meta.packages.iter().map(|x| &*x.name).collect::<Vec<_>>();
+    assert_eq!(names, ["foo", "foo-doc"]);
+    assert!(meta.packages[1].architecture.contains_all());
+    assert_eq!(
+      meta.packages[1].bugs.as_ref().map(|x| x.as_str()),
+      Some("https://example.org/foo/issues")
+    );
+
+    let err = evaluate(r#"#{ version: read_file("VERSION") }"#, "x86_64").unwrap_err();
+    assert!(err.to_string().contains("not available"));
+    assert!(evaluate("loop {}", "x86_64").is_err());
+  }
+}
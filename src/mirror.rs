@@ -0,0 +1,158 @@
+use crate::build::{measure_urls, FetchOptions};
+use crate::segment_info;
+use crate::util::config_dir;
+use anyhow::{bail, Context};
+use clap::Subcommand;
+use indicatif::HumanBytes;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Write};
+use std::path::PathBuf;
+use url::Url;
+
+#[derive(Subcommand)]
+pub enum MirrorCommand {
+  /// Measure the configured mirrors and order them fastest first, which is
+  /// the order sources are fetched from them in
+  Rank {
+    /// File to download from each mirror to measure throughput, relative to
+    /// the mirrored prefix; without it, mirrors are ranked by latency
+    #[arg(long, value_name = "PATH")]
+    probe: Option<String>,
+    #[command(flatten)]
+    fetch: FetchOptions,
+  },
+}
+
+/// Mirrors of upstream URL prefixes, in the order to fetch from them, kept in
+/// `mirrors.json` of the config directory. A prefix may list itself to be
+/// tried after some of its mirrors; otherwise it is tried first.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Mirrors(BTreeMap<Url, Vec<Url>>);
+
+impl Mirrors {
+  fn path() -> Option<PathBuf> {
+    Some(config_dir()?.join("mirrors.json"))
+  }
+
+  pub fn load() -> anyhow::Result<Self> {
+    let Some(path) = Self::path().filter(|x| x.exists()) else {
+      return Ok(Self::default());
+    };
+    let f = File::open(&path)?;
+    (serde_json::from_reader(BufReader::new(f)))
+      .with_context(|| format!("invalid mirror list {}", path.display()))
+  }
+
+  fn save(&self) -> anyhow::Result<PathBuf> {
+    let path = Self::path().context("neither XDG_CONFIG_HOME nor HOME is set")?;
+    fs::create_dir_all(path.parent().unwrap())?;
+    let mut f = BufWriter::new(File::create(&path)?);
+    serde_json::to_writer_pretty(&mut f, self)?;
+    f.write_all(b"\n")?;
+    Ok(path)
+  }
+
+  /// URLs to fetch `url` from in order, through the mirrors of the longest
+  /// configured prefix it is under.
+  pub fn candidates(&self, url: &Url) -> Vec<Url> {
+    let prefix = (self.0.iter())
+      .filter(|(prefix, _)| url.as_str().starts_with(prefix.as_str()))
+      .max_by_key(|(prefix, _)| prefix.as_str().len());
+    let Some((prefix, mirrors)) = prefix else {
+      return vec![url.clone()];
+    };
+    let rest = &url.as_str()[prefix.as_str().len()..];
+    let mut urls = (mirrors.iter())
+      .filter_map(|x| Url::parse(&format!("{x}{rest}")).ok())
+      .collect::<Vec<_>>();
+    if !mirrors.contains(prefix) {
+      urls.insert(0, url.clone());
+    }
+    urls
+  }
+}
+
+pub fn rank(probe: Option<String>, fetch: FetchOptions) -> anyhow::Result<()> {
+  let mut mirrors = Mirrors::load()?;
+  if mirrors.0.is_empty() {
+    let path = Mirrors::path().unwrap_or_default();
+    bail!("no mirrors configured in {}", path.display());
+  }
+  for (prefix, list) in &mut mirrors.0 {
+    segment_info!("Ranking mirrors of", "{prefix}");
+    if !list.contains(prefix) {
+      list.insert(0, prefix.clone());
+    }
+    let urls = (list.iter())
+      .map(|x| match &probe {
+        Some(probe) => x.join(probe),
+        None => Ok(x.clone()),
+      })
+      .collect::<Result<Vec<_>, _>>()?;
+    let speeds = measure_urls(&urls, &fetch)?;
+    let mut ranked = list.drain(..).zip(speeds).collect::<Vec<_>>();
+    // Reachable mirrors first, the fastest or quickest to answer ahead.
+    ranked.sort_by(|(_, a), (_, b)| match (a, b) {
+      (Ok(a), Ok(b)) => match (a.bytes_per_sec, b.bytes_per_sec) {
+        (Some(x), Some(y)) if probe.is_some() => y.total_cmp(&x),
+        _ => a.latency.cmp(&b.latency),
+      },
+      (a, b) => a.is_err().cmp(&b.is_err()),
+    });
+    for (i, (url, speed)) in ranked.iter().enumerate() {
+      match speed {
+        Ok(x) => {
+          let throughput = (x.bytes_per_sec.filter(|_| probe.is_some()))
+            .map(|x| format!(", {}/s", HumanBytes(x as u64)))
+            .unwrap_or_default();
+          println!(
+            "{:>3}. {url} ({}ms{throughput})",
+            i + 1,
+            x.latency.as_millis()
+          );
+        }
+        Err(e) => println!("{:>3}. {url} (unreachable: {e})", i + 1),
+      }
+    }
+    *list = ranked.into_iter().map(|x| x.0).collect();
+  }
+  let path = mirrors.save()?;
+  println!("Saved the ranking to {}", path.display());
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_candidates() {
+    let mirrors: Mirrors = serde_json::from_value(serde_json::json!({
+      "https://ftp.gnu.org/gnu/": ["https://a.org/gnu/", "https://ftp.gnu.org/gnu/"],
+      "https://ftp.gnu.org/gnu/gcc/": ["https://b.org/gcc/"],
+    }))
+    .unwrap();
+    let candidates = |url: &str| {
+      let urls = mirrors.candidates(&url.parse().unwrap());
+      urls.iter().map(|x| x.to_string()).collect::<Vec<_>>()
+    };
+    assert_eq!(
+      candidates("https://ftp.gnu.org/gnu/make/make-4.4.tar.gz"),
+      [
+        "https://a.org/gnu/make/make-4.4.tar.gz",
+        "https://ftp.gnu.org/gnu/make/make-4.4.tar.gz"
+      ]
+    );
+    assert_eq!(
+      candidates("https://ftp.gnu.org/gnu/gcc/gcc.tar.xz"),
+      [
+        "https://ftp.gnu.org/gnu/gcc/gcc.tar.xz",
+        "https://b.org/gcc/gcc.tar.xz"
+      ]
+    );
+    assert_eq!(candidates("https://x.org/a"), ["https://x.org/a"]);
+  }
+}
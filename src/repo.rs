@@ -0,0 +1,881 @@
+use crate::build::{hash_file, PackageMeta};
+use crate::filedb::FileDatabase;
+use crate::search::SearchIndex;
+use crate::sign::{sign_file, DigestBackend, Signature, SigningConfig};
+use crate::trust::{check_index, read_signature};
+use crate::types::{ChecksumKind, Hash, PackageName};
+use crate::version::PackageVersion;
+use crate::{segment_info, warning};
+use anyhow::{bail, Context};
+use clap::Subcommand;
+use indicatif::HumanBytes;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use zstd::stream::read::Decoder as ZstDecoder;
+
+/// Name of the index file in a repository directory.
+const INDEX_FILE: &str = "index.json";
+
+/// Directory next to package archives holding the zstd dictionaries some of
+/// them are compressed with, as `<id>.dict`.
+pub const DICTIONARY_DIR: &str = "dictionaries";
+
+/// Opens the package archive at `path` for decompression, along with the
+/// dictionary it needs, if any.
+fn open_archive(path: &Path) -> anyhow::Result<tar::Archive<ZstDecoder<'static, BufReader<File>>>> {
+  let mut file = BufReader::new(File::open(path)?);
+  let dict = match zstd::zstd_safe::get_dict_id_from_frame(file.fill_buf()?) {
+    0 => Vec::new(),
+    id => {
+      let dict = (path.parent().unwrap_or(Path::new(".")))
+        .join(DICTIONARY_DIR)
+        .join(format!("{id}.dict"));
+      fs::read(&dict).with_context(|| {
+        format!(
+          "{} needs the zstd dictionary {}",
+          path.display(),
+          dict.display()
+        )
+      })?
+    }
+  };
+  Ok(tar::Archive::new(ZstDecoder::with_dictionary(file, &dict)?))
+}
+
+#[derive(Subcommand)]
+pub enum RepoCommand {
+  /// Index the package archives in a directory
+  Index {
+    #[arg(default_value = ".")]
+    dir: PathBuf,
+  },
+  /// Check the index of a repository against the trust policy, and its
+  /// package archives against the index
+  Verify {
+    #[arg(default_value = ".")]
+    dir: PathBuf,
+  },
+  /// Find packages in an index providing or depending on a package or
+  /// soname, or in a group
+  #[command(group = clap::ArgGroup::new("lookup").required(true))]
+  Query {
+    /// Repository directory, or its index file
+    #[arg(default_value = ".")]
+    dir: PathBuf,
+    /// List packages providing NAME, as a package name or soname
+    #[arg(long, value_name = "NAME", group = "lookup")]
+    provides: Option<Box<str>>,
+    /// List packages depending on NAME, or on a soname it provides
+    #[arg(long, value_name = "NAME", group = "lookup")]
+    depends: Option<Box<str>>,
+    /// List packages in the group NAME
+    #[arg(long, value_name = "NAME", group = "lookup")]
+    group: Option<Box<str>>,
+  },
+  /// Search the packages of an index by name, description, provides and
+  /// keywords
+  Search {
+    /// Repository directory, or its index file
+    #[arg(long, default_value = ".")]
+    dir: PathBuf,
+    /// Words packages have words starting with, all of them
+    #[arg(required = true)]
+    terms: Vec<String>,
+  },
+  /// List declared dependencies that packages in an index do not link
+  /// against
+  Orphans {
+    /// Repository directory, or its index file
+    #[arg(default_value = ".")]
+    dir: PathBuf,
+    /// Dependencies never to report, e.g. ones only loaded with dlopen; may
+    /// be repeated or comma-separated
+    #[arg(long, value_name = "NAME", value_delimiter = ',')]
+    allow: Vec<PackageName>,
+  },
+  /// Rank the packages of an index, to help curate the base system
+  #[command(group = clap::ArgGroup::new("report").required(true).multiple(false))]
+  Report {
+    /// Repository directory, or its index file
+    #[arg(default_value = ".")]
+    dir: PathBuf,
+    /// Packages with the largest archives
+    #[arg(long, group = "report")]
+    largest: bool,
+    /// Packages pulling in the most, archives of their dependencies included
+    #[arg(long, group = "report")]
+    closures: bool,
+    /// Packages the most others depend on
+    #[arg(long, group = "report")]
+    rdepends: bool,
+    /// Packages nothing depends on, all of them
+    #[arg(long, group = "report")]
+    leaves: bool,
+    /// Number of packages to list
+    #[arg(long, value_name = "N", default_value_t = 20)]
+    top: usize,
+  },
+  /// List the packages added, removed, upgraded and downgraded between two
+  /// indexes of a repository
+  Changelog {
+    /// Older repository directory, or its index file
+    old: PathBuf,
+    /// Newer repository directory, or its index file
+    new: PathBuf,
+    /// Print JSON instead of text
+    #[arg(long)]
+    json: bool,
+  },
+}
+
+/// The newest version of a package in a repository.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RepoEntry {
+  pub version: PackageVersion,
+  #[serde(default)]
+  pub description: Box<str>,
+  /// File name of the package archive.
+  pub file: Box<str>,
+  /// Checksum of the archive, so that a signed index covers the packages.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub sha256: Option<Hash>,
+  /// Size of the archive in bytes.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub size: Option<u64>,
+  #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+  pub groups: BTreeSet<Box<str>>,
+  #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+  pub provides: BTreeSet<PackageName>,
+  #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+  pub conflicts: BTreeSet<PackageName>,
+  #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+  pub depends: BTreeSet<PackageName>,
+  #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+  pub sonames: BTreeSet<Box<str>>,
+  #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+  pub needed_sonames: BTreeSet<Box<str>>,
+}
+
+impl RepoEntry {
+  /// Checks the archive of this entry in `dir` against its checksum.
+  pub fn verify(&self, dir: &Path, digest: DigestBackend) -> anyhow::Result<()> {
+    let Some(expected) = &self.sha256 else {
+      bail!("the index has no checksum for {}", self.file);
+    };
+    let (sum, _) = hash_file(&dir.join(&*self.file), digest, &ChecksumKind::Sha256)
+      .with_context(|| format!("cannot read {}", self.file))?;
+    if sum != **expected {
+      bail!(
+        "SHA-256 checksum for {} does not correspond:\n\texpected: {}\n\tgot:      {}",
+        self.file,
+        hex::encode(expected),
+        hex::encode(sum)
+      );
+    }
+    Ok(())
+  }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct RepoIndex {
+  pub packages: BTreeMap<PackageName, RepoEntry>,
+  /// Words of the packages, see [`SearchIndex`].
+  #[serde(default, skip_serializing_if = "SearchIndex::is_empty")]
+  pub search: SearchIndex,
+}
+
+impl RepoIndex {
+  /// Loads the index at `path`, or the one in `path` if it is a directory,
+  /// provided it satisfies the trust policy of its repository.
+  pub fn load(path: &Path) -> anyhow::Result<Self> {
+    Ok(Self::load_verified(path)?.0)
+  }
+
+  /// Like [`Self::load`], also returning the key the index is signed with.
+  pub fn load_verified(path: &Path) -> anyhow::Result<(Self, Option<String>)> {
+    let path = if path.is_dir() {
+      path.join(INDEX_FILE)
+    } else {
+      path.into()
+    };
+    let data = fs::read(&path).with_context(|| format!("cannot open {}", path.display()))?;
+    let dir = match path.parent() {
+      Some(x) if !x.as_os_str().is_empty() => x,
+      _ => Path::new("."),
+    };
+    let location = fs::canonicalize(dir)?.display().to_string();
+    let signature = read_signature(&path)?;
+    let key = check_index(&data, signature.as_deref(), &location, true)?;
+    let index = (serde_json::from_slice(&data))
+      .with_context(|| format!("invalid repository index {}", path.display()))?;
+    Ok((index, key))
+  }
+
+  pub fn save(&self, dir: &Path) -> anyhow::Result<()> {
+    let mut f = BufWriter::new(File::create(dir.join(INDEX_FILE))?);
+    serde_json::to_writer_pretty(&mut f, self)?;
+    f.write_all(b"\n")?;
+    Ok(())
+  }
+
+  /// Indexes the package archives in `dir`, along with the files of the
+  /// packages indexed.
+  pub fn scan(dir: &Path, digest: DigestBackend) -> anyhow::Result<(Self, FileDatabase)> {
+    let mut index = Self::default();
+    let mut files = BTreeMap::new();
+    let mut infos = BTreeMap::new();
+    let mut entries = dir.read_dir()?.collect::<Result<Vec<_>, _>>()?;
+    entries.sort_by_key(|x| x.file_name());
+    for entry in entries {
+      let file = entry.file_name().to_string_lossy().into_owned();
+      if !file.ends_with(".tar.zst") || !entry.file_type()?.is_file() {
+        continue;
+      }
+      let (meta, paths) = match read_contents(&entry.path()) {
+        Ok(x) => x,
+        Err(e) => {
+          warning!("skipping {file}: {e}");
+          continue;
+        }
+      };
+      if !meta.variant.is_empty() {
+        println!("Leaving out {file}, a {} build", meta.variant.join("+"));
+        continue;
+      }
+      let name = meta.info.name.clone();
+      let version = meta.info.version.clone();
+      if (index.packages.get(&name)).is_some_and(|x| x.version >= version) {
+        continue;
+      }
+      infos.insert(name.clone(), meta.info.clone());
+      let entry = RepoEntry {
+        version,
+        description: meta.info.description,
+        file: file.into(),
+        sha256: None,
+        size: Some(entry.metadata()?.len()),
+        groups: meta.info.groups,
+        provides: meta.info.provides,
+        conflicts: meta.info.conflicts,
+        depends: meta.info.depends,
+        sonames: meta.sonames,
+        needed_sonames: meta.needed_sonames,
+      };
+      files.insert(name.clone(), paths);
+      index.packages.insert(name, entry);
+    }
+    for info in infos.values() {
+      index.search.insert(info);
+    }
+    for entry in index.packages.values_mut() {
+      let (sum, _) = hash_file(&dir.join(&*entry.file), digest, &ChecksumKind::Sha256)?;
+      entry.sha256 = Some(sum.into());
+    }
+    let mut db = FileDatabase::default();
+    for (name, paths) in files {
+      db.insert(&name, paths);
+    }
+    Ok((index, db))
+  }
+
+  /// Packages named `name`, or providing it as a package or soname.
+  pub fn providers<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a PackageName> {
+    (self.packages.iter())
+      .filter(move |(x, entry)| {
+        ***x == *name
+          || entry.provides.iter().any(|x| **x == *name)
+          || entry.sonames.iter().any(|x| **x == *name)
+      })
+      .map(|(x, _)| x)
+  }
+
+  /// Packages in the group `name`.
+  pub fn group<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a PackageName> {
+    (self.packages.iter())
+      .filter(move |(_, entry)| entry.groups.iter().any(|x| **x == *name))
+      .map(|(x, _)| x)
+  }
+
+  /// Packages depending on `name`, or on a package providing it, by name or
+  /// by linking against its sonames.
+  pub fn dependents<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a PackageName> {
+    let mut targets = BTreeSet::from([name]);
+    for provider in self.providers(name) {
+      let entry = &self.packages[provider];
+      targets.insert(provider);
+      targets.extend(entry.provides.iter().map(|x| &**x));
+      targets.extend(entry.sonames.iter().map(|x| &**x));
+    }
+    (self.packages.iter())
+      .filter(move |(_, entry)| {
+        (entry.depends.iter()).any(|x| targets.contains(&**x))
+          || (entry.needed_sonames.iter()).any(|x| targets.contains(&**x))
+      })
+      .map(|(x, _)| x)
+  }
+
+  /// `name` and everything it depends on, as far as this index has it.
+  pub fn closure<'a>(&'a self, name: &'a PackageName) -> BTreeSet<&'a PackageName> {
+    let mut seen = BTreeSet::new();
+    let mut queue = vec![name];
+    while let Some(name) = queue.pop() {
+      if !seen.insert(name) {
+        continue;
+      }
+      for dep in &self.packages[name].depends {
+        let provider = match self.packages.get_key_value(dep) {
+          Some((x, _)) => Some(x),
+          None => self.providers(dep).next(),
+        };
+        queue.extend(provider);
+      }
+    }
+    seen
+  }
+}
+
+/// Declared dependencies of packages in `index` that they link against no
+/// soname of. This is best-effort: dependencies shipping no shared libraries
+/// may be run instead, so only library ones are reported, and packages
+/// linking nothing at all are left out. Names in `allow` are never reported.
+pub fn orphaned_depends(
+  index: &RepoIndex,
+  allow: &[PackageName],
+) -> BTreeMap<PackageName, BTreeSet<PackageName>> {
+  (index.packages.iter())
+    .filter(|(_, entry)| !entry.needed_sonames.is_empty())
+    .filter_map(|(name, entry)| {
+      let orphans = (entry.depends.iter())
+        .filter(|x| !allow.contains(x))
+        .filter(|x| {
+          let sonames = (index.providers(x))
+            .flat_map(|x| &index.packages[x].sonames)
+            .collect::<BTreeSet<_>>();
+          !sonames.is_empty() && sonames.is_disjoint(&entry.needed_sonames.iter().collect())
+        })
+        .cloned()
+        .collect::<BTreeSet<_>>();
+      (!orphans.is_empty()).then(|| (name.clone(), orphans))
+    })
+    .collect()
+}
+
+/// Reads `metadata.json` from the package archive at `path`.
+pub fn read_metadata(path: &Path) -> anyhow::Result<PackageMeta> {
+  let mut archive = open_archive(path)?;
+  for entry in archive.entries()? {
+    let entry = entry?;
+    if *entry.path()? == *Path::new("metadata.json") {
+      return PackageMeta::from_reader(entry);
+    }
+  }
+  bail!("no metadata.json in {}", path.display())
+}
+
+/// Reads the metadata of the package archive at `path` along with the paths
+/// it ships, directories left out.
+pub fn read_contents(path: &Path) -> anyhow::Result<(PackageMeta, Vec<PathBuf>)> {
+  let mut archive = open_archive(path)?;
+  let mut paths = Vec::new();
+  for entry in archive.entries()? {
+    let entry = entry?;
+    let path = entry.path()?.into_owned();
+    if path == Path::new("metadata.json") {
+      return Ok((PackageMeta::from_reader(entry)?, paths));
+    }
+    if !entry.header().entry_type().is_dir() {
+      paths.push(path);
+    }
+  }
+  bail!("no metadata.json in {}", path.display())
+}
+
+/// A repository directory and its index.
+pub struct Repo {
+  pub dir: PathBuf,
+  pub index: RepoIndex,
+}
+
+impl Repo {
+  /// Opens the repository at `path`, a directory or its index file, provided
+  /// the index satisfies the trust policy.
+  pub fn open(path: &Path) -> anyhow::Result<Self> {
+    let index = RepoIndex::load(path)?;
+    let dir = match path.is_dir() {
+      true => path,
+      false => path.parent().unwrap_or(Path::new(".")),
+    };
+    Ok(Self {
+      dir: dir.into(),
+      index,
+    })
+  }
+}
+
+/// A package picked from one of several repositories.
+pub struct Resolved<'a> {
+  pub repo: &'a Repo,
+  pub name: &'a PackageName,
+  pub entry: &'a RepoEntry,
+}
+
+impl Resolved<'_> {
+  pub fn archive(&self) -> PathBuf {
+    self.repo.dir.join(&*self.entry.file)
+  }
+}
+
+/// Finds `name` in the first of `repos` having it, or else a package
+/// providing it in the first one having such.
+fn find<'a>(repos: &'a [Repo], name: &str) -> Option<Resolved<'a>> {
+  let by_name = (repos.iter()).find(|repo| repo.index.packages.contains_key(name));
+  let (repo, name) = match by_name {
+    Some(repo) => (repo, name),
+    None => (repos.iter()).find_map(|repo| Some((repo, &**repo.index.providers(name).next()?)))?,
+  };
+  let (name, entry) = repo.index.packages.get_key_value(name)?;
+  Some(Resolved { repo, name, entry })
+}
+
+fn visit<'a>(
+  repos: &'a [Repo],
+  name: &str,
+  seen: &mut BTreeSet<&'a PackageName>,
+  order: &mut Vec<Resolved<'a>>,
+) -> anyhow::Result<()> {
+  let Some(package) = find(repos, name) else {
+    bail!("no repository has `{name}` or a package providing it");
+  };
+  if !seen.insert(package.name) {
+    return Ok(());
+  }
+  for dep in &package.entry.depends {
+    visit(repos, dep, seen, order).with_context(|| format!("needed by {}", package.name))?;
+  }
+  order.push(package);
+  Ok(())
+}
+
+/// Packages to install for `names` and everything they depend on, taken from
+/// the earliest of `repos` having them. Dependencies come first, except
+/// within cycles.
+pub fn resolve<'a>(repos: &'a [Repo], names: &[PackageName]) -> anyhow::Result<Vec<Resolved<'a>>> {
+  let mut seen = BTreeSet::new();
+  let mut order = Vec::new();
+  for name in names {
+    visit(repos, name, &mut seen, &mut order)?;
+  }
+  Ok(order)
+}
+
+/// Unpacks the package archive at `path` into `root`, leaving out its
+/// metadata. Returns the paths unpacked, relative to `root`.
+pub fn unpack(path: &Path, root: &Path) -> anyhow::Result<Vec<PathBuf>> {
+  let mut archive = open_archive(path)?;
+  archive.set_preserve_permissions(true);
+  let mut paths = Vec::new();
+  for entry in archive.entries()? {
+    let mut entry = entry?;
+    let path = entry.path()?.into_owned();
+    if path != Path::new("metadata.json") {
+      entry.unpack_in(root)?;
+      paths.push(path);
+    }
+  }
+  Ok(paths)
+}
+
+/// Packages in `index` that link against sonames the freshly `built` packages
+/// no longer provide, with those sonames.
+pub fn rebuild_impact(
+  index: &RepoIndex,
+  built: &[PackageMeta],
+) -> BTreeMap<PackageName, BTreeSet<Box<str>>> {
+  let provided = (built.iter())
+    .flat_map(|x| &x.sonames)
+    .collect::<BTreeSet<_>>();
+  // Sonames moving between split packages of the same build are fine.
+  let dropped = (built.iter())
+    .filter_map(|x| index.packages.get(&x.info.name))
+    .flat_map(|x| &x.sonames)
+    .filter(|x| !provided.contains(x))
+    .collect::<BTreeSet<_>>();
+  let built = built.iter().map(|x| &x.info.name).collect::<BTreeSet<_>>();
+  (index.packages.iter())
+    .filter(|(name, _)| !built.contains(name))
+    .filter_map(|(name, entry)| {
+      let sonames = (entry.needed_sonames.iter())
+        .filter(|x| dropped.contains(x))
+        .cloned()
+        .collect::<BTreeSet<_>>();
+      (!sonames.is_empty()).then(|| (name.clone(), sonames))
+    })
+    .collect()
+}
+
+/// Reports paths shipped by packages that can be installed together, failing
+/// on those the previous file database of `dir` did not have yet. Without
+/// one, they are all taken as known.
+fn check_file_conflicts(dir: &Path, index: &RepoIndex, files: &FileDatabase) -> anyhow::Result<()> {
+  let old = FileDatabase::load(dir)?;
+  let mut known = 0;
+  let mut new = 0;
+  for (path, owners) in files.collisions(index) {
+    match &old {
+      Some(old) if !old.has_collision(path, owners) => {
+        let owners = owners.iter().map(|x| x.to_string()).collect::<Vec<_>>();
+        warning!("/{} is in {}", path.display(), owners.join(", "));
+        new += 1;
+      }
+      _ => known += 1,
+    }
+  }
+  if known > 0 {
+    println!("{known} known file conflict(s) remain");
+  }
+  if new > 0 {
+    bail!("{new} new file conflict(s) between packages not declaring a conflict");
+  }
+  Ok(())
+}
+
+pub fn index(dir: PathBuf) -> anyhow::Result<()> {
+  let config = SigningConfig::load()?;
+  let signer = config.signer()?;
+  let (index, files) = RepoIndex::scan(&dir, config.digest)?;
+  check_file_conflicts(&dir, &index, &files)?;
+  index.save(&dir)?;
+  files.save(&dir)?;
+  segment_info!("Indexed", "{} packages", index.packages.len());
+  // A signature of the previous index would no longer match.
+  let path = dir.join(INDEX_FILE);
+  match fs::remove_file(Signature::path_for(&path)) {
+    Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+    _ => {}
+  }
+  if let Some(signer) = signer {
+    sign_file(&*signer, &path)?;
+    segment_info!("Signed index with key", "{}", signer.key_id());
+  }
+  Ok(())
+}
+
+pub fn verify(dir: PathBuf) -> anyhow::Result<()> {
+  let (index, key) = RepoIndex::load_verified(&dir)?;
+  match key {
+    Some(key) => {
+      segment_info!("Index signed by trusted key", "{key}");
+    }
+    None => println!("The index is not signed, which the trust policy allows here"),
+  }
+  let digest = SigningConfig::load()?.digest;
+  let mut failed = 0;
+  for entry in index.packages.values() {
+    if let Err(e) = entry.verify(&dir, digest) {
+      warning!("{e:#}");
+      failed += 1;
+    }
+  }
+  if failed > 0 {
+    bail!(
+      "{failed} of {} package(s) failed verification",
+      index.packages.len()
+    );
+  }
+  segment_info!("Verified", "{} packages", index.packages.len());
+  Ok(())
+}
+
+pub fn query(
+  dir: PathBuf,
+  provides: Option<Box<str>>,
+  depends: Option<Box<str>>,
+  group: Option<Box<str>>,
+) -> anyhow::Result<()> {
+  let index = RepoIndex::load(&dir)?;
+  let found = match (&provides, &depends, &group) {
+    (Some(name), _, _) => index.providers(name).collect::<Vec<_>>(),
+    (_, Some(name), _) => index.dependents(name).collect(),
+    (_, _, Some(name)) => index.group(name).collect(),
+    (None, None, None) => unreachable!(),
+  };
+  for name in found {
+    let entry = &index.packages[name];
+    println!("{name} {}  {}", entry.version, entry.file);
+  }
+  Ok(())
+}
+
+pub fn search(dir: PathBuf, terms: Vec<String>) -> anyhow::Result<()> {
+  let index = RepoIndex::load(&dir)?;
+  if index.search.is_empty() && !index.packages.is_empty() {
+    bail!("the index has no search terms, index the repository again");
+  }
+  for name in index.search.search(&terms.join(" ")) {
+    let entry = &index.packages[name];
+    println!("{name} {}  {}", entry.version, entry.description);
+  }
+  Ok(())
+}
+
+pub fn orphans(dir: PathBuf, allow: Vec<PackageName>) -> anyhow::Result<()> {
+  let index = RepoIndex::load(&dir)?;
+  let orphans = orphaned_depends(&index, &allow);
+  for (name, depends) in &orphans {
+    let depends = depends.iter().map(|x| x.to_string()).collect::<Vec<_>>();
+    println!("{name}: {}", depends.join(", "));
+  }
+  segment_info!(
+    "Found",
+    "{} package(s) with unlinked dependencies",
+    orphans.len()
+  );
+  Ok(())
+}
+
+/// Lists the packages of the repository at `dir` nothing depends on, or the
+/// `top` ones by archive size, by size with their dependencies, or by number
+/// of dependents.
+pub fn report(
+  dir: PathBuf,
+  largest: bool,
+  closures: bool,
+  rdepends: bool,
+  leaves: bool,
+  top: usize,
+) -> anyhow::Result<()> {
+  let repo = Repo::open(&dir)?;
+  let index = &repo.index;
+  if leaves {
+    for (name, entry) in &index.packages {
+      if index.dependents(name).next().is_none() {
+        println!("{name} {}", entry.version);
+      }
+    }
+    return Ok(());
+  }
+  // Indexes made before sizes were recorded still have the archives at hand.
+  let size = |entry: &RepoEntry| {
+    (entry.size)
+      .or_else(|| {
+        fs::metadata(repo.dir.join(&*entry.file))
+          .ok()
+          .map(|x| x.len())
+      })
+      .unwrap_or_default()
+  };
+  let mut ranked = (index.packages.iter())
+    .map(|(name, entry)| {
+      if largest {
+        let bytes = size(entry);
+        (name, bytes, HumanBytes(bytes).to_string())
+      } else if closures {
+        let closure = index.closure(name);
+        let bytes = closure.iter().map(|x| size(&index.packages[*x])).sum();
+        let summary = format!("{}, {} package(s)", HumanBytes(bytes), closure.len());
+        (name, bytes, summary)
+      } else if rdepends {
+        let count = index.dependents(name).count();
+        (name, count as u64, format!("{count} dependent(s)"))
+      } else {
+        unreachable!()
+      }
+    })
+    .collect::<Vec<_>>();
+  ranked.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+  for (name, _, summary) in ranked.into_iter().take(top) {
+    println!("{name} {}  {summary}", index.packages[name].version);
+  }
+  Ok(())
+}
+
+/// A package whose version changed between two indexes.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct VersionChange {
+  pub old: PackageVersion,
+  pub new: PackageVersion,
+}
+
+/// How the packages of a repository changed between two of its indexes.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Changelog {
+  pub added: BTreeMap<PackageName, PackageVersion>,
+  pub removed: BTreeMap<PackageName, PackageVersion>,
+  pub upgraded: BTreeMap<PackageName, VersionChange>,
+  pub downgraded: BTreeMap<PackageName, VersionChange>,
+}
+
+impl Changelog {
+  pub fn between(old: &RepoIndex, new: &RepoIndex) -> Self {
+    let mut changelog = Self::default();
+    for (name, entry) in &old.packages {
+      let Some(newer) = new.packages.get(name) else {
+        (changelog.removed).insert(name.clone(), entry.version.clone());
+        continue;
+      };
+      let change = || VersionChange {
+        old: entry.version.clone(),
+        new: newer.version.clone(),
+      };
+      if newer.version > entry.version {
+        changelog.upgraded.insert(name.clone(), change());
+      } else if newer.version < entry.version {
+        changelog.downgraded.insert(name.clone(), change());
+      }
+    }
+    for (name, entry) in &new.packages {
+      if !old.packages.contains_key(name) {
+        (changelog.added).insert(name.clone(), entry.version.clone());
+      }
+    }
+    changelog
+  }
+}
+
+pub fn changelog(old: PathBuf, new: PathBuf, json: bool) -> anyhow::Result<()> {
+  let changelog = Changelog::between(&RepoIndex::load(&old)?, &RepoIndex::load(&new)?);
+  if json {
+    serde_json::to_writer_pretty(io::stdout().lock(), &changelog)?;
+    println!();
+    return Ok(());
+  }
+  let sections = [
+    ("Added:", &changelog.added),
+    ("Removed:", &changelog.removed),
+  ];
+  for (title, packages) in sections.into_iter().filter(|x| !x.1.is_empty()) {
+    segment_info!(title);
+    for (name, version) in packages {
+      println!("  {name} {version}");
+    }
+  }
+  let sections = [
+    ("Upgraded:", &changelog.upgraded),
+    ("Downgraded:", &changelog.downgraded),
+  ];
+  for (title, packages) in sections.into_iter().filter(|x| !x.1.is_empty()) {
+    segment_info!(title);
+    for (name, change) in packages {
+      println!("  {name} {} -> {}", change.old, change.new);
+    }
+  }
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use serde_json::json;
+
+  #[test]
+  fn test_rebuild_impact() {
+    let index = serde_json::from_value(json!({ "packages": {
+      "libfoo": { "version": "1.0", "file": "a", "sonames": ["libfoo.so.1"] },
+      "libfoo-extra": { "version": "1.0", "file": "b", "sonames": ["libfoo-x.so.1"] },
+      "app": { "version": "1", "file": "c", "needed_sonames": ["libc.so.6", "libfoo.so.1"] },
+      "tool": { "version": "1", "file": "d", "needed_sonames": ["libfoo-x.so.1"] },
+      "other": { "version": "1", "file": "e", "needed_sonames": ["libc.so.6"] },
+    }}))
+    .unwrap();
+    let meta = |name: &str, sonames: &[&str]| {
+      serde_json::from_value(json!({
+        "architecture": "x86_64",
+        "info": { "name": name, "description": "", "version": "2.0", "architecture": ["x86_64"] },
+        "sonames": sonames,
+      }))
+      .unwrap()
+    };
+    let built = [
+      meta("libfoo", &["libfoo.so.2"]),
+      meta("libfoo-extra", &["libfoo-x.so.1"]),
+    ];
+    let impact = rebuild_impact(&index, &built);
+    assert_eq!(
+      impact.into_iter().collect::<Vec<_>>(),
+      [("app".parse().unwrap(), ["libfoo.so.1".into()].into())]
+    );
+    assert!(rebuild_impact(&index, &[meta("libfoo", &["libfoo.so.1"])]).is_empty());
+  }
+
+  #[test]
+  fn test_lookups() {
+    let index: RepoIndex = serde_json::from_value(json!({ "packages": {
+      "openssl": { "version": "3", "file": "a", "sonames": ["libssl.so.3"] },
+      "bash": { "version": "5", "file": "b", "provides": ["sh"] },
+      "curl": { "version": "8", "file": "c", "needed_sonames": ["libssl.so.3"] },
+      "ca-certs": { "version": "1", "file": "d", "depends": ["openssl", "sh"] },
+    }}))
+    .unwrap();
+    let names = |x: Vec<&PackageName>| x.iter().map(|x| x.to_string()).collect::<Vec<_>>();
+    assert_eq!(names(index.providers("libssl.so.3").collect()), ["openssl"]);
+    assert_eq!(names(index.providers("sh").collect()), ["bash"]);
+    assert_eq!(
+      names(index.dependents("openssl").collect()),
+      ["ca-certs", "curl"]
+    );
+    assert_eq!(
+      names(index.dependents("libssl.so.3").collect()),
+      ["ca-certs", "curl"]
+    );
+    assert_eq!(names(index.dependents("bash").collect()), ["ca-certs"]);
+    assert!(index.dependents("curl").next().is_none());
+    let certs = "ca-certs".parse().unwrap();
+    assert_eq!(
+      names(index.closure(&certs).into_iter().collect()),
+      ["bash", "ca-certs", "openssl"]
+    );
+  }
+
+  #[test]
+  fn test_orphaned_depends() {
+    let index: RepoIndex = serde_json::from_value(json!({ "packages": {
+      "openssl": { "version": "3", "file": "a", "sonames": ["libssl.so.3"] },
+      "zlib": { "version": "1", "file": "b", "sonames": ["libz.so.1"] },
+      "bash": { "version": "5", "file": "c" },
+      "curl": {
+        "version": "8", "file": "d",
+        "depends": ["openssl", "zlib", "bash"], "needed_sonames": ["libssl.so.3"],
+      },
+      "openssl-dev": { "version": "3", "file": "e", "depends": ["openssl"] },
+    }}))
+    .unwrap();
+    let orphans = orphaned_depends(&index, &[]);
+    assert_eq!(
+      orphans.into_iter().collect::<Vec<_>>(),
+      [("curl".parse().unwrap(), ["zlib".parse().unwrap()].into())]
+    );
+    assert!(orphaned_depends(&index, &["zlib".parse().unwrap()]).is_empty());
+  }
+
+  #[test]
+  fn test_changelog() {
+    let old: RepoIndex = serde_json::from_value(json!({ "packages": {
+      "bash": { "version": "5.1", "file": "a" },
+      "curl": { "version": "8.1-1", "file": "b" },
+      "zlib": { "version": "1.3", "file": "c" },
+      "wget": { "version": "1.21", "file": "d" },
+    }}))
+    .unwrap();
+    let new: RepoIndex = serde_json::from_value(json!({ "packages": {
+      "bash": { "version": "5.1", "file": "a" },
+      "curl": { "version": "8.1-2", "file": "b" },
+      "zlib": { "version": "1.2.13", "file": "c" },
+      "wget2": { "version": "2.1", "file": "e" },
+    }}))
+    .unwrap();
+    let changelog = Changelog::between(&old, &new);
+    assert_eq!(
+      serde_json::to_value(&changelog).unwrap(),
+      json!({
+        "added": { "wget2": "2.1" },
+        "removed": { "wget": "1.21" },
+        "upgraded": { "curl": { "old": "8.1-1", "new": "8.1-2" } },
+        "downgraded": { "zlib": { "old": "1.3", "new": "1.2.13" } },
+      })
+    );
+    assert!(Changelog::between(&new, &new).upgraded.is_empty());
+  }
+}
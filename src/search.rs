@@ -0,0 +1,112 @@
+use crate::types::{PackageInfo, PackageName};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::ops::Bound;
+
+/// Words too common to tell packages apart.
+const STOP_WORDS: [&str; 10] = [
+  "a", "an", "and", "for", "in", "of", "on", "the", "to", "with",
+];
+
+/// Lowercase words of `text`, stop words left out.
+fn words(text: &str) -> impl Iterator<Item = String> + '_ {
+  (text.split(|x: char| !x.is_alphanumeric()))
+    .filter(|x| !x.is_empty())
+    .map(str::to_lowercase)
+    .filter(|x| !STOP_WORDS.contains(&&**x))
+}
+
+/// Packages of a repository by the words of their names, descriptions,
+/// provides and keywords, kept in its index so that mirrors can serve
+/// searches as static files.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct SearchIndex {
+  terms: BTreeMap<Box<str>, BTreeSet<PackageName>>,
+}
+
+impl SearchIndex {
+  pub fn is_empty(&self) -> bool {
+    self.terms.is_empty()
+  }
+
+  pub fn insert(&mut self, info: &PackageInfo) {
+    let names = std::iter::once(&info.name).chain(&info.provides);
+    let mut terms = (names.clone())
+      .map(|x| x.to_lowercase())
+      .collect::<BTreeSet<_>>();
+    terms.extend(names.flat_map(|x| words(x)));
+    terms.extend(words(&info.description));
+    for keyword in &info.keywords {
+      terms.insert(keyword.to_lowercase());
+      terms.extend(words(keyword));
+    }
+    for term in terms {
+      let packages = self.terms.entry(term.into()).or_default();
+      packages.insert(info.name.clone());
+    }
+  }
+
+  /// Packages with words starting with each word of `query`, those named
+  /// after one of them first.
+  pub fn search(&self, query: &str) -> Vec<&PackageName> {
+    let query = words(query).collect::<BTreeSet<_>>();
+    let mut found: Option<BTreeSet<&PackageName>> = None;
+    for word in &query {
+      let matching = (self.terms)
+        .range::<str, _>((Bound::Included(&**word), Bound::Unbounded))
+        .take_while(|(term, _)| term.starts_with(&**word))
+        .flat_map(|(_, packages)| packages)
+        .collect::<BTreeSet<_>>();
+      found = Some(match found {
+        Some(found) => found.intersection(&matching).copied().collect(),
+        None => matching,
+      });
+    }
+    let mut found = found.unwrap_or_default().into_iter().collect::<Vec<_>>();
+    found.sort_by_key(|x| !query.contains(&x.to_lowercase()));
+    found
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use serde_json::json;
+
+  #[test]
+  fn test_search() {
+    let mut index = SearchIndex::default();
+    let info = |value| serde_json::from_value::<PackageInfo>(value).unwrap();
+    let base = json!({ "name": "", "description": "", "version": "1", "architecture": ["any"] });
+    let package = |fields: serde_json::Value| {
+      let mut value = base.clone();
+      (value.as_object_mut().unwrap()).extend(fields.as_object().unwrap().clone());
+      info(value)
+    };
+    index.insert(&package(json!({
+      "name": "curl",
+      "description": "Command line tool for transferring data with URLs",
+      "keywords": ["HTTP", "download"],
+    })));
+    index.insert(&package(json!({
+      "name": "libcurl-tools",
+      "description": "Extra tools for curl",
+    })));
+    index.insert(&package(json!({
+      "name": "wget",
+      "description": "Network downloader",
+      "provides": ["http-client"],
+    })));
+    let names = |query| {
+      (index.search(query).into_iter())
+        .map(|x| x.to_string())
+        .collect::<Vec<_>>()
+    };
+    assert_eq!(names("curl"), ["curl", "libcurl-tools"]);
+    assert_eq!(names("download"), ["curl", "wget"]);
+    assert_eq!(names("http client"), ["wget"]);
+    assert_eq!(names("tool for curl"), ["curl", "libcurl-tools"]);
+    assert!(names("the").is_empty());
+  }
+}
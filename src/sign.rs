@@ -0,0 +1,336 @@
+use crate::key::Keyring;
+use crate::types::ChecksumKind;
+use crate::util::config_dir;
+use anyhow::{bail, Context};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine as _;
+use openssl::pkey::{HasPublic, Id, PKey, PKeyRef, Private, Public};
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// A running digest of some data.
+pub trait Digester: Send {
+  fn update(&mut self, data: &[u8]);
+  fn finish(self: Box<Self>) -> Vec<u8>;
+}
+
+macro_rules! impl_digester {
+  ($($ty:ty => $update:path, $finish:path;)*) => {$(
+    impl Digester for $ty {
+      fn update(&mut self, data: &[u8]) {
+        $update(self, data)
+      }
+
+      fn finish(self: Box<Self>) -> Vec<u8> {
+        $finish(*self).to_vec()
+      }
+    }
+  )*};
+}
+
+impl_digester! {
+  openssl::sha::Sha256 => openssl::sha::Sha256::update, openssl::sha::Sha256::finish;
+  openssl::sha::Sha512 => openssl::sha::Sha512::update, openssl::sha::Sha512::finish;
+  sha2::Sha256 => sha2::Digest::update, sha2::Digest::finalize;
+  sha2::Sha512 => sha2::Digest::update, sha2::Digest::finalize;
+}
+
+/// Implementation checksums are computed with.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum DigestBackend {
+  #[default]
+  Openssl,
+  RustCrypto,
+}
+
+impl DigestBackend {
+  pub fn digester(self, kind: &ChecksumKind) -> Box<dyn Digester> {
+    use sha2::Digest;
+    match (self, kind) {
+      (Self::Openssl, ChecksumKind::Sha256) => Box::new(openssl::sha::Sha256::new()),
+      (Self::Openssl, ChecksumKind::Sha512) => Box::new(openssl::sha::Sha512::new()),
+      (Self::RustCrypto, ChecksumKind::Sha256) => Box::new(sha2::Sha256::new()),
+      (Self::RustCrypto, ChecksumKind::Sha512) => Box::new(sha2::Sha512::new()),
+    }
+  }
+}
+
+/// Produces signatures, possibly without the private key ever entering this
+/// process.
+pub trait Signer {
+  /// Identifies the verification key, see [`key_id`].
+  fn key_id(&self) -> &str;
+  fn sign(&self, message: &[u8]) -> anyhow::Result<Vec<u8>>;
+}
+
+/// Signature algorithm of the only key type supported.
+pub const ALGORITHM: &str = "ed25519";
+
+/// Short fingerprint of a public key: the first 8 bytes of the SHA-256 of its
+/// DER encoding, in hex.
+pub fn key_id<T: HasPublic>(key: &PKeyRef<T>) -> anyhow::Result<String> {
+  if key.id() != Id::ED25519 {
+    bail!("unsupported key type, only Ed25519 keys can sign packages");
+  }
+  let digest = openssl::sha::sha256(&key.public_key_to_der()?);
+  Ok(hex::encode(&digest[..8]))
+}
+
+/// Signs with a private key read from a PEM file, through OpenSSL.
+pub struct KeySigner {
+  key: PKey<Private>,
+  id: String,
+}
+
+impl KeySigner {
+  pub fn load(path: &Path) -> anyhow::Result<Self> {
+    let pem = fs::read(path).with_context(|| format!("cannot read key {}", path.display()))?;
+    #[cfg(unix)]
+    if crate::util::file_mode(&fs::metadata(path)?) & 0o077 != 0 {
+      crate::warning!(
+        "private key {} is accessible by other users",
+        path.display()
+      );
+    }
+    let key = PKey::private_key_from_pem(&pem)
+      .with_context(|| format!("invalid private key {}", path.display()))?;
+    let id = key_id(&key).with_context(|| format!("cannot sign with {}", path.display()))?;
+    Ok(Self { key, id })
+  }
+}
+
+impl Signer for KeySigner {
+  fn key_id(&self) -> &str {
+    &self.id
+  }
+
+  fn sign(&self, message: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let mut signer = openssl::sign::Signer::new_without_digest(&self.key)?;
+    Ok(signer.sign_oneshot_to_vec(message)?)
+  }
+}
+
+/// Hands the message to an external program on stdin and takes the raw
+/// signature from its stdout, for keys kept by ssh-agent, a KMS or an HSM.
+/// Its public key must be known up front to name the signatures.
+pub struct CommandSigner {
+  command: Vec<String>,
+  id: String,
+}
+
+impl CommandSigner {
+  pub fn new(command: Vec<String>, public_key: &Path) -> anyhow::Result<Self> {
+    if command.is_empty() {
+      bail!("empty signing command");
+    }
+    let pem = (fs::read(public_key))
+      .with_context(|| format!("cannot read key {}", public_key.display()))?;
+    let key = PKey::public_key_from_pem(&pem)
+      .with_context(|| format!("invalid public key {}", public_key.display()))?;
+    let id = key_id(&key).with_context(|| format!("cannot sign with {}", public_key.display()))?;
+    Ok(Self { command, id })
+  }
+}
+
+impl Signer for CommandSigner {
+  fn key_id(&self) -> &str {
+    &self.id
+  }
+
+  fn sign(&self, message: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let program = &self.command[0];
+    let mut child = Command::new(&self.command[0])
+      .args(&self.command[1..])
+      .stdin(Stdio::piped())
+      .stdout(Stdio::piped())
+      .spawn()
+      .with_context(|| format!("cannot run signing command {program}"))?;
+    let mut stdin = child.stdin.take().expect("stdin should be piped");
+    let output = thread::scope(|s| {
+      // Fed on the side, so that a program answering early does not block.
+      s.spawn(move || stdin.write_all(message));
+      child.wait_with_output()
+    })?;
+    if !output.status.success() {
+      bail!("signing command {program} failed with {}", output.status);
+    }
+    if output.stdout.is_empty() {
+      bail!("signing command {program} printed no signature");
+    }
+    Ok(output.stdout)
+  }
+}
+
+/// Detached signature of a file, kept next to it as `<file>.sig`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Signature {
+  /// [`key_id`] of the signing key.
+  pub key: String,
+  pub algorithm: String,
+  /// Signing time in seconds since the Unix epoch, signed along with the
+  /// file so that old signatures can be told apart.
+  pub created: u64,
+  /// Base64 of the signature of [`Signature::message`].
+  pub signature: String,
+}
+
+impl Signature {
+  pub fn path_for(file: &Path) -> PathBuf {
+    let mut path = file.as_os_str().to_owned();
+    path.push(".sig");
+    path.into()
+  }
+
+  /// What actually gets signed for `data` signed at `created`.
+  fn message(created: u64, data: &[u8]) -> Vec<u8> {
+    const DOMAIN: &[u8] = b"ewepkg signature v1\0";
+    [DOMAIN, &created.to_le_bytes(), data].concat()
+  }
+
+  /// Checks that this is a signature of `data` by `key`.
+  pub fn verify(&self, key: &PKeyRef<Public>, data: &[u8]) -> anyhow::Result<()> {
+    if self.algorithm != ALGORITHM {
+      bail!("unsupported signature algorithm `{}`", self.algorithm);
+    }
+    let signature = (STANDARD.decode(&self.signature)).context("invalid signature encoding")?;
+    let mut verifier = openssl::sign::Verifier::new_without_digest(key)?;
+    // Malformed signatures fail like wrong ones.
+    let valid = verifier.verify_oneshot(&signature, &Self::message(self.created, data));
+    if !valid.unwrap_or(false) {
+      bail!("signature by key {} does not match", self.key);
+    }
+    Ok(())
+  }
+}
+
+/// Seconds since the Unix epoch.
+pub fn unix_time() -> u64 {
+  let now = SystemTime::now().duration_since(UNIX_EPOCH);
+  now.unwrap_or_default().as_secs()
+}
+
+/// Signs the file at `path` with `signer`, writing the signature next to it.
+pub fn sign_file(signer: &dyn Signer, path: &Path) -> anyhow::Result<PathBuf> {
+  let created = unix_time();
+  let signature = signer.sign(&Signature::message(created, &fs::read(path)?))?;
+  let signature = Signature {
+    key: signer.key_id().into(),
+    algorithm: ALGORITHM.into(),
+    created,
+    signature: STANDARD.encode(signature),
+  };
+  let sig_path = Signature::path_for(path);
+  let mut f = BufWriter::new(File::create(&sig_path)?);
+  serde_json::to_writer_pretty(&mut f, &signature)?;
+  f.write_all(b"\n")?;
+  Ok(sig_path)
+}
+
+/// How to sign, set up in `signing.json` of the config directory. Relative
+/// paths in it are relative to that directory.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SigningConfig {
+  /// Implementation of the checksums of sources, cached builds and
+  /// repository indexes.
+  #[serde(default)]
+  pub digest: DigestBackend,
+  /// Signer of repository indexes; they are left unsigned without one.
+  pub signer: Option<SignerConfig>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub enum SignerConfig {
+  /// An Ed25519 private key in a PEM file.
+  Key { path: PathBuf },
+  /// A signing key of the keyring, see `ewe key`.
+  Keyring { id: String },
+  /// A program signing what it reads on stdin, see [`CommandSigner`].
+  Command {
+    command: Vec<String>,
+    public_key: PathBuf,
+  },
+}
+
+impl SigningConfig {
+  pub fn path() -> Option<PathBuf> {
+    Some(config_dir()?.join("signing.json"))
+  }
+
+  pub fn load() -> anyhow::Result<Self> {
+    let Some(path) = Self::path().filter(|x| x.exists()) else {
+      return Ok(Self::default());
+    };
+    let f = File::open(&path)?;
+    (serde_json::from_reader(BufReader::new(f)))
+      .with_context(|| format!("invalid signing config {}", path.display()))
+  }
+
+  pub fn signer(&self) -> anyhow::Result<Option<Box<dyn Signer>>> {
+    let dir = config_dir().unwrap_or_default();
+    Ok(match &self.signer {
+      None => None,
+      Some(SignerConfig::Key { path }) => Some(Box::new(KeySigner::load(&dir.join(path))?)),
+      Some(SignerConfig::Keyring { id }) => {
+        let path = Keyring::open()?.private_path(id)?;
+        Some(Box::new(KeySigner::load(&path)?))
+      }
+      Some(SignerConfig::Command {
+        command,
+        public_key,
+      }) => Some(Box::new(CommandSigner::new(
+        command.clone(),
+        &dir.join(public_key),
+      )?)),
+    })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use tempfile::tempdir;
+
+  #[test]
+  fn test_digest_backends() {
+    let data = (0..100_000).map(|x| x as u8).collect::<Vec<_>>();
+    for kind in [ChecksumKind::Sha256, ChecksumKind::Sha512] {
+      let [a, b] = [DigestBackend::Openssl, DigestBackend::RustCrypto].map(|backend| {
+        let mut digester = backend.digester(&kind);
+        data.chunks(4096).for_each(|x| digester.update(x));
+        digester.finish()
+      });
+      assert_eq!(a, b);
+    }
+  }
+
+  #[test]
+  fn test_key_signer() {
+    let dir = tempdir().unwrap();
+    let key = PKey::generate_ed25519().unwrap();
+    let path = dir.path().join("key.pem");
+    fs::write(&path, key.private_key_to_pem_pkcs8().unwrap()).unwrap();
+    let index = dir.path().join("index.json");
+    fs::write(&index, "{}\n").unwrap();
+
+    let signer = KeySigner::load(&path).unwrap();
+    assert_eq!(signer.key_id(), key_id(&key).unwrap());
+    let sig_path = sign_file(&signer, &index).unwrap();
+    let sig: Signature = serde_json::from_slice(&fs::read(sig_path).unwrap()).unwrap();
+    let public = PKey::public_key_from_der(&key.public_key_to_der().unwrap()).unwrap();
+    sig.verify(&public, b"{}\n").unwrap();
+    assert!(sig.verify(&public, b"{}\n\n").is_err());
+    let old = Signature {
+      created: sig.created - 1,
+      ..sig
+    };
+    assert!(old.verify(&public, b"{}\n").is_err());
+  }
+}
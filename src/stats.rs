@@ -0,0 +1,189 @@
+use crate::types::PackageName;
+use crate::util::cache_dir;
+use crate::{segment_info, warning};
+use indicatif::{HumanBytes, HumanDuration};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Write};
+use std::path::PathBuf;
+use std::time::Duration;
+use tempfile::NamedTempFile;
+
+/// Weight of the newest sample in running averages, so that they follow a
+/// mirror or package changing over time.
+const WEIGHT: f64 = 0.3;
+
+fn average(old: f64, count: u64, sample: f64) -> f64 {
+  match count {
+    0 => sample,
+    _ => old + (sample - old) * WEIGHT,
+  }
+}
+
+/// Downloads from one host.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct HostStats {
+  pub downloads: u64,
+  /// Running average in bytes per second.
+  pub speed: f64,
+}
+
+/// Builds of one package.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct BuildStats {
+  pub builds: u64,
+  /// Running average in seconds.
+  pub duration: f64,
+}
+
+/// Hashing with one digest.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct HashStats {
+  pub files: u64,
+  /// Running average in bytes per second.
+  pub speed: f64,
+}
+
+/// Fetch and build statistics of this machine, kept across runs in the cache
+/// directory.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Stats {
+  #[serde(default)]
+  pub hosts: BTreeMap<String, HostStats>,
+  #[serde(default)]
+  pub builds: BTreeMap<PackageName, BuildStats>,
+  #[serde(default)]
+  pub hashing: BTreeMap<String, HashStats>,
+}
+
+impl Stats {
+  fn path() -> Option<PathBuf> {
+    Some(cache_dir()?.join("stats.json"))
+  }
+
+  /// Loads the statistics, starting over if there are none or they are
+  /// unreadable.
+  pub fn load() -> Self {
+    let Some(path) = Self::path() else {
+      return Self::default();
+    };
+    let Ok(f) = File::open(&path) else {
+      return Self::default();
+    };
+    serde_json::from_reader(BufReader::new(f)).unwrap_or_else(|e| {
+      warning!("ignoring invalid statistics {}: {e}", path.display());
+      Self::default()
+    })
+  }
+
+  fn save(&self) -> anyhow::Result<()> {
+    let Some(path) = Self::path() else {
+      return Ok(());
+    };
+    let dir = path.parent().unwrap();
+    fs::create_dir_all(dir)?;
+    // Replaced in one go, so that concurrent builds never read half of it.
+    let mut f = BufWriter::new(NamedTempFile::new_in(dir)?);
+    serde_json::to_writer(&mut f, self)?;
+    f.write_all(b"\n")?;
+    f.into_inner()?.persist(path)?;
+    Ok(())
+  }
+
+  /// Records samples with `f`. Statistics are best-effort: failing to save
+  /// them is only warned about, and concurrent runs may lose samples.
+  pub fn update(f: impl FnOnce(&mut Self)) {
+    let mut stats = Self::load();
+    f(&mut stats);
+    if let Err(e) = stats.save() {
+      warning!("cannot save statistics: {e}");
+    }
+  }
+
+  pub fn record_download(&mut self, host: &str, bytes: u64, elapsed: Duration) {
+    let secs = elapsed.as_secs_f64();
+    if secs <= 0.0 {
+      return;
+    }
+    let stats = self.hosts.entry(host.into()).or_default();
+    stats.speed = average(stats.speed, stats.downloads, bytes as f64 / secs);
+    stats.downloads += 1;
+  }
+
+  /// Records hashing `bytes` with the digest `kind`. Small files are left
+  /// out, as thread startup dominates them.
+  pub fn record_hash(&mut self, kind: &str, bytes: u64, elapsed: Duration) {
+    const MIN_BYTES: u64 = 1 << 20;
+    let secs = elapsed.as_secs_f64();
+    if bytes < MIN_BYTES || secs <= 0.0 {
+      return;
+    }
+    let stats = self.hashing.entry(kind.into()).or_default();
+    stats.speed = average(stats.speed, stats.files, bytes as f64 / secs);
+    stats.files += 1;
+  }
+
+  pub fn record_build(&mut self, name: &PackageName, elapsed: Duration) {
+    let stats = self.builds.entry(name.clone()).or_default();
+    stats.duration = average(stats.duration, stats.builds, elapsed.as_secs_f64());
+    stats.builds += 1;
+  }
+
+  /// How long building `name` usually takes.
+  pub fn estimate(&self, name: &PackageName) -> Option<Duration> {
+    let stats = self.builds.get(name)?;
+    Some(Duration::from_secs_f64(stats.duration))
+  }
+}
+
+/// Prints the statistics, fastest hosts and slowest builds first.
+pub fn show() -> anyhow::Result<()> {
+  let stats = Stats::load();
+  if let Some(path) = Stats::path() {
+    println!("Statistics in {}", path.display());
+  }
+
+  segment_info!("Download speeds:");
+  let mut hosts = stats.hosts.iter().collect::<Vec<_>>();
+  hosts.sort_by(|a, b| b.1.speed.total_cmp(&a.1.speed));
+  for (host, x) in hosts {
+    let speed = HumanBytes(x.speed as u64);
+    println!("  {host}: {speed}/s over {} download(s)", x.downloads);
+  }
+
+  segment_info!("Build durations:");
+  let mut builds = stats.builds.iter().collect::<Vec<_>>();
+  builds.sort_by(|a, b| b.1.duration.total_cmp(&a.1.duration));
+  for (name, x) in builds {
+    let duration = HumanDuration(Duration::from_secs_f64(x.duration));
+    println!("  {name}: {duration} over {} build(s)", x.builds);
+  }
+
+  segment_info!("Hashing speeds:");
+  for (kind, x) in &stats.hashing {
+    let speed = HumanBytes(x.speed as u64);
+    println!("  {kind}: {speed}/s over {} file(s)", x.files);
+  }
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_averages() {
+    let mut stats = Stats::default();
+    let name = "foo".parse().unwrap();
+    stats.record_build(&name, Duration::from_secs(100));
+    assert_eq!(stats.estimate(&name), Some(Duration::from_secs(100)));
+    stats.record_build(&name, Duration::from_secs(200));
+    assert_eq!(stats.estimate(&name), Some(Duration::from_secs(130)));
+
+    stats.record_download("a.org", 1000, Duration::from_secs(2));
+    stats.record_download("a.org", 100, Duration::ZERO);
+    assert_eq!(stats.hosts["a.org"].speed, 500.0);
+    assert_eq!(stats.hosts["a.org"].downloads, 1);
+  }
+}
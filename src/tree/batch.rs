@@ -0,0 +1,387 @@
+use super::{IndexEntry, TreeIndex};
+use crate::build::{
+  host_arch, BuildCacheOptions, BuildManifest, BuildOptions, Builder, Limits, QemuOptions,
+  QEMU_HOST,
+};
+use crate::repo::DICTIONARY_DIR;
+use crate::stats::Stats;
+use crate::types::{Hash, PackageName};
+use crate::{segment_info, warning};
+use anyhow::{anyhow, bail};
+use clap::Args;
+use console::style;
+use indicatif::HumanDuration;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+/// Options for building a whole tree.
+#[derive(Debug, Clone, Args)]
+pub struct BatchOptions {
+  /// Builder to spread builds over, as `ARCH=HOST` for an SSH host,
+  /// `ARCH=local` for this machine, cross-building foreign architectures, or
+  /// `ARCH=qemu` for emulation on it; may be repeated. Defaults to this
+  /// machine
+  #[arg(long = "builder", value_name = "ARCH=HOST")]
+  pub builders: Vec<Builder>,
+
+  /// ewepkg executable on remote builders
+  #[arg(long, value_name = "PATH", default_value = "ewe")]
+  pub remote_ewe: String,
+
+  /// Directory to collect packages, manifests and build logs in
+  #[arg(long, value_name = "DIR", default_value = "out")]
+  pub output: PathBuf,
+
+  /// Only build the scripts that failed in the previous run, leaving out
+  /// those it did not get to
+  #[arg(long)]
+  pub retry_failed: bool,
+
+  #[command(flatten)]
+  pub qemu: QemuOptions,
+}
+
+/// Name of the file in the output directory recording the progress of an
+/// unfinished tree build.
+const PROGRESS_FILE: &str = "progress.json";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum State {
+  Pending,
+  Running,
+  Built,
+  Failed,
+  Skipped,
+  /// Not to be built in this run.
+  LeftOut,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Finished {
+  state: State,
+  /// Digest of the script when it finished.
+  sha256: Hash,
+}
+
+/// How the builds of earlier runs over a tree ended, by script path, so that
+/// a run can continue where a failed or interrupted one stopped.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct Progress {
+  scripts: BTreeMap<String, Finished>,
+}
+
+impl Progress {
+  fn load(path: &Path) -> anyhow::Result<Self> {
+    let f = match File::open(path) {
+      Ok(f) => f,
+      Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Self::default()),
+      Err(e) => return Err(e.into()),
+    };
+    match serde_json::from_reader(BufReader::new(f)) {
+      Ok(progress) => Ok(progress),
+      Err(e) => {
+        warning!("ignoring invalid progress file {}: {e}", path.display());
+        Ok(Self::default())
+      }
+    }
+  }
+
+  fn save(&self, path: &Path) -> anyhow::Result<()> {
+    let mut f = BufWriter::new(File::create(path)?);
+    serde_json::to_writer(&mut f, self)?;
+    f.write_all(b"\n")?;
+    Ok(())
+  }
+
+  /// The state script `path` is in at the start of a run: done if it was
+  /// built unchanged before, and with `retry_failed` left out unless it failed.
+  fn initial_state(&self, path: &str, entry: &IndexEntry, retry_failed: bool) -> State {
+    match self.scripts.get(path) {
+      Some(x) if x.state == State::Built && x.sha256 == entry.sha256 => State::Built,
+      Some(x) if x.state == State::Failed => State::Pending,
+      _ if retry_failed => State::LeftOut,
+      _ => State::Pending,
+    }
+  }
+}
+
+/// Build scripts each script has to wait for, as indices into `scripts`.
+fn dependencies(scripts: &[(&String, &IndexEntry)]) -> Vec<BTreeSet<usize>> {
+  let mut providers = BTreeMap::<&PackageName, usize>::new();
+  for (i, (_, entry)) in scripts.iter().enumerate() {
+    for package in &entry.packages {
+      providers.insert(&package.name, i);
+      for name in &package.provides {
+        providers.entry(name).or_insert(i);
+      }
+    }
+  }
+  (scripts.iter().enumerate())
+    .map(|(i, (_, entry))| {
+      let source = &entry.source;
+      (source.depends.iter())
+        .chain(&source.build_depends)
+        .filter_map(|x| providers.get(x).copied())
+        .filter(|x| *x != i)
+        .collect()
+    })
+    .collect()
+}
+
+/// Estimated time until the unfinished scripts are built, if every one of
+/// them was built before, assuming builds keep all `builders` busy.
+fn remaining(
+  stats: &Stats,
+  scripts: &[(&String, &IndexEntry)],
+  states: &[State],
+  builders: usize,
+) -> Option<Duration> {
+  let unfinished = (scripts.iter().zip(states))
+    .filter(|(_, x)| matches!(x, State::Pending | State::Running))
+    .map(|((_, entry), _)| stats.estimate(&entry.source.name))
+    .collect::<Option<Vec<_>>>()?;
+  Some(unfinished.into_iter().sum::<Duration>() / builders as u32)
+}
+
+/// Moves the packages and manifest the build of `entry` left in `dir` into
+/// `output`, and copies the zstd dictionaries they may need.
+fn collect(entry: &IndexEntry, arch: &str, dir: &Path, output: &Path) -> anyhow::Result<()> {
+  let info = &entry.source;
+  let arch = if info.architecture.contains_all() {
+    "all"
+  } else {
+    arch
+  };
+  let name = format!("{}_{}_{arch}.manifest.json", info.name, info.version);
+  let manifest: BuildManifest =
+    serde_json::from_reader(BufReader::new(File::open(dir.join(&name))?))?;
+  for file in (manifest.packages.iter().map(|x| &**x)).chain([&*name]) {
+    let (src, dst) = (dir.join(file), output.join(file));
+    if fs::rename(&src, &dst).is_err() {
+      fs::copy(&src, &dst)?;
+      fs::remove_file(&src)?;
+    }
+  }
+  let dicts = dir.join(DICTIONARY_DIR);
+  if dicts.is_dir() {
+    fs::create_dir_all(output.join(DICTIONARY_DIR))?;
+    for entry in dicts.read_dir()? {
+      let entry = entry?;
+      fs::copy(
+        entry.path(),
+        output.join(DICTIONARY_DIR).join(entry.file_name()),
+      )?;
+    }
+  }
+  Ok(())
+}
+
+/// Builds every script below `tree` in dependency order, running independent
+/// ones in parallel on the builders for their architecture. Progress is kept
+/// in the output directory until a run has no failures, so that a run goes on
+/// where a failed or interrupted one stopped.
+pub fn build_all(
+  tree: PathBuf,
+  limits: Limits,
+  options: BuildOptions,
+  cache: BuildCacheOptions,
+  mut batch: BatchOptions,
+) -> anyhow::Result<()> {
+  let host = host_arch()?;
+  let mut builders = batch.builders;
+  if builders.is_empty() {
+    let arch = host.clone();
+    builders.push(Builder { arch, host: None });
+  }
+  // Builds run in the directories of their scripts.
+  if let Some(repo) = &mut batch.qemu.qemu_repo {
+    *repo = repo.canonicalize()?;
+  }
+  let logs = batch.output.join("logs");
+  fs::create_dir_all(&logs)?;
+  let output = batch.output.canonicalize()?;
+  let exe = std::env::current_exe()?;
+
+  let (index, _, _) = TreeIndex::update(&tree, limits, 0)?;
+  let stats = Stats::load();
+  let scripts = index.scripts.iter().collect::<Vec<_>>();
+  let deps = dependencies(&scripts);
+  let progress_path = output.join(PROGRESS_FILE);
+  let mut progress = Progress::load(&progress_path)?;
+  progress
+    .scripts
+    .retain(|x, _| index.scripts.contains_key(x));
+  let mut states = (scripts.iter())
+    .map(|(path, entry)| progress.initial_state(path, entry, batch.retry_failed))
+    .collect::<Vec<_>>();
+  let count = |states: &[State], state| states.iter().filter(|x| **x == state).count();
+  if batch.retry_failed && count(&states, State::Pending) == 0 {
+    warning!("no failed builds to retry in {}", progress_path.display());
+    return Ok(());
+  }
+  if count(&states, State::Built) > 0 {
+    segment_info!(
+      "Continuing",
+      "{} build script(s) already built, see {}",
+      count(&states, State::Built),
+      progress_path.display()
+    );
+  }
+  let finish = |progress: &mut Progress, i: usize, state| -> anyhow::Result<()> {
+    let (path, entry) = scripts[i];
+    let sha256 = entry.sha256.clone();
+    (progress.scripts).insert(path.clone(), Finished { state, sha256 });
+    progress.save(&progress_path)
+  };
+  let mut idle = (0..builders.len()).collect::<BTreeSet<_>>();
+  let (tx, rx) = mpsc::channel();
+  let mut running = 0;
+
+  loop {
+    let unbuildable = |states: &[State], i: usize| {
+      let arches = &scripts[i].1.source.architecture;
+      let failed = |x: &usize| matches!(states[*x], State::Failed | State::Skipped);
+      if deps[i].iter().any(failed) {
+        Some("a dependency failed")
+      } else if (deps[i].iter()).any(|x| states[*x] == State::LeftOut) {
+        Some("a dependency is left out")
+      } else if !builders.iter().any(|x| arches.contains(&x.arch)) {
+        Some("no builder for its architectures")
+      } else {
+        None
+      }
+    };
+    // Skipping one script may mean skipping others, whatever their order.
+    while let Some((i, reason)) = (0..scripts.len())
+      .filter(|i| states[*i] == State::Pending)
+      .find_map(|i| Some((i, unbuildable(&states, i)?)))
+    {
+      warning!("skipping {}, {reason}", scripts[i].1.source.name);
+      states[i] = State::Skipped;
+      finish(&mut progress, i, State::Skipped)?;
+    }
+
+    for i in 0..scripts.len() {
+      let ready = deps[i].iter().all(|x| states[*x] == State::Built);
+      if states[i] != State::Pending || !ready {
+        continue;
+      }
+      let (path, entry) = scripts[i];
+      let name = &entry.source.name;
+      let arches = &entry.source.architecture;
+      let Some(b) = (idle.iter().copied()).find(|x| arches.contains(&builders[*x].arch)) else {
+        continue;
+      };
+      idle.remove(&b);
+      states[i] = State::Running;
+      running += 1;
+
+      let builder = builders[b].clone();
+      let usually = (stats.estimate(name))
+        .map(|x| format!(", usually takes {}", HumanDuration(x)))
+        .unwrap_or_default();
+      segment_info!("Building", "{name} on {}{usually}", builder.name());
+      let script = tree.join(path);
+      let dir = script.parent().unwrap().to_path_buf();
+      let log = File::create(logs.join(format!("{name}.log")))?;
+      let mut command = Command::new(&exe);
+      command
+        .arg("build")
+        .arg(script.file_name().unwrap())
+        .args(limits.to_args())
+        .args(options.to_args())
+        .args(cache.to_args())
+        .current_dir(&dir)
+        .stdin(Stdio::null())
+        .stdout(log.try_clone()?)
+        .stderr(log);
+      match builder.host.as_deref() {
+        Some(QEMU_HOST) => {
+          let arch = &builder.arch;
+          command.args(["--arch", arch, "--builder", &format!("{arch}={QEMU_HOST}")]);
+          command.args(batch.qemu.to_args());
+        }
+        Some(host) => {
+          command.args(["--remote", host, "--remote-ewe", &batch.remote_ewe]);
+        }
+        None if builder.arch != host => {
+          command.args(["--arch", &builder.arch]);
+        }
+        None => {}
+      }
+      let (tx, output) = (tx.clone(), output.clone());
+      let entry = entry.clone();
+      thread::spawn(move || {
+        let result = match command.status() {
+          Ok(status) if status.success() => collect(&entry, &builder.arch, &dir, &output),
+          Ok(status) => Err(anyhow!("build exited with {status}")),
+          Err(e) => Err(e.into()),
+        };
+        tx.send((i, b, result)).unwrap();
+      });
+    }
+
+    if running == 0 {
+      break;
+    }
+    let (i, b, result) = rx.recv()?;
+    running -= 1;
+    idle.insert(b);
+    let name = &scripts[i].1.source.name;
+    match result {
+      Ok(()) => {
+        states[i] = State::Built;
+        finish(&mut progress, i, State::Built)?;
+        let left = (remaining(&stats, &scripts, &states, builders.len()))
+          .filter(|_| running > 0 || states.contains(&State::Pending))
+          .map(|x| format!(", about {} left", HumanDuration(x)))
+          .unwrap_or_default();
+        segment_info!("Built", "{name}{left}");
+      }
+      Err(e) => {
+        states[i] = State::Failed;
+        finish(&mut progress, i, State::Failed)?;
+        eprintln!(
+          "{} {name} failed: {e}, see {}",
+          style("error:").red().bold(),
+          logs.join(format!("{name}.log")).display()
+        );
+      }
+    }
+  }
+
+  let count = |state| count(&states, state);
+  let cycle = count(State::Pending);
+  if cycle > 0 {
+    warning!("{cycle} build script(s) skipped for depending on each other");
+  }
+  segment_info!(
+    "Finished",
+    "{} built, {} failed, {} skipped",
+    count(State::Built),
+    count(State::Failed),
+    count(State::Skipped) + cycle,
+  );
+  if count(State::Failed) > 0 {
+    bail!(
+      "{} build(s) failed, run again with --retry-failed once fixed",
+      count(State::Failed)
+    );
+  }
+  // Left out scripts are still to be built in the next run.
+  if count(State::LeftOut) > 0 {
+    return Ok(());
+  }
+  match fs::remove_file(&progress_path) {
+    Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+    _ => Ok(()),
+  }
+}
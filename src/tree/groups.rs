@@ -0,0 +1,95 @@
+use crate::types::PackageInfo;
+use anyhow::{bail, Context};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// Name of the file at the root of a package tree listing the groups
+/// packages may be in.
+pub const GROUPS_FILE: &str = "GROUPS";
+
+/// The groups of a package tree, one per line with an optional description:
+///
+/// ```text
+/// base    Minimal installable system
+/// devel   Compilers and build tools
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct Groups {
+  groups: BTreeMap<Box<str>, Box<str>>,
+}
+
+impl Groups {
+  pub fn parse(s: &str) -> anyhow::Result<Self> {
+    let mut groups = BTreeMap::new();
+    for (i, line) in s.lines().enumerate() {
+      let line = line.split('#').next().unwrap_or_default().trim();
+      if line.is_empty() {
+        continue;
+      }
+      let (name, description) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+      let valid = (name.chars()).all(|x| x.is_ascii_lowercase() || x.is_ascii_digit() || x == '-');
+      if !valid {
+        bail!("line {}: invalid group name `{name}`", i + 1);
+      }
+      if groups
+        .insert(name.into(), description.trim().into())
+        .is_some()
+      {
+        bail!("line {}: group `{name}` is listed twice", i + 1);
+      }
+    }
+    Ok(Self { groups })
+  }
+
+  /// Reads the groups file of the tree at `dir`, if there is one.
+  pub fn load(dir: &Path) -> anyhow::Result<Option<Self>> {
+    let path = dir.join(GROUPS_FILE);
+    match fs::read_to_string(&path) {
+      Ok(s) => Self::parse(&s)
+        .with_context(|| format!("invalid {}", path.display()))
+        .map(Some),
+      Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+      Err(e) => Err(e.into()),
+    }
+  }
+
+  /// Finds the groups file of the tree the build script at `path` is in, by
+  /// looking through its parent directories.
+  pub fn find(path: &Path) -> anyhow::Result<Option<Self>> {
+    let path = path.canonicalize()?;
+    for dir in path.ancestors().skip(1) {
+      if let Some(groups) = Self::load(dir)? {
+        return Ok(Some(groups));
+      }
+    }
+    Ok(None)
+  }
+
+  pub fn contains(&self, name: &str) -> bool {
+    self.groups.contains_key(name)
+  }
+
+  /// Groups of `info` that are not in the tree.
+  pub fn unknown<'a>(&'a self, info: &'a PackageInfo) -> impl Iterator<Item = &'a str> {
+    (info.groups.iter())
+      .map(|x| &**x)
+      .filter(|x| !self.contains(x))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_groups() {
+    let groups = Groups::parse("# comment\nbase  Minimal system\n\ndevel\n").unwrap();
+    assert!(groups.contains("base"));
+    assert!(groups.contains("devel"));
+    assert!(!groups.contains("games"));
+    assert!(Groups::parse("Base").is_err());
+    assert!(Groups::parse("base\nbase again").is_err());
+  }
+}
@@ -0,0 +1,224 @@
+mod batch;
+mod groups;
+mod owners;
+mod policy;
+mod query;
+
+pub use batch::{build_all, BatchOptions};
+pub use groups::{Groups, GROUPS_FILE};
+pub use owners::Owners;
+pub use policy::{Policy, POLICY_FILE};
+
+use crate::build::{find_scripts, host_arch, load_source, Limits};
+use crate::types::{Hash, PackageInfo, SourceInfo};
+use crate::util::par_map;
+use crate::{segment_info, warning};
+use clap::Subcommand;
+use openssl::sha::sha256;
+use query::Query;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+/// Name of the index file at the root of a package tree.
+const INDEX_FILE: &str = ".ewe-index.json";
+
+/// An evaluated build script.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IndexEntry {
+  /// Digest of the script the entry was evaluated from.
+  pub sha256: Hash,
+  pub source: SourceInfo,
+  pub packages: Vec<PackageInfo>,
+}
+
+/// Metadata of every build script in a package tree, keyed by their path
+/// relative to the tree root.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct TreeIndex {
+  /// Version of ewepkg and architecture the scripts were evaluated with,
+  /// which their metadata may depend on.
+  #[serde(default)]
+  evaluator: String,
+  pub scripts: BTreeMap<String, IndexEntry>,
+}
+
+/// Identifies how build scripts get evaluated on this host.
+fn evaluator() -> anyhow::Result<String> {
+  Ok(format!("{} {}", env!("CARGO_PKG_VERSION"), host_arch()?))
+}
+
+impl TreeIndex {
+  /// Loads the index of `tree`, or returns an empty one if there is none yet
+  /// or it was evaluated differently.
+  fn load(tree: &Path) -> anyhow::Result<Self> {
+    let path = tree.join(INDEX_FILE);
+    let f = match File::open(&path) {
+      Ok(f) => f,
+      Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Self::default()),
+      Err(e) => return Err(e.into()),
+    };
+    let index: Self = match serde_json::from_reader(BufReader::new(f)) {
+      Ok(index) => index,
+      Err(e) => {
+        warning!("ignoring invalid index {}: {e}", path.display());
+        return Ok(Self::default());
+      }
+    };
+    if index.evaluator != evaluator()? {
+      return Ok(Self::default());
+    }
+    Ok(index)
+  }
+
+  /// Brings the index of `tree` up to date with its build scripts, evaluating
+  /// only new and changed ones on up to `jobs` threads and skipping those that
+  /// fail. Returns the index, how many scripts were evaluated and how many
+  /// failed.
+  pub fn update(tree: &Path, limits: Limits, jobs: usize) -> anyhow::Result<(Self, usize, usize)> {
+    let mut scripts = Vec::new();
+    find_scripts(tree, &mut scripts)?;
+    let mut old = Self::load(tree)?;
+    let mut index = Self {
+      evaluator: evaluator()?,
+      ..Default::default()
+    };
+    let mut changed = Vec::new();
+    for path in scripts {
+      let rel = path.strip_prefix(tree).unwrap_or(&path);
+      let rel = rel.to_string_lossy().into_owned();
+      let sha256 = Hash::from(sha256(&fs::read(&path)?).to_vec());
+      match old.scripts.remove(&rel).filter(|x| x.sha256 == sha256) {
+        Some(entry) => {
+          index.scripts.insert(rel, entry);
+        }
+        None => changed.push((path, rel, sha256)),
+      }
+    }
+
+    // Each script gets an engine of its own, and only its metadata is kept.
+    let entries = par_map(&changed, jobs, |(path, _, sha256)| {
+      let source = load_source(path, limits)?;
+      anyhow::Ok(IndexEntry {
+        sha256: sha256.clone(),
+        packages: source.packages.iter().map(|x| x.info.clone()).collect(),
+        source: source.info,
+      })
+    });
+    let mut failed = 0;
+    for ((path, rel, _), entry) in changed.iter().zip(entries) {
+      match entry {
+        Ok(entry) => {
+          index.scripts.insert(rel.clone(), entry);
+        }
+        Err(e) => {
+          failed += 1;
+          warning!("skipping {}: {e}", path.display());
+        }
+      }
+    }
+    index.save(tree)?;
+    Ok((index, changed.len(), failed))
+  }
+
+  pub fn save(&self, tree: &Path) -> anyhow::Result<()> {
+    let mut f = BufWriter::new(File::create(tree.join(INDEX_FILE))?);
+    serde_json::to_writer(&mut f, self)?;
+    f.write_all(b"\n")?;
+    Ok(())
+  }
+
+  /// Every package as a JSON object, with fields of its source it does not
+  /// have itself, like `build_depends`.
+  pub fn packages(&self) -> serde_json::Result<Vec<(&str, Value)>> {
+    let mut packages = Vec::new();
+    for (path, entry) in &self.scripts {
+      let Value::Object(source) = serde_json::to_value(&entry.source)? else {
+        unreachable!()
+      };
+      for package in &entry.packages {
+        let mut value = serde_json::to_value(package)?;
+        let object = value.as_object_mut().unwrap();
+        for (key, x) in &source {
+          object.entry(key).or_insert_with(|| x.clone());
+        }
+        packages.push((&**path, value));
+      }
+    }
+    Ok(packages)
+  }
+}
+
+/// Evaluates the new and changed build scripts below `tree` into its index.
+pub fn index(tree: PathBuf, limits: Limits, jobs: usize) -> anyhow::Result<()> {
+  let (index, evaluated, failed) = TreeIndex::update(&tree, limits, jobs)?;
+  segment_info!(
+    "Indexed",
+    "{} build scripts ({evaluated} evaluated, {failed} failed)",
+    index.scripts.len()
+  );
+  Ok(())
+}
+
+/// Prints the maintainers of the package `name`.
+pub fn owner(tree: PathBuf, name: String) -> anyhow::Result<()> {
+  let Some(owners) = Owners::load(&tree)? else {
+    anyhow::bail!("no {} in {}", owners::OWNERS_FILE, tree.display());
+  };
+  let owners = owners.of(&name);
+  if owners.is_empty() {
+    anyhow::bail!("{name} has no maintainers");
+  }
+  for owner in owners {
+    println!("{owner}");
+  }
+  Ok(())
+}
+
+/// Prints the packages in the index of `tree` matching `query`.
+pub fn query(tree: PathBuf, query: String, limits: Limits) -> anyhow::Result<()> {
+  let query = Query::parse(&query)?;
+  let (index, _, _) = TreeIndex::update(&tree, limits, 0)?;
+  for (path, package) in index.packages()? {
+    if query.matches(&package) {
+      let field = |x: &str| package[x].as_str().unwrap_or_default().to_string();
+      println!("{} {}  {path}", field("name"), field("version"));
+    }
+  }
+  Ok(())
+}
+
+#[derive(Subcommand)]
+pub enum TreeCommand {
+  /// Evaluate the build scripts in the tree into its index, reusing the
+  /// metadata of unchanged ones
+  Index {
+    #[arg(default_value = ".")]
+    tree: PathBuf,
+    #[command(flatten)]
+    limits: Limits,
+    /// Number of build scripts to evaluate at once, 0 for one per CPU
+    #[arg(long, short, value_name = "N", default_value_t = 0)]
+    jobs: usize,
+  },
+  /// List packages in the index matching a query, like
+  /// `depends contains openssl and version < 3`
+  Query {
+    query: String,
+    /// Root of the package tree
+    #[arg(long, default_value = ".")]
+    tree: PathBuf,
+    #[command(flatten)]
+    limits: Limits,
+  },
+  /// Show the maintainers of a package, as listed in the tree's MAINTAINERS
+  Owner {
+    name: String,
+    /// Root of the package tree
+    #[arg(long, default_value = ".")]
+    tree: PathBuf,
+  },
+}
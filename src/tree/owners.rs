@@ -0,0 +1,95 @@
+use crate::util::glob_match;
+use anyhow::{bail, Context};
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// Name of the file at the root of a package tree mapping package names to
+/// their maintainers.
+pub const OWNERS_FILE: &str = "MAINTAINERS";
+
+/// Package name globs and who maintains them, in the style of CODEOWNERS:
+///
+/// ```text
+/// # Everything else
+/// *          @core
+/// python-*   alice@example.org @python
+/// ```
+///
+/// The last matching line wins, and a line without maintainers leaves the
+/// packages it matches unowned.
+#[derive(Debug, Clone, Default)]
+pub struct Owners {
+  rules: Vec<(Box<str>, Vec<Box<str>>)>,
+}
+
+impl Owners {
+  pub fn parse(s: &str) -> anyhow::Result<Self> {
+    let mut rules = Vec::new();
+    for (i, line) in s.lines().enumerate() {
+      let line = line.split('#').next().unwrap_or_default();
+      let mut fields = line.split_whitespace();
+      let Some(pattern) = fields.next() else {
+        continue;
+      };
+      if pattern.contains('/') {
+        bail!("line {}: `{pattern}` is not a package name glob", i + 1);
+      }
+      rules.push((pattern.into(), fields.map(Into::into).collect()));
+    }
+    Ok(Self { rules })
+  }
+
+  /// Reads the owners file of the tree at `dir`, if there is one.
+  pub fn load(dir: &Path) -> anyhow::Result<Option<Self>> {
+    let path = dir.join(OWNERS_FILE);
+    match fs::read_to_string(&path) {
+      Ok(s) => Self::parse(&s)
+        .with_context(|| format!("invalid {}", path.display()))
+        .map(Some),
+      Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+      Err(e) => Err(e.into()),
+    }
+  }
+
+  /// Finds the owners file of the tree the build script at `path` is in, by
+  /// looking through its parent directories.
+  pub fn find(path: &Path) -> anyhow::Result<Self> {
+    let path = path.canonicalize()?;
+    for dir in path.ancestors().skip(1) {
+      if let Some(owners) = Self::load(dir)? {
+        return Ok(owners);
+      }
+    }
+    Ok(Self::default())
+  }
+
+  /// Maintainers of the package `name`.
+  pub fn of(&self, name: &str) -> &[Box<str>] {
+    (self.rules.iter().rev())
+      .find(|(pattern, _)| glob_match(pattern, name))
+      .map_or(&[], |(_, owners)| owners)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_owners() {
+    let owners = Owners::parse(
+      "# comment\n*  @core\npython-*  alice @python # team\npython-six\n\nlib?  bob\n",
+    )
+    .unwrap();
+    assert_eq!(owners.of("curl"), [Box::from("@core")]);
+    assert_eq!(
+      owners.of("python-requests"),
+      [Box::from("alice"), "@python".into()]
+    );
+    assert!(owners.of("python-six").is_empty());
+    assert_eq!(owners.of("libx"), [Box::from("bob")]);
+    assert_eq!(owners.of("libxx"), [Box::from("@core")]);
+    assert!(Owners::parse("core/* alice").is_err());
+  }
+}
@@ -0,0 +1,77 @@
+use anyhow::{bail, Context};
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// Name of the file at the root of a package tree with rules its official
+/// builds follow.
+pub const POLICY_FILE: &str = "POLICY";
+
+/// Rules for building the packages of a tree, one per line:
+///
+/// ```text
+/// # Every downloaded source is pinned by a checksum
+/// require-checksums
+/// ```
+///
+/// Developer builds, made with `--dev`, are exempt from them.
+#[derive(Debug, Clone, Default)]
+pub struct Policy {
+  /// Whether sources downloaded over HTTP need checksums.
+  pub require_checksums: bool,
+}
+
+impl Policy {
+  pub fn parse(s: &str) -> anyhow::Result<Self> {
+    let mut policy = Self::default();
+    for (i, line) in s.lines().enumerate() {
+      let line = line.split('#').next().unwrap_or_default().trim();
+      match line {
+        "" => {}
+        "require-checksums" => policy.require_checksums = true,
+        _ => bail!("line {}: unknown rule `{line}`", i + 1),
+      }
+    }
+    Ok(policy)
+  }
+
+  /// Reads the policy file of the tree at `dir`, if there is one.
+  pub fn load(dir: &Path) -> anyhow::Result<Option<Self>> {
+    let path = dir.join(POLICY_FILE);
+    match fs::read_to_string(&path) {
+      Ok(s) => Self::parse(&s)
+        .with_context(|| format!("invalid {}", path.display()))
+        .map(Some),
+      Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+      Err(e) => Err(e.into()),
+    }
+  }
+
+  /// Finds the policy of the tree the build script at `path` is in, by
+  /// looking through its parent directories.
+  pub fn find(path: &Path) -> anyhow::Result<Self> {
+    let path = path.canonicalize()?;
+    for dir in path.ancestors().skip(1) {
+      if let Some(policy) = Self::load(dir)? {
+        return Ok(policy);
+      }
+    }
+    Ok(Self::default())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_policy() {
+    assert!(!Policy::parse("# nothing yet\n").unwrap().require_checksums);
+    assert!(
+      Policy::parse("require-checksums  # official builds\n")
+        .unwrap()
+        .require_checksums
+    );
+    assert!(Policy::parse("require-signatures").is_err());
+  }
+}
@@ -0,0 +1,252 @@
+use crate::version::PackageVersion;
+use anyhow::bail;
+use serde_json::Value;
+use std::cmp::Ordering;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Op {
+  Eq,
+  Ne,
+  /// An element of a list, or a substring of a string.
+  Contains,
+  /// Case-insensitive substring.
+  Matches,
+  Lt,
+  Le,
+  Gt,
+  Ge,
+}
+
+/// A filter over indexed packages, e.g.
+/// `depends contains openssl and not (version >= 3)`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Query {
+  And(Box<Query>, Box<Query>),
+  Or(Box<Query>, Box<Query>),
+  Not(Box<Query>),
+  Cond {
+    field: String,
+    op: Op,
+    value: String,
+  },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Token {
+  Word(String),
+  Str(String),
+  Op(String),
+  Open,
+  Close,
+}
+
+const OP_CHARS: &str = "=!<>~";
+
+fn tokenize(s: &str) -> anyhow::Result<Vec<Token>> {
+  let mut tokens = Vec::new();
+  let mut chars = s.chars().peekable();
+  while let Some(c) = chars.next() {
+    match c {
+      c if c.is_whitespace() => {}
+      '(' => tokens.push(Token::Open),
+      ')' => tokens.push(Token::Close),
+      '"' | '\'' => {
+        let mut value = String::new();
+        loop {
+          match chars.next() {
+            Some(x) if x == c => break,
+            Some(x) => value.push(x),
+            None => bail!("unterminated string in query"),
+          }
+        }
+        tokens.push(Token::Str(value));
+      }
+      c if OP_CHARS.contains(c) => {
+        let mut op = c.to_string();
+        while let Some(x) = chars.next_if(|x| OP_CHARS.contains(*x)) {
+          op.push(x);
+        }
+        tokens.push(Token::Op(op));
+      }
+      c => {
+        let mut word = c.to_string();
+        while let Some(x) =
+          chars.next_if(|x| !x.is_whitespace() && !"()\"'".contains(*x) && !OP_CHARS.contains(*x))
+        {
+          word.push(x);
+        }
+        tokens.push(Token::Word(word));
+      }
+    }
+  }
+  Ok(tokens)
+}
+
+struct Parser {
+  tokens: Vec<Token>,
+  pos: usize,
+}
+
+impl Parser {
+  fn peek_word(&self, word: &str) -> bool {
+    matches!(self.tokens.get(self.pos), Some(Token::Word(x)) if x == word)
+  }
+
+  fn next(&mut self) -> Option<Token> {
+    let token = self.tokens.get(self.pos).cloned();
+    self.pos += 1;
+    token
+  }
+
+  fn or(&mut self) -> anyhow::Result<Query> {
+    let mut query = self.and()?;
+    while self.peek_word("or") {
+      self.pos += 1;
+      query = Query::Or(Box::new(query), Box::new(self.and()?));
+    }
+    Ok(query)
+  }
+
+  fn and(&mut self) -> anyhow::Result<Query> {
+    let mut query = self.not()?;
+    while self.peek_word("and") {
+      self.pos += 1;
+      query = Query::And(Box::new(query), Box::new(self.not()?));
+    }
+    Ok(query)
+  }
+
+  fn not(&mut self) -> anyhow::Result<Query> {
+    if self.peek_word("not") {
+      self.pos += 1;
+      return Ok(Query::Not(Box::new(self.not()?)));
+    }
+    match self.next() {
+      Some(Token::Open) => {
+        let query = self.or()?;
+        if self.next() != Some(Token::Close) {
+          bail!("expected `)` in query");
+        }
+        Ok(query)
+      }
+      Some(Token::Word(field)) => {
+        let op = match self.next() {
+          Some(Token::Op(x)) => match &*x {
+            "=" | "==" => Op::Eq,
+            "!=" => Op::Ne,
+            "~" => Op::Matches,
+            "<" => Op::Lt,
+            "<=" => Op::Le,
+            ">" => Op::Gt,
+            ">=" => Op::Ge,
+            x => bail!("unknown operator `{x}` in query"),
+          },
+          Some(Token::Word(x)) if x == "contains" => Op::Contains,
+          _ => bail!("expected an operator after `{field}` in query"),
+        };
+        let value = match self.next() {
+          Some(Token::Word(x) | Token::Str(x)) => x,
+          _ => bail!("expected a value after `{field}` in query"),
+        };
+        Ok(Query::Cond { field, op, value })
+      }
+      _ => bail!("expected a condition in query"),
+    }
+  }
+}
+
+/// Strings a field is compared by: list elements, or the names of objects
+/// like optional dependencies.
+fn field_values(value: &Value) -> Vec<String> {
+  let scalar = |x: &Value| match x {
+    Value::String(x) => Some(x.clone()),
+    Value::Null => None,
+    Value::Object(x) => x.get("name").and_then(Value::as_str).map(Into::into),
+    x => Some(x.to_string()),
+  };
+  match value {
+    Value::Array(xs) => xs.iter().filter_map(scalar).collect(),
+    x => scalar(x).into_iter().collect(),
+  }
+}
+
+/// Compares as versions where both sides are valid ones, and as strings
+/// otherwise.
+fn compare(a: &str, b: &str) -> Ordering {
+  match (a.parse::<PackageVersion>(), b.parse::<PackageVersion>()) {
+    (Ok(a), Ok(b)) => a.cmp(&b),
+    _ => a.cmp(b),
+  }
+}
+
+impl Query {
+  pub fn parse(s: &str) -> anyhow::Result<Self> {
+    let mut parser = Parser {
+      tokens: tokenize(s)?,
+      pos: 0,
+    };
+    let query = parser.or()?;
+    if parser.pos < parser.tokens.len() {
+      bail!("unexpected trailing input in query");
+    }
+    Ok(query)
+  }
+
+  /// Whether the JSON object `item` passes this filter.
+  pub fn matches(&self, item: &Value) -> bool {
+    match self {
+      Self::And(a, b) => a.matches(item) && b.matches(item),
+      Self::Or(a, b) => a.matches(item) || b.matches(item),
+      Self::Not(x) => !x.matches(item),
+      Self::Cond { field, op, value } => {
+        let field = item.get(field).unwrap_or(&Value::Null);
+        let values = field_values(field);
+        let cmp = |f: fn(Ordering) -> bool| values.iter().any(|x| f(compare(x, value)));
+        match op {
+          Op::Eq => values.contains(value),
+          Op::Ne => !values.contains(value),
+          Op::Contains => match field {
+            Value::String(x) => x.contains(&**value),
+            _ => values.contains(value),
+          },
+          Op::Matches => {
+            let value = value.to_lowercase();
+            (values.iter()).any(|x| x.to_lowercase().contains(&value))
+          }
+          Op::Lt => cmp(Ordering::is_lt),
+          Op::Le => cmp(Ordering::is_le),
+          Op::Gt => cmp(Ordering::is_gt),
+          Op::Ge => cmp(Ordering::is_ge),
+        }
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use serde_json::json;
+
+  #[test]
+  fn test_query() {
+    let item = json!({
+      "name": "curl", "version": "8.1.2", "description": "URL transfer tool",
+      "depends": ["openssl", "zlib"],
+      "optional_depends": [{ "name": "ca-certificates" }],
+    });
+    let matches = |x: &str| Query::parse(x).unwrap().matches(&item);
+    assert!(matches("depends contains openssl"));
+    assert!(matches("optional_depends contains ca-certificates"));
+    assert!(matches("name = curl and version >= 8.1"));
+    assert!(matches("name = curl and not (version > 8.1.10)"));
+    assert!(!matches("version > 8.1.10"));
+    assert!(matches("name='wget' or description ~ 'url'"));
+    assert!(matches("version < 1:1.0 and description < 'V'"));
+    assert!(matches("homepage != 'https://curl.se'"));
+    assert!(!matches("conflicts contains curl-minimal"));
+    assert!(Query::parse("name =").is_err());
+    assert!(Query::parse("(name = a").is_err());
+    assert!(Query::parse("name = a b").is_err());
+  }
+}
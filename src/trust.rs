@@ -0,0 +1,162 @@
+use crate::key::Keyring;
+use crate::sign::{unix_time, Signature};
+use crate::util::config_dir;
+use anyhow::{bail, Context};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{self, BufReader};
+use std::path::{Path, PathBuf};
+
+/// What a repository index must satisfy to be used.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Policy {
+  /// Refuse indexes without a signature.
+  pub require_signature: bool,
+  /// Accept unsigned indexes of repositories on this machine all the same.
+  pub allow_unsigned_local: bool,
+  /// IDs of the keys allowed to sign the index; without any, every trusted
+  /// key of the keyring is.
+  pub keys: Vec<String>,
+  /// Refuse signatures made longer ago than this, so that a stale index
+  /// cannot be passed off as current.
+  pub max_age_days: Option<u64>,
+}
+
+impl Default for Policy {
+  fn default() -> Self {
+    Self {
+      require_signature: true,
+      allow_unsigned_local: true,
+      keys: Vec::new(),
+      max_age_days: None,
+    }
+  }
+}
+
+/// Policies for repository indexes, kept in `trust.json` of the config
+/// directory.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TrustConfig {
+  /// Policy of repositories not listed.
+  #[serde(default)]
+  pub default: Policy,
+  /// Policies of repositories and mirrors by location prefix, a directory or
+  /// a URL; the longest matching one applies.
+  #[serde(default)]
+  pub repositories: BTreeMap<String, Policy>,
+}
+
+/// Whether the location `prefix` covers `location`, as a whole path component
+/// or URL segment.
+fn covers(prefix: &str, location: &str) -> bool {
+  match location.strip_prefix(prefix) {
+    Some(rest) => rest.is_empty() || prefix.ends_with('/') || rest.starts_with('/'),
+    None => false,
+  }
+}
+
+impl TrustConfig {
+  fn path() -> Option<PathBuf> {
+    Some(config_dir()?.join("trust.json"))
+  }
+
+  pub fn load() -> anyhow::Result<Self> {
+    let Some(path) = Self::path().filter(|x| x.exists()) else {
+      return Ok(Self::default());
+    };
+    let f = File::open(&path)?;
+    (serde_json::from_reader(BufReader::new(f)))
+      .with_context(|| format!("invalid trust policy {}", path.display()))
+  }
+
+  pub fn policy(&self, location: &str) -> &Policy {
+    (self.repositories.iter())
+      .filter(|(prefix, _)| covers(prefix, location))
+      .max_by_key(|(prefix, _)| prefix.len())
+      .map_or(&self.default, |(_, policy)| policy)
+  }
+}
+
+/// Checks `data` of the index at `location` against its signature, if any,
+/// under the policy for `location`. `local` tells whether the repository is
+/// on this machine. Returns the ID of the key that signed it.
+pub fn check_index(
+  data: &[u8],
+  signature: Option<&[u8]>,
+  location: &str,
+  local: bool,
+) -> anyhow::Result<Option<String>> {
+  let config = TrustConfig::load()?;
+  let policy = config.policy(location);
+  let Some(signature) = signature else {
+    if policy.require_signature && !(local && policy.allow_unsigned_local) {
+      bail!("the index of {location} is not signed, as its trust policy requires");
+    }
+    return Ok(None);
+  };
+  let signature: Signature =
+    serde_json::from_slice(signature).context("invalid index signature")?;
+  check_signature(&signature, data, policy)
+    .with_context(|| format!("cannot trust the index of {location}"))?;
+  Ok(Some(signature.key))
+}
+
+fn check_signature(signature: &Signature, data: &[u8], policy: &Policy) -> anyhow::Result<()> {
+  if !policy.keys.is_empty() && !policy.keys.contains(&signature.key) {
+    bail!(
+      "it is signed by key {}, not one of the keys allowed for it",
+      signature.key
+    );
+  }
+  let trusted = Keyring::open()?.trusted()?;
+  let Some(key) = trusted.get(&signature.key) else {
+    bail!(
+      "it is signed by key {}, which is not trusted",
+      signature.key
+    );
+  };
+  signature.verify(key, data)?;
+  if let Some(days) = policy.max_age_days {
+    let age = unix_time().saturating_sub(signature.created) / 86400;
+    if age > days {
+      bail!("it was signed {age} days ago, more than the {days} days allowed");
+    }
+  }
+  Ok(())
+}
+
+/// Reads the signature kept next to the file at `path`, if there is one.
+pub fn read_signature(path: &Path) -> io::Result<Option<Vec<u8>>> {
+  match fs::read(Signature::path_for(path)) {
+    Ok(x) => Ok(Some(x)),
+    Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+    Err(e) => Err(e),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_policy() {
+    let config: TrustConfig = serde_json::from_value(serde_json::json!({
+      "default": { "allow_unsigned_local": false },
+      "repositories": {
+        "/srv/repo": { "require_signature": false },
+        "https://pkgs.example.org/": { "keys": ["0123456789abcdef"] },
+      },
+    }))
+    .unwrap();
+    let policy = |x| config.policy(x);
+    assert!(!policy("/srv/repo").require_signature);
+    assert!(!policy("/srv/repo/x86_64").require_signature);
+    assert!(policy("/srv/repo2").require_signature);
+    assert!(!policy("/srv/repo2").allow_unsigned_local);
+    assert_eq!(policy("https://pkgs.example.org/main").keys.len(), 1);
+    assert!(policy("https://pkgs.example.org.evil/").keys.is_empty());
+  }
+}
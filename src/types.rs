@@ -1,6 +1,4 @@
 use crate::version::PackageVersion;
-use openssl::error::ErrorStack;
-use openssl::hash::{Hasher, MessageDigest};
 use serde::de::Error;
 use serde::{de, Deserialize, Deserializer, Serialize};
 use smartstring::{LazyCompact, SmartString};
@@ -157,20 +155,148 @@ impl Ord for OptionalDepends {
   }
 }
 
+/// A command the installer should run once after a transaction that touched
+/// any of the watched paths, e.g. `ldconfig` for `/usr/lib`.
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(try_from = "TriggerHelper")]
+pub struct Trigger {
+  /// Absolute paths; anything below them counts as well.
+  pub paths: BTreeSet<Box<str>>,
+  pub command: Box<str>,
+}
+
+#[derive(Deserialize)]
+struct TriggerHelper {
+  paths: BTreeSet<Box<str>>,
+  command: Box<str>,
+}
+
+impl TryFrom<TriggerHelper> for Trigger {
+  type Error = String;
+
+  fn try_from(TriggerHelper { paths, command }: TriggerHelper) -> Result<Self, Self::Error> {
+    if paths.is_empty() {
+      return Err("trigger watches no paths".into());
+    }
+    if let Some(path) = paths.iter().find(|x| !x.starts_with('/')) {
+      return Err(format!("trigger path `{path}` is not absolute"));
+    }
+    if command.trim().is_empty() {
+      return Err("trigger has an empty command".into());
+    }
+    Ok(Self { paths, command })
+  }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "LocationHelper", into = "LocationHelper")]
 pub enum SourceLocation {
-  #[serde(rename = "url")]
   Http(Url),
-
-  #[serde(rename = "path")]
   Local(Box<Path>),
+  /// Content given inline as a `data:` URL.
+  Data(Url),
+  /// A Git repository checked out at a tag, branch or commit.
+  Git {
+    url: Url,
+    rev: Box<str>,
+  },
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct LocationHelper {
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  url: Option<Url>,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  path: Option<Box<Path>>,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  git: Option<Url>,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  rev: Option<Box<str>>,
+}
+
+fn git_location(url: Url, rev: Option<Box<str>>) -> Result<SourceLocation, String> {
+  let Some(rev) = rev else {
+    return Err("git source without `rev`, pin it to a tag, branch or commit".into());
+  };
+  if rev.is_empty() || rev.starts_with('-') {
+    return Err(format!("invalid git revision `{rev}`"));
+  }
+  match url.scheme() {
+    "https" | "http" | "git" | "ssh" | "file" => Ok(SourceLocation::Git { url, rev }),
+    scheme => Err(format!("unsupported git URL scheme `{scheme}`")),
+  }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn file_url_path(url: &Url) -> Option<Box<Path>> {
+  url.to_file_path().ok().map(Into::into)
+}
+
+/// WebAssembly has no file paths of its own, so take the URL's as it is.
+#[cfg(target_arch = "wasm32")]
+fn file_url_path(url: &Url) -> Option<Box<Path>> {
+  let path = percent_encoding::percent_decode_str(url.path())
+    .decode_utf8()
+    .ok()?;
+  Some(Path::new(&*path).into())
+}
+
+impl TryFrom<LocationHelper> for SourceLocation {
+  type Error = String;
+
+  fn try_from(x: LocationHelper) -> Result<Self, Self::Error> {
+    if x.rev.is_some() && x.git.is_none() {
+      return Err("`rev` is only for `git` sources".into());
+    }
+    let url = match (x.url, x.path, x.git) {
+      (Some(url), None, None) => url,
+      (None, Some(path), None) => return Ok(Self::Local(path)),
+      (None, None, Some(url)) => return git_location(url, x.rev),
+      (None, None, None) => return Err("source has none of `url`, `path` and `git`".into()),
+      _ => return Err("source has more than one of `url`, `path` and `git`".into()),
+    };
+    match url.scheme() {
+      "http" | "https" => Ok(Self::Http(url)),
+      "data" => Ok(Self::Data(url)),
+      "file" => file_url_path(&url)
+        .map(Self::Local)
+        .ok_or_else(|| format!("invalid file URL `{url}`")),
+      scheme => Err(format!("unsupported URL scheme `{scheme}`")),
+    }
+  }
+}
+
+impl From<SourceLocation> for LocationHelper {
+  fn from(x: SourceLocation) -> Self {
+    match x {
+      SourceLocation::Http(url) | SourceLocation::Data(url) => Self {
+        url: Some(url),
+        ..Self::default()
+      },
+      SourceLocation::Local(path) => Self {
+        path: Some(path),
+        ..Self::default()
+      },
+      SourceLocation::Git { url, rev } => Self {
+        git: Some(url),
+        rev: Some(rev),
+        ..Self::default()
+      },
+    }
+  }
 }
 
 impl SourceLocation {
   pub fn file_name(&self) -> Option<&str> {
     match self {
-      Self::Http(url) => url.path_segments()?.last(),
+      Self::Http(url) => url.path_segments()?.next_back(),
       Self::Local(path) => path.file_name()?.to_str(),
+      // Inline content has to be named with `rename`.
+      Self::Data(_) => None,
+      Self::Git { url, .. } => {
+        let name = url.path_segments()?.rfind(|x| !x.is_empty())?;
+        Some(name.strip_suffix(".git").unwrap_or(name)).filter(|x| !x.is_empty())
+      }
     }
   }
 }
@@ -180,6 +306,14 @@ impl Display for SourceLocation {
     match self {
       SourceLocation::Http(url) => write!(f, "{url}"),
       SourceLocation::Local(path) => write!(f, "{}", path.display()),
+      SourceLocation::Data(url) => {
+        let url = url.as_str();
+        match url.char_indices().nth(40) {
+          Some((i, _)) => write!(f, "{}...", &url[..i]),
+          None => f.write_str(url),
+        }
+      }
+      SourceLocation::Git { url, rev } => write!(f, "{url}#{rev}"),
     }
   }
 }
@@ -193,17 +327,18 @@ pub enum ChecksumKind {
 }
 
 impl ChecksumKind {
-  pub fn new_hasher(&self) -> Result<Hasher, ErrorStack> {
+  pub fn name(&self) -> &'static str {
     match self {
-      Self::Sha256 => Hasher::new(MessageDigest::sha256()),
-      Self::Sha512 => Hasher::new(MessageDigest::sha512()),
+      Self::Sha256 => "SHA-256",
+      Self::Sha512 => "SHA-512",
     }
   }
 
-  pub fn name(&self) -> &'static str {
+  /// Name of the field of source files holding checksums of this kind.
+  pub fn field(&self) -> &'static str {
     match self {
-      Self::Sha256 => "SHA-256",
-      Self::Sha512 => "SHA-512",
+      Self::Sha256 => "sha256sum",
+      Self::Sha512 => "sha512sum",
     }
   }
 }
@@ -211,6 +346,12 @@ impl ChecksumKind {
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct Hash(#[serde(with = "hex::serde")] Vec<u8>);
 
+impl From<Vec<u8>> for Hash {
+  fn from(x: Vec<u8>) -> Self {
+    Self(x)
+  }
+}
+
 impl AsRef<[u8]> for Hash {
   fn as_ref(&self) -> &[u8] {
     self
@@ -242,6 +383,21 @@ struct SourceFileHelper {
 
   #[serde(default = "get_true")]
   pub extract: bool,
+
+  #[serde(default)]
+  pub tree_digest: bool,
+
+  #[serde(default)]
+  pub allow_special_files: bool,
+
+  #[serde(default)]
+  pub submodules: bool,
+
+  #[serde(default)]
+  pub mirrors: Vec<Url>,
+
+  #[serde(default)]
+  pub skip_checksum: bool,
 }
 
 #[derive(Debug, Clone, Serialize)]
@@ -257,6 +413,30 @@ pub struct SourceFile {
 
   #[serde(skip_serializing_if = "bool::clone")]
   pub extract: bool,
+
+  /// Whether to record a digest of the extracted tree in the lockfile and
+  /// check it when reusing a cached download.
+  #[serde(skip_serializing_if = "std::ops::Not::not")]
+  pub tree_digest: bool,
+
+  /// Whether the archive may contain device nodes and FIFOs.
+  #[serde(skip_serializing_if = "std::ops::Not::not")]
+  pub allow_special_files: bool,
+
+  /// Whether to check out the submodules of a git source, recursively.
+  #[serde(skip_serializing_if = "std::ops::Not::not")]
+  pub submodules: bool,
+
+  /// Other URLs serving the same file, tried in order when `url` fails or
+  /// serves something failing verification.
+  #[serde(skip_serializing_if = "Vec::is_empty")]
+  pub mirrors: Vec<Url>,
+
+  /// Whether the source is knowingly left without checksums, e.g. because
+  /// upstream regenerates it. Its first download is pinned in the lockfile
+  /// instead.
+  #[serde(skip_serializing_if = "std::ops::Not::not")]
+  pub skip_checksum: bool,
 }
 
 impl SourceFile {
@@ -277,20 +457,56 @@ impl<'de> Deserialize<'de> for SourceFile {
       rename,
       checksums,
       extract,
+      tree_digest,
+      allow_special_files,
+      submodules,
+      mirrors,
+      skip_checksum,
     } = SourceFileHelper::deserialize(de)?;
     if rename.is_none() && location.file_name().is_none() {
       return Err(D::Error::custom("no file name given"));
     }
+    if matches!(location, SourceLocation::Git { .. }) {
+      if !checksums.is_empty() {
+        return Err(D::Error::custom(
+          "git sources are pinned with `rev`, not checksums",
+        ));
+      }
+      if tree_digest {
+        return Err(D::Error::custom("`tree_digest` is not for git sources"));
+      }
+      if skip_checksum {
+        return Err(D::Error::custom("`skip_checksum` is not for git sources"));
+      }
+    } else if submodules {
+      return Err(D::Error::custom("`submodules` is only for git sources"));
+    }
+    if skip_checksum && !checksums.is_empty() {
+      return Err(D::Error::custom(
+        "`skip_checksum` is set, but so are checksums",
+      ));
+    }
+    if !mirrors.is_empty() && !matches!(location, SourceLocation::Http(_)) {
+      return Err(D::Error::custom("`mirrors` are only for HTTP sources"));
+    }
+    if let Some(x) = (mirrors.iter()).find(|x| !matches!(x.scheme(), "http" | "https")) {
+      return Err(D::Error::custom(format!("mirror `{x}` is not an HTTP URL")));
+    }
     Ok(Self {
       location,
       rename,
       checksums,
       extract,
+      tree_digest,
+      allow_special_files,
+      submodules,
+      mirrors,
+      skip_checksum,
     })
   }
 }
 
-// TODO: license, backup
+// TODO: backup
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct PackageInfo {
   pub name: PackageName,
@@ -301,6 +517,29 @@ pub struct PackageInfo {
   #[serde(default, skip_serializing_if = "Option::is_none")]
   pub homepage: Option<Url>,
 
+  /// Where upstream takes bug reports, like an issue tracker or a `mailto:`
+  /// address.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub bugs: Option<Url>,
+
+  /// Upstream version control repository.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub repository: Option<Url>,
+
+  /// SPDX license expression, or `custom`.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub license: Option<Box<str>>,
+
+  /// Groups of the package tree's `GROUPS` file the package is in, like
+  /// `base`.
+  #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+  pub groups: BTreeSet<Box<str>>,
+
+  /// Words to find the package by in searches, besides those of its name
+  /// and description.
+  #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+  pub keywords: BTreeSet<Box<str>>,
+
   #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
   pub provides: BTreeSet<PackageName>,
 
@@ -312,6 +551,9 @@ pub struct PackageInfo {
 
   #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
   pub optional_depends: BTreeSet<OptionalDepends>,
+
+  #[serde(default, skip_serializing_if = "Vec::is_empty")]
+  pub triggers: Vec<Trigger>,
 }
 
 impl PartialEq for PackageInfo {
@@ -334,6 +576,66 @@ impl Ord for PackageInfo {
   }
 }
 
+/// Fields a split package overrides of the source it is built from.
+#[derive(Debug, Deserialize)]
+pub struct PackageInfoDelta {
+  name: Option<PackageName>,
+  description: Option<Box<str>>,
+  version: Option<PackageVersion>,
+  architecture: Option<ArchList>,
+  homepage: Option<Url>,
+  bugs: Option<Url>,
+  repository: Option<Url>,
+  license: Option<Box<str>>,
+
+  #[serde(default)]
+  groups: Option<BTreeSet<Box<str>>>,
+
+  #[serde(default)]
+  keywords: Option<BTreeSet<Box<str>>>,
+
+  #[serde(default)]
+  provides: Option<BTreeSet<PackageName>>,
+
+  #[serde(default)]
+  conflicts: Option<BTreeSet<PackageName>>,
+
+  #[serde(default)]
+  depends: Option<BTreeSet<PackageName>>,
+
+  #[serde(default)]
+  optional_depends: Option<BTreeSet<OptionalDepends>>,
+
+  #[serde(default)]
+  triggers: Option<Vec<Trigger>>,
+}
+
+impl PackageInfoDelta {
+  pub fn merge_into(self, info: &PackageInfo) -> PackageInfo {
+    PackageInfo {
+      name: self.name.unwrap_or_else(|| info.name.clone()),
+      description: self.description.unwrap_or_else(|| info.description.clone()),
+      version: self.version.unwrap_or_else(|| info.version.clone()),
+      architecture: self
+        .architecture
+        .unwrap_or_else(|| info.architecture.clone()),
+      homepage: self.homepage.or_else(|| info.homepage.clone()),
+      bugs: self.bugs.or_else(|| info.bugs.clone()),
+      repository: self.repository.or_else(|| info.repository.clone()),
+      license: self.license.or_else(|| info.license.clone()),
+      groups: self.groups.unwrap_or_else(|| info.groups.clone()),
+      keywords: self.keywords.unwrap_or_else(|| info.keywords.clone()),
+      provides: self.provides.unwrap_or_else(|| info.provides.clone()),
+      conflicts: self.conflicts.unwrap_or_else(|| info.conflicts.clone()),
+      depends: self.depends.unwrap_or_else(|| info.depends.clone()),
+      optional_depends: self
+        .optional_depends
+        .unwrap_or_else(|| info.optional_depends.clone()),
+      triggers: self.triggers.unwrap_or_else(|| info.triggers.clone()),
+    }
+  }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct SourceInfo {
   #[serde(flatten)]
@@ -344,6 +646,26 @@ pub struct SourceInfo {
 
   #[serde(default, skip_serializing_if = "Vec::is_empty")]
   pub source: Vec<SourceFile>,
+
+  #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+  pub options: BTreeSet<ScriptOption>,
+
+  /// `SOURCE_DATE_EPOCH` of builds, in seconds since the Unix epoch. Without
+  /// it, the release time of the sources is taken when fetching them.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub source_date_epoch: Option<u64>,
+}
+
+/// Ways of building a script that it declares support for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ScriptOption {
+  /// A reduced-feature stage1 build for bootstrapping toolchains, checked by
+  /// the script through the `bootstrap` variable.
+  Bootstrap,
+  /// Keep static libraries in packages, which are dropped otherwise.
+  #[serde(rename = "staticlibs")]
+  StaticLibs,
 }
 
 impl Deref for SourceInfo {
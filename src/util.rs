@@ -1,5 +1,11 @@
-use tempfile::tempfile;
-use tokio::fs::File;
+use std::collections::VecDeque;
+use std::env::var_os;
+use std::fs::{self, File, Metadata};
+use std::io::Read;
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread;
 use tokio::io;
 use tokio::task::spawn_blocking;
 
@@ -17,16 +23,217 @@ where
 {
   match spawn_blocking(f).await {
     Ok(res) => res,
-    Err(_) => Err(io::Error::new(
-      io::ErrorKind::Other,
-      "background task failed",
-    )),
+    Err(_) => Err(io::Error::other("background task failed")),
   }
 }
 
-pub async fn tempfile_async() -> io::Result<File> {
-  let std_file = asyncify(tempfile).await?;
-  Ok(File::from_std(std_file))
+// Taken from ZipArchive::enclosed_name
+pub fn is_safe_name(name: &str) -> bool {
+  !name.contains('\0') && is_enclosed(Path::new(name))
+}
+
+/// Whether relative `path` never leaves the directory it is relative to.
+pub fn is_enclosed(path: &Path) -> bool {
+  let mut depth = 0usize;
+  for component in path.components() {
+    match component {
+      Component::Prefix(_) | Component::RootDir => return false,
+      Component::ParentDir => {
+        if depth == 0 {
+          return false;
+        }
+        depth -= 1;
+      }
+      Component::Normal(_) => depth += 1,
+      Component::CurDir => {}
+    }
+  }
+  true
+}
+
+/// Calls `f` on consecutive chunks of the file at `path`. Chunks overlap by
+/// `overlap` bytes, so that nothing shorter than that gets split up.
+pub fn scan_file(path: &Path, overlap: usize, f: impl FnMut(&[u8])) -> io::Result<()> {
+  scan_reader(File::open(path)?, overlap, f)
+}
+
+/// Like [`scan_file`], for anything readable.
+pub fn scan_reader(
+  mut file: impl Read,
+  overlap: usize,
+  mut f: impl FnMut(&[u8]),
+) -> io::Result<()> {
+  let mut buf = vec![0; (1 << 16).max(overlap * 2)];
+  let mut len = 0;
+  loop {
+    let bytes = file.read(&mut buf[len..])?;
+    if bytes == 0 {
+      return Ok(());
+    }
+    len += bytes;
+    f(&buf[..len]);
+    let keep = overlap.min(len);
+    buf.copy_within(len - keep..len, 0);
+    len = keep;
+  }
+}
+
+/// Calls `f` on each of `items` on up to `jobs` threads, or one per CPU if
+/// `jobs` is 0, and returns the results in the order of `items`.
+pub fn par_map<T: Sync, R: Send>(items: &[T], jobs: usize, f: impl Fn(&T) -> R + Sync) -> Vec<R> {
+  let jobs = match jobs {
+    0 => thread::available_parallelism().map_or(1, |x| x.get()),
+    x => x,
+  };
+  let next = AtomicUsize::new(0);
+  let results = Mutex::new((0..items.len()).map(|_| None).collect::<Vec<_>>());
+  thread::scope(|s| {
+    for _ in 0..jobs.min(items.len()) {
+      s.spawn(|| loop {
+        let i = next.fetch_add(1, Ordering::Relaxed);
+        let Some(item) = items.get(i) else { break };
+        let result = f(item);
+        results.lock().unwrap()[i] = Some(result);
+      });
+    }
+  });
+  (results.into_inner().unwrap().into_iter())
+    .map(|x| x.expect("worker thread panicked"))
+    .collect()
+}
+
+fn home_dir() -> Option<PathBuf> {
+  let home = var_os("HOME");
+  #[cfg(windows)]
+  let home = home.or_else(|| var_os("USERPROFILE"));
+  home.filter(|x| !x.is_empty()).map(PathBuf::from)
+}
+
+/// Lists everything below `dir`, relative to it, without following symlinks.
+pub fn walk(dir: &Path) -> io::Result<Vec<PathBuf>> {
+  Walk::new(dir).collect()
+}
+
+/// Iterator over everything below a directory in the order of [`walk`],
+/// holding only the directory being listed and those left to list.
+pub struct Walk {
+  dir: PathBuf,
+  /// Directories left to list.
+  stack: Vec<PathBuf>,
+  /// Entries of the directory being listed, and whether they are
+  /// directories.
+  entries: VecDeque<(PathBuf, bool)>,
+}
+
+impl Walk {
+  pub fn new(dir: &Path) -> Self {
+    Self {
+      dir: dir.into(),
+      stack: vec![PathBuf::new()],
+      entries: VecDeque::new(),
+    }
+  }
+
+  fn list(&mut self, rel: &Path) -> io::Result<()> {
+    let mut entries = self
+      .dir
+      .join(rel)
+      .read_dir()?
+      .collect::<io::Result<Vec<_>>>()?;
+    entries.sort_by_key(|x| x.file_name());
+    for entry in entries {
+      let is_dir = entry.file_type()?.is_dir();
+      self
+        .entries
+        .push_back((rel.join(entry.file_name()), is_dir));
+    }
+    Ok(())
+  }
+}
+
+impl Iterator for Walk {
+  type Item = io::Result<PathBuf>;
+
+  fn next(&mut self) -> Option<Self::Item> {
+    while self.entries.is_empty() {
+      let rel = self.stack.pop()?;
+      if let Err(e) = self.list(&rel) {
+        self.stack.clear();
+        self.entries.clear();
+        return Some(Err(e));
+      }
+    }
+    let (path, is_dir) = self.entries.pop_front()?;
+    if is_dir {
+      self.stack.push(path.clone());
+    }
+    Some(Ok(path))
+  }
+}
+
+/// Returns `$XDG_CACHE_HOME/ewepkg`, falling back to `~/.cache/ewepkg`.
+pub fn cache_dir() -> Option<PathBuf> {
+  let base = var_os("XDG_CACHE_HOME")
+    .filter(|x| !x.is_empty())
+    .map(PathBuf::from)
+    .or_else(|| home_dir().map(|x| x.join(".cache")))?;
+  Some(base.join("ewepkg"))
+}
+
+/// Returns `$XDG_CONFIG_HOME/ewepkg`, falling back to `~/.config/ewepkg`.
+pub fn config_dir() -> Option<PathBuf> {
+  let base = var_os("XDG_CONFIG_HOME")
+    .filter(|x| !x.is_empty())
+    .map(PathBuf::from)
+    .or_else(|| home_dir().map(|x| x.join(".config")))?;
+  Some(base.join("ewepkg"))
+}
+
+/// Whether someone is at the terminal to answer questions.
+pub fn is_interactive() -> bool {
+  use std::io::IsTerminal;
+  std::io::stdin().is_terminal() && std::io::stdout().is_terminal()
+}
+
+/// Asks `question` on the terminal, true if answered yes.
+pub fn confirm(question: &str) -> std::io::Result<bool> {
+  use std::io::Write;
+  print!("{question} [y/N] ");
+  std::io::stdout().flush()?;
+  let mut answer = String::new();
+  std::io::stdin().read_line(&mut answer)?;
+  Ok(matches!(&*answer.trim().to_ascii_lowercase(), "y" | "yes"))
+}
+
+/// Returns the Unix permission bits of `meta`. Other hosts only know whether
+/// a file is read-only.
+#[cfg(unix)]
+pub fn file_mode(meta: &Metadata) -> u32 {
+  use std::os::unix::fs::MetadataExt;
+  meta.mode()
+}
+
+#[cfg(not(unix))]
+pub fn file_mode(meta: &Metadata) -> u32 {
+  match meta.permissions().readonly() {
+    true => 0o444,
+    false => 0o644,
+  }
+}
+
+/// Sets the Unix permission bits of `path`, or only its read-only flag on
+/// other hosts.
+#[cfg(unix)]
+pub fn set_file_mode(path: &Path, mode: u32) -> std::io::Result<()> {
+  use std::os::unix::fs::PermissionsExt;
+  fs::set_permissions(path, fs::Permissions::from_mode(mode))
+}
+
+#[cfg(not(unix))]
+pub fn set_file_mode(path: &Path, mode: u32) -> std::io::Result<()> {
+  let mut perms = fs::metadata(path)?.permissions();
+  perms.set_readonly(mode & 0o222 == 0);
+  fs::set_permissions(path, perms)
 }
 
 #[macro_export]
@@ -46,3 +253,43 @@ macro_rules! segment_info {
     println!($($arg)*);
   };
 }
+
+#[macro_export]
+macro_rules! warning {
+  ($($arg:tt)*) => {
+    eprintln!(
+      "{} {}",
+      console::style("warning:").yellow().bold(),
+      format_args!($($arg)*)
+    );
+  };
+}
+
+/// Matches `s` against a shell-style pattern with `*` and `?`.
+pub fn glob_match(pattern: &str, s: &str) -> bool {
+  let (pattern, s) = (pattern.as_bytes(), s.as_bytes());
+  let (mut p, mut i) = (0, 0);
+  // Where the last `*` was, and how much of `s` it has taken so far.
+  let mut star = None;
+  while i < s.len() {
+    match pattern.get(p) {
+      Some(b'*') => {
+        star = Some((p, i));
+        p += 1;
+      }
+      Some(&x) if x == b'?' || x == s[i] => {
+        p += 1;
+        i += 1;
+      }
+      _ => match star {
+        Some((sp, si)) => {
+          star = Some((sp, si + 1));
+          p = sp + 1;
+          i = si + 1;
+        }
+        None => return false,
+      },
+    }
+  }
+  pattern[p..].iter().all(|x| *x == b'*')
+}
@@ -6,7 +6,7 @@ use std::num::ParseIntError;
 use std::str::FromStr;
 use thiserror::Error;
 
-fn is_allowed_in_version(c: char) -> bool {
+pub(crate) fn is_allowed_in_version(c: char) -> bool {
   c.is_ascii_alphanumeric() || ".+~".contains(c)
 }
 
@@ -64,18 +64,16 @@ pub fn cmp_version(mut a: &str, mut b: &str) -> Ordering {
   while !a.is_empty() || !b.is_empty() {
     let (asub1, a1) = a.split_at(a.find(char::is_numeric).unwrap_or(a.len()));
     let (bsub1, b1) = b.split_at(b.find(char::is_numeric).unwrap_or(b.len()));
-    dbg!(asub1, bsub1);
     match cmp_lexical(asub1, bsub1) {
       Equal => {}
-      ord => return dbg!(ord),
+      ord => return ord,
     }
     let is_not_numeric = |c: char| !c.is_numeric();
     let (asub2, a2) = a1.split_at(a1.find(is_not_numeric).unwrap_or(a1.len()));
     let (bsub2, b2) = b1.split_at(b1.find(is_not_numeric).unwrap_or(b1.len()));
-    dbg!(asub2, bsub2);
     match cmp_numerical(asub2, bsub2) {
       Equal => (a, b) = (a2, b2),
-      ord => return dbg!(ord),
+      ord => return ord,
     }
   }
   Equal
@@ -0,0 +1,152 @@
+//! WebAssembly exports, built with
+//! `cargo build --release --lib --target wasm32-unknown-unknown --features wasm`,
+//! so that web tooling parses metadata and orders versions exactly like
+//! ewepkg does.
+//!
+//! Strings are passed as UTF-8 in linear memory: the caller gets a buffer
+//! from [`ewepkg_alloc`], writes into it, and hands over its pointer and
+//! length. Text results, JSON or an error message, are read back through
+//! [`ewepkg_result_ptr`] and [`ewepkg_result_len`] until the next call.
+
+use crate::metadata;
+use crate::version::PackageVersion;
+use std::cell::RefCell;
+use std::{slice, str};
+
+thread_local! {
+  static RESULT: RefCell<Vec<u8>> = const { RefCell::new(Vec::new()) };
+}
+
+fn set_result(x: impl Into<Vec<u8>>) {
+  RESULT.with(|r| *r.borrow_mut() = x.into());
+}
+
+/// Reads the UTF-8 string of `len` bytes at `ptr`.
+///
+/// # Safety
+///
+/// `ptr` must point to `len` readable bytes.
+unsafe fn read_str<'a>(ptr: *const u8, len: usize) -> Result<&'a str, str::Utf8Error> {
+  if len == 0 {
+    return Ok("");
+  }
+  str::from_utf8(slice::from_raw_parts(ptr, len))
+}
+
+/// Allocates `len` bytes for the caller to write an argument to.
+#[no_mangle]
+pub extern "C" fn ewepkg_alloc(len: usize) -> *mut u8 {
+  let mut buf = Vec::<u8>::with_capacity(len);
+  let ptr = buf.as_mut_ptr();
+  std::mem::forget(buf);
+  ptr
+}
+
+/// Frees a buffer from [`ewepkg_alloc`].
+///
+/// # Safety
+///
+/// `ptr` must come from [`ewepkg_alloc`] with the same `len`, and not have
+/// been freed yet.
+#[no_mangle]
+pub unsafe extern "C" fn ewepkg_dealloc(ptr: *mut u8, len: usize) {
+  drop(Vec::from_raw_parts(ptr, 0, len));
+}
+
+#[no_mangle]
+pub extern "C" fn ewepkg_result_ptr() -> *const u8 {
+  RESULT.with(|r| r.borrow().as_ptr())
+}
+
+#[no_mangle]
+pub extern "C" fn ewepkg_result_len() -> usize {
+  RESULT.with(|r| r.borrow().len())
+}
+
+/// Evaluates a build script for its metadata, see [`metadata::evaluate`].
+/// Returns 0 with the metadata as JSON in the result, or 1 with an error
+/// message.
+///
+/// # Safety
+///
+/// Both pointers must point to as many readable bytes as their lengths say.
+#[no_mangle]
+pub unsafe extern "C" fn ewepkg_evaluate(
+  script: *const u8,
+  script_len: usize,
+  arch: *const u8,
+  arch_len: usize,
+) -> i32 {
+  let evaluate = || -> anyhow::Result<Vec<u8>> {
+    let script = read_str(script, script_len)?;
+    let metadata = metadata::evaluate(script, read_str(arch, arch_len)?)?;
+    Ok(serde_json::to_vec(&metadata)?)
+  };
+  match evaluate() {
+    Ok(json) => {
+      set_result(json);
+      0
+    }
+    Err(e) => {
+      set_result(format!("{e:#}"));
+      1
+    }
+  }
+}
+
+/// Compares two package versions, returning -1, 0 or 1 if `a` is older than,
+/// the same as or newer than `b`, or 2 with an error message in the result
+/// if either is invalid.
+///
+/// # Safety
+///
+/// Both pointers must point to as many readable bytes as their lengths say.
+#[no_mangle]
+pub unsafe extern "C" fn ewepkg_compare_versions(
+  a: *const u8,
+  a_len: usize,
+  b: *const u8,
+  b_len: usize,
+) -> i32 {
+  let parse = |ptr, len| -> anyhow::Result<PackageVersion> {
+    let s = read_str(ptr, len)?;
+    s.parse()
+      .map_err(|e| anyhow::anyhow!("invalid version `{s}`: {e}"))
+  };
+  match (parse(a, a_len), parse(b, b_len)) {
+    (Ok(a), Ok(b)) => a.cmp(&b) as i32,
+    (Err(e), _) | (_, Err(e)) => {
+      set_result(e.to_string());
+      2
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn result() -> String {
+    let bytes = unsafe { slice::from_raw_parts(ewepkg_result_ptr(), ewepkg_result_len()) };
+    String::from_utf8(bytes.to_vec()).unwrap()
+  }
+
+  #[test]
+  fn test_exports() {
+    let cmp = |a: &str, b: &str| unsafe {
+      ewepkg_compare_versions(a.as_ptr(), a.len(), b.as_ptr(), b.len())
+    };
+    assert_eq!(cmp("1.0~rc1-1", "1.0-1"), -1);
+    assert_eq!(cmp("1:1.0-1", "2.0-1"), 1);
+    assert_eq!(cmp("1.0-1", "1-0-1"), 2);
+    assert!(result().contains("1-0-1"));
+
+    let script = r#"#{ name: "a", description: "A", version: "1-1", architecture: [arch] }"#;
+    let arch = "riscv64";
+    let status =
+      unsafe { ewepkg_evaluate(script.as_ptr(), script.len(), arch.as_ptr(), arch.len()) };
+    assert_eq!(status, 0);
+    let json: serde_json::Value = serde_json::from_str(&result()).unwrap();
+    assert_eq!(json["packages"][0]["architecture"][0], "riscv64");
+  }
+}